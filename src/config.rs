@@ -8,33 +8,147 @@ use crate::storage::msg_index::MSG_INDEX_UNIT_SIZE;
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ConfigOptions {
+    // id of this broker, which is encoded in the message ids
+    pub broker_id: u32,
     pub msg_store_path: String,
     pub topic_store_path: String,
     pub index_file_size: u64,
     pub msg_store_file_size: u64,
+    pub checkpoint_interval_ms: u64,
+    pub flush_policy: FlushPolicy,
+    // interval and unflushed page count to trigger the asynchronous flush
+    pub flush_interval_ms: u64,
+    pub flush_min_pages: usize,
+    // retention of the data, per topic settings can shorten them, or extend the retention time
+    pub retention_ms: Option<u64>,
+    pub retention_bytes: Option<u64>,
+    pub clean_interval_ms: u64,
+    // how long the latest tombstone of a key is kept in the compacted topics
+    pub tombstone_retention_ms: u64,
+    // hash slot and entry number of each key index file
+    pub key_index_slot_num: usize,
+    pub key_index_entry_num: usize,
+    // rebuild the message indexes from this commit log offset on start
+    pub rebuild_index_from: Option<u64>,
+    // max payload size of a message, the compressed payloads are checked after decompressed
+    pub max_msg_size: usize,
     pub storage: StorageConfig,
+    pub encryption: EncryptionConfig,
 }
 
+/*
+ * When the written data is flushed to disk, before acknowledging the producer:
+ * Sync - flush on every write.
+ * GroupCommit - batch the concurrent writes into one flush.
+ * Async - flush in background on an interval or a count of unflushed pages, acknowledge
+ *         without waiting.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushPolicy {
+    #[default]
+    Sync,
+    GroupCommit,
+    Async,
+}
+
+/*
+ * Codec of the compressed message payloads, which are stored compressed in the commit log and
+ * the object store. It's chosen by the topic config or for each batch.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionCodec {
+    Lz4,
+    Zstd,
+    Snappy,
+}
+
+/*
+ * Encryption of the message records in the commit log, so they are encrypted in the object store
+ * too. The new records are encrypted by data keys wrapped by the active key, or written in
+ * plaintext without one. The id of the key is in each record header, the rotated keys should be
+ * kept to unwrap the data keys of the records written by them until the records expire.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub active_key_id: Option<u32>,
+    pub keys: Vec<EncryptionKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionKey {
+    // written in the record headers, 0 is reserved for the records not encrypted
+    pub id: u32,
+    // AES-256 key in base64
+    #[serde(skip_serializing)]
+    pub key: SecretString,
+}
+
+/*
+ * Tiered storage: the sealed commit log files are uploaded to the object store, and deleted
+ * locally after the local retention time. Reads of the deleted files are served from the object
+ * store.
+ *
+ * Diskless mode: the commit log lives in the object store, the local files are a write-ahead
+ * buffer of it. The local file is sealed and uploaded once it reaches the WAL segment size or
+ * the WAL flush interval, and deleted after uploaded.
+ */
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct StorageConfig {
+    pub tiered: bool,
+    pub local_retention_ms: u64,
+    // diskless mode implies the tiered storage, the local retention time is ignored
+    pub diskless: bool,
+    // the WAL segments roll at this size, or on the flush interval if the producers wait for the upload
+    pub wal_segment_size: u64,
+    pub wal_flush_interval_ms: u64,
+    // the writes are rejected when the size of the data not uploaded exceeds it
+    pub wal_max_size: u64,
+    pub ack_mode: AckMode,
+    // max time to wait for the upload with the upload ack mode
+    pub upload_ack_timeout_ms: u64,
+    // interval to check the sealed files to upload and the uploaded files to delete, the WAL
+    // segments which aren't full are sealed on it in the diskless mode
+    pub upload_interval_ms: u64,
+    // the sealed files are uploaded in the parts of this size, the progress is kept after each part
+    pub upload_part_size: usize,
+    // the remote files are read in the blocks of this size, and the blocks are cached in memory
+    pub read_block_size: usize,
+    pub read_cache_size: usize,
     #[serde(flatten)]
     pub store: ObjectStoreConfig,
 }
 
-impl Default for StorageConfig {
-    fn default() -> Self {
-        Self {
-            store: ObjectStoreConfig::default(),
-        }
-    }
+/*
+ * When the producer is acknowledged in the diskless mode:
+ * Local - the message is written to the local WAL, it's flushed by the flush policy.
+ * Upload - the WAL segment of the message is uploaded to the object store.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckMode {
+    #[default]
+    Local,
+    Upload,
 }
 
+/*
+ * Service of the object store:
+ * S3 - AWS S3 or the S3 compatible services.
+ * Oss - Aliyun OSS.
+ * Fs - a local directory, mainly for test.
+ * Memory - in memory and not shared between the operators, only for test.
+ */
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ObjectStoreConfig {
     S3(S3Config),
     Oss(OssConfig),
+    Fs(FsConfig),
+    Memory,
 }
 
 impl Default for ObjectStoreConfig {
@@ -45,15 +159,73 @@ impl Default for ObjectStoreConfig {
 
 const DEFAULT_INDEX_FILE_SIZE: u64 = 300000 * MSG_INDEX_UNIT_SIZE as u64;
 const DEFAULT_MSG_STORE_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+const DEFAULT_CHECKPOINT_INTERVAL_MS: u64 = 1000;
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 500;
+const DEFAULT_FLUSH_MIN_PAGES: usize = 4;
+const DEFAULT_CLEAN_INTERVAL_MS: u64 = 60 * 1000;
+const DEFAULT_TOMBSTONE_RETENTION_MS: u64 = 24 * 60 * 60 * 1000;
+const DEFAULT_KEY_INDEX_SLOT_NUM: usize = 500000;
+const DEFAULT_KEY_INDEX_ENTRY_NUM: usize = 2000000;
+const DEFAULT_MAX_MSG_SIZE: usize = 4 * 1024 * 1024;
+const DEFAULT_LOCAL_RETENTION_MS: u64 = 60 * 60 * 1000;
+const DEFAULT_UPLOAD_INTERVAL_MS: u64 = 1000;
+const DEFAULT_UPLOAD_PART_SIZE: usize = 8 * 1024 * 1024;
+const DEFAULT_WAL_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+const DEFAULT_WAL_FLUSH_INTERVAL_MS: u64 = 200;
+const DEFAULT_WAL_MAX_SIZE: u64 = 256 * 1024 * 1024;
+const DEFAULT_UPLOAD_ACK_TIMEOUT_MS: u64 = 30 * 1000;
+const DEFAULT_READ_BLOCK_SIZE: usize = 1024 * 1024;
+const DEFAULT_READ_CACHE_SIZE: usize = 256 * 1024 * 1024;
 
 impl Default for ConfigOptions {
     fn default() -> Self {
         Self {
+            broker_id: 0,
             msg_store_path: String::default(),
             topic_store_path: String::default(),
             index_file_size: DEFAULT_INDEX_FILE_SIZE,
             msg_store_file_size: DEFAULT_MSG_STORE_FILE_SIZE,
+            checkpoint_interval_ms: DEFAULT_CHECKPOINT_INTERVAL_MS,
+            flush_policy: FlushPolicy::default(),
+            flush_interval_ms: DEFAULT_FLUSH_INTERVAL_MS,
+            flush_min_pages: DEFAULT_FLUSH_MIN_PAGES,
+            retention_ms: None,
+            retention_bytes: None,
+            clean_interval_ms: DEFAULT_CLEAN_INTERVAL_MS,
+            tombstone_retention_ms: DEFAULT_TOMBSTONE_RETENTION_MS,
+            key_index_slot_num: DEFAULT_KEY_INDEX_SLOT_NUM,
+            key_index_entry_num: DEFAULT_KEY_INDEX_ENTRY_NUM,
+            rebuild_index_from: None,
+            max_msg_size: DEFAULT_MAX_MSG_SIZE,
             storage: StorageConfig::default(),
+            encryption: EncryptionConfig::default(),
+        }
+    }
+}
+
+impl StorageConfig {
+    // Whether the commit log files are uploaded to the object store.
+    pub fn is_tiered(&self) -> bool {
+        self.tiered || self.diskless
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            tiered: false,
+            local_retention_ms: DEFAULT_LOCAL_RETENTION_MS,
+            diskless: false,
+            wal_segment_size: DEFAULT_WAL_SEGMENT_SIZE,
+            wal_flush_interval_ms: DEFAULT_WAL_FLUSH_INTERVAL_MS,
+            wal_max_size: DEFAULT_WAL_MAX_SIZE,
+            ack_mode: AckMode::default(),
+            upload_ack_timeout_ms: DEFAULT_UPLOAD_ACK_TIMEOUT_MS,
+            upload_interval_ms: DEFAULT_UPLOAD_INTERVAL_MS,
+            upload_part_size: DEFAULT_UPLOAD_PART_SIZE,
+            read_block_size: DEFAULT_READ_BLOCK_SIZE,
+            read_cache_size: DEFAULT_READ_CACHE_SIZE,
+            store: ObjectStoreConfig::default(),
         }
     }
 }
@@ -106,6 +278,11 @@ impl Default for S3Config {
 }
 
 impl ConfigOptions {
+    // Size of the local commit log files, they are the WAL segments in the diskless mode.
+    pub fn commit_log_file_size(&self) -> u64 {
+        if self.storage.diskless { self.storage.wal_segment_size } else { self.msg_store_file_size }
+    }
+
     pub fn load_layered_options<'de, T: Serialize + Deserialize<'de> + Default>() -> Result<T> {
         let default_opts = T::default();
 
@@ -130,3 +307,8 @@ impl ConfigOptions {
     }
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FsConfig {
+    pub root: String,
+}
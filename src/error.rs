@@ -1,4 +1,5 @@
 use snafu::{Location, Snafu};
+use crate::config::CompressionCodec;
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
@@ -39,16 +40,93 @@ pub enum Error {
         msg: String,
     },
 
+    #[snafu(display("Corrupted record at offset {}: {}", offset, msg))]
+    CorruptedRecord {
+        location: Location,
+        offset: usize,
+        msg: String,
+    },
+
+    #[snafu(display("Record at offset {} is in version {}, the supported versions are up to {}", offset, version, max_version))]
+    UnsupportedRecordVersion {
+        location: Location,
+        offset: usize,
+        version: u8,
+        max_version: u8,
+    },
+
+    #[snafu(display("Failed to compress or decompress with {:?}: {}", codec, msg))]
+    Compression {
+        location: Location,
+        codec: CompressionCodec,
+        msg: String,
+    },
+
+    #[snafu(display("Failed to encrypt or decrypt with key {}: {}", key_id, msg))]
+    Encryption {
+        location: Location,
+        key_id: u32,
+        msg: String,
+    },
+
+    #[snafu(display("Failed to flush the commit log: {}", msg))]
+    FlushCommitLog {
+        location: Location,
+        msg: String,
+    },
+
+    #[snafu(display("Offset {} is out of range, the earliest available offset is {}", offset, min_offset))]
+    OffsetOutOfRange {
+        location: Location,
+        offset: usize,
+        min_offset: usize,
+    },
+
+    #[snafu(display("Message {} is not found, it may be removed or moved by compaction", msg_id))]
+    MessageNotFound {
+        location: Location,
+        msg_id: String,
+    },
+
     #[snafu(display("Failed to build the object store"))]
     ObjectStoreBuild {
         location: Location,
-        source: opendal::Error
+        #[snafu(source(from(opendal::Error, Box::new)))]
+        source: Box<opendal::Error>,
     },
 
     #[snafu(display("Failed to access the object store"))]
     ObjectStoreAccess {
         location: Location,
-        source: opendal::Error
+        #[snafu(source(from(opendal::Error, Box::new)))]
+        source: Box<opendal::Error>,
+    },
+
+    #[snafu(display("Failed to request the object store: {}", msg))]
+    ObjectStoreRequest {
+        location: Location,
+        msg: String,
+    },
+
+    #[snafu(display("WAL buffer is full: buffered_size={}, max_size={}", buffered_size, max_size))]
+    WalBufferFull {
+        location: Location,
+        buffered_size: usize,
+        max_size: u64,
+    },
+
+    #[snafu(display("Commit log isn't continuous for the upload: uploaded_offset={}, next_file_offset={}",
+                    uploaded_offset, next_file_offset))]
+    UploadGap {
+        location: Location,
+        uploaded_offset: usize,
+        next_file_offset: usize,
+    },
+
+    #[snafu(display("Failed to encode or decode the manifest"))]
+    ManifestCodec {
+        location: Location,
+        source: serde_json::Error,
     },
 }
 
@@ -1,28 +1,178 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
 use async_trait::async_trait;
 
 use axum::{routing::{get, post}, http::{Response}, body::{Body}, Router, debug_handler, Json};
-use axum::extract::State;
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
 use serde_json::Value;
-use crate::config::ConfigOptions;
+use snafu::{location, Location};
+use crate::config::{CompressionCodec, ConfigOptions};
+use crate::error::Error::InvalidInput;
+use crate::error::Result;
 
 use crate::server::Server;
-use crate::message::{ConsumeMessageRequest, Message};
+use crate::message::{CommitOffsetRequest, ConsumeMessageRequest, FetchOffsetRequest, FetchOffsetResult,
+                     GetMessageByIdRequest, Message, QueryByKeyRequest, QueryOffsetRequest, QueryOffsetResult,
+                     RebuildIndexRequest};
 use crate::storage::msg_store::MessageStore;
+use crate::storage::record::current_timestamp_ms;
 use crate::topic_mgr::{Topic, TopicMgr};
 
 pub struct HttpServer;
 
+const OCTET_STREAM: &str = "application/octet-stream";
+// metadata of the binary messages
+const TOPIC_HEADER: &str = "x-photonmq-topic";
+const QUEUE_ID_HEADER: &str = "x-photonmq-queue-id";
+const TIMESTAMP_HEADER: &str = "x-photonmq-timestamp";
+const KEY_HEADER: &str = "x-photonmq-key";
+// codec of the payload compressed by the producer
+const COMPRESSION_HEADER: &str = "x-photonmq-compression";
+// prefix of the message headers, like x-photonmq-header-trace-id
+const MSG_HEADER_PREFIX: &str = "x-photonmq-header-";
+// queue offset to consume next, which the consumer group commits after processing the messages
+const NEXT_OFFSET_HEADER: &str = "x-photonmq-next-offset";
+
+fn is_octet_stream(headers: &HeaderMap, header_name: HeaderName) -> bool {
+    headers.get(header_name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with(OCTET_STREAM))
+        .unwrap_or(false)
+}
+
+// The number in the header, out of the range of the type is invalid too.
+fn parse_header<T: FromStr>(name: &str, value: String) -> Result<T> {
+    value.parse::<T>().map_err(|_| InvalidInput {
+        location: location!(),
+        msg: format!("Invalid header {}: {}", name, value),
+    })
+}
+
+// The message of a binary body, whose metadata is in the headers and the payload is the body.
+fn decode_raw_message(headers: &HeaderMap, body: Bytes) -> Result<Message> {
+    let get_header = |name: &str| -> Result<Option<String>> {
+        match headers.get(name) {
+            Some(value) => value.to_str().map(|value| Some(value.to_string())).map_err(|_| InvalidInput {
+                location: location!(),
+                msg: format!("Invalid header {}", name),
+            }),
+            None => Ok(None),
+        }
+    };
+    let topic = get_header(TOPIC_HEADER)?.ok_or_else(|| InvalidInput {
+        location: location!(),
+        msg: format!("Missing header {}", TOPIC_HEADER),
+    })?;
+    let queue_id = match get_header(QUEUE_ID_HEADER)? {
+        Some(queue_id) => parse_header::<u32>(QUEUE_ID_HEADER, queue_id)?,
+        None => 0,
+    };
+    let timestamp = match get_header(TIMESTAMP_HEADER)? {
+        Some(timestamp) => parse_header::<u64>(TIMESTAMP_HEADER, timestamp)?,
+        None => current_timestamp_ms(),
+    };
+
+    let compression = match get_header(COMPRESSION_HEADER)? {
+        Some(codec) => Some(serde_json::from_value::<CompressionCodec>(Value::String(codec.clone()))
+            .map_err(|_| InvalidInput {
+                location: location!(),
+                msg: format!("Invalid header {}: {}", COMPRESSION_HEADER, codec),
+            })?),
+        None => None,
+    };
+
+    let mut msg_headers = HashMap::new();
+    for name in headers.keys() {
+        if let Some(msg_header) = name.as_str().strip_prefix(MSG_HEADER_PREFIX) {
+            if let Some(value) = get_header(name.as_str())? {
+                msg_headers.insert(msg_header.to_string(), value);
+            }
+        }
+    }
+
+    Ok(Message {
+        topic,
+        queue_id,
+        timestamp,
+        payload: Some(body.to_vec()),
+        key: get_header(KEY_HEADER)?,
+        header: if msg_headers.is_empty() { None } else { Some(msg_headers) },
+        compression,
+    })
+}
+
+/*
+ * The payload is the body, and the metadata is in the headers, like the binary produce request. A
+ * key or a message header which can't be a header value fails the response, instead of being dropped.
+ */
+fn encode_raw_message(msg: Message) -> Result<Response<Body>> {
+    let header_value = |name: &str, value: &str| -> Result<HeaderValue> {
+        HeaderValue::from_str(value).map_err(|_| InvalidInput {
+            location: location!(),
+            msg: format!("Invalid value of header {}: {:?}", name, value),
+        })
+    };
+
+    let mut builder = Response::builder()
+        .header(CONTENT_TYPE, OCTET_STREAM)
+        .header(TOPIC_HEADER, header_value(TOPIC_HEADER, msg.topic.as_str())?)
+        .header(QUEUE_ID_HEADER, msg.queue_id)
+        .header(TIMESTAMP_HEADER, msg.timestamp);
+    if let Some(key) = msg.key.as_deref() {
+        builder = builder.header(KEY_HEADER, header_value(KEY_HEADER, key)?);
+    }
+    // the codec in the same name as the JSON API
+    if let Some(Value::String(codec)) = msg.compression.and_then(|codec| serde_json::to_value(codec).ok()) {
+        builder = builder.header(COMPRESSION_HEADER, codec);
+    }
+    for (name, value) in msg.header.iter().flatten() {
+        let header_name = format!("{}{}", MSG_HEADER_PREFIX, name);
+        let name = HeaderName::try_from(header_name.as_str()).map_err(|_| InvalidInput {
+            location: location!(),
+            msg: format!("Invalid header name {}", header_name),
+        })?;
+        builder = builder.header(name, header_value(header_name.as_str(), value)?);
+    }
+
+    Ok(builder.body(Body::from(msg.payload.unwrap_or_default())).unwrap())
+}
+
+/*
+ * The message is JSON with a string payload, or a base64 one in payload_base64, or the raw payload
+ * of an application/octet-stream body, whose metadata is in the x-photonmq-* headers.
+ */
 #[debug_handler]
 async fn produce_message(State(msg_store_state): State<Arc<MessageStore>>,
-                         Json(produce_msg): Json<Message>) -> Response<Body> {
-    println!("produce message: {:?}", &produce_msg);
+                         headers: HeaderMap, body: Bytes) -> Response<Body> {
+    let decode_result = if is_octet_stream(&headers, CONTENT_TYPE) {
+        decode_raw_message(&headers, body)
+    } else {
+        serde_json::from_slice::<Message>(&body).map_err(|error| InvalidInput {
+            location: location!(),
+            msg: format!("Invalid message JSON: {}", error),
+        })
+    };
+    let produce_msg = match decode_result {
+        Ok(produce_msg) => produce_msg,
+        Err(error) => {
+            let err_msg = format!("Write message error: {:?}", error);
+            return Response::new(Body::from(err_msg));
+        }
+    };
+    println!("produce message: topic={}, queue_id={}, key={:?}, payload_size={:?}", &produce_msg.topic,
+             produce_msg.queue_id, &produce_msg.key, produce_msg.payload.as_ref().map(|payload| payload.len()));
 
     let write_result = msg_store_state.write_msg(produce_msg).await;
     match write_result {
-        Ok(_) => {
-            Response::new(Body::from("Hello, Produce Message"))
+        Ok(put_result) => {
+            let result_json = serde_json::to_string(&put_result).unwrap();
+            Response::new(Body::from(result_json))
         }
         Err(error) => {
             let err_msg = format!("Write message error: {:?}", error);
@@ -31,24 +181,171 @@ async fn produce_message(State(msg_store_state): State<Arc<MessageStore>>,
     }
 }
 
+// Codec to compress the payloads of the batch, which overrides the one of the topic.
+#[derive(Debug, Deserialize)]
+struct ProduceBatchParams {
+    compression: Option<CompressionCodec>,
+}
+
+/*
+ * The messages of one queue are a JSON list by default, or in the binary format of RawMessageHeader
+ * if the body is application/octet-stream. They are written at once.
+ */
 #[debug_handler]
-async fn consume_message(State(msg_store_state): State<Arc<MessageStore>>,
+async fn produce_batch(State(msg_store_state): State<Arc<MessageStore>>, Query(params): Query<ProduceBatchParams>,
+                       headers: HeaderMap, body: Bytes) -> Response<Body> {
+    let decode_result = if is_octet_stream(&headers, CONTENT_TYPE) {
+        Message::decode_raw_list(&body)
+    } else {
+        serde_json::from_slice::<Vec<Message>>(&body).map_err(|error| InvalidInput {
+            location: location!(),
+            msg: format!("Invalid message list JSON: {}", error),
+        })
+    };
+    let produce_msgs = match decode_result {
+        Ok(produce_msgs) => produce_msgs,
+        Err(error) => {
+            let err_msg = format!("Write batch error: {:?}", error);
+            return Response::new(Body::from(err_msg));
+        }
+    };
+    println!("produce batch: topic={:?}, queue_id={:?}, msg_count={}", produce_msgs.first().map(|msg| &msg.topic),
+             produce_msgs.first().map(|msg| msg.queue_id), produce_msgs.len());
+
+    let write_result = msg_store_state.write_batch(produce_msgs, params.compression).await;
+    match write_result {
+        Ok(put_result) => {
+            let result_json = serde_json::to_string(&put_result).unwrap();
+            Response::new(Body::from(result_json))
+        }
+        Err(error) => {
+            let err_msg = format!("Write batch error: {:?}", error);
+            Response::new(Body::from(err_msg))
+        }
+    }
+}
+
+/*
+ * The messages are returned in the binary format of RawMessageHeader if application/octet-stream is
+ * accepted. The offset to consume next is in the x-photonmq-next-offset header.
+ */
+#[debug_handler]
+async fn consume_message(State(msg_store_state): State<Arc<MessageStore>>, headers: HeaderMap,
                          Json(consume_msg): Json<ConsumeMessageRequest>) -> Response<Body> {
     println!("consume message: {:?}", &consume_msg);
 
-    let read_result = msg_store_state.read_msg(consume_msg).await;
+    let read_result = msg_store_state.consume(consume_msg).await;
     match read_result {
+        Ok(consume_result) if is_octet_stream(&headers, ACCEPT) => {
+            let mut buf = Vec::new();
+            for msg in &consume_result.msg_list {
+                if let Err(error) = msg.encode_raw(&mut buf) {
+                    return Response::new(Body::from(format!("consume message error: {:?}", error)));
+                }
+            }
+            Response::builder()
+                .header(CONTENT_TYPE, OCTET_STREAM)
+                .header(NEXT_OFFSET_HEADER, consume_result.next_offset)
+                .body(Body::from(buf)).unwrap()
+        }
+        Ok(consume_result) => {
+            let msg_json = serde_json::to_string(&consume_result.msg_list).unwrap();
+            Response::builder()
+                .header(NEXT_OFFSET_HEADER, consume_result.next_offset)
+                .body(Body::from(msg_json)).unwrap()
+        }
+        Err(error) => {
+            let err_msg = format!("consume message error: {:?}", error);
+            Response::new(Body::from(err_msg))
+        }
+    }
+}
+
+#[debug_handler]
+async fn query_offset(State(msg_store_state): State<Arc<MessageStore>>,
+                      Json(query_request): Json<QueryOffsetRequest>) -> Response<Body> {
+    println!("query offset: {:?}", &query_request);
+
+    let query_result = msg_store_state.get_offset_by_timestamp(
+        query_request.topic.as_str(), query_request.queue_id, query_request.timestamp).await;
+    match query_result {
+        Ok(queue_offset) => {
+            let result_json = serde_json::to_string(&QueryOffsetResult { queue_offset }).unwrap();
+            Response::new(Body::from(result_json))
+        }
+        Err(error) => {
+            let err_msg = format!("query offset error: {:?}", error);
+            Response::new(Body::from(err_msg))
+        }
+    }
+}
+
+#[debug_handler]
+async fn query_by_key(State(msg_store_state): State<Arc<MessageStore>>,
+                      Json(query_request): Json<QueryByKeyRequest>) -> Response<Body> {
+    println!("query by key: {:?}", &query_request);
+
+    let query_result = msg_store_state.query_by_key(
+        query_request.topic.as_str(), query_request.key.as_str(),
+        query_request.begin_timestamp..query_request.end_timestamp, query_request.max_msg_count).await;
+    match query_result {
         Ok(msg_list) => {
             let msg_json = serde_json::to_string(&msg_list).unwrap();
             Response::new(Body::from(msg_json))
         }
         Err(error) => {
-            let err_msg = format!("consume message error: {:?}", error);
+            let err_msg = format!("query by key error: {:?}", error);
+            Response::new(Body::from(err_msg))
+        }
+    }
+}
+
+#[debug_handler]
+async fn get_message_by_id(State(msg_store_state): State<Arc<MessageStore>>, headers: HeaderMap,
+                           Json(get_request): Json<GetMessageByIdRequest>) -> Response<Body> {
+    println!("get message by id: {:?}", &get_request);
+
+    let read_result = msg_store_state.get_message_by_id(get_request.msg_id.as_str()).await;
+    match read_result {
+        Ok(msg) if is_octet_stream(&headers, ACCEPT) => match encode_raw_message(msg) {
+            Ok(response) => response,
+            Err(error) => Response::new(Body::from(format!("get message by id error: {:?}", error))),
+        },
+        Ok(msg) => {
+            let msg_json = serde_json::to_string(&msg).unwrap();
+            Response::new(Body::from(msg_json))
+        }
+        Err(error) => {
+            let err_msg = format!("get message by id error: {:?}", error);
             Response::new(Body::from(err_msg))
         }
     }
 }
 
+#[debug_handler]
+async fn rebuild_index(State(msg_store_state): State<Arc<MessageStore>>,
+                       Json(rebuild_request): Json<RebuildIndexRequest>) -> Response<Body> {
+    println!("rebuild index: {:?}", &rebuild_request);
+
+    let rebuild_result = msg_store_state.rebuild_index(rebuild_request.from_offset).await;
+    match rebuild_result {
+        Ok(dispatched_offset) => {
+            Response::new(Body::from(format!("rebuild ok, dispatched to offset {}", dispatched_offset)))
+        }
+        Err(error) => {
+            let err_msg = format!("rebuild index error: {:?}", error);
+            Response::new(Body::from(err_msg))
+        }
+    }
+}
+
+#[debug_handler]
+async fn store_status(State(msg_store_state): State<Arc<MessageStore>>) -> Response<Body> {
+    let store_status = msg_store_state.get_store_status().await;
+    let result_json_str = serde_json::to_string(&store_status).unwrap();
+    Response::new(Body::from(result_json_str))
+}
+
 #[debug_handler]
 async fn create_topic(State(topic_mgr_state): State<Arc<TopicMgr>>,
                       Json(new_topic): Json<Topic>) -> Response<Body> {
@@ -86,26 +383,68 @@ async fn list_topics(State(topic_mgr_state): State<Arc<TopicMgr>>) -> Response<B
     Response::new(Body::from(result_json_str))
 }
 
+#[debug_handler]
+async fn commit_offset(State(topic_mgr_state): State<Arc<TopicMgr>>,
+                       Json(commit_request): Json<CommitOffsetRequest>) -> Response<Body> {
+    println!("commit offset: {:?}", &commit_request);
+
+    let commit_result = topic_mgr_state.commit_offset(commit_request.group.as_str(), commit_request.topic.as_str(),
+                                                      commit_request.queue_id, commit_request.offset);
+    match commit_result {
+        Ok(()) => Response::new(Body::from("commit ok")),
+        Err(error) => {
+            let err_msg = format!("commit offset error: {:?}", error);
+            Response::new(Body::from(err_msg))
+        }
+    }
+}
+
+#[debug_handler]
+async fn fetch_offset(State(topic_mgr_state): State<Arc<TopicMgr>>,
+                      Json(fetch_request): Json<FetchOffsetRequest>) -> Response<Body> {
+    let fetch_result = topic_mgr_state.fetch_offset(fetch_request.group.as_str(), fetch_request.topic.as_str(),
+                                                    fetch_request.queue_id);
+    match fetch_result {
+        Ok(offset) => {
+            let result_json = serde_json::to_string(&FetchOffsetResult { offset }).unwrap();
+            Response::new(Body::from(result_json))
+        }
+        Err(error) => {
+            let err_msg = format!("fetch offset error: {:?}", error);
+            Response::new(Body::from(err_msg))
+        }
+    }
+}
+
 
 #[async_trait]
 impl Server for HttpServer {
     async fn start(&self, listening: SocketAddr, config: ConfigOptions) {
-        let msg_store = MessageStore::new(&config).unwrap();
-        let msg_store_state = Arc::new(msg_store);
-
         let topic_mgr = TopicMgr::new(config.topic_store_path.as_str()).unwrap();
         let topic_mgr_state = Arc::new(topic_mgr);
 
+        let msg_store = MessageStore::new(&config, topic_mgr_state.clone()).await.unwrap();
+        msg_store.start();
+        let msg_store_state = Arc::new(msg_store);
+
         let message_routes = Router::new()
             .route("/produce_message", post(produce_message))
+            .route("/produce_batch", post(produce_batch))
             .route("/consume_message", get(consume_message))
-            .with_state(msg_store_state);
+            .route("/query_offset", get(query_offset))
+            .route("/query_by_key", get(query_by_key))
+            .route("/get_message_by_id", get(get_message_by_id))
+            .route("/admin/rebuild_index", post(rebuild_index))
+            .route("/admin/store_status", get(store_status))
+            .with_state(msg_store_state.clone());
 
         let topic_routes = Router::new()
             .route("/create_topic", post(create_topic))
             .route("/delete_topic", post(delete_topic))
             .route("/get_topic", get(get_topic))
             .route("/list_topics", get(list_topics))
+            .route("/commit_offset", post(commit_offset))
+            .route("/fetch_offset", get(fetch_offset))
             .with_state(topic_mgr_state);
 
         let app = Router::new()
@@ -126,5 +465,50 @@ impl Server for HttpServer {
         if let Err(e) = server.await {
             eprintln!("Axum HTTP server error: {}", e);
         }
+
+        if let Err(e) = msg_store_state.shutdown().await {
+            eprintln!("Message store shutdown error: {:?}", e);
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use axum::body::Bytes;
+    use axum::http::{HeaderMap, HeaderValue};
+    use crate::http_server::{decode_raw_message, encode_raw_message, KEY_HEADER, QUEUE_ID_HEADER, TOPIC_HEADER};
+    use crate::message::Message;
+
+    #[test]
+    pub fn test_raw_message_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TOPIC_HEADER, HeaderValue::from_static("test_topic"));
+        headers.insert(QUEUE_ID_HEADER, HeaderValue::from_static("4294967295"));
+        let msg = decode_raw_message(&headers, Bytes::from_static(b"payload")).unwrap();
+        assert_eq!(msg.queue_id, u32::MAX);
+        // the queue id out of the range of u32 isn't truncated
+        headers.insert(QUEUE_ID_HEADER, HeaderValue::from_static("4294967296"));
+        assert!(decode_raw_message(&headers, Bytes::from_static(b"payload")).is_err());
+
+        let mut msg = Message {
+            topic: "test_topic".to_string(),
+            queue_id: 0,
+            timestamp: 0,
+            payload: Some(b"payload".to_vec()),
+            key: Some("key".to_string()),
+            header: None,
+            compression: None,
+        };
+        let response = encode_raw_message(msg.clone()).unwrap();
+        assert_eq!(response.headers().get(KEY_HEADER).unwrap(), "key");
+        // the key or the header which can't be a header value isn't dropped
+        msg.key = Some("key\n".to_string());
+        assert!(encode_raw_message(msg.clone()).is_err());
+        msg.key = None;
+        msg.header = Some(HashMap::from([("trace id".to_string(), "1".to_string())]));
+        assert!(encode_raw_message(msg.clone()).is_err());
+        msg.header = Some(HashMap::from([("trace-id".to_string(), "1\r".to_string())]));
+        assert!(encode_raw_message(msg).is_err());
+    }
+}
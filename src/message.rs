@@ -1,55 +1,416 @@
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
-use snafu::ResultExt;
-use crate::error::{DecodeMsgBinSnafu, EncodeMsgBinSnafu, Result};
+use snafu::{location, Location, ResultExt};
+use crate::config::{CompressionCodec, FlushPolicy};
+use crate::error::Error::InvalidInput;
+use crate::error::{DecodeMsgBinSnafu, Result};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub topic: String,
     pub queue_id: u32,
     pub timestamp: u64,
-    pub payload: Option<String>,
+    // raw bytes, a string in JSON, or a base64 string in the payload_base64 field of JSON
+    #[serde(flatten, with = "json_payload")]
+    pub payload: Option<Vec<u8>>,
     pub key: Option<String>,
     pub header: Option<HashMap<String, String>>,
+    // codec of the payload if it's compressed, by the producer or for the consumer accepting it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionCodec>,
+}
+
+/*
+ * The message in the bincode layout of the commit log records written before the record header,
+ * see migration. The payload was a string then, its bincode layout is the same as the bytes.
+ */
+#[derive(Serialize, Deserialize)]
+struct BaselineMessage {
+    topic: String,
+    queue_id: u32,
+    timestamp: u64,
+    payload: Option<Vec<u8>>,
+    key: Option<String>,
+    header: Option<HashMap<String, String>>,
+}
+
+/*
+ * The payload is a plain string in the payload field of JSON. Binary payloads are base64 encoded in
+ * the payload_base64 field instead, it's written only for the payloads which aren't valid UTF-8.
+ */
+mod json_payload {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    struct JsonPayload {
+        #[serde(default)]
+        payload: Option<String>,
+        #[serde(default)]
+        payload_base64: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum JsonPayloadRef<'a> {
+        // null for a tombstone
+        Text { payload: Option<&'a str> },
+        Binary { payload_base64: String },
+    }
+
+    pub fn serialize<S: Serializer>(payload: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        let json_payload = match payload {
+            Some(payload) => match std::str::from_utf8(payload) {
+                Ok(payload) => JsonPayloadRef::Text { payload: Some(payload) },
+                Err(_) => JsonPayloadRef::Binary { payload_base64: STANDARD.encode(payload) },
+            },
+            None => JsonPayloadRef::Text { payload: None },
+        };
+        json_payload.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        match JsonPayload::deserialize(deserializer)? {
+            JsonPayload { payload_base64: Some(_), payload: Some(_) } =>
+                Err(de::Error::custom("payload and payload_base64 can't be both set")),
+            JsonPayload { payload_base64: Some(payload_base64), .. } =>
+                STANDARD.decode(payload_base64).map(Some).map_err(de::Error::custom),
+            JsonPayload { payload, .. } => Ok(payload.map(String::into_bytes)),
+        }
+    }
+}
+
+/*
+ * Metadata of a message in the binary consume response. Each message is written as the length of
+ * the metadata JSON in u32 big endian, the metadata JSON, then the payload_size bytes of the payload.
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawMessageHeader {
+    pub topic: String,
+    pub queue_id: u32,
+    pub timestamp: u64,
+    pub key: Option<String>,
+    pub header: Option<HashMap<String, String>>,
+    // none for a tombstone
+    pub payload_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionCodec>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ConsumeMessageRequest {
+    pub topic: String,
+    pub queue_id: u32,
+    // start queue offset, the committed offset of the group is used if it's absent
+    #[serde(default)]
+    pub offset: Option<usize>,
+    pub max_msg_count: usize,
+    // consumer group, it starts from the earliest message if it hasn't committed an offset
+    #[serde(default)]
+    pub group: Option<String>,
+    // codecs the consumer decompresses itself, the payloads compressed by them are returned as stored
+    #[serde(default)]
+    pub accepted_compressions: Vec<CompressionCodec>,
+}
+
+// Messages read from a queue, and the queue offset to consume next, which the consumer group commits.
+#[derive(Debug)]
+pub struct ConsumeMessageResult {
+    pub msg_list: Vec<Message>,
+    pub next_offset: usize,
+}
+
+// Commit the next queue offset the consumer group consumes from the queue.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommitOffsetRequest {
+    pub group: String,
     pub topic: String,
     pub queue_id: u32,
     pub offset: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchOffsetRequest {
+    pub group: String,
+    pub topic: String,
+    pub queue_id: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FetchOffsetResult {
+    // None if the group hasn't committed on the queue
+    pub offset: Option<usize>,
+}
+
+// Query the earliest queue offset whose message is stored at or after the timestamp in milliseconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryOffsetRequest {
+    pub topic: String,
+    pub queue_id: u32,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryOffsetResult {
+    pub queue_offset: usize,
+}
+
+// Query the messages of the key stored in the time range [begin_timestamp, end_timestamp).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryByKeyRequest {
+    pub topic: String,
+    pub key: String,
+    #[serde(default)]
+    pub begin_timestamp: u64,
+    #[serde(default = "max_timestamp")]
+    pub end_timestamp: u64,
     pub max_msg_count: usize,
 }
 
+fn max_timestamp() -> u64 {
+    u64::MAX
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueriedMessage {
+    pub queue_id: u32,
+    pub queue_offset: usize,
+    pub message: Message,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetMessageByIdRequest {
+    pub msg_id: String,
+}
+
+/*
+ * Message ID, which locates the message by the broker and the physical offset in the commit log.
+ * Compaction moves the records, so the message is found by its queue position in the message
+ * index once the offset points to another one.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageId {
+    pub broker_id: u32,
+    pub offset: u64,
+    pub topic: String,
+    pub queue_id: u32,
+    pub queue_offset: u64,
+}
+
+// Length of the fields before the topic in the encoded message id.
+const MESSAGE_ID_PREFIX_LEN: usize = 8 + 16 + 8 + 16;
+
+impl MessageId {
+    pub fn new(broker_id: u32, offset: u64, topic: &str, queue_id: u32, queue_offset: u64) -> Self {
+        MessageId { broker_id, offset, topic: topic.to_string(), queue_id, queue_offset }
+    }
+
+    // Whether the message in the queue position is the one of the ID.
+    pub fn matches(&self, topic: &str, queue_id: u32, queue_offset: u64) -> bool {
+        self.topic == topic && self.queue_id == queue_id && self.queue_offset == queue_offset
+    }
+
+    // Encode to the hex string of the broker id, the offset, the queue id, the queue offset and the topic bytes.
+    pub fn encode(&self) -> String {
+        let topic_hex: String = self.topic.bytes().map(|b| format!("{:02X}", b)).collect();
+        format!("{:08X}{:016X}{:08X}{:016X}{}", self.broker_id, self.offset, self.queue_id, self.queue_offset, topic_hex)
+    }
+
+    pub fn decode(msg_id: &str) -> Result<Self> {
+        let invalid_id = || InvalidInput {
+            location: location!(),
+            msg: format!("Invalid message id {}", msg_id),
+        };
+        if msg_id.len() <= MESSAGE_ID_PREFIX_LEN || !msg_id.len().is_multiple_of(2) || !msg_id.is_ascii() {
+            return Err(invalid_id());
+        }
+
+        let broker_id = u32::from_str_radix(&msg_id[..8], 16).map_err(|_| invalid_id())?;
+        let offset = u64::from_str_radix(&msg_id[8..24], 16).map_err(|_| invalid_id())?;
+        let queue_id = u32::from_str_radix(&msg_id[24..32], 16).map_err(|_| invalid_id())?;
+        let queue_offset = u64::from_str_radix(&msg_id[32..48], 16).map_err(|_| invalid_id())?;
+        let topic_bytes = (MESSAGE_ID_PREFIX_LEN..msg_id.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&msg_id[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>().map_err(|_| invalid_id())?;
+        let topic = String::from_utf8(topic_bytes).map_err(|_| invalid_id())?;
+
+        Ok(MessageId { broker_id, offset, topic, queue_id, queue_offset })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PutMessageResult {
+    pub msg_id: String,
+    pub queue_offset: usize,
+    // the flush policy applied before the result is returned
+    pub durability: FlushPolicy,
+    // whether the message has been uploaded to the object store before the result is returned
+    pub uploaded: bool,
+}
+
+// Result of a batch, its messages are assigned the queue offsets [start_queue_offset, end_queue_offset).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PutBatchResult {
+    pub msg_ids: Vec<String>,
+    pub start_queue_offset: usize,
+    pub end_queue_offset: usize,
+    pub durability: FlushPolicy,
+    pub uploaded: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoreStatus {
+    pub commit_log_min_offset: usize,
+    pub commit_log_max_offset: usize,
+    // messages before this offset of the commit log are visible to the consumers
+    pub dispatched_offset: usize,
+    // flushes of the group commit since the store started, each one for all the writers waiting then
+    pub group_commit_count: usize,
+    // cache of the data read from the object store, if the tiered storage is enabled
+    pub remote_read_cache: Option<CacheStats>,
+    // payload sizes of the messages written to each topic since the store started
+    pub compression: HashMap<String, CompressionStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompressionStats {
+    pub raw_bytes: u64,
+    pub stored_bytes: u64,
+    // raw bytes / stored bytes
+    pub compression_ratio: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hit_count: u64,
+    pub miss_count: u64,
+    pub cached_bytes: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RebuildIndexRequest {
+    pub from_offset: usize,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DispatchMessage {
     pub topic: String,
     pub queue_id: u32,
+    // queue offset assigned when the message is written
+    pub queue_offset: usize,
     pub msg_offset: usize,
     pub msg_size: usize,
     pub timestamp: u64,
+    pub key: Option<String>,
 }
 
 impl Message {
-    // Encode the message into a binary format
-    pub fn encode(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self).context(EncodeMsgBinSnafu)
+    // Encode the message in the bincode layout of the records written before the record header, to test migrating them.
+    #[cfg(test)]
+    pub fn encode_baseline(&self) -> Result<Vec<u8>> {
+        let baseline_msg = BaselineMessage {
+            topic: self.topic.clone(),
+            queue_id: self.queue_id,
+            timestamp: self.timestamp,
+            payload: self.payload.clone(),
+            key: self.key.clone(),
+            header: self.header.clone(),
+        };
+        bincode::serialize(&baseline_msg).context(crate::error::EncodeMsgBinSnafu)
+    }
+
+    // Decode the bincode message of the records written before the record header.
+    pub fn decode_baseline(encoded: &[u8]) -> Result<Self> {
+        let baseline_msg: BaselineMessage = bincode::deserialize(encoded).context(DecodeMsgBinSnafu)?;
+        Ok(Message {
+            topic: baseline_msg.topic,
+            queue_id: baseline_msg.queue_id,
+            timestamp: baseline_msg.timestamp,
+            payload: baseline_msg.payload,
+            key: baseline_msg.key,
+            header: baseline_msg.header,
+            compression: None,
+        })
+    }
+
+    // Encode the message into the binary consume response, see RawMessageHeader.
+    pub fn encode_raw(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let raw_header = RawMessageHeader {
+            topic: self.topic.clone(),
+            queue_id: self.queue_id,
+            timestamp: self.timestamp,
+            key: self.key.clone(),
+            header: self.header.clone(),
+            payload_size: self.payload.as_ref().map(|payload| payload.len()),
+            compression: self.compression,
+        };
+        let raw_header = serde_json::to_vec(&raw_header).map_err(|error| InvalidInput {
+            location: location!(),
+            msg: format!("Failed to encode the message header: {}", error),
+        })?;
+
+        buf.extend_from_slice(&(raw_header.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&raw_header);
+        if let Some(payload) = &self.payload {
+            buf.extend_from_slice(payload);
+        }
+        Ok(())
     }
 
-    // Decode a binary message into a CustomMessage
-    pub fn decode(encoded: &[u8]) -> Result<Self> {
-        bincode::deserialize(encoded).context(DecodeMsgBinSnafu)
+    // Decode the messages in the binary format written by encode_raw.
+    pub fn decode_raw_list(data: &[u8]) -> Result<Vec<Message>> {
+        let invalid_data = |msg: &str| InvalidInput {
+            location: location!(),
+            msg: msg.to_string(),
+        };
+
+        let mut msgs = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let header_len = data.get(pos..pos + 4)
+                .map(|len_bytes| u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize)
+                .ok_or_else(|| invalid_data("Incomplete message header length"))?;
+            pos += 4;
+            let raw_header: RawMessageHeader = data.get(pos..pos + header_len)
+                .and_then(|header_bytes| serde_json::from_slice(header_bytes).ok())
+                .ok_or_else(|| invalid_data("Invalid message header"))?;
+            pos += header_len;
+
+            let payload = match raw_header.payload_size {
+                Some(payload_size) => {
+                    let payload = data.get(pos..pos + payload_size)
+                        .ok_or_else(|| invalid_data("Incomplete message payload"))?;
+                    pos += payload_size;
+                    Some(payload.to_vec())
+                }
+                None => None,
+            };
+            msgs.push(Message {
+                topic: raw_header.topic,
+                queue_id: raw_header.queue_id,
+                timestamp: raw_header.timestamp,
+                payload,
+                key: raw_header.key,
+                header: raw_header.header,
+                compression: raw_header.compression,
+            });
+        }
+
+        Ok(msgs)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::ops::Deref;
-    use crate::message::Message;
+    use crate::config::CompressionCodec;
+    use crate::message::{Message, MessageId, RawMessageHeader};
 
     #[tokio::test]
     pub async fn encode_and_decode() {
-        let payload = "This is a custom message payload.".to_string();
+        let payload = b"This is a custom message payload.".to_vec();
 
         let message = Message {
             topic: "my_topic".to_string(),
@@ -58,16 +419,126 @@ mod tests {
             timestamp: 1631894400,
             payload: Some(payload),
             header: None,
+            compression: None,
         };
 
         // Encode the message into a binary format
-        let encoded_message = message.encode();
+        let encoded_message = message.encode_baseline();
 
         // Decode the binary message into a CustomMessage
-        let decode_result = Message::decode(encoded_message.unwrap().deref());
+        let decode_result = Message::decode_baseline(encoded_message.unwrap().deref());
         match decode_result {
             Ok(decode_msg) => { println!("{:?}", decode_msg); }
             Err(error) => { println!("Failed to decode the message {:?}", error); }
         }
     }
+
+    #[tokio::test]
+    pub async fn binary_payload() {
+        let message = Message {
+            topic: "my_topic".to_string(),
+            queue_id: 0,
+            key: None,
+            timestamp: 1631894400,
+            payload: Some(vec![0, 159, 146, 150, 255]),
+            header: None,
+            compression: None,
+        };
+
+        // base64 in JSON, as the payload isn't valid UTF-8
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["payload_base64"], "AJ+Slv8=");
+        assert!(json.get("payload").is_none());
+        let decoded: Message = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.payload, message.payload);
+        assert!(serde_json::from_str::<Message>(
+            r#"{"topic": "my_topic", "queue_id": 0, "timestamp": 0, "payload_base64": "not base64!"}"#).is_err());
+        assert!(serde_json::from_str::<Message>(
+            r#"{"topic": "my_topic", "queue_id": 0, "timestamp": 0, "payload": "a", "payload_base64": "YQ=="}"#).is_err());
+
+        // plain string in JSON by default
+        let decoded: Message = serde_json::from_str(
+            r#"{"topic": "my_topic", "queue_id": 0, "timestamp": 0, "payload": "not base64!"}"#).unwrap();
+        assert_eq!(decoded.payload.as_deref(), Some(b"not base64!".as_slice()));
+        assert_eq!(serde_json::to_value(&decoded).unwrap()["payload"], "not base64!");
+        let tombstone: Message = serde_json::from_str(r#"{"topic": "my_topic", "queue_id": 0, "timestamp": 0}"#).unwrap();
+        assert_eq!(tombstone.payload, None);
+        assert!(serde_json::to_value(&tombstone).unwrap()["payload"].is_null());
+
+        // raw bytes in the bincode of the baseline records
+        let decoded = Message::decode_baseline(&message.encode_baseline().unwrap()).unwrap();
+        assert_eq!(decoded.payload, message.payload);
+    }
+
+    #[tokio::test]
+    pub async fn decode_string_payload() {
+        // the string payload of the baseline records
+        #[derive(serde::Serialize)]
+        struct OldMessage {
+            topic: String,
+            queue_id: u32,
+            timestamp: u64,
+            payload: Option<String>,
+            key: Option<String>,
+            header: Option<std::collections::HashMap<String, String>>,
+        }
+        let old_message = OldMessage {
+            topic: "my_topic".to_string(),
+            queue_id: 1,
+            timestamp: 1631894400,
+            payload: Some("old payload".to_string()),
+            key: Some("key".to_string()),
+            header: None,
+        };
+
+        let decoded = Message::decode_baseline(&bincode::serialize(&old_message).unwrap()).unwrap();
+        assert_eq!(decoded.payload.as_deref(), Some(b"old payload".as_slice()));
+        assert_eq!(decoded.key.as_deref(), Some("key"));
+    }
+
+    #[tokio::test]
+    pub async fn encode_raw_message() {
+        let message = Message {
+            topic: "my_topic".to_string(),
+            queue_id: 0,
+            key: None,
+            timestamp: 0,
+            payload: Some(vec![1, 2, 3]),
+            header: None,
+            compression: Some(CompressionCodec::Lz4),
+        };
+        let mut buf = Vec::new();
+        message.encode_raw(&mut buf).unwrap();
+
+        let header_len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+        let raw_header: RawMessageHeader = serde_json::from_slice(&buf[4..4 + header_len]).unwrap();
+        assert_eq!(raw_header.payload_size, Some(3));
+        assert_eq!(&buf[4 + header_len..], &[1, 2, 3]);
+
+        // a tombstone after it
+        Message { payload: None, ..message }.encode_raw(&mut buf).unwrap();
+        let msgs = Message::decode_raw_list(&buf).unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].payload, Some(vec![1, 2, 3]));
+        assert_eq!(msgs[0].compression, Some(CompressionCodec::Lz4));
+        assert_eq!(msgs[1].payload, None);
+        assert!(Message::decode_raw_list(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn encode_and_decode_msg_id() {
+        let msg_id = MessageId::new(3, 1024, "t1", 2, 7);
+        let encoded_msg_id = msg_id.encode();
+        assert_eq!(encoded_msg_id, "0000000300000000000004000000000200000000000000077431");
+        assert_eq!(MessageId::decode(encoded_msg_id.as_str()).unwrap(), msg_id);
+
+        assert!(msg_id.matches("t1", 2, 7));
+        assert!(!msg_id.matches("t1", 2, 8));
+        assert!(!msg_id.matches("t2", 2, 7));
+
+        assert!(MessageId::decode("00000003").is_err());
+        assert!(MessageId::decode("000000030000000000000400000000020000000000000007").is_err());
+        assert!(MessageId::decode("000000030000000000000400000000020000000000000007743").is_err());
+        assert!(MessageId::decode("00000003000000000000040000000002000000000000000774GG").is_err());
+    }
 }
@@ -6,7 +6,6 @@ mod config;
 mod error;
 mod storage;
 
-use std::env;
 use std::error::Error;
 use std::net::SocketAddr;
 use dotenv::dotenv;
@@ -5,3 +5,18 @@ mod index_store;
 pub mod msg_index;
 mod mmap_file;
 mod object_store;
+mod multipart_upload;
+pub mod record;
+mod msg_record;
+mod compression;
+mod encryption;
+mod checkpoint;
+mod compaction;
+mod migration;
+mod key_index;
+mod tiered_store;
+mod block_cache;
+mod manifest;
+mod segment;
+#[cfg(test)]
+mod memory_segment;
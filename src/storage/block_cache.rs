@@ -0,0 +1,165 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::message::CacheStats;
+
+// Cached block of a file: (file path, block index)
+type BlockKey = (String, usize);
+
+struct CachedBlock {
+    data: Arc<Vec<u8>>,
+    // access sequence, the block with the smallest one is evicted first
+    access_seq: u64,
+}
+
+#[derive(Default)]
+struct BlockCacheState {
+    blocks: HashMap<BlockKey, CachedBlock>,
+    // access sequence -> block, in the order of the last access
+    lru_list: BTreeMap<u64, BlockKey>,
+    next_access_seq: u64,
+    cached_bytes: usize,
+}
+
+/*
+ * LRU cache of the blocks read from the object store, shared by all the remote files. Files are
+ * read in the blocks aligned to the block size, and the total size of the cached blocks is bounded
+ * by the capacity.
+ */
+pub struct BlockCache {
+    capacity: usize,
+    block_size: usize,
+    state: Mutex<BlockCacheState>,
+    hit_count: AtomicU64,
+    miss_count: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize, block_size: usize) -> Self {
+        BlockCache {
+            capacity,
+            block_size: block_size.max(1),
+            state: Mutex::new(BlockCacheState::default()),
+            hit_count: AtomicU64::new(0),
+            miss_count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn get(&self, file_path: &str, block_index: usize) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        let access_seq = state.next_access_seq;
+        let key = (file_path.to_string(), block_index);
+
+        let cached_block = match state.blocks.get_mut(&key) {
+            Some(cached_block) => cached_block,
+            None => {
+                self.miss_count.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        let last_access_seq = cached_block.access_seq;
+        cached_block.access_seq = access_seq;
+        let data = cached_block.data.clone();
+
+        state.lru_list.remove(&last_access_seq);
+        state.lru_list.insert(access_seq, key);
+        state.next_access_seq += 1;
+        self.hit_count.fetch_add(1, Ordering::Relaxed);
+
+        Some(data)
+    }
+
+    // Cache the block, evict the least recently used ones if the capacity is exceeded.
+    pub fn put(&self, file_path: &str, block_index: usize, data: Arc<Vec<u8>>) {
+        if data.len() > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let key = (file_path.to_string(), block_index);
+        if let Some(cached_block) = state.blocks.remove(&key) {
+            state.lru_list.remove(&cached_block.access_seq);
+            state.cached_bytes -= cached_block.data.len();
+        }
+
+        while state.cached_bytes + data.len() > self.capacity {
+            let evicted_key = match state.lru_list.pop_first() {
+                Some((_, evicted_key)) => evicted_key,
+                None => break,
+            };
+            if let Some(evicted_block) = state.blocks.remove(&evicted_key) {
+                state.cached_bytes -= evicted_block.data.len();
+            }
+        }
+
+        let access_seq = state.next_access_seq;
+        state.next_access_seq += 1;
+        state.cached_bytes += data.len();
+        state.lru_list.insert(access_seq, key.clone());
+        state.blocks.insert(key, CachedBlock { data, access_seq });
+    }
+
+    // Drop the cached blocks of the file, it's deleted or rewritten.
+    pub fn invalidate(&self, file_path: &str) {
+        let mut state = self.state.lock().unwrap();
+        let invalid_keys: Vec<BlockKey> = state.blocks.keys()
+            .filter(|(path, _)| path == file_path)
+            .cloned()
+            .collect();
+
+        for key in invalid_keys {
+            if let Some(cached_block) = state.blocks.remove(&key) {
+                state.lru_list.remove(&cached_block.access_seq);
+                state.cached_bytes -= cached_block.data.len();
+            }
+        }
+    }
+
+    pub fn get_stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            hit_count: self.hit_count.load(Ordering::Relaxed),
+            miss_count: self.miss_count.load(Ordering::Relaxed),
+            cached_bytes: state.cached_bytes,
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::storage::block_cache::BlockCache;
+
+    #[tokio::test]
+    pub async fn test_lru_eviction() {
+        let block_cache = BlockCache::new(300, 100);
+        for block_index in 0..3 {
+            block_cache.put("file_a", block_index, Arc::new(vec![block_index as u8; 100]));
+        }
+        assert_eq!(block_cache.get("file_a", 0).unwrap()[0], 0);
+
+        // the block 1 is the least recently used one
+        block_cache.put("file_b", 0, Arc::new(vec![9; 100]));
+        assert!(block_cache.get("file_a", 1).is_none());
+        assert!(block_cache.get("file_a", 0).is_some());
+        assert!(block_cache.get("file_a", 2).is_some());
+        assert!(block_cache.get("file_b", 0).is_some());
+
+        // blocks larger than the capacity aren't cached
+        block_cache.put("file_c", 0, Arc::new(vec![0; 400]));
+        assert!(block_cache.get("file_c", 0).is_none());
+
+        block_cache.invalidate("file_a");
+        assert!(block_cache.get("file_a", 0).is_none());
+
+        let cache_stats = block_cache.get_stats();
+        assert_eq!(cache_stats.hit_count, 4);
+        assert_eq!(cache_stats.miss_count, 3);
+        assert_eq!(cache_stats.cached_bytes, 100);
+    }
+}
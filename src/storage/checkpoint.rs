@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use crate::error::{DecodeMsgBinSnafu, EncodeMsgBinSnafu, Result};
+use crate::storage::record::{read_record_file, write_record_file};
+
+const CHECKPOINT_FILE_NAME: &str = "checkpoint";
+
+/*
+ * Positions which have been durably flushed, recovery starts from them.
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    // physical offset of the commit log
+    pub commit_log_offset: u64,
+    // index count of each message index: topic -> queue id -> index offset
+    pub index_offsets: HashMap<String, HashMap<u32, u64>>,
+    // store timestamp of the last dispatched message
+    pub dispatch_timestamp: u64,
+}
+
+impl Checkpoint {
+    fn checkpoint_path(store_path: &str) -> PathBuf {
+        PathBuf::from(store_path).join(CHECKPOINT_FILE_NAME)
+    }
+
+    pub fn load(store_path: &str) -> Result<Option<Self>> {
+        let checkpoint_body = match read_record_file(&Self::checkpoint_path(store_path))? {
+            Some(checkpoint_body) => checkpoint_body,
+            None => return Ok(None),
+        };
+        let checkpoint: Checkpoint = bincode::deserialize(&checkpoint_body).context(DecodeMsgBinSnafu)?;
+
+        println!("loaded checkpoint: {:?}", &checkpoint);
+        Ok(Some(checkpoint))
+    }
+
+    // Write to a temporary file then rename, so a crash won't leave a partial checkpoint.
+    pub fn flush(&self, store_path: &str) -> Result<()> {
+        let checkpoint_body = bincode::serialize(self).context(EncodeMsgBinSnafu)?;
+        write_record_file(&Self::checkpoint_path(store_path), &checkpoint_body)
+    }
+
+    pub fn get_index_offset(&self, topic: &str, queue_id: u32) -> Option<usize> {
+        self.index_offsets.get(topic)
+            .and_then(|queue_offsets| queue_offsets.get(&queue_id))
+            .map(|offset| *offset as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use crate::error::Result;
+    use crate::storage::checkpoint::Checkpoint;
+
+    pub fn create_temp_dir(prefix: &str) -> TempDir {
+        tempfile::Builder::new().prefix(prefix).tempdir().unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn test_flush_load() -> Result<()> {
+        let dir_path = create_temp_dir("checkpoint_test");
+        let store_path = dir_path.path().to_str().unwrap();
+
+        assert!(Checkpoint::load(store_path)?.is_none());
+
+        let mut checkpoint = Checkpoint {
+            commit_log_offset: 1024,
+            dispatch_timestamp: 1631894400,
+            ..Default::default()
+        };
+        checkpoint.index_offsets.entry("test_topic".to_string()).or_default().insert(1, 16);
+        checkpoint.flush(store_path)?;
+
+        let loaded_checkpoint = Checkpoint::load(store_path)?.unwrap();
+        assert_eq!(loaded_checkpoint.commit_log_offset, 1024);
+        assert_eq!(loaded_checkpoint.dispatch_timestamp, 1631894400);
+        assert_eq!(loaded_checkpoint.get_index_offset("test_topic", 1), Some(16));
+        assert_eq!(loaded_checkpoint.get_index_offset("test_topic", 2), None);
+
+        Ok(())
+    }
+}
@@ -1,56 +1,285 @@
-use std::fs;
+use std::collections::HashMap;
 use std::path::PathBuf;
-use memmap2::MmapMut;
-use snafu::{location, Location, ResultExt};
-use crate::error::Error::InvalidInput;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use snafu::{location, Location};
+use crate::error::Error::{CorruptedRecord, InvalidInput, UploadGap};
 use crate::storage::msg_index::MessageIndexUnit;
-use crate::error::{Result, StdIOSnafu};
-use crate::storage::mapped_file_queue::MappedFileQueue;
+use crate::error::Result;
+use crate::storage::mapped_file_queue::{MappedFileQueue, RemoteSegments};
+use crate::storage::mmap_file::MemoryMappedFile;
+use crate::storage::segment::Segment;
+use crate::storage::msg_record::check_msg_record;
+use crate::storage::record::{check_record_version, current_timestamp_ms, parse_record_size, RECORD_MAGIC, RECORD_PREFIX_SIZE};
 
-pub struct CommitLog {
-    mapped_file_queue: MappedFileQueue,
+pub const COMMIT_LOG_DIR: &str = "commitlog";
+
+pub struct CommitLog<S: Segment = MemoryMappedFile> {
+    store_path: String,
+    mapped_file_queue: MappedFileQueue<S>,
+    // next queue offset of each message queue: topic -> queue id -> queue offset
+    queue_offset_table: HashMap<String, HashMap<u32, usize>>,
+    last_store_timestamp: u64,
+    // data before this offset is uploaded or being uploaded to the object store, it mustn't be rewritten
+    uploading_offset: usize,
+}
+
+// A sealed file to upload, its offset range ends at the start of the next file.
+pub struct SealedFile {
+    pub start_offset: usize,
+    pub end_offset: usize,
+    // size of the data in the file, which is read in parts while uploading
+    pub data_size: usize,
+    // last modified time in milliseconds
+    pub last_modified: u64,
+}
+
+impl<S: Segment> CommitLog<S> where S::Context: Default {
+    pub async fn new(store_path: &str, max_file_size: u64, checkpoint_offset: Option<usize>) -> Result<Self> {
+        Self::with_context(S::Context::default(), store_path, max_file_size, checkpoint_offset).await
+    }
 }
 
-impl CommitLog {
-    pub fn new(store_path: &str, max_file_size: u64) -> Result<Self> {
+impl<S: Segment> CommitLog<S> {
+    // Open the commit log whose files are opened with the given context.
+    pub async fn with_context(context: S::Context, store_path: &str, max_file_size: u64,
+                              checkpoint_offset: Option<usize>) -> Result<Self> {
         let base_dir = PathBuf::from(store_path);
-        let commit_log_dir = base_dir.join("commitlog");
+        let commit_log_dir = base_dir.join(COMMIT_LOG_DIR);
+
+        let mut mapped_file_queue = MappedFileQueue::with_context(
+            context, commit_log_dir.as_path().to_str().unwrap(), max_file_size)?;
+        // stop at the first record or batch which is incomplete or fails the checksum, fail at a newer version one
+        mapped_file_queue.recovery(|data: &[u8], offset: usize| {
+            check_record_version(&data[offset..], offset)?;
+            Ok(check_msg_record(&data[offset..]))
+        }, checkpoint_offset).await?;
+
+        Ok(CommitLog {
+            store_path: commit_log_dir.as_path().to_str().unwrap().to_string(),
+            mapped_file_queue,
+            queue_offset_table: HashMap::new(),
+            last_store_timestamp: 0,
+            uploading_offset: 0,
+        })
+    }
+
+    pub fn get_max_offset(&self) -> usize {
+        self.mapped_file_queue.get_max_offset()
+    }
+
+    pub fn get_min_offset(&self) -> usize {
+        self.mapped_file_queue.get_min_offset()
+    }
+
+    // The min offset of the stored records, including the ones only in the uploaded segments.
+    pub fn get_store_min_offset(&self) -> usize {
+        self.mapped_file_queue.get_readable_min_offset()
+    }
+
+    // Read the records which are deleted from the local files from the uploaded segments.
+    pub fn set_remote_segments(&mut self, remote_segments: Arc<dyn RemoteSegments>) {
+        self.mapped_file_queue.set_remote_segments(remote_segments);
+    }
+
+    pub fn get_flushed_offset(&self) -> usize {
+        self.mapped_file_queue.get_flushed_offset()
+    }
+
+    // Size of the data which hasn't been flushed to disk.
+    pub fn get_unflushed_size(&self) -> usize {
+        self.mapped_file_queue.get_max_offset() - self.mapped_file_queue.get_flushed_offset()
+    }
+
+    pub async fn flush(&mut self) -> Result<usize> {
+        self.mapped_file_queue.flush().await
+    }
+
+    /*
+     * Delete the oldest files which are expired by the retention time or size, return the min
+     * offset of the left data. Files ending after the given offset are kept, in the tiered storage
+     * they haven't been uploaded.
+     */
+    pub async fn delete_expired_files(&mut self, retention_ms: Option<u64>, retention_bytes: Option<u64>,
+                                      max_deletable_offset: usize) -> Result<usize> {
+        let max_offset = self.get_max_offset();
+        let mapped_files = self.mapped_file_queue.get_mapped_files();
+
+        let mut expired_offset = self.get_min_offset();
+        for mapped_file in mapped_files.iter().take(mapped_files.len().saturating_sub(1)) {
+            if mapped_file.get_max_offset() > max_deletable_offset {
+                break;
+            }
+            let expired_by_time = match retention_ms {
+                Some(retention_ms) => mapped_file.is_older_than(retention_ms)?,
+                None => false,
+            };
+            let expired_by_size = retention_bytes
+                .map(|retention_bytes| (max_offset - mapped_file.get_min_offset()) as u64 > retention_bytes)
+                .unwrap_or(false);
+
+            if !expired_by_time && !expired_by_size {
+                break;
+            }
+            expired_offset = mapped_file.get_max_offset();
+        }
+
+        self.mapped_file_queue.delete_files_before(expired_offset).await?;
+        Ok(self.get_min_offset())
+    }
+
+    // Offset ranges of the sealed files, which won't be written any more.
+    pub fn get_sealed_file_ranges(&self) -> Vec<(usize, usize)> {
+        let mapped_files = self.mapped_file_queue.get_mapped_files();
+        mapped_files.iter().take(mapped_files.len().saturating_sub(1))
+            .map(|f| (f.get_min_offset(), f.get_max_offset()))
+            .collect()
+    }
+
+    pub fn get_uploading_offset(&self) -> usize {
+        self.uploading_offset
+    }
+
+    pub fn set_uploading_offset(&mut self, uploading_offset: usize) {
+        self.uploading_offset = uploading_offset;
+    }
+
+    /*
+     * Find the sealed file which starts at the given offset and ends before the max offset, and
+     * mark it as uploading. The uploaded data must be continuous, it fails if the file is missing.
+     */
+    pub fn take_file_to_upload(&mut self, offset: usize, max_offset: usize) -> Result<Option<SealedFile>> {
+        let (start_offset, data_end_offset) = match self.get_sealed_file_ranges().into_iter()
+            .find(|(start_offset, _)| *start_offset >= offset) {
+            Some((start_offset, _)) if start_offset != offset => return Err(UploadGap {
+                location: location!(),
+                uploaded_offset: offset,
+                next_file_offset: start_offset,
+            }),
+            Some(file_range) => file_range,
+            None => return Ok(None),
+        };
+        // the range of a sealed file ends at the start of its next file
+        let end_offset = self.mapped_file_queue.get_next_file_start_offset(start_offset).unwrap_or(data_end_offset);
+        if end_offset > max_offset {
+            return Ok(None);
+        }
+
+        let data_size = data_end_offset - start_offset;
+        let mapped_files = self.mapped_file_queue.get_mapped_files();
+        let file_index = mapped_files.partition_point(|f| f.get_min_offset() < start_offset);
+        let last_modified = mapped_files[file_index].get_last_modified()?
+            .duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+
+        self.uploading_offset = self.uploading_offset.max(end_offset);
+        Ok(Some(SealedFile { start_offset, end_offset, data_size, last_modified }))
+    }
+
+    // Seal the file being written even if it's not full, so it can be uploaded.
+    pub fn seal_active_file(&mut self) -> bool {
+        self.mapped_file_queue.roll_file()
+    }
 
-        fs::create_dir_all(&commit_log_dir).context(StdIOSnafu)?;
-        let read_dir = commit_log_dir.read_dir().unwrap();
-        let file_num = read_dir.count();
+    // Drop all the local files and start writing from the given offset, the data before it is in the object store.
+    pub async fn reset_offset(&mut self, offset: usize) -> Result<()> {
+        println!("reset commit log: offset={}", offset);
+        self.mapped_file_queue.reset(offset).await
+    }
+
+    pub async fn read_file_data(&self, offset: usize, size: usize) -> Result<Vec<u8>> {
+        self.mapped_file_queue.read(offset, size).await
+    }
 
-        let mut mapped_file_queue = MappedFileQueue::new(
-            commit_log_dir.as_path().to_str().unwrap(), max_file_size)?;
-        if file_num != 0 {
-            mapped_file_queue.recovery(|mmap: &MmapMut, offset: usize| {
-                let size = std::mem::size_of::<usize>();
-                if offset + size < mmap.len() {
-                    let mut buffer: Vec<u8> = vec![0; size];
-                    buffer.copy_from_slice(&mmap[offset..offset + size]);
+    /*
+     * Delete the oldest local files which have been uploaded before the given offset, and not
+     * modified for the local retention time. Return the deleted file count.
+     */
+    pub async fn delete_uploaded_files(&mut self, uploaded_offset: usize, local_retention_ms: u64) -> Result<usize> {
+        let mapped_files = self.mapped_file_queue.get_mapped_files();
 
-                    let msg_len = usize::from_le_bytes(buffer.as_slice().try_into().unwrap());
-                    if msg_len > 0 {
-                        return Some(msg_len + size);
-                    }
-                }
-                None
-            });
+        let mut deleted_offset = self.get_min_offset();
+        for mapped_file in mapped_files.iter().take(mapped_files.len().saturating_sub(1)) {
+            if mapped_file.get_max_offset() > uploaded_offset || !mapped_file.is_older_than(local_retention_ms)? {
+                break;
+            }
+            deleted_offset = mapped_file.get_max_offset();
         }
 
-        Ok(CommitLog { mapped_file_queue })
+        self.mapped_file_queue.delete_files_before(deleted_offset).await
+    }
+
+    /*
+     * Copy the records kept by compaction to a new file, which replaces the sealed file at the
+     * given offset later. The new file keeps the modified time of the sealed one, which the
+     * retention and the upload go by. Return the end offset of the copied records.
+     */
+    pub async fn write_compacted_file(&self, start_offset: usize, records: &[(usize, usize)]) -> Result<usize> {
+        let mapped_files = self.mapped_file_queue.get_mapped_files();
+        let sealed_file = mapped_files.iter().find(|f| f.get_min_offset() == start_offset).ok_or_else(|| InvalidInput {
+            location: location!(),
+            msg: format!("No sealed file starts at offset {}", start_offset),
+        })?;
+        let last_modified = sealed_file.get_last_modified()?;
+
+        let compacted_file_path = compacted_file_path(self.store_path.as_str(), start_offset);
+        let mut compacted_file = self.mapped_file_queue.open_file(compacted_file_path.to_str().unwrap(), start_offset)?;
+        for (record_offset, record_size) in records {
+            let record = self.mapped_file_queue.read(*record_offset, *record_size).await?;
+            compacted_file.append(&record).await?;
+        }
+        compacted_file.flush_from(start_offset).await?;
+        compacted_file.set_last_modified(last_modified)?;
+
+        Ok(compacted_file.get_max_offset())
+    }
+
+    // Replace the sealed file with the compacted one, whose data ends at the given offset.
+    pub async fn replace_with_compacted_file(&mut self, start_offset: usize, max_offset: usize) -> Result<()> {
+        let compacted_file_path = compacted_file_path(self.store_path.as_str(), start_offset);
+        self.mapped_file_queue.replace_file(start_offset, compacted_file_path.to_str().unwrap(), max_offset).await
+    }
+
+    pub fn set_queue_offset_table(&mut self, queue_offset_table: HashMap<String, HashMap<u32, usize>>) {
+        self.queue_offset_table = queue_offset_table;
+    }
+
+    // The queue offset of the next message, without taking it.
+    pub fn peek_queue_offset(&self, topic: &str, queue_id: u32) -> usize {
+        self.queue_offset_table.get(topic)
+            .and_then(|topic_offset_map| topic_offset_map.get(&queue_id))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Assign the queue offsets for the messages written, they are indexed in the same order.
+    pub fn next_queue_offsets(&mut self, topic: &str, queue_id: u32, msg_count: usize) -> usize {
+        let topic_offset_map = self.queue_offset_table.entry(topic.to_string()).or_default();
+        let queue_offset = topic_offset_map.entry(queue_id).or_insert(0);
+        *queue_offset += msg_count;
+
+        *queue_offset - msg_count
     }
 
-    pub fn write_records(&mut self, data: &Vec<u8>) -> Result<usize> {
+    // Store timestamp for the message to write, it never goes back even if the clock does.
+    pub fn next_store_timestamp(&mut self) -> u64 {
+        self.last_store_timestamp = self.last_store_timestamp.max(current_timestamp_ms());
+        self.last_store_timestamp
+    }
+
+    pub fn set_last_store_timestamp(&mut self, store_timestamp: u64) {
+        self.last_store_timestamp = store_timestamp;
+    }
+
+    pub async fn write_records(&mut self, data: &[u8]) -> Result<usize> {
         // Write the record to the current file
-        self.mapped_file_queue.append(data)
+        self.mapped_file_queue.append(data).await
     }
 
-    pub fn read_records(&self, msg_index_unit: &MessageIndexUnit) -> Result<Vec<u8>> {
+    pub async fn read_records(&self, msg_index_unit: &MessageIndexUnit) -> Result<Vec<u8>> {
         if msg_index_unit.size > 0 {
             // Read and return records
             self.mapped_file_queue.read(
-                msg_index_unit.offset as usize, msg_index_unit.size as usize)
+                msg_index_unit.offset as usize, msg_index_unit.size as usize).await
         } else {
             Err(InvalidInput {
                 location: location!(),
@@ -58,4 +287,85 @@ impl CommitLog {
             })
         }
     }
-}
\ No newline at end of file
+
+    // Find the start offset of the first record at or after the given offset.
+    pub async fn align_record_offset(&self, offset: usize) -> Result<usize> {
+        let max_offset = self.get_max_offset();
+        if offset >= max_offset {
+            return Ok(max_offset);
+        }
+
+        // each file starts with a complete record, scan from there
+        let mut record_offset = match self.mapped_file_queue.get_file_start_offset(offset) {
+            Some(file_start_offset) => file_start_offset,
+            None => return Ok(self.get_store_min_offset()),
+        };
+        while record_offset < offset {
+            record_offset = self.skip_padding(record_offset).await;
+            if record_offset >= offset {
+                break;
+            }
+            record_offset += self.read_record_size(record_offset).await?;
+        }
+
+        Ok(record_offset)
+    }
+
+    // Read the records from the given offset, until the total size exceeds the max size.
+    pub async fn read_records_from(&self, offset: usize, max_size: usize) -> Result<Vec<(usize, Vec<u8>)>> {
+        let max_offset = self.get_max_offset();
+        let mut record_offset = offset;
+        let mut records = Vec::new();
+
+        while record_offset < max_offset && record_offset - offset < max_size {
+            record_offset = self.skip_padding(record_offset).await;
+            if record_offset >= max_offset {
+                break;
+            }
+            let record = self.read_record_at(record_offset).await?;
+            let record_size = record.len();
+            records.push((record_offset, record));
+            record_offset += record_size;
+        }
+
+        Ok(records)
+    }
+
+    /*
+     * A compacted file is shorter than the offset range it covers, the rest of the range is
+     * padding. Move the offset in the padding to the start of the next file.
+     */
+    async fn skip_padding(&self, offset: usize) -> usize {
+        if let Some(next_file_offset) = self.mapped_file_queue.get_next_file_start_offset(offset) {
+            let is_padding = self.mapped_file_queue.read(offset, 1).await
+                .map(|magic| magic[0] != RECORD_MAGIC)
+                .unwrap_or(true);
+            if is_padding {
+                return next_file_offset;
+            }
+        }
+
+        offset
+    }
+
+    // Size of the record which starts at the given offset.
+    pub async fn read_record_size(&self, offset: usize) -> Result<usize> {
+        let prefix_bytes = self.mapped_file_queue.read(offset, RECORD_PREFIX_SIZE).await?;
+        parse_record_size(&prefix_bytes).ok_or_else(|| CorruptedRecord {
+            location: location!(),
+            offset,
+            msg: "Invalid record header".to_string(),
+        })
+    }
+
+    // Read the whole record which starts at the given offset.
+    pub async fn read_record_at(&self, offset: usize) -> Result<Vec<u8>> {
+        let record_size = self.read_record_size(offset).await?;
+        self.mapped_file_queue.read(offset, record_size).await
+    }
+}
+
+// Path of the file to write the compacted records of the sealed file which starts at the offset.
+pub fn compacted_file_path(commit_log_path: &str, start_offset: usize) -> PathBuf {
+    PathBuf::from(commit_log_path).join(format!("{:020}.compacted", start_offset))
+}
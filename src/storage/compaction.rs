@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tokio::sync::Mutex;
+use crate::config::ConfigOptions;
+use crate::error::{DecodeMsgBinSnafu, EncodeMsgBinSnafu, Result, StdIOSnafu};
+use crate::storage::commit_log::{compacted_file_path, CommitLog, COMMIT_LOG_DIR};
+use crate::storage::encryption::KeyRing;
+use crate::storage::index_store::IndexStore;
+use crate::storage::msg_index::{MessageIndexUnit, COMPACTED_MSG_SIZE};
+use crate::storage::msg_record::{decode_batch_header, decode_msg_record, MessageRecord};
+use crate::storage::record::{current_timestamp_ms, read_record_file, write_record_file};
+use crate::topic_mgr::{CleanupPolicy, TopicMgr};
+
+const COMPACTION_TASK_FILE_NAME: &str = "compaction";
+// Max size of the records read from the commit log at a time.
+const COMPACTION_BATCH_SIZE: usize = 1024 * 1024;
+
+/*
+ * Compaction of a sealed commit log file, persisted before the compacted file replaces the
+ * original one. An interrupted compaction is finished on restart by replaying the task.
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CompactionTask {
+    // start offset of the compacted file
+    pub file_offset: u64,
+    pub index_rewrites: Vec<IndexRewrite>,
+}
+
+// New position of a message in the compacted file, the size is COMPACTED_MSG_SIZE if it's removed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexRewrite {
+    pub topic: String,
+    pub queue_id: u32,
+    pub index_offset: u64,
+    pub msg_offset: u64,
+    pub msg_size: u32,
+    pub store_timestamp: u64,
+}
+
+impl CompactionTask {
+    fn task_path(store_path: &str) -> PathBuf {
+        PathBuf::from(store_path).join(COMPACTION_TASK_FILE_NAME)
+    }
+
+    pub fn load(store_path: &str) -> Result<Option<Self>> {
+        match read_record_file(&Self::task_path(store_path))? {
+            Some(task_body) => Ok(Some(bincode::deserialize(&task_body).context(DecodeMsgBinSnafu)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn flush(&self, store_path: &str) -> Result<()> {
+        let task_body = bincode::serialize(self).context(EncodeMsgBinSnafu)?;
+        write_record_file(&Self::task_path(store_path), &task_body)
+    }
+
+    pub fn remove(store_path: &str) -> Result<()> {
+        fs::remove_file(Self::task_path(store_path)).context(StdIOSnafu)
+    }
+
+    // Rewriting the index units is idempotent, so it's safe to replay.
+    pub async fn rewrite_index(&self, index_store: &mut IndexStore) -> Result<()> {
+        for rewrite in &self.index_rewrites {
+            let index_unit = MessageIndexUnit {
+                offset: rewrite.msg_offset,
+                size: rewrite.msg_size,
+                store_timestamp: rewrite.store_timestamp,
+            };
+            index_store.rewrite_msg_index(rewrite.topic.as_str(), rewrite.queue_id,
+                                          rewrite.index_offset as usize, &index_unit).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/*
+ * Move the compacted file of an interrupted compaction in place, it should be called before
+ * loading the commit log. The returned task should be replayed after loading the indexes.
+ */
+pub fn recover_compacted_file(store_path: &str) -> Result<Option<CompactionTask>> {
+    let commit_log_dir = PathBuf::from(store_path).join(COMMIT_LOG_DIR);
+    let compaction_task = CompactionTask::load(store_path)?;
+
+    if let Some(compaction_task) = &compaction_task {
+        let file_offset = compaction_task.file_offset as usize;
+        let compacted_file = compacted_file_path(commit_log_dir.to_str().unwrap(), file_offset);
+        if compacted_file.exists() {
+            println!("recover compacted file: {:?}", &compacted_file);
+            fs::rename(&compacted_file, commit_log_dir.join(format!("{:020}", file_offset)))
+                .context(StdIOSnafu)?;
+        }
+    }
+
+    // the compacted files without a task are left before the task is persisted, drop them
+    if commit_log_dir.exists() {
+        for entry in commit_log_dir.read_dir().context(StdIOSnafu)?.flatten() {
+            if entry.path().extension().map(|ext| ext == "compacted").unwrap_or(false) {
+                println!("remove interrupted compacted file: {:?}", entry.path());
+                fs::remove_file(entry.path()).context(StdIOSnafu)?;
+            }
+        }
+    }
+
+    Ok(compaction_task)
+}
+
+/*
+ * Offset of the latest message of each key in the compacted topics. The records appended since
+ * the last compaction are scanned, and the offsets of the records moved by compaction are
+ * updated, so the commit log isn't scanned again on each compaction.
+ */
+#[derive(Default)]
+pub struct LatestOffsets {
+    // the topics whose keys are tracked, the scan starts over if the compacted topics change
+    topics: HashSet<String>,
+    // records before this offset of the commit log have been scanned
+    scanned_offset: usize,
+    // (topic, key) -> offset
+    offsets: HashMap<(String, String), usize>,
+}
+
+impl LatestOffsets {
+    // Scan the records from the scanned offset to the given end offset.
+    async fn scan(&mut self, commit_log: &Mutex<CommitLog>, key_ring: &KeyRing, compacted_topics: &HashMap<String, u64>,
+                  end_offset: usize) -> Result<()> {
+        let min_offset = commit_log.lock().await.get_min_offset();
+        if !self.is_tracking(compacted_topics) {
+            self.topics = compacted_topics.keys().cloned().collect();
+            self.scanned_offset = 0;
+            self.offsets.clear();
+        }
+        // the messages deleted by the retention are gone
+        self.offsets.retain(|_, offset| *offset >= min_offset);
+        self.scanned_offset = self.scanned_offset.max(min_offset);
+
+        while self.scanned_offset < end_offset {
+            let records = commit_log.lock().await.read_records_from(self.scanned_offset, COMPACTION_BATCH_SIZE).await?;
+            if records.is_empty() {
+                break;
+            }
+
+            for (record_offset, record) in records {
+                if record_offset >= end_offset {
+                    return Ok(());
+                }
+                self.scanned_offset = record_offset + record.len();
+                if decode_batch_header(&record, record_offset)?.is_some() {
+                    continue;
+                }
+
+                let msg = decode_msg_record(&record, record_offset, key_ring)?.msg;
+                if let (true, Some(key)) = (compacted_topics.contains_key(&msg.topic), msg.key) {
+                    self.offsets.insert((msg.topic, key), record_offset);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Files ending after this offset may hold the latest messages of the compacted topics, they
+     * are kept by the retention. Nothing is known to be deletable before the topics are scanned.
+     */
+    pub fn get_retained_offset(&self, compacted_topics: &HashMap<String, u64>) -> usize {
+        if compacted_topics.is_empty() {
+            return usize::MAX;
+        }
+        if !self.is_tracking(compacted_topics) {
+            return 0;
+        }
+
+        self.offsets.values().copied().min().unwrap_or(usize::MAX).min(self.scanned_offset)
+    }
+
+    fn is_tracking(&self, compacted_topics: &HashMap<String, u64>) -> bool {
+        compacted_topics.len() == self.topics.len() && compacted_topics.keys().all(|topic| self.topics.contains(topic))
+    }
+
+    fn is_latest(&self, topic_key: &(String, String), record_offset: usize) -> bool {
+        self.offsets.get(topic_key).map(|latest_offset| *latest_offset == record_offset).unwrap_or(true)
+    }
+}
+
+// The topics whose cleanup policy is compact: topic -> tombstone retention time
+pub fn get_compacted_topics(config: &ConfigOptions, topic_mgr: &TopicMgr) -> Result<HashMap<String, u64>> {
+    let mut compacted_topics = HashMap::new();
+    for topic in topic_mgr.list_topics()? {
+        let topic_config = topic.get_config();
+        if topic_config.cleanup_policy == Some(CleanupPolicy::Compact) {
+            let tombstone_retention_ms = topic_config.tombstone_retention_ms.unwrap_or(config.tombstone_retention_ms);
+            compacted_topics.insert(topic.get_topic_name().to_string(), tombstone_retention_ms);
+        }
+    }
+
+    Ok(compacted_topics)
+}
+
+/*
+ * Compact the sealed commit log files before the given offset, keep only the latest message of
+ * each key in the topics whose cleanup policy is compact, and drop the latest tombstones after
+ * the retention time. Messages of the other topics are kept. Return the compacted file count.
+ */
+pub async fn compact_commit_log(config: &ConfigOptions, topic_mgr: &TopicMgr, commit_log: &Mutex<CommitLog>,
+                                index_store: &Mutex<IndexStore>, key_ring: &KeyRing, latest_offsets: &mut LatestOffsets,
+                                compactable_offset: usize) -> Result<usize> {
+    let compacted_topics = get_compacted_topics(config, topic_mgr)?;
+    if compacted_topics.is_empty() {
+        return Ok(0);
+    }
+
+    latest_offsets.scan(commit_log, key_ring, &compacted_topics, compactable_offset).await?;
+
+    let mut compacted_num = 0;
+    let file_ranges = commit_log.lock().await.get_sealed_file_ranges();
+    for (file_offset, file_max_offset) in file_ranges {
+        if file_max_offset > compactable_offset {
+            break;
+        }
+
+        let mut index_store = index_store.lock().await;
+        let mut commit_log = commit_log.lock().await;
+        // the uploaded files are immutable
+        if file_offset < commit_log.get_uploading_offset() {
+            continue;
+        }
+        if compact_file(config.msg_store_path.as_str(), &mut commit_log, &mut index_store, key_ring, &compacted_topics,
+                        latest_offsets, (file_offset, file_max_offset)).await? {
+            compacted_num += 1;
+        }
+    }
+
+    Ok(compacted_num)
+}
+
+/*
+ * Rewrite a sealed file with the kept records, and rewrite the index units pointing to it.
+ * Queue offsets are stable, the index units of the removed messages become holes. Return
+ * false if no record is removed.
+ */
+async fn compact_file(store_path: &str, commit_log: &mut CommitLog, index_store: &mut IndexStore, key_ring: &KeyRing,
+                      compacted_topics: &HashMap<String, u64>, latest_offsets: &mut LatestOffsets,
+                      (file_offset, file_max_offset): (usize, usize)) -> Result<bool> {
+    /*
+     * (record offset, record size, whether it's kept) of all the records in the file. The batch
+     * headers are dropped if the file is rewritten, since their batches may be partially removed.
+     * The latest records of the keys are moved or removed with them.
+     */
+    let mut latest_records = Vec::new();
+    let mut file_records = Vec::new();
+    let mut batch_headers = 0;
+    let mut read_offset = file_offset;
+    while read_offset < file_max_offset {
+        let records = commit_log.read_records_from(read_offset, COMPACTION_BATCH_SIZE).await?;
+        let mut file_end = records.is_empty();
+        for (record_offset, record) in records {
+            if record_offset >= file_max_offset {
+                file_end = true;
+                break;
+            }
+            read_offset = record_offset + record.len();
+
+            if decode_batch_header(&record, record_offset)?.is_some() {
+                batch_headers += 1;
+                file_records.push((record_offset, record.len(), false));
+                continue;
+            }
+            let MessageRecord { store_timestamp, msg, .. } = decode_msg_record(&record, record_offset, key_ring)?;
+            let kept = match (compacted_topics.get(&msg.topic), msg.key) {
+                (Some(tombstone_retention_ms), Some(key)) => {
+                    let topic_key = (msg.topic, key);
+                    let is_latest = latest_offsets.is_latest(&topic_key, record_offset);
+                    // by the time it's stored, a rewritten file is modified again
+                    let is_expired_tombstone = msg.payload.is_none()
+                        && store_timestamp.saturating_add(*tombstone_retention_ms) <= current_timestamp_ms();
+                    if is_latest {
+                        latest_records.push((file_records.len(), topic_key));
+                    }
+                    is_latest && !is_expired_tombstone
+                }
+                _ => true,
+            };
+            file_records.push((record_offset, record.len(), kept));
+        }
+
+        if file_end {
+            break;
+        }
+    }
+
+    if file_records.iter().filter(|(_, _, kept)| !*kept).count() == batch_headers {
+        return Ok(false);
+    }
+
+    // the records are packed to the start of the file, a removed one is located at the next kept one
+    let mut new_offsets = Vec::with_capacity(file_records.len());
+    let mut kept_records = Vec::new();
+    let mut write_offset = file_offset;
+    for (record_offset, record_size, kept) in &file_records {
+        new_offsets.push(write_offset);
+        if *kept {
+            kept_records.push((*record_offset, *record_size));
+            write_offset += record_size;
+        }
+    }
+
+    let mut index_rewrites = Vec::new();
+    for (topic, queue_id, index_offset, index_unit) in index_store.get_msg_indexes_in_range(file_offset, file_max_offset).await? {
+        let msg_offset = index_unit.offset as usize;
+        let record_index = file_records.partition_point(|(record_offset, _, _)| *record_offset < msg_offset);
+        let new_offset = new_offsets.get(record_index).copied().unwrap_or(write_offset);
+        let is_kept = !index_unit.is_compacted() && file_records.get(record_index)
+            .map(|(record_offset, _, kept)| *record_offset == msg_offset && *kept)
+            .unwrap_or(false);
+        let new_size = if is_kept { index_unit.size } else { COMPACTED_MSG_SIZE };
+
+        if new_offset != msg_offset || new_size != index_unit.size {
+            index_rewrites.push(IndexRewrite {
+                topic,
+                queue_id,
+                index_offset: index_offset as u64,
+                msg_offset: new_offset as u64,
+                msg_size: new_size,
+                store_timestamp: index_unit.store_timestamp,
+            });
+        }
+    }
+
+    let max_offset = commit_log.write_compacted_file(file_offset, &kept_records).await?;
+    let compaction_task = CompactionTask { file_offset: file_offset as u64, index_rewrites };
+    compaction_task.flush(store_path)?;
+
+    commit_log.replace_with_compacted_file(file_offset, max_offset).await?;
+    compaction_task.rewrite_index(index_store).await?;
+    CompactionTask::remove(store_path)?;
+
+    for (record_index, topic_key) in latest_records {
+        if file_records[record_index].2 {
+            latest_offsets.offsets.insert(topic_key, new_offsets[record_index]);
+        } else {
+            // the expired tombstone is removed, so are the earlier messages of the key
+            latest_offsets.offsets.remove(&topic_key);
+        }
+    }
+
+    println!("compacted commit log file: offset={}, records={}, kept={}, max_offset={}",
+             file_offset, file_records.len(), kept_records.len(), max_offset);
+    Ok(true)
+}
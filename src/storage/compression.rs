@@ -0,0 +1,98 @@
+use std::io::Read;
+use snafu::{location, Location};
+use crate::config::CompressionCodec;
+use crate::error::Error::Compression;
+use crate::error::Result;
+
+// zstd level which trades little speed for most of the ratio
+const ZSTD_LEVEL: i32 = 3;
+
+pub fn compress(codec: CompressionCodec, data: &[u8]) -> Result<Vec<u8>> {
+    match codec {
+        CompressionCodec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        CompressionCodec::Zstd => zstd::stream::encode_all(data, ZSTD_LEVEL)
+            .map_err(|error| compression_error(codec, error)),
+        CompressionCodec::Snappy => snap::raw::Encoder::new().compress_vec(data)
+            .map_err(|error| compression_error(codec, error)),
+    }
+}
+
+/*
+ * Decompress the data which is at most max_size bytes when decompressed, the data beyond it is
+ * rejected before it's allocated. The sizes in the lz4 and snappy data are checked up front, the
+ * zstd stream is read up to the limit.
+ */
+pub fn decompress(codec: CompressionCodec, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    let decompressed_size = match codec {
+        CompressionCodec::Lz4 => Some(lz4_flex::block::uncompressed_size(data)
+            .map_err(|error| compression_error(codec, error))?.0),
+        CompressionCodec::Snappy => Some(snap::raw::decompress_len(data)
+            .map_err(|error| compression_error(codec, error))?),
+        CompressionCodec::Zstd => None,
+    };
+    if decompressed_size.is_some_and(|decompressed_size| decompressed_size > max_size) {
+        return Err(size_exceeded_error(codec, max_size));
+    }
+
+    match codec {
+        CompressionCodec::Lz4 => lz4_flex::decompress_size_prepended(data)
+            .map_err(|error| compression_error(codec, error)),
+        CompressionCodec::Zstd => {
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::new(data)
+                .and_then(|decoder| decoder.take(max_size as u64 + 1).read_to_end(&mut decompressed))
+                .map_err(|error| compression_error(codec, error))?;
+            if decompressed.len() > max_size {
+                return Err(size_exceeded_error(codec, max_size));
+            }
+            Ok(decompressed)
+        }
+        CompressionCodec::Snappy => snap::raw::Decoder::new().decompress_vec(data)
+            .map_err(|error| compression_error(codec, error)),
+    }
+}
+
+fn size_exceeded_error(codec: CompressionCodec, max_size: usize) -> crate::error::Error {
+    Compression {
+        location: location!(),
+        codec,
+        msg: format!("Decompressed data exceeds the max size {}", max_size),
+    }
+}
+
+fn compression_error(codec: CompressionCodec, error: impl std::fmt::Display) -> crate::error::Error {
+    Compression {
+        location: location!(),
+        codec,
+        msg: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CompressionCodec;
+    use crate::error::Result;
+    use crate::storage::compression::{compress, decompress};
+
+    #[tokio::test]
+    pub async fn test_codecs() -> Result<()> {
+        let data = r#"{"user": "photon", "action": "produce"}"#.repeat(50).into_bytes();
+        for codec in [CompressionCodec::Lz4, CompressionCodec::Zstd, CompressionCodec::Snappy] {
+            let compressed = compress(codec, &data)?;
+            assert!(compressed.len() * 5 < data.len(), "{:?} ratio is too low", codec);
+            assert_eq!(decompress(codec, &compressed, data.len())?, data);
+            assert_eq!(decompress(codec, &compress(codec, &[])?, 0)?, Vec::<u8>::new());
+
+            assert!(decompress(codec, b"not compressed data", data.len()).is_err());
+            // the data larger than the max size is rejected
+            assert!(decompress(codec, &compressed, data.len() - 1).is_err());
+        }
+
+        // the lz4 size prefix is checked before the allocation
+        let mut forged = compress(CompressionCodec::Lz4, b"small")?;
+        forged[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decompress(CompressionCodec::Lz4, &forged, 1024).is_err());
+
+        Ok(())
+    }
+}
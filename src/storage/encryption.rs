@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use secrecy::ExposeSecret;
+use snafu::{location, Location};
+use crate::config::EncryptionConfig;
+use crate::error::Error::{Encryption, InvalidInput};
+use crate::error::Result;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+// size of a data key encrypted by a master key, | nonce | encrypted key | tag |
+pub const WRAPPED_KEY_SIZE: usize = NONCE_SIZE + KEY_SIZE + TAG_SIZE;
+// records encrypted by a data key before a new one is generated, far below the 2^32 limit of the random nonces
+const DATA_KEY_MAX_RECORDS: u64 = 1 << 24;
+// unwrapped data keys kept for reading, the cache is cleared when it's full
+const MAX_UNWRAPPED_KEYS: usize = 64;
+
+// Data key encrypting the new records, together with its copy wrapped by the master key.
+struct DataKey {
+    key_id: u32,
+    wrapped_key: Vec<u8>,
+    cipher: Aes256Gcm,
+    record_count: u64,
+}
+
+/*
+ * Envelope encryption of the records. The configured AES-256-GCM keys are the master keys by
+ * their ids, which only encrypt the data keys. A random data key encrypts the records until it has
+ * encrypted DATA_KEY_MAX_RECORDS ones, or the active master key changes. The encrypted data is
+ * | wrapped data key | nonce (12) | ciphertext | tag (16) |, the data key is wrapped by the master
+ * key of the id in the record header, so a record can be read on its own.
+ */
+#[derive(Default)]
+pub struct KeyRing {
+    active_key_id: Option<u32>,
+    ciphers: HashMap<u32, Aes256Gcm>,
+    active_data_key: Mutex<Option<DataKey>>,
+    // (master key id, wrapped data key) -> data key
+    unwrapped_keys: Mutex<HashMap<(u32, Vec<u8>), Aes256Gcm>>,
+}
+
+impl KeyRing {
+    pub fn new(config: &EncryptionConfig) -> Result<Self> {
+        let mut ciphers = HashMap::new();
+        for encryption_key in &config.keys {
+            if encryption_key.id == 0 {
+                return Err(invalid_config("Encryption key id 0 is reserved".to_string()));
+            }
+
+            let key = STANDARD.decode(encryption_key.key.expose_secret())
+                .map_err(|_| invalid_config(format!("Encryption key {} isn't valid base64", encryption_key.id)))?;
+            if key.len() != KEY_SIZE {
+                return Err(invalid_config(format!("Encryption key {} is {} bytes, it should be {}",
+                                                  encryption_key.id, key.len(), KEY_SIZE)));
+            }
+            if ciphers.insert(encryption_key.id, Aes256Gcm::new_from_slice(&key).unwrap()).is_some() {
+                return Err(invalid_config(format!("Encryption key {} is duplicated", encryption_key.id)));
+            }
+        }
+
+        if let Some(active_key_id) = config.active_key_id {
+            if !ciphers.contains_key(&active_key_id) {
+                return Err(invalid_config(format!("Active encryption key {} isn't configured", active_key_id)));
+            }
+        }
+
+        Ok(KeyRing { active_key_id: config.active_key_id, ciphers, ..Default::default() })
+    }
+
+    // Id of the master key to wrap the data keys of the new records, None to write them in plaintext.
+    pub fn active_key_id(&self) -> Option<u32> {
+        self.active_key_id
+    }
+
+    // Encrypt the data by the data key wrapped by the master key, the aad is authenticated but not encrypted.
+    pub fn encrypt(&self, key_id: u32, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut active_data_key = self.active_data_key.lock().unwrap();
+        let is_usable = active_data_key.as_ref()
+            .map(|data_key| data_key.key_id == key_id && data_key.record_count < DATA_KEY_MAX_RECORDS)
+            .unwrap_or(false);
+        if !is_usable {
+            *active_data_key = Some(self.new_data_key(key_id)?);
+        }
+        let data_key = active_data_key.as_mut().unwrap();
+        data_key.record_count += 1;
+
+        let mut encrypted = Vec::with_capacity(WRAPPED_KEY_SIZE + NONCE_SIZE + data.len() + TAG_SIZE);
+        encrypted.extend_from_slice(&data_key.wrapped_key);
+        encrypted.extend(seal(&data_key.cipher, key_id, data, aad)?);
+        Ok(encrypted)
+    }
+
+    // Decrypt the data encrypted by encrypt, it fails if the data, the wrapped key or the aad is modified.
+    pub fn decrypt(&self, key_id: u32, encrypted: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if encrypted.len() < WRAPPED_KEY_SIZE {
+            return Err(encryption_error(key_id, "Encrypted data is shorter than the wrapped key"));
+        }
+
+        let (wrapped_key, sealed) = encrypted.split_at(WRAPPED_KEY_SIZE);
+        let cipher = self.unwrap_data_key(key_id, wrapped_key)?;
+        open(&cipher, key_id, sealed, aad)
+    }
+
+    fn new_data_key(&self, key_id: u32) -> Result<DataKey> {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let wrapped_key = seal(self.cipher(key_id)?, key_id, &key, &key_id.to_le_bytes())?;
+        Ok(DataKey { key_id, wrapped_key, cipher: Aes256Gcm::new(&key), record_count: 0 })
+    }
+
+    fn unwrap_data_key(&self, key_id: u32, wrapped_key: &[u8]) -> Result<Aes256Gcm> {
+        let cache_key = (key_id, wrapped_key.to_vec());
+        if let Some(cipher) = self.unwrapped_keys.lock().unwrap().get(&cache_key) {
+            return Ok(cipher.clone());
+        }
+
+        let key = open(self.cipher(key_id)?, key_id, wrapped_key, &key_id.to_le_bytes())?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| encryption_error(key_id, "Invalid data key"))?;
+        let mut unwrapped_keys = self.unwrapped_keys.lock().unwrap();
+        if unwrapped_keys.len() >= MAX_UNWRAPPED_KEYS {
+            unwrapped_keys.clear();
+        }
+        unwrapped_keys.insert(cache_key, cipher.clone());
+        Ok(cipher)
+    }
+
+    fn cipher(&self, key_id: u32) -> Result<&Aes256Gcm> {
+        self.ciphers.get(&key_id).ok_or_else(|| encryption_error(key_id, "Key isn't configured"))
+    }
+}
+
+// Encrypt the data with a random nonce, the result is | nonce | ciphertext | tag |.
+fn seal(cipher: &Aes256Gcm, key_id: u32, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|_| encryption_error(key_id, "Failed to encrypt"))?;
+
+    let mut encrypted = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend(ciphertext);
+    Ok(encrypted)
+}
+
+fn open(cipher: &Aes256Gcm, key_id: u32, encrypted: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if encrypted.len() < NONCE_SIZE {
+        return Err(encryption_error(key_id, "Encrypted data is shorter than the nonce"));
+    }
+
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| encryption_error(key_id, "Failed to decrypt, the key or the data is wrong"))
+}
+
+fn invalid_config(msg: String) -> crate::error::Error {
+    InvalidInput {
+        location: location!(),
+        msg,
+    }
+}
+
+fn encryption_error(key_id: u32, msg: &str) -> crate::error::Error {
+    Encryption {
+        location: location!(),
+        key_id,
+        msg: msg.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use secrecy::SecretString;
+    use crate::config::{EncryptionConfig, EncryptionKey};
+    use crate::error::Error::Encryption;
+    use crate::error::Result;
+    use crate::storage::encryption::{KeyRing, DATA_KEY_MAX_RECORDS, WRAPPED_KEY_SIZE};
+
+    // Config of the keys filled with their ids.
+    pub fn test_encryption_config(active_key_id: Option<u32>, key_ids: &[u32]) -> EncryptionConfig {
+        EncryptionConfig {
+            active_key_id,
+            keys: key_ids.iter().map(|key_id| EncryptionKey {
+                id: *key_id,
+                key: SecretString::from(STANDARD.encode([*key_id as u8; 32])),
+            }).collect(),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_encrypt_decrypt() -> Result<()> {
+        let key_ring = KeyRing::new(&test_encryption_config(Some(1), &[1, 2]))?;
+        assert_eq!(key_ring.active_key_id(), Some(1));
+
+        let encrypted = key_ring.encrypt(1, b"hello record", b"header")?;
+        assert_ne!(encrypted, key_ring.encrypt(1, b"hello record", b"header")?);
+        assert_eq!(key_ring.decrypt(1, &encrypted, b"header")?, b"hello record");
+
+        assert!(matches!(key_ring.decrypt(2, &encrypted, b"header"), Err(Encryption { key_id: 2, .. })));
+        assert!(key_ring.decrypt(1, &encrypted, b"other header").is_err());
+        let mut tampered = encrypted.clone();
+        tampered[20] ^= 0xFF;
+        assert!(key_ring.decrypt(1, &tampered, b"header").is_err());
+        assert!(matches!(key_ring.decrypt(3, &encrypted, b"header"), Err(Encryption { key_id: 3, .. })));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_data_keys() -> Result<()> {
+        let key_ring = KeyRing::new(&test_encryption_config(Some(1), &[1, 2]))?;
+        let wrapped_key = |encrypted: &[u8]| encrypted[..WRAPPED_KEY_SIZE].to_vec();
+
+        // the data key is shared by the records of the master key until it's used up
+        let first = key_ring.encrypt(1, b"first", b"")?;
+        let second = key_ring.encrypt(1, b"second", b"")?;
+        assert_eq!(wrapped_key(&first), wrapped_key(&second));
+        key_ring.active_data_key.lock().unwrap().as_mut().unwrap().record_count = DATA_KEY_MAX_RECORDS;
+        let third = key_ring.encrypt(1, b"third", b"")?;
+        assert_ne!(wrapped_key(&second), wrapped_key(&third));
+        let rotated = key_ring.encrypt(2, b"rotated", b"")?;
+        assert_ne!(wrapped_key(&third), wrapped_key(&rotated));
+
+        // each record can be decrypted on its own, by a key ring with the same master keys
+        let other_key_ring = KeyRing::new(&test_encryption_config(None, &[1, 2]))?;
+        for (encrypted, key_id, data) in [(&first, 1, b"first".as_slice()), (&third, 1, b"third"), (&rotated, 2, b"rotated")] {
+            assert_eq!(other_key_ring.decrypt(key_id, encrypted, b"")?, data);
+        }
+        // the data key is wrapped by the master key of the id
+        assert!(other_key_ring.decrypt(2, &first, b"").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_invalid_config() -> Result<()> {
+        assert!(KeyRing::new(&test_encryption_config(None, &[]))?.active_key_id().is_none());
+        assert!(KeyRing::new(&test_encryption_config(Some(2), &[1])).is_err());
+        assert!(KeyRing::new(&test_encryption_config(Some(1), &[1, 1])).is_err());
+        assert!(KeyRing::new(&test_encryption_config(None, &[0])).is_err());
+
+        let mut config = test_encryption_config(Some(1), &[1]);
+        config.keys[0].key = SecretString::from(STANDARD.encode([1u8; 16]));
+        assert!(KeyRing::new(&config).is_err());
+        config.keys[0].key = SecretString::from("not base64!".to_string());
+        assert!(KeyRing::new(&config).is_err());
+
+        Ok(())
+    }
+}
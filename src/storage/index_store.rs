@@ -1,14 +1,40 @@
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::ops::Range;
 use std::path::PathBuf;
 use crate::config::ConfigOptions;
 use crate::message::DispatchMessage;
-use crate::storage::msg_index::{MessageIndex, MessageIndexUnit};
-use crate::error::Result;
+use crate::storage::checkpoint::Checkpoint;
+use crate::storage::key_index::{KeyIndex, KeyIndexEntry};
+use crate::storage::msg_index::{MessageIndex, MessageIndexUnit, MSG_INDEX_UNIT_SIZE};
+use crate::topic_mgr::{CleanupPolicy, TopicConfig};
+use snafu::{location, Location, ResultExt};
+use crate::error::Error::OffsetOutOfRange;
+use crate::error::{Result, StdIOSnafu};
+
+pub const INDEX_DIR: &str = "index";
+const INDEX_VERSION_FILE_NAME: &str = "version";
+// Version of the index unit layout, indexes of the other versions are rebuilt from the commit log
+// at the queue offsets stored in the records.
+const INDEX_VERSION: u32 = 2;
+
+// Index units of a queue for the messages in an offset range of the commit log.
+pub struct IndexSegment {
+    pub topic: String,
+    pub queue_id: u32,
+    // byte offset of the first index unit in the queue
+    pub start_offset: usize,
+    pub data: Vec<u8>,
+}
 
 pub struct IndexStore {
     config: ConfigOptions,
     index_map: HashMap<String, HashMap<u32, MessageIndex>>,
     index_store_path: String,
+    key_index_map: HashMap<String, KeyIndex>,
+    key_index_store_path: String,
+    dispatch_timestamp: u64,
 }
 
 impl IndexStore {
@@ -16,38 +42,348 @@ impl IndexStore {
     pub fn new(config: ConfigOptions) -> Result<Self> {
         let msg_store_path_clone = &config.msg_store_path;
         let base_dir = PathBuf::from(msg_store_path_clone);
-        let index_store_path = base_dir.join("index").as_path().to_str().unwrap().to_string();
+        let index_store_path = base_dir.join(INDEX_DIR).as_path().to_str().unwrap().to_string();
+        let key_index_store_path = base_dir.join("keyindex").as_path().to_str().unwrap().to_string();
+
+        Ok(IndexStore {
+            config,
+            index_map: HashMap::new(),
+            index_store_path,
+            key_index_map: HashMap::new(),
+            key_index_store_path,
+            dispatch_timestamp: 0,
+        })
+    }
+
+    // Whether the existed indexes can be loaded, the ones without the version file are written by old versions.
+    pub fn is_compatible(&self) -> Result<bool> {
+        let index_store_dir = PathBuf::from(&self.index_store_path);
+        if !index_store_dir.exists() {
+            return Ok(true);
+        }
+
+        let version_path = index_store_dir.join(INDEX_VERSION_FILE_NAME);
+        if !version_path.exists() {
+            return Ok(index_store_dir.read_dir().context(StdIOSnafu)?.next().is_none());
+        }
+        let version = fs::read_to_string(&version_path).context(StdIOSnafu)?;
+
+        Ok(version.trim().parse::<u32>().ok() == Some(INDEX_VERSION))
+    }
+
+    pub fn write_version(&self) -> Result<()> {
+        let index_store_dir = PathBuf::from(&self.index_store_path);
+        fs::create_dir_all(&index_store_dir).context(StdIOSnafu)?;
+        fs::write(index_store_dir.join(INDEX_VERSION_FILE_NAME), INDEX_VERSION.to_string()).context(StdIOSnafu)
+    }
+
+    /*
+     * Load all the existed message indexes from the checkpoint, and drop the index units which
+     * point past the recovered end of the commit log.
+     */
+    pub async fn recovery(&mut self, min_msg_offset: usize, max_msg_offset: usize,
+                          checkpoint: Option<&Checkpoint>) -> Result<()> {
+        if let Some(checkpoint) = checkpoint {
+            self.dispatch_timestamp = checkpoint.dispatch_timestamp;
+        }
+
+        let key_index_store_dir = PathBuf::from(&self.key_index_store_path);
+        if key_index_store_dir.exists() {
+            for topic_entry in key_index_store_dir.read_dir().context(StdIOSnafu)?.flatten() {
+                let topic = topic_entry.file_name().to_str().unwrap().to_string();
+                let key_index = KeyIndex::new(self.key_index_store_path.as_str(), topic.as_str(),
+                                              self.config.key_index_slot_num, self.config.key_index_entry_num)?;
+                self.key_index_map.insert(topic, key_index);
+            }
+        }
+
+        let index_store_dir = PathBuf::from(&self.index_store_path);
+        if !index_store_dir.exists() {
+            return Ok(());
+        }
+
+        for topic_entry in index_store_dir.read_dir().context(StdIOSnafu)?.flatten() {
+            if !topic_entry.path().is_dir() {
+                continue;
+            }
+            let topic = topic_entry.file_name().to_str().unwrap().to_string();
+
+            for queue_entry in topic_entry.path().read_dir().context(StdIOSnafu)?.flatten() {
+                let queue_id: u32 = match queue_entry.file_name().to_str().unwrap().parse() {
+                    Ok(queue_id) => queue_id,
+                    Err(_) => continue,
+                };
+
+                let checkpoint_index = checkpoint
+                    .and_then(|c| c.get_index_offset(topic.as_str(), queue_id));
+                let mut msg_index = MessageIndex::new(
+                    self.index_store_path.as_str(), topic.as_str(), queue_id,
+                    self.config.index_file_size, checkpoint_index).await?;
+                msg_index.truncate_invalid_tail(max_msg_offset).await?;
+                // the commit log files may be deleted before the index files
+                msg_index.delete_expired_files(min_msg_offset, None, None).await?;
 
-        Ok(IndexStore { config, index_map: HashMap::new(), index_store_path })
+                self.index_map.entry(topic.clone()).or_default().insert(queue_id, msg_index);
+            }
+        }
+        // the key indexes may be ahead of the message indexes, their messages will be dispatched again
+        self.truncate_key_indexes()?;
+
+        Ok(())
     }
 
-    pub fn put_msg_index(&mut self, dispatch_msg: &DispatchMessage) -> Result<usize> {
+    /*
+     * Drop the index units of the messages at or after the given offset in the commit log, so
+     * they can be rebuilt. All the index files are removed if no message is left.
+     */
+    pub async fn truncate(&mut self, msg_offset: usize, min_msg_offset: usize) -> Result<()> {
+        if msg_offset <= min_msg_offset {
+            self.index_map.clear();
+            self.key_index_map.clear();
+
+            for index_store_path in [&self.index_store_path, &self.key_index_store_path] {
+                let index_store_dir = PathBuf::from(index_store_path);
+                if index_store_dir.exists() {
+                    println!("remove all message indexes: {:?}", &index_store_dir);
+                    fs::remove_dir_all(&index_store_dir).context(StdIOSnafu)?;
+                }
+            }
+            // the rebuilt indexes are in the current version
+            return self.write_version();
+        }
+
+        for topic_index_map in self.index_map.values_mut() {
+            for msg_index in topic_index_map.values_mut() {
+                msg_index.truncate_by_msg_offset(msg_offset).await?;
+            }
+        }
+        self.truncate_key_indexes()?;
+
+        Ok(())
+    }
+
+    // Drop the key index entries of the messages which are not in the message indexes.
+    fn truncate_key_indexes(&mut self) -> Result<()> {
+        let max_indexes = self.get_max_indexes();
+        for (topic, key_index) in self.key_index_map.iter_mut() {
+            let queue_indexes = max_indexes.get(topic);
+            key_index.truncate(|queue_id| {
+                queue_indexes.and_then(|queue_indexes| queue_indexes.get(&queue_id)).copied().unwrap_or(0) as u64
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn put_msg_index(&mut self, dispatch_msg: &DispatchMessage) -> Result<usize> {
         let msg_index = self.find_or_create_index(
-            dispatch_msg.topic.as_str(), dispatch_msg.queue_id);
-        msg_index.put_msg_index(dispatch_msg.msg_offset, dispatch_msg.msg_size)
+            dispatch_msg.topic.as_str(), dispatch_msg.queue_id).await?;
+        let index_offset = msg_index.put_msg_index_at(
+            dispatch_msg.queue_offset, dispatch_msg.msg_offset, dispatch_msg.msg_size, dispatch_msg.timestamp).await?;
+
+        if let Some(key) = &dispatch_msg.key {
+            let key_index_entry = KeyIndexEntry {
+                queue_id: dispatch_msg.queue_id,
+                queue_offset: index_offset as u64,
+                store_timestamp: dispatch_msg.timestamp,
+            };
+            self.find_or_create_key_index(dispatch_msg.topic.as_str())?
+                .put_key(key.as_str(), &key_index_entry, dispatch_msg.msg_offset + dispatch_msg.msg_size)?;
+        }
+        self.dispatch_timestamp = dispatch_msg.timestamp;
+
+        Ok(index_offset)
     }
 
-    pub fn read_msg_index(&mut self, topic: &str, queue_id: u32,
-                          index_offset: usize, max_msg_count: usize) -> Vec<MessageIndexUnit> {
-        let msg_index = self.find_or_create_index(topic, queue_id);
+    // Index the key of a message whose message index is already there, like the downloaded ones.
+    pub fn put_key(&mut self, topic: &str, key: &str, key_index_entry: &KeyIndexEntry, msg_end_offset: usize) -> Result<()> {
+        self.find_or_create_key_index(topic)?.put_key(key, key_index_entry, msg_end_offset)
+    }
+
+    // The end offset in the commit log of the last message indexed by the given queue.
+    pub async fn get_max_msg_offset(&mut self, topic: &str, queue_id: u32) -> Result<usize> {
+        self.find_or_create_index(topic, queue_id).await?.get_max_msg_offset().await
+    }
+
+    // The next queue offset to index.
+    pub async fn get_max_index(&mut self, topic: &str, queue_id: u32) -> Result<usize> {
+        Ok(self.find_or_create_index(topic, queue_id).await?.get_max_index())
+    }
+
+    // The earliest queue offset which can be read, the ones before it are expired.
+    pub async fn get_min_index(&mut self, topic: &str, queue_id: u32) -> Result<usize> {
+        Ok(self.find_or_create_index(topic, queue_id).await?.get_min_index())
+    }
+
+    // Index count of all the message indexes: topic -> queue id -> index count.
+    pub fn get_max_indexes(&self) -> HashMap<String, HashMap<u32, usize>> {
+        self.index_map.iter().map(|(topic, topic_index_map)| {
+            let queue_indexes = topic_index_map.iter()
+                .map(|(queue_id, msg_index)| (*queue_id, msg_index.get_max_index()))
+                .collect();
+            (topic.clone(), queue_indexes)
+        }).collect()
+    }
+
+    /*
+     * Delete the expired index files of each queue, the per topic retention settings override
+     * the global ones. Messages deleted from the commit log are always expired, the compacted
+     * topics aren't expired otherwise.
+     */
+    pub async fn delete_expired_files<F>(&mut self, min_msg_offset: usize, topic_config: F) -> Result<()>
+        where F: Fn(&str) -> Result<TopicConfig> + Sync {
+        for (topic, topic_index_map) in self.index_map.iter_mut() {
+            let topic_config = topic_config(topic.as_str())?;
+            let (retention_ms, retention_bytes) = if topic_config.cleanup_policy == Some(CleanupPolicy::Compact) {
+                (None, None)
+            } else {
+                (topic_config.retention_ms.or(self.config.retention_ms),
+                 topic_config.retention_bytes.or(self.config.retention_bytes))
+            };
+
+            for msg_index in topic_index_map.values_mut() {
+                msg_index.delete_expired_files(min_msg_offset, retention_ms, retention_bytes).await?;
+            }
+        }
+
+        for key_index in self.key_index_map.values_mut() {
+            key_index.delete_expired_files(min_msg_offset)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Index units of all the queues which point to the given offset range of the commit log:
+     * (topic, queue id, index offset, index unit).
+     */
+    pub async fn get_msg_indexes_in_range(&self, start_msg_offset: usize, end_msg_offset: usize)
+                                          -> Result<Vec<(String, u32, usize, MessageIndexUnit)>> {
+        let mut msg_indexes = Vec::new();
+        for (topic, topic_index_map) in self.index_map.iter() {
+            for (queue_id, msg_index) in topic_index_map.iter() {
+                let (start_index, end_index) = msg_index.find_index_range(start_msg_offset, end_msg_offset).await?;
+                for index_offset in start_index..end_index {
+                    let index_unit = msg_index.read_msg_index(index_offset).await?;
+                    msg_indexes.push((topic.clone(), *queue_id, index_offset, index_unit));
+                }
+            }
+        }
+
+        Ok(msg_indexes)
+    }
+
+    // Index units of each queue which point to the given offset range of the commit log.
+    pub async fn read_index_segments(&self, start_msg_offset: usize, end_msg_offset: usize) -> Result<Vec<IndexSegment>> {
+        let mut index_segments = Vec::new();
+        for (topic, topic_index_map) in self.index_map.iter() {
+            for (queue_id, msg_index) in topic_index_map.iter() {
+                let (start_index, end_index) = msg_index.find_index_range(start_msg_offset, end_msg_offset).await?;
+                if start_index < end_index {
+                    index_segments.push(IndexSegment {
+                        topic: topic.clone(),
+                        queue_id: *queue_id,
+                        start_offset: start_index * MSG_INDEX_UNIT_SIZE,
+                        data: msg_index.read_index_data(start_index, end_index).await?,
+                    });
+                }
+            }
+        }
+
+        Ok(index_segments)
+    }
+
+    // Byte offset of the first local index file of each queue: (topic, queue id, offset).
+    pub fn get_min_file_offsets(&self) -> Vec<(String, u32, usize)> {
+        self.index_map.iter().flat_map(|(topic, topic_index_map)| {
+            topic_index_map.iter().map(|(queue_id, msg_index)| (topic.clone(), *queue_id, msg_index.get_min_file_offset()))
+        }).collect()
+    }
+
+    pub async fn rewrite_msg_index(&mut self, topic: &str, queue_id: u32, index_offset: usize,
+                                   index_unit: &MessageIndexUnit) -> Result<()> {
+        self.find_or_create_index(topic, queue_id).await?.rewrite_msg_index(index_offset, index_unit).await
+    }
+
+    // The first index offset of the queue whose message is stored at or after the timestamp.
+    pub async fn find_index_by_timestamp(&mut self, topic: &str, queue_id: u32, store_timestamp: u64) -> Result<usize> {
+        self.find_or_create_index(topic, queue_id).await?.find_index_by_timestamp(store_timestamp).await
+    }
+
+    pub fn get_dispatch_timestamp(&self) -> u64 {
+        self.dispatch_timestamp
+    }
+
+    // Flush all the message indexes, return the flushed index offset of each one.
+    pub async fn flush(&mut self) -> Result<HashMap<String, HashMap<u32, u64>>> {
+        let mut index_offsets = HashMap::new();
+        for (topic, topic_index_map) in self.index_map.iter_mut() {
+            let queue_offsets: &mut HashMap<u32, u64> = index_offsets.entry(topic.clone()).or_default();
+            for (queue_id, msg_index) in topic_index_map.iter_mut() {
+                queue_offsets.insert(*queue_id, msg_index.flush().await? as u64);
+            }
+        }
+
+        for key_index in self.key_index_map.values() {
+            key_index.flush()?;
+        }
+
+        Ok(index_offsets)
+    }
+
+    pub async fn read_msg_index(&mut self, topic: &str, queue_id: u32,
+                                index_offset: usize, max_msg_count: usize) -> Result<Vec<MessageIndexUnit>> {
+        let msg_index = self.find_or_create_index(topic, queue_id).await?;
+        if index_offset < msg_index.get_min_index() {
+            return Err(OffsetOutOfRange {
+                location: location!(),
+                offset: index_offset,
+                min_offset: msg_index.get_min_index(),
+            });
+        }
 
         let mut index_list = Vec::new();
         for index in index_offset..index_offset + max_msg_count {
-            if let Ok(index_unit) = msg_index.read_msg_index(index) {
+            if let Ok(index_unit) = msg_index.read_msg_index(index).await {
                 index_list.push(index_unit);
             }
         }
 
-        index_list
+        Ok(index_list)
+    }
+
+    /*
+     * Find the queue offsets of the key stored in the time range, from the latest one. Messages
+     * of other keys with the same hash may be included.
+     */
+    pub fn query_by_key(&self, topic: &str, key: &str, time_range: &Range<u64>, max_num: usize) -> Vec<KeyIndexEntry> {
+        match self.key_index_map.get(topic) {
+            Some(key_index) => key_index.query(key, time_range, max_num),
+            None => Vec::new(),
+        }
     }
 
-    fn find_or_create_index(&mut self, topic: &str, queue_id: u32) -> &mut MessageIndex {
-        let topic_index_map = self.index_map.entry(topic.to_string()).or_insert_with(|| HashMap::new());
+    fn find_or_create_key_index(&mut self, topic: &str) -> Result<&mut KeyIndex> {
+        if !self.key_index_map.contains_key(topic) {
+            let key_index = KeyIndex::new(self.key_index_store_path.as_str(), topic,
+                                          self.config.key_index_slot_num, self.config.key_index_entry_num)?;
+            self.key_index_map.insert(topic.to_string(), key_index);
+        }
 
-        topic_index_map.entry(queue_id).or_insert_with(|| {
-            MessageIndex::new(
-                self.index_store_path.as_str(),
-                topic, queue_id, self.config.index_file_size).unwrap()
-        })
+        Ok(self.key_index_map.get_mut(topic).unwrap())
     }
-}
\ No newline at end of file
+
+    async fn find_or_create_index(&mut self, topic: &str, queue_id: u32) -> Result<&mut MessageIndex> {
+        let topic_index_map = self.index_map.entry(topic.to_string()).or_default();
+
+        match topic_index_map.entry(queue_id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let msg_index = MessageIndex::new(
+                    self.index_store_path.as_str(),
+                    topic, queue_id, self.config.index_file_size, None).await?;
+                Ok(entry.insert(msg_index))
+            }
+        }
+    }
+}
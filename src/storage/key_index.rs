@@ -0,0 +1,355 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::ops::Range;
+use std::path::PathBuf;
+use memmap2::MmapMut;
+use snafu::ResultExt;
+use crate::error::{Result, StdIOSnafu};
+
+/*
+ * Key index file layout:
+ * | header (32) | hash slots (4 * slot num) | entries (28 * entry num) |
+ * header: | begin timestamp (8) | end timestamp (8) | end msg offset (8) | entry count (4) | reserved (4) |
+ * slot: | entry number of the latest entry in the slot (4) |
+ * entry: | key hash (4) | queue id (4) | queue offset (8) | store timestamp (8) | previous entry number (4) |
+ * Entry numbers start from 1, and 0 means none, the entries in a slot are chained from the latest.
+ */
+const KEY_INDEX_HEADER_SIZE: usize = 32;
+const KEY_INDEX_SLOT_SIZE: usize = 4;
+const KEY_INDEX_ENTRY_SIZE: usize = 4 + 4 + 8 + 8 + 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyIndexEntry {
+    pub queue_id: u32,
+    pub queue_offset: u64,
+    pub store_timestamp: u64,
+}
+
+struct KeyIndexFile {
+    file_path: String,
+    mmap: MmapMut,
+    slot_num: usize,
+    entry_num: usize,
+    begin_timestamp: u64,
+    end_timestamp: u64,
+    // end offset in the commit log of the last indexed message
+    end_msg_offset: u64,
+    entry_count: usize,
+}
+
+impl KeyIndexFile {
+    fn new(file_path: &str, slot_num: usize, entry_num: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file_path).context(StdIOSnafu)?;
+        let file_size = KEY_INDEX_HEADER_SIZE + slot_num * KEY_INDEX_SLOT_SIZE + entry_num * KEY_INDEX_ENTRY_SIZE;
+        file.set_len(file_size as u64).context(StdIOSnafu)?;
+
+        let mmap = unsafe { MmapMut::map_mut(&file).context(StdIOSnafu)? };
+
+        Ok(KeyIndexFile {
+            file_path: file_path.to_string(),
+            begin_timestamp: read_u64(&mmap, 0),
+            end_timestamp: read_u64(&mmap, 8),
+            end_msg_offset: read_u64(&mmap, 16),
+            entry_count: read_u32(&mmap, 24) as usize,
+            mmap,
+            slot_num,
+            entry_num,
+        })
+    }
+
+    fn is_full(&self) -> bool {
+        self.entry_count >= self.entry_num
+    }
+
+    fn slot_pos(&self, key_hash: u32) -> usize {
+        KEY_INDEX_HEADER_SIZE + (key_hash as usize % self.slot_num) * KEY_INDEX_SLOT_SIZE
+    }
+
+    fn entry_pos(&self, entry_number: usize) -> usize {
+        KEY_INDEX_HEADER_SIZE + self.slot_num * KEY_INDEX_SLOT_SIZE + (entry_number - 1) * KEY_INDEX_ENTRY_SIZE
+    }
+
+    // Append the entry and link it to the slot, the header is updated at last.
+    fn put(&mut self, key_hash: u32, entry: &KeyIndexEntry, msg_end_offset: usize) {
+        let slot_pos = self.slot_pos(key_hash);
+        let prev_entry_number = read_u32(&self.mmap, slot_pos);
+
+        let entry_number = self.entry_count + 1;
+        let entry_pos = self.entry_pos(entry_number);
+        self.mmap[entry_pos..entry_pos + 4].copy_from_slice(&u32::to_le_bytes(key_hash));
+        self.mmap[entry_pos + 4..entry_pos + 8].copy_from_slice(&u32::to_le_bytes(entry.queue_id));
+        self.mmap[entry_pos + 8..entry_pos + 16].copy_from_slice(&u64::to_le_bytes(entry.queue_offset));
+        self.mmap[entry_pos + 16..entry_pos + 24].copy_from_slice(&u64::to_le_bytes(entry.store_timestamp));
+        self.mmap[entry_pos + 24..entry_pos + 28].copy_from_slice(&u32::to_le_bytes(prev_entry_number));
+        self.mmap[slot_pos..slot_pos + 4].copy_from_slice(&u32::to_le_bytes(entry_number as u32));
+
+        if self.entry_count == 0 {
+            self.begin_timestamp = entry.store_timestamp;
+        }
+        self.end_timestamp = entry.store_timestamp;
+        self.end_msg_offset = msg_end_offset as u64;
+        self.entry_count = entry_number;
+        self.write_header();
+    }
+
+    /*
+     * Drop the latest entries while they are invalid, the slots are linked back to their previous
+     * entries. Return whether all the entries are dropped.
+     */
+    fn truncate<F>(&mut self, is_invalid: F) -> bool where F: Fn(&KeyIndexEntry) -> bool {
+        while self.entry_count > 0 {
+            let entry_pos = self.entry_pos(self.entry_count);
+            let entry = KeyIndexEntry {
+                queue_id: read_u32(&self.mmap, entry_pos + 4),
+                queue_offset: read_u64(&self.mmap, entry_pos + 8),
+                store_timestamp: read_u64(&self.mmap, entry_pos + 16),
+            };
+            if !is_invalid(&entry) {
+                break;
+            }
+
+            let slot_pos = self.slot_pos(read_u32(&self.mmap, entry_pos));
+            let prev_entry_number = read_u32(&self.mmap, entry_pos + 24);
+            self.mmap[slot_pos..slot_pos + 4].copy_from_slice(&u32::to_le_bytes(prev_entry_number));
+            self.entry_count -= 1;
+        }
+
+        if self.entry_count == 0 {
+            self.begin_timestamp = 0;
+            self.end_timestamp = 0;
+        } else {
+            self.end_timestamp = read_u64(&self.mmap, self.entry_pos(self.entry_count) + 16);
+        }
+        self.write_header();
+
+        self.entry_count == 0
+    }
+
+    fn write_header(&mut self) {
+        self.mmap[0..8].copy_from_slice(&u64::to_le_bytes(self.begin_timestamp));
+        self.mmap[8..16].copy_from_slice(&u64::to_le_bytes(self.end_timestamp));
+        self.mmap[16..24].copy_from_slice(&u64::to_le_bytes(self.end_msg_offset));
+        self.mmap[24..28].copy_from_slice(&u32::to_le_bytes(self.entry_count as u32));
+    }
+
+    // Collect the entries of the key hash in the time range, from the latest one.
+    fn query(&self, key_hash: u32, time_range: &Range<u64>, max_num: usize, entries: &mut Vec<KeyIndexEntry>) {
+        if self.entry_count == 0 || self.end_timestamp < time_range.start || self.begin_timestamp >= time_range.end {
+            return;
+        }
+
+        let mut entry_number = read_u32(&self.mmap, self.slot_pos(key_hash)) as usize;
+        while entry_number > 0 && entry_number <= self.entry_count && entries.len() < max_num {
+            let entry_pos = self.entry_pos(entry_number);
+            let store_timestamp = read_u64(&self.mmap, entry_pos + 16);
+            if store_timestamp < time_range.start {
+                // entries in the chain are older and older
+                break;
+            }
+
+            if read_u32(&self.mmap, entry_pos) == key_hash && store_timestamp < time_range.end {
+                entries.push(KeyIndexEntry {
+                    queue_id: read_u32(&self.mmap, entry_pos + 4),
+                    queue_offset: read_u64(&self.mmap, entry_pos + 8),
+                    store_timestamp,
+                });
+            }
+            entry_number = read_u32(&self.mmap, entry_pos + 24) as usize;
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.mmap.flush().context(StdIOSnafu)
+    }
+}
+
+fn read_u32(mmap: &MmapMut, pos: usize) -> u32 {
+    u32::from_le_bytes(mmap[pos..pos + 4].try_into().unwrap())
+}
+
+fn read_u64(mmap: &MmapMut, pos: usize) -> u64 {
+    u64::from_le_bytes(mmap[pos..pos + 8].try_into().unwrap())
+}
+
+/*
+ * Hash-slot index of the message keys in a topic. Files are named by the count of the entries
+ * before them, and a new one is created when the last one is full.
+ */
+pub struct KeyIndex {
+    store_path: String,
+    slot_num: usize,
+    entry_num: usize,
+    key_index_files: Vec<KeyIndexFile>,
+}
+
+impl KeyIndex {
+    pub fn new(store_path: &str, topic: &str, slot_num: usize, entry_num: usize) -> Result<Self> {
+        let key_index_dir = PathBuf::from(store_path).join(topic);
+        fs::create_dir_all(&key_index_dir).context(StdIOSnafu)?;
+
+        let mut file_entries = Vec::new();
+        for entry in key_index_dir.read_dir().context(StdIOSnafu)?.flatten() {
+            if let Ok(entry_offset) = entry.file_name().to_str().unwrap().parse::<usize>() {
+                file_entries.push((entry_offset, entry.path()));
+            }
+        }
+        file_entries.sort_by_key(|(entry_offset, _)| *entry_offset);
+
+        let mut key_index_files = Vec::new();
+        for (_, file_path) in file_entries {
+            key_index_files.push(KeyIndexFile::new(file_path.to_str().unwrap(), slot_num, entry_num)?);
+        }
+
+        Ok(KeyIndex {
+            store_path: key_index_dir.to_str().unwrap().to_string(),
+            slot_num,
+            entry_num,
+            key_index_files,
+        })
+    }
+
+    fn get_writable_file(&mut self) -> Result<&mut KeyIndexFile> {
+        let need_new_file = self.key_index_files.last().map(|f| f.is_full()).unwrap_or(true);
+        if need_new_file {
+            let entry_offset = match self.key_index_files.last() {
+                Some(last_file) => {
+                    // only the last file is flushed with the checkpoint, the full one is flushed now
+                    last_file.flush()?;
+                    let last_file_offset: usize = PathBuf::from(&last_file.file_path).file_name().unwrap()
+                        .to_str().unwrap().parse().unwrap();
+                    last_file_offset + last_file.entry_count
+                }
+                None => 0,
+            };
+            let file_path = PathBuf::from(&self.store_path).join(format!("{:020}", entry_offset));
+
+            println!("new key index file: {:?}", &file_path);
+            let key_index_file = KeyIndexFile::new(file_path.to_str().unwrap(), self.slot_num, self.entry_num)?;
+            self.key_index_files.push(key_index_file);
+        }
+
+        Ok(self.key_index_files.last_mut().unwrap())
+    }
+
+    pub fn put_key(&mut self, key: &str, entry: &KeyIndexEntry, msg_end_offset: usize) -> Result<()> {
+        let key_hash = hash_key(key);
+        self.get_writable_file()?.put(key_hash, entry, msg_end_offset);
+
+        Ok(())
+    }
+
+    /*
+     * Find the entries of the key stored in the time range, from the latest one. Entries of other
+     * keys with the same hash may be included, the caller should check the keys of the messages.
+     */
+    pub fn query(&self, key: &str, time_range: &Range<u64>, max_num: usize) -> Vec<KeyIndexEntry> {
+        let key_hash = hash_key(key);
+        let mut entries = Vec::new();
+        for key_index_file in self.key_index_files.iter().rev() {
+            if entries.len() >= max_num {
+                break;
+            }
+            key_index_file.query(key_hash, time_range, max_num, &mut entries);
+        }
+
+        entries
+    }
+
+    // Flush the last file, the previous ones are flushed when they are full.
+    pub fn flush(&self) -> Result<()> {
+        if let Some(key_index_file) = self.key_index_files.last() {
+            key_index_file.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Drop the entries of the messages which are no longer in the message indexes, they are the
+     * latest ones since the entries are appended in the order of the commit log. The max queue
+     * offsets are the index counts of the queues.
+     */
+    pub fn truncate<F>(&mut self, max_queue_offset: F) -> Result<()> where F: Fn(u32) -> u64 {
+        while let Some(key_index_file) = self.key_index_files.last_mut() {
+            let is_empty = key_index_file.truncate(|entry| entry.queue_offset >= max_queue_offset(entry.queue_id));
+            if !is_empty {
+                break;
+            }
+
+            let key_index_file = self.key_index_files.pop().unwrap();
+            let file_path = key_index_file.file_path.clone();
+            drop(key_index_file);
+
+            println!("delete truncated key index file: {:?}", &file_path);
+            fs::remove_file(&file_path).context(StdIOSnafu)?;
+        }
+
+        Ok(())
+    }
+
+    // Delete the full files whose messages are all deleted from the commit log.
+    pub fn delete_expired_files(&mut self, min_msg_offset: usize) -> Result<()> {
+        while self.key_index_files.len() > 1 && self.key_index_files[0].is_full()
+            && (self.key_index_files[0].end_msg_offset as usize) <= min_msg_offset {
+            let key_index_file = self.key_index_files.remove(0);
+            let file_path = key_index_file.file_path.clone();
+            drop(key_index_file);
+
+            println!("delete expired key index file: {:?}", &file_path);
+            fs::remove_file(&file_path).context(StdIOSnafu)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn hash_key(key: &str) -> u32 {
+    crc32fast::hash(key.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use crate::error::Result;
+    use crate::storage::key_index::{KeyIndex, KeyIndexEntry};
+
+    pub fn create_temp_dir(prefix: &str) -> TempDir {
+        tempfile::Builder::new().prefix(prefix).tempdir().unwrap()
+    }
+
+    #[tokio::test]
+    pub async fn test_put_query() -> Result<()> {
+        let dir_path = create_temp_dir("key_index_test");
+        let store_path = dir_path.path().to_str().unwrap();
+
+        // few slots and entries, so keys share slots and files roll
+        let mut key_index = KeyIndex::new(store_path, "test_topic", 4, 8)?;
+        for i in 0..20u64 {
+            let entry = KeyIndexEntry { queue_id: 0, queue_offset: i, store_timestamp: 1000 + i };
+            key_index.put_key(format!("key_{}", i % 5).as_str(), &entry, (i as usize + 1) * 100)?;
+        }
+
+        let entries = key_index.query("key_3", &(0..u64::MAX), 10);
+        let queue_offsets: Vec<u64> = entries.iter().map(|entry| entry.queue_offset).collect();
+        assert_eq!(queue_offsets, vec![18, 13, 8, 3]);
+
+        let entries = key_index.query("key_3", &(1005..1015), 10);
+        let queue_offsets: Vec<u64> = entries.iter().map(|entry| entry.queue_offset).collect();
+        assert_eq!(queue_offsets, vec![13, 8]);
+        assert_eq!(key_index.query("key_3", &(0..u64::MAX), 1).len(), 1);
+        drop(key_index);
+
+        // reload from disk, the files with deleted messages are removed
+        let mut key_index = KeyIndex::new(store_path, "test_topic", 4, 8)?;
+        assert_eq!(key_index.query("key_3", &(0..u64::MAX), 10).len(), 4);
+        key_index.delete_expired_files(1000)?;
+        assert_eq!(key_index.query("key_3", &(0..u64::MAX), 10).len(), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use opendal::{ErrorKind, Operator};
+use serde::{Deserialize, Serialize};
+use snafu::{location, Location, ResultExt};
+use crate::error::Error::InvalidInput;
+use crate::error::{ManifestCodecSnafu, ObjectStoreAccessSnafu, Result};
+
+const MANIFEST_FILE_NAME: &str = "manifest";
+// Version of the manifest layout, manifests written by newer versions are rejected.
+const MANIFEST_VERSION: u32 = 1;
+
+// A file of the queue uploaded to the object store, it covers the offset range [start_offset, end_offset).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentMeta {
+    // object name in the directory of the queue
+    pub name: String,
+    pub start_offset: u64,
+    pub end_offset: u64,
+    // the data of a compacted file may end before the end offset
+    pub data_size: u64,
+    // crc32 of the data
+    pub checksum: u32,
+    // last modified time of the local file when it's uploaded, in milliseconds
+    pub sealed_timestamp: u64,
+}
+
+impl SegmentMeta {
+    // The checksum is filled while the segment is uploaded.
+    pub fn new(start_offset: usize, end_offset: usize, data_size: usize, sealed_timestamp: u64) -> Self {
+        SegmentMeta {
+            name: segment_name(start_offset),
+            start_offset: start_offset as u64,
+            end_offset: end_offset as u64,
+            data_size: data_size as u64,
+            checksum: 0,
+            sealed_timestamp,
+        }
+    }
+}
+
+/*
+ * The segments of a file queue in the object store, in the order of their offsets. It's stored as
+ * a JSON object in the directory of the queue and rewritten after each change, so the queue can be
+ * recovered from the object store alone.
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub segments: Vec<SegmentMeta>,
+}
+
+impl Manifest {
+    pub fn new(segments: Vec<SegmentMeta>) -> Self {
+        Manifest { version: MANIFEST_VERSION, segments }
+    }
+
+    // Load the manifest of the queue directory, None if the queue has nothing uploaded.
+    pub async fn load(object_store: &Operator, queue_dir: &str) -> Result<Option<Self>> {
+        let manifest_bytes = match object_store.read(manifest_path(queue_dir).as_str()).await {
+            Ok(manifest_bytes) => manifest_bytes,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).context(ObjectStoreAccessSnafu),
+        };
+
+        let manifest: Manifest = serde_json::from_slice(&manifest_bytes).context(ManifestCodecSnafu)?;
+        if manifest.version > MANIFEST_VERSION {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Unsupported manifest version {} of {}", manifest.version, queue_dir),
+            });
+        }
+
+        Ok(Some(manifest))
+    }
+
+    // A single put replaces the whole object, so readers see either the old manifest or the new one.
+    pub async fn store(&self, object_store: &Operator, queue_dir: &str) -> Result<()> {
+        let manifest_bytes = serde_json::to_vec(self).context(ManifestCodecSnafu)?;
+        object_store.write(manifest_path(queue_dir).as_str(), manifest_bytes).await.context(ObjectStoreAccessSnafu)
+    }
+}
+
+// Segments are named by their start offsets like the local files.
+pub fn segment_name(start_offset: usize) -> String {
+    format!("{:020}", start_offset)
+}
+
+pub fn segment_path(queue_dir: &str, segment: &SegmentMeta) -> String {
+    format!("{}/{}", queue_dir, segment.name)
+}
+
+fn manifest_path(queue_dir: &str) -> String {
+    format!("{}/{}", queue_dir, MANIFEST_FILE_NAME)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ObjectStoreConfig;
+    use crate::error::Result;
+    use crate::storage::manifest::{Manifest, SegmentMeta};
+    use crate::storage::object_store::build_operator;
+
+    #[tokio::test]
+    pub async fn test_store_load() -> Result<()> {
+        let object_store = build_operator(&ObjectStoreConfig::Memory)?;
+        assert!(Manifest::load(&object_store, "commitlog").await?.is_none());
+
+        let segment = SegmentMeta::new(1024, 2048, 1000, 1631894400000);
+        assert_eq!(segment.name, "00000000000000001024");
+        Manifest::new(vec![segment.clone()]).store(&object_store, "commitlog").await?;
+        let manifest = Manifest::load(&object_store, "commitlog").await?.unwrap();
+        assert_eq!(manifest.segments, vec![segment]);
+
+        // the manifests of newer versions can't be read
+        let mut manifest = Manifest::new(Vec::new());
+        manifest.version += 1;
+        manifest.store(&object_store, "commitlog").await?;
+        assert!(Manifest::load(&object_store, "commitlog").await.is_err());
+
+        Ok(())
+    }
+}
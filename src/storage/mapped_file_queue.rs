@@ -1,54 +1,270 @@
-use std::path::{Path, PathBuf};
-use memmap2::MmapMut;
+use std::path::PathBuf;
+use std::sync::Arc;
+use async_trait::async_trait;
 use snafu::{location, Location};
-use crate::error::Error::InvalidInput;
+use crate::error::Error::{CorruptedRecord, InvalidInput};
 use crate::error::Result;
 use crate::storage::mmap_file::MemoryMappedFile;
+use crate::storage::segment::Segment;
 
-pub struct MappedFileQueue {
+// Number of the newest files to scan record by record during recovery.
+const RECOVERY_SCAN_FILE_NUM: usize = 3;
+
+/*
+ * The segments moved to the object store, which hold the data before the local files of a queue.
+ * The reads before the first local file fall back to them, so the readers of the queue don't care
+ * where the data lives.
+ */
+#[async_trait]
+pub trait RemoteSegments: Send + Sync {
+    fn get_min_offset(&self) -> Option<usize>;
+
+    // The offset range [start offset, end offset) of the segment which contains the given offset.
+    fn get_segment_range(&self, offset: usize) -> Option<(usize, usize)>;
+
+    async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>>;
+}
+
+pub struct MappedFileQueue<S: Segment = MemoryMappedFile> {
     store_path: String,
     max_file_size: u64,
-    mapped_files: Vec<MemoryMappedFile>,
+    context: S::Context,
+    mapped_files: Vec<S>,
+    flushed_offset: usize,
+    remote_segments: Option<Arc<dyn RemoteSegments>>,
 }
 
-impl MappedFileQueue {
-    pub fn new(store_path: &str, max_file_size: u64) -> Result<Self> {
-        Ok(MappedFileQueue { store_path: store_path.to_string(), max_file_size, mapped_files: Vec::new() })
+impl<S: Segment> MappedFileQueue<S> {
+    // The segments of the queue are opened with the given context.
+    pub fn with_context(context: S::Context, store_path: &str, max_file_size: u64) -> Result<Self> {
+        Ok(MappedFileQueue {
+            store_path: store_path.to_string(),
+            max_file_size,
+            context,
+            mapped_files: Vec::new(),
+            flushed_offset: 0,
+            remote_segments: None,
+        })
+    }
+
+    // Serve the reads before the local files from the remote segments.
+    pub fn set_remote_segments(&mut self, remote_segments: Arc<dyn RemoteSegments>) {
+        self.remote_segments = Some(remote_segments);
+    }
+
+    // The remote segments to read the given offset from, if it's before the local files.
+    fn get_remote_segments(&self, offset: usize) -> Option<&Arc<dyn RemoteSegments>> {
+        self.remote_segments.as_ref()
+            .filter(|_| self.mapped_files.first().is_none_or(|f| offset < f.get_min_offset()))
     }
 
-    #[allow(dead_code)]
-    pub fn get_mapped_files(&self) -> &Vec<MemoryMappedFile> {
+    pub fn get_mapped_files(&self) -> &Vec<S> {
         &self.mapped_files
     }
 
     /*
-     * Recovery from restart or fault, load existed files.
+     * Recovery from restart or fault, load existed files in the order of their start offset.
+     *
+     * Files are created at the max offset of the previous one, so the size of a sealed file is
+     * the start offset of its next. With a checkpoint offset, the data before it is known to be
+     * flushed, and scanning starts from there. Otherwise only the newest several files are
+     * scanned record by record. A scanned file whose data at the start isn't recognised by the
+     * reader fails the recovery, it's not cleared as the stale bytes of a torn write.
      */
-    pub fn recovery<Func>(&mut self, reader: Func)
-        where Func: Fn(&MmapMut, usize) -> Option<usize> {
-        let store_path_dir = Path::new(&self.store_path);
-        // TODO only read the several newest file
-        for entry in store_path_dir.read_dir().unwrap() {
-            if let Ok(entry) = entry {
-                let entry_path = entry.path();
-                if entry_path.is_file() {
-                    let mapped_file_name = entry_path.file_name().unwrap().to_str().unwrap();
-                    let mapped_file_path = entry_path.to_str().unwrap();
-                    let start_offset: usize = mapped_file_name.parse().expect("Error while parse file name");
-                    let mut mapped_file = MemoryMappedFile::new(
-                        mapped_file_path, start_offset, self.max_file_size).expect("Error while load mapped file");
-
-                    mapped_file.read_record(&reader);
-
-                    println!("loaded mapped file: {:?}, offset={}, max_offset={}", &entry.path(),
-                             mapped_file.get_min_offset(), mapped_file.get_max_offset());
-                    self.mapped_files.push(mapped_file);
-                }
+    pub async fn recovery<Func>(&mut self, reader: Func, checkpoint_offset: Option<usize>) -> Result<()>
+        where Func: Fn(&[u8], usize) -> Result<Option<usize>> + Sync {
+        let mut file_entries = S::list(&self.context, self.store_path.as_str()).await?;
+        file_entries.sort_by_key(|(start_offset, _)| *start_offset);
+
+        let scan_from = match checkpoint_offset {
+            Some(offset) => file_entries.partition_point(|(start_offset, _)| *start_offset <= offset)
+                .saturating_sub(1),
+            None => file_entries.len().saturating_sub(RECOVERY_SCAN_FILE_NUM),
+        };
+        for (file_index, (start_offset, mapped_file_path)) in file_entries.iter().enumerate() {
+            let mut mapped_file = S::open(&self.context, mapped_file_path, *start_offset, self.max_file_size)?;
+
+            if file_index < scan_from {
+                let (next_start_offset, _) = &file_entries[file_index + 1];
+                mapped_file.set_max_offset(*next_start_offset);
+            } else {
+                let scan_offset = checkpoint_offset
+                    .filter(|offset| file_index == scan_from && offset > start_offset
+                        && (*offset - start_offset) as u64 <= self.max_file_size)
+                    .unwrap_or(*start_offset);
+                let start_offset = *start_offset;
+                mapped_file.scan(&|data: &[u8], read_pos: usize| {
+                    let record_size = reader(data, read_pos)?;
+                    if record_size.is_none() && read_pos == 0 && data.iter().any(|&b| b != 0) {
+                        return Err(CorruptedRecord {
+                            location: location!(),
+                            offset: start_offset,
+                            msg: format!("Unrecognised data at the start of {}", mapped_file_path),
+                        });
+                    }
+                    Ok(record_size)
+                }, scan_offset).await?;
+            }
+
+            println!("loaded mapped file: {:?}, offset={}, max_offset={}", mapped_file_path,
+                     mapped_file.get_min_offset(), mapped_file.get_max_offset());
+            self.mapped_files.push(mapped_file);
+        }
+        self.flushed_offset = self.get_max_offset();
+
+        // clear the stale bytes after the last valid record, so new appends won't mix with them, the
+        // data before it is recognised from the file start or known to be flushed by the checkpoint
+        if let Some(mapped_file) = self.mapped_files.last_mut() {
+            let max_offset = mapped_file.get_max_offset();
+            mapped_file.truncate(max_offset).await?;
+        }
+
+        Ok(())
+    }
+
+    // The start offset of the file which contains the given offset.
+    pub fn get_file_start_offset(&self, offset: usize) -> Option<usize> {
+        if let Some(remote_segments) = self.get_remote_segments(offset) {
+            return remote_segments.get_segment_range(offset).map(|(start_offset, _)| start_offset);
+        }
+        let file_index = self.mapped_files.partition_point(|f| f.get_min_offset() <= offset);
+        file_index.checked_sub(1).map(|i| self.mapped_files[i].get_min_offset())
+    }
+
+    // The start offset of the file after the one which contains the given offset.
+    pub fn get_next_file_start_offset(&self, offset: usize) -> Option<usize> {
+        if let Some(remote_segments) = self.get_remote_segments(offset) {
+            return remote_segments.get_segment_range(offset).map(|(_, end_offset)| end_offset);
+        }
+        let file_index = self.mapped_files.partition_point(|f| f.get_min_offset() <= offset);
+        self.mapped_files.get(file_index).map(|f| f.get_min_offset())
+    }
+
+    pub fn get_min_offset(&self) -> usize {
+        self.mapped_files.first().map(|f| f.get_min_offset()).unwrap_or(0)
+    }
+
+    // The min offset which can be read, including the data of the remote segments.
+    pub fn get_readable_min_offset(&self) -> usize {
+        self.remote_segments.as_ref().and_then(|r| r.get_min_offset())
+            .unwrap_or_else(|| self.get_min_offset())
+    }
+
+    pub fn get_max_offset(&self) -> usize {
+        self.mapped_files.last().map(|f| f.get_max_offset()).unwrap_or(0)
+    }
+
+    pub fn get_flushed_offset(&self) -> usize {
+        self.flushed_offset
+    }
+
+    // Flush the files which have data after the last flushed offset, return the new flushed offset.
+    pub async fn flush(&mut self) -> Result<usize> {
+        for mapped_file in self.mapped_files.iter_mut().rev() {
+            if mapped_file.get_max_offset() <= self.flushed_offset {
+                break;
+            }
+            mapped_file.flush_from(self.flushed_offset).await?;
+        }
+        self.flushed_offset = self.get_max_offset();
+
+        Ok(self.flushed_offset)
+    }
+
+    /*
+     * Delete the files whose data are all before the given offset, the last file is always kept
+     * for writing. Return the deleted file count.
+     */
+    pub async fn delete_files_before(&mut self, offset: usize) -> Result<usize> {
+        let mut deleted_num = 0;
+        while self.mapped_files.len() > 1 && self.mapped_files[0].get_max_offset() <= offset {
+            let mapped_file = self.mapped_files.remove(0);
+            println!("delete expired mapped file: {:?}", mapped_file.get_path());
+            mapped_file.delete().await?;
+            deleted_num += 1;
+        }
+
+        Ok(deleted_num)
+    }
+
+    /*
+     * Discard all the data after the offset, files which start after the offset will be deleted.
+     */
+    pub async fn truncate(&mut self, offset: usize) -> Result<()> {
+        let mut retained_files = Vec::new();
+        for mapped_file in self.mapped_files.drain(..) {
+            if mapped_file.get_min_offset() > offset {
+                println!("delete mapped file: {:?}", mapped_file.get_path());
+                mapped_file.delete().await?;
+            } else {
+                retained_files.push(mapped_file);
+            }
+        }
+        self.mapped_files = retained_files;
+
+        if let Some(mapped_file) = self.mapped_files.last_mut() {
+            if mapped_file.get_max_offset() > offset {
+                mapped_file.truncate(offset).await?;
             }
         }
+        self.flushed_offset = self.flushed_offset.min(offset);
+
+        Ok(())
+    }
+
+    /*
+     * Replace the sealed file which starts at the given offset with a rewritten one, the new file
+     * keeps the start offset but may be shorter. The new file is renamed to the path of the old
+     * one, so a crash leaves either the old file or the new one.
+     */
+    pub async fn replace_file(&mut self, start_offset: usize, new_file_path: &str, max_offset: usize) -> Result<()> {
+        let file_index = self.mapped_files.iter().position(|f| f.get_min_offset() == start_offset)
+            .filter(|file_index| file_index + 1 < self.mapped_files.len())
+            .ok_or_else(|| InvalidInput {
+                location: location!(),
+                msg: format!("No sealed file starts at offset {}", start_offset),
+            })?;
+
+        let file_path = self.mapped_files[file_index].get_path().to_string();
+        S::rename(&self.context, new_file_path, file_path.as_str()).await?;
+
+        let mut mapped_file = S::open(&self.context, file_path.as_str(), start_offset, self.max_file_size)?;
+        mapped_file.set_max_offset(max_offset);
+        self.mapped_files[file_index] = mapped_file;
+
+        println!("replaced mapped file: {:?}, offset={}, max_offset={}", &file_path, start_offset, max_offset);
+        Ok(())
+    }
+
+    // Open a standalone file of the queue at the path, like the rewritten one to replace a sealed file.
+    pub fn open_file(&self, file_path: &str, start_offset: usize) -> Result<S> {
+        S::open(&self.context, file_path, start_offset, self.max_file_size)
+    }
+
+    // Seal the last file by creating a new one at its end, return false if the last file is empty.
+    pub fn roll_file(&mut self) -> bool {
+        let max_offset = match self.mapped_files.last() {
+            Some(mapped_file) if mapped_file.get_max_offset() > mapped_file.get_min_offset() => mapped_file.get_max_offset(),
+            _ => return false,
+        };
+        self.create_mapped_file(max_offset);
+
+        true
+    }
+
+    // Delete all the files, and start the queue from the given offset.
+    pub async fn reset(&mut self, start_offset: usize) -> Result<()> {
+        for mapped_file in self.mapped_files.drain(..) {
+            println!("delete mapped file: {:?}", mapped_file.get_path());
+            mapped_file.delete().await?;
+        }
+        self.create_mapped_file(start_offset);
+        self.flushed_offset = start_offset;
+
+        Ok(())
     }
 
-    pub fn create_mapped_file(&mut self, start_offset: usize) -> &mut MemoryMappedFile {
+    pub fn create_mapped_file(&mut self, start_offset: usize) -> &mut S {
         let store_path_clone = self.store_path.clone();
         let base_dir = PathBuf::from(store_path_clone);
         let file_name = format!("{:020}", start_offset);
@@ -56,23 +272,23 @@ impl MappedFileQueue {
 
         println!("new memory mapped file: {:?}", &file_path);
 
-        let mapped_file = MemoryMappedFile::new(
-            file_path.as_path().to_str().unwrap(), start_offset, self.max_file_size).unwrap();
+        let mapped_file = S::open(&self.context, file_path.as_path().to_str().unwrap(), start_offset,
+                                  self.max_file_size).unwrap();
 
         self.mapped_files.push(mapped_file);
         self.mapped_files.last_mut().unwrap()
     }
 
-    fn get_last_mapped_file_mut(&mut self) -> &mut MemoryMappedFile {
-        if self.mapped_files.len() == 0 {
+    fn get_last_mapped_file_mut(&mut self) -> &mut S {
+        if self.mapped_files.is_empty() {
             self.create_mapped_file(0);
         }
         self.mapped_files.last_mut().unwrap()
     }
 
-    pub fn append(&mut self, data: &Vec<u8>) -> Result<usize> {
+    pub async fn append(&mut self, data: &[u8]) -> Result<usize> {
         let mapped_file = self.get_last_mapped_file_mut();
-        let append_result = mapped_file.append(data);
+        let append_result = mapped_file.append(data).await;
 
         match append_result {
             Ok(write_offset) => { Ok(write_offset) }
@@ -83,7 +299,7 @@ impl MappedFileQueue {
                         let max_offset = mapped_file.get_max_offset();
                         let new_mapped_file = self.create_mapped_file(max_offset);
 
-                        new_mapped_file.append(data)
+                        new_mapped_file.append(data).await
                     }
                     other => {
                         Err(other)
@@ -93,12 +309,30 @@ impl MappedFileQueue {
         }
     }
 
-    pub fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
-        let mapped_file_result = self.mapped_files.iter().find(
-            |&f| f.get_min_offset() <= offset && offset < f.get_max_offset());
+    // Overwrite the written data at the given offset.
+    pub async fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let file_index = self.mapped_files.partition_point(|f| f.get_min_offset() <= offset);
+        match file_index.checked_sub(1) {
+            Some(file_index) => self.mapped_files[file_index].write_at(offset, data).await,
+            None => Err(InvalidInput {
+                location: location!(),
+                msg: "Invalid file offset".to_string(),
+            }),
+        }
+    }
+
+    pub async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
+        if let Some(remote_segments) = self.get_remote_segments(offset) {
+            return remote_segments.read(offset, data_size).await;
+        }
+        // files are sorted by the start offset, find the last one which starts before the offset
+        let file_index = self.mapped_files.partition_point(|f| f.get_min_offset() <= offset);
+        let mapped_file_result = file_index.checked_sub(1)
+            .map(|i| &self.mapped_files[i])
+            .filter(|f| offset < f.get_max_offset());
         match mapped_file_result {
             Some(mapped_file) => {
-                mapped_file.read(offset, data_size)
+                mapped_file.read(offset, data_size).await
             }
             None => {
                 Err(InvalidInput {
@@ -112,9 +346,13 @@ impl MappedFileQueue {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use async_trait::async_trait;
     use tempfile::{TempDir};
     use crate::error::Result;
-    use crate::storage::mapped_file_queue::MappedFileQueue;
+    use crate::storage::mapped_file_queue::{MappedFileQueue, RemoteSegments};
+    use crate::storage::memory_segment::{MemorySegment, MemorySegmentStore};
+    use crate::storage::segment::Segment;
 
     pub fn create_temp_dir(prefix: &str) -> TempDir {
         tempfile::Builder::new().prefix(prefix).tempdir().unwrap()
@@ -124,17 +362,180 @@ mod tests {
     pub async fn test_write_read() -> Result<()> {
         let dir_path = create_temp_dir("topic_mgr_test");
         // Create or open the memory-mapped file.
-        let mut mapped_file_queue = MappedFileQueue::new(
-            dir_path.path().to_str().unwrap(), 20)?;
+        let mut mapped_file_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 20)?;
 
         let test_str = "hello world".as_bytes();
         let test_data = Vec::from(test_str);
 
-        mapped_file_queue.append(&test_data).expect("Error while write");
-        mapped_file_queue.append(&test_data).expect("Error while write");
+        mapped_file_queue.append(&test_data).await.expect("Error while write");
+        mapped_file_queue.append(&test_data).await.expect("Error while write");
 
         assert_eq!(mapped_file_queue.get_mapped_files().len(), 2);
 
         Ok(())
     }
+
+    #[tokio::test]
+    pub async fn test_truncate() -> Result<()> {
+        let dir_path = create_temp_dir("mapped_file_queue_test");
+        let mut mapped_file_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 20)?;
+
+        let test_data = Vec::from("hello world".as_bytes());
+        mapped_file_queue.append(&test_data).await?;
+        mapped_file_queue.append(&test_data).await?;
+        assert_eq!(mapped_file_queue.get_max_offset(), 22);
+
+        mapped_file_queue.truncate(5).await?;
+        assert_eq!(mapped_file_queue.get_mapped_files().len(), 1);
+        assert_eq!(mapped_file_queue.get_max_offset(), 5);
+
+        // reload from disk, the truncated bytes shouldn't be seen again
+        let mut reloaded_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 20)?;
+        reloaded_queue.recovery(|mmap, offset| {
+            if offset < mmap.len() && mmap[offset] != 0 { Ok(Some(1)) } else { Ok(None) }
+        }, None).await?;
+        assert_eq!(reloaded_queue.get_max_offset(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_unrecognised_file_recovery() -> Result<()> {
+        let dir_path = create_temp_dir("mapped_file_queue_test");
+        let mut mapped_file_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 10)?;
+        mapped_file_queue.append(&[1; 5]).await?;
+        mapped_file_queue.append(&[0xFF; 5]).await?;
+        drop(mapped_file_queue);
+
+        // the data which isn't recognised from the file start fails the recovery, and it's kept
+        let mut reloaded_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 10)?;
+        assert!(reloaded_queue.recovery(|data, offset| {
+            if offset < data.len() && data[offset] == 2 { Ok(Some(5)) } else { Ok(None) }
+        }, None).await.is_err());
+        drop(reloaded_queue);
+
+        // the torn write after a valid record is cleared
+        let mut reloaded_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 10)?;
+        reloaded_queue.recovery(|data, offset| {
+            if offset < data.len() && data[offset] == 1 { Ok(Some(5)) } else { Ok(None) }
+        }, None).await?;
+        assert_eq!(reloaded_queue.get_max_offset(), 5);
+        assert_eq!(reloaded_queue.read(0, 5).await?, vec![1; 5]);
+        drop(reloaded_queue);
+
+        let mut reloaded_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 10)?;
+        reloaded_queue.recovery(|data, offset| {
+            if offset < data.len() && data[offset] != 0 { Ok(Some(5)) } else { Ok(None) }
+        }, None).await?;
+        assert_eq!(reloaded_queue.get_max_offset(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_ordered_recovery() -> Result<()> {
+        let dir_path = create_temp_dir("mapped_file_queue_test");
+        let mut mapped_file_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 10)?;
+
+        for i in 0..12u8 {
+            mapped_file_queue.append(&[i + 1; 5]).await?;
+        }
+        drop(mapped_file_queue);
+
+        let mut reloaded_queue: MappedFileQueue = MappedFileQueue::with_context(
+            (), dir_path.path().to_str().unwrap(), 10)?;
+        reloaded_queue.recovery(|mmap, offset| {
+            if offset < mmap.len() && mmap[offset] != 0 { Ok(Some(5)) } else { Ok(None) }
+        }, None).await?;
+
+        let start_offsets: Vec<usize> = reloaded_queue.get_mapped_files().iter()
+            .map(|f| f.get_min_offset()).collect();
+        assert_eq!(start_offsets, vec![0, 10, 20, 30, 40, 50]);
+        assert_eq!(reloaded_queue.get_max_offset(), 60);
+
+        for i in 0..12u8 {
+            assert_eq!(reloaded_queue.read(i as usize * 5, 5).await?, vec![i + 1; 5]);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_memory_segment_queue() -> Result<()> {
+        let segment_store = MemorySegmentStore::default();
+        let mut mapped_file_queue: MappedFileQueue<MemorySegment> = MappedFileQueue::with_context(
+            segment_store.clone(), "queue", 10)?;
+
+        for i in 0..6u8 {
+            mapped_file_queue.append(&[i + 1; 5]).await?;
+        }
+        mapped_file_queue.delete_files_before(10).await?;
+        assert_eq!(mapped_file_queue.get_min_offset(), 10);
+        assert!(mapped_file_queue.read(5, 5).await.is_err());
+
+        // the files are kept by the segment store, reload them from the checkpoint
+        let mut reloaded_queue: MappedFileQueue<MemorySegment> = MappedFileQueue::with_context(
+            segment_store, "queue", 10)?;
+        reloaded_queue.recovery(|data, offset| {
+            if offset < data.len() && data[offset] != 0 { Ok(Some(5)) } else { Ok(None) }
+        }, Some(25)).await?;
+        assert_eq!((reloaded_queue.get_min_offset(), reloaded_queue.get_max_offset()), (10, 30));
+        assert_eq!(reloaded_queue.read(25, 5).await?, vec![6; 5]);
+
+        reloaded_queue.truncate(12).await?;
+        assert_eq!(reloaded_queue.get_mapped_files().len(), 1);
+        assert_eq!(reloaded_queue.get_mapped_files()[0].get_max_offset(), 12);
+
+        Ok(())
+    }
+
+    // A single remote segment which covers the range [0, end offset) with the given data.
+    struct TestRemoteSegment {
+        end_offset: usize,
+        data: Vec<u8>,
+    }
+
+    #[async_trait]
+    impl RemoteSegments for TestRemoteSegment {
+        fn get_min_offset(&self) -> Option<usize> {
+            Some(0)
+        }
+
+        fn get_segment_range(&self, offset: usize) -> Option<(usize, usize)> {
+            Some((0, self.end_offset)).filter(|_| offset < self.end_offset)
+        }
+
+        async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
+            Ok(self.data[offset..offset + data_size].to_vec())
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_remote_segments() -> Result<()> {
+        let mut mapped_file_queue: MappedFileQueue<MemorySegment> = MappedFileQueue::with_context(
+            MemorySegmentStore::default(), "queue", 10)?;
+        for i in 0..4u8 {
+            mapped_file_queue.append(&[i + 1; 5]).await?;
+        }
+        mapped_file_queue.delete_files_before(10).await?;
+        assert_eq!(mapped_file_queue.get_readable_min_offset(), 10);
+
+        // the data before the local files is read from the remote segment, which is shorter than its range
+        mapped_file_queue.set_remote_segments(Arc::new(TestRemoteSegment { end_offset: 10, data: vec![1; 5] }));
+        assert_eq!(mapped_file_queue.get_readable_min_offset(), 0);
+        assert_eq!(mapped_file_queue.read(0, 5).await?, vec![1; 5]);
+        assert_eq!(mapped_file_queue.get_file_start_offset(5), Some(0));
+        assert_eq!(mapped_file_queue.get_next_file_start_offset(5), Some(10));
+        assert_eq!(mapped_file_queue.read(15, 5).await?, vec![4; 5]);
+
+        Ok(())
+    }
 }
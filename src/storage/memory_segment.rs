@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use snafu::{location, Location};
+use crate::error::Error::InvalidInput;
+use crate::error::Result;
+use crate::storage::segment::Segment;
+
+struct MemoryFile {
+    data: Vec<u8>,
+    last_modified: SystemTime,
+}
+
+/*
+ * The files of the memory segments by their paths, a segment reopened on the same store sees the
+ * data written before, like a file on the disk. It's cheap to clone and the clones are shared.
+ */
+#[derive(Clone, Default)]
+pub struct MemorySegmentStore {
+    files: Arc<Mutex<HashMap<String, Arc<Mutex<MemoryFile>>>>>,
+}
+
+// A segment kept in memory, it's fast to create and drop, so it's used by the tests of the queues.
+pub struct MemorySegment {
+    store: MemorySegmentStore,
+    path: String,
+    file: Arc<Mutex<MemoryFile>>,
+    min_offset: usize,
+    max_offset: usize,
+}
+
+#[async_trait]
+impl Segment for MemorySegment {
+    type Context = MemorySegmentStore;
+
+    fn open(context: &MemorySegmentStore, path: &str, start_offset: usize, file_size: u64) -> Result<Self> {
+        let file = context.files.lock().unwrap().entry(path.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(MemoryFile { data: Vec::new(), last_modified: SystemTime::now() })))
+            .clone();
+        file.lock().unwrap().data.resize(file_size as usize, 0);
+
+        Ok(MemorySegment {
+            store: context.clone(),
+            path: path.to_string(),
+            file,
+            min_offset: start_offset,
+            max_offset: start_offset,
+        })
+    }
+
+    async fn list(context: &MemorySegmentStore, dir: &str) -> Result<Vec<(usize, String)>> {
+        let files = context.files.lock().unwrap();
+        Ok(files.keys()
+            .filter(|path| Path::new(path.as_str()).parent() == Some(Path::new(dir)))
+            .filter_map(|path| {
+                let file_name = Path::new(path.as_str()).file_name()?.to_str()?;
+                file_name.parse::<usize>().ok().map(|start_offset| (start_offset, path.clone()))
+            })
+            .collect())
+    }
+
+    async fn rename(context: &MemorySegmentStore, path: &str, new_path: &str) -> Result<()> {
+        let mut files = context.files.lock().unwrap();
+        match files.remove(path) {
+            Some(file) => {
+                files.insert(new_path.to_string(), file);
+                Ok(())
+            }
+            None => Err(InvalidInput {
+                location: location!(),
+                msg: format!("Memory segment {} doesn't exist", path),
+            }),
+        }
+    }
+
+    fn get_path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    fn get_last_modified(&self) -> Result<SystemTime> {
+        Ok(self.file.lock().unwrap().last_modified)
+    }
+
+    fn set_last_modified(&mut self, last_modified: SystemTime) -> Result<()> {
+        self.file.lock().unwrap().last_modified = last_modified;
+        Ok(())
+    }
+
+    fn get_min_offset(&self) -> usize {
+        self.min_offset
+    }
+
+    fn get_max_offset(&self) -> usize {
+        self.max_offset
+    }
+
+    fn set_max_offset(&mut self, max_offset: usize) {
+        self.max_offset = max_offset;
+    }
+
+    async fn scan<F>(&mut self, reader: &F, start_offset: usize) -> Result<()>
+        where F: Fn(&[u8], usize) -> Result<Option<usize>> + Sync {
+        let file = self.file.lock().unwrap();
+        self.max_offset = start_offset;
+        let mut read_pos = start_offset - self.min_offset;
+        while let Some(record_size) = reader(&file.data, read_pos)? {
+            read_pos += record_size;
+            self.max_offset += record_size;
+        }
+
+        Ok(())
+    }
+
+    async fn truncate(&mut self, offset: usize) -> Result<()> {
+        if offset < self.min_offset || offset > self.max_offset {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Truncate offset {} is out of the segment range", offset),
+            });
+        }
+
+        self.max_offset = offset;
+        let mut file = self.file.lock().unwrap();
+        file.data[offset - self.min_offset..].fill(0);
+        file.last_modified = SystemTime::now();
+        Ok(())
+    }
+
+    // Nothing to persist, the data is lost with the store.
+    async fn flush_from(&mut self, _offset: usize) -> Result<()> {
+        Ok(())
+    }
+
+    async fn append(&mut self, data: &[u8]) -> Result<usize> {
+        let mut file = self.file.lock().unwrap();
+        let write_pos = self.max_offset - self.min_offset;
+        if write_pos + data.len() > file.data.len() {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: "Data size exceeds the remaining size of the segment.".to_string(),
+            });
+        }
+
+        file.data[write_pos..write_pos + data.len()].copy_from_slice(data);
+        file.last_modified = SystemTime::now();
+        let old_offset = self.max_offset;
+        self.max_offset += data.len();
+
+        Ok(old_offset)
+    }
+
+    async fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        if offset < self.min_offset || offset + data.len() > self.max_offset {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Write offset {} is out of the segment range", offset),
+            });
+        }
+
+        let mut file = self.file.lock().unwrap();
+        let write_pos = offset - self.min_offset;
+        file.data[write_pos..write_pos + data.len()].copy_from_slice(data);
+        file.last_modified = SystemTime::now();
+        Ok(())
+    }
+
+    async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
+        if offset < self.min_offset || offset + data_size > self.max_offset {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Invalid range: offset={}, size={}", offset, data_size),
+            });
+        }
+
+        let read_pos = offset - self.min_offset;
+        Ok(self.file.lock().unwrap().data[read_pos..read_pos + data_size].to_vec())
+    }
+
+    // The file is removed only if it's not replaced by a rename.
+    async fn delete(self) -> Result<()> {
+        let mut files = self.store.files.lock().unwrap();
+        if files.get(&self.path).map(|file| Arc::ptr_eq(file, &self.file)).unwrap_or(false) {
+            files.remove(&self.path);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Result;
+    use crate::storage::memory_segment::{MemorySegment, MemorySegmentStore};
+    use crate::storage::segment::Segment;
+
+    #[tokio::test]
+    pub async fn test_write_read() -> Result<()> {
+        let store = MemorySegmentStore::default();
+        let mut segment = MemorySegment::open(&store, "queue/00000000000000000100", 100, 16)?;
+
+        assert_eq!(segment.append(b"hello").await?, 100);
+        assert_eq!(segment.append(b"world").await?, 105);
+        assert_eq!(segment.read(103, 4).await?, b"lowo".to_vec());
+        assert!(segment.read(108, 4).await.is_err());
+        // the segment is full
+        assert!(segment.append(b"hello world").await.is_err());
+
+        segment.write_at(105, b"WORLD").await?;
+        segment.truncate(107).await?;
+        assert_eq!(segment.get_max_offset(), 107);
+        assert_eq!(segment.read(100, 7).await?, b"helloWO".to_vec());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_reopen() -> Result<()> {
+        let store = MemorySegmentStore::default();
+        let mut segment = MemorySegment::open(&store, "queue/00000000000000000000", 0, 16)?;
+        segment.append(&[1; 10]).await?;
+        MemorySegment::open(&store, "other/00000000000000000000", 0, 16)?;
+        drop(segment);
+
+        // the data is kept by the store, and found by scanning the records
+        assert_eq!(MemorySegment::list(&store, "queue").await?, vec![(0, "queue/00000000000000000000".to_string())]);
+        let mut segment = MemorySegment::open(&store, "queue/00000000000000000000", 0, 16)?;
+        segment.scan(&|data: &[u8], pos: usize| {
+            if pos + 5 <= data.len() && data[pos] != 0 { Ok(Some(5)) } else { Ok(None) }
+        }, 0).await?;
+        assert_eq!(segment.get_max_offset(), 10);
+
+        MemorySegment::rename(&store, "queue/00000000000000000000", "queue/00000000000000000010").await?;
+        assert_eq!(MemorySegment::list(&store, "queue").await?, vec![(10, "queue/00000000000000000010".to_string())]);
+        // the renamed file isn't removed by the stale segment
+        segment.delete().await?;
+        assert_eq!(MemorySegment::list(&store, "queue").await?.len(), 1);
+
+        Ok(())
+    }
+}
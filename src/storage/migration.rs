@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use memmap2::Mmap;
+use snafu::ResultExt;
+use crate::error::{Result, StdIOSnafu};
+use crate::message::Message;
+use crate::storage::commit_log::{CommitLog, COMMIT_LOG_DIR};
+use crate::storage::encryption::KeyRing;
+use crate::storage::index_store::INDEX_DIR;
+use crate::storage::mmap_file::MemoryMappedFile;
+use crate::storage::msg_record::{check_msg_record, encode_msg_record};
+use crate::storage::segment::Segment;
+
+// Directory of the migrated commit log before it's swapped in.
+const MIGRATING_DIR: &str = "migrating";
+// Directory the baseline commit log is moved to while the migrated one is swapped in.
+const BASELINE_COMMIT_LOG_DIR: &str = "commitlog.baseline";
+const BASELINE_LENGTH_SIZE: usize = std::mem::size_of::<u64>();
+
+/*
+ * Migrate the commit log written before the record header, whose records are | length (8) |
+ * bincode of the message |, to the current record layout. The messages are assigned the queue
+ * offsets in the order they are written, the same as the indexes of that version, which are
+ * dropped and rebuilt from the migrated records.
+ *
+ * The migrated files are written to a separate directory, then swapped in by renames, so an
+ * interrupted migration is restarted or completed on the next start. Return the migrated
+ * message count, 0 if the commit log is already in the current layout.
+ */
+pub async fn migrate_baseline_commit_log(store_path: &str, file_size: u64, key_ring: &KeyRing) -> Result<usize> {
+    let base_dir = PathBuf::from(store_path);
+    let commit_log_dir = base_dir.join(COMMIT_LOG_DIR);
+    let migrating_dir = base_dir.join(MIGRATING_DIR);
+    let baseline_dir = base_dir.join(BASELINE_COMMIT_LOG_DIR);
+
+    if baseline_dir.exists() {
+        // the migrated files are complete once the baseline ones are moved away
+        if !commit_log_dir.exists() {
+            fs::rename(migrating_dir.join(COMMIT_LOG_DIR), &commit_log_dir).context(StdIOSnafu)?;
+        }
+        remove_dir(&migrating_dir)?;
+        remove_dir(&baseline_dir)?;
+        return Ok(0);
+    }
+    remove_dir(&migrating_dir)?;
+
+    let mut file_entries = MemoryMappedFile::list(&(), commit_log_dir.to_str().unwrap()).await?;
+    file_entries.sort_by_key(|(start_offset, _)| *start_offset);
+    let is_baseline = match file_entries.first() {
+        Some((_, file_path)) => {
+            let data = map_file(file_path)?;
+            check_msg_record(&data).is_none() && parse_baseline_record(&data).is_some()
+        }
+        None => false,
+    };
+    if !is_baseline {
+        return Ok(0);
+    }
+
+    println!("migrate the commit log written before the record header: {:?}", &commit_log_dir);
+    remove_dir(&base_dir.join(INDEX_DIR))?;
+    let mut migrated_log: CommitLog = CommitLog::new(migrating_dir.to_str().unwrap(), file_size, None).await?;
+    let mut queue_offsets: HashMap<(String, u32), usize> = HashMap::new();
+    let mut msg_num = 0;
+    for (_, file_path) in &file_entries {
+        let data = map_file(file_path)?;
+        let mut offset = 0;
+        // the data after the last complete record is the zeroed space or a torn write
+        while let Some((msg, record_size)) = parse_baseline_record(&data[offset..]) {
+            let queue_offset = queue_offsets.entry((msg.topic.clone(), msg.queue_id)).or_insert(0);
+            // the baseline records have no store timestamp
+            migrated_log.write_records(&encode_msg_record(&msg, *queue_offset, msg.timestamp, key_ring)?).await?;
+            *queue_offset += 1;
+            offset += record_size;
+            msg_num += 1;
+        }
+    }
+    migrated_log.flush().await?;
+    drop(migrated_log);
+
+    fs::rename(&commit_log_dir, &baseline_dir).context(StdIOSnafu)?;
+    fs::rename(migrating_dir.join(COMMIT_LOG_DIR), &commit_log_dir).context(StdIOSnafu)?;
+    remove_dir(&migrating_dir)?;
+    remove_dir(&baseline_dir)?;
+
+    println!("migrated the commit log: msg_num={}", msg_num);
+    Ok(msg_num)
+}
+
+// Parse the baseline record at the beginning of data, return the message and the record size.
+fn parse_baseline_record(data: &[u8]) -> Option<(Message, usize)> {
+    if data.len() < BASELINE_LENGTH_SIZE {
+        return None;
+    }
+    let msg_len = u64::from_le_bytes(data[..BASELINE_LENGTH_SIZE].try_into().unwrap()) as usize;
+    if msg_len == 0 || msg_len > data.len() - BASELINE_LENGTH_SIZE {
+        return None;
+    }
+
+    let msg = Message::decode_baseline(&data[BASELINE_LENGTH_SIZE..BASELINE_LENGTH_SIZE + msg_len]).ok()?;
+    Some((msg, BASELINE_LENGTH_SIZE + msg_len))
+}
+
+fn map_file(file_path: &str) -> Result<Mmap> {
+    let file = File::open(file_path).context(StdIOSnafu)?;
+    unsafe { Mmap::map(&file).context(StdIOSnafu) }
+}
+
+fn remove_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir).context(StdIOSnafu)?;
+    }
+    Ok(())
+}
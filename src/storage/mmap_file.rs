@@ -1,10 +1,16 @@
+use std::fs;
 use std::fs::OpenOptions;
+use std::path::Path;
+use std::time::SystemTime;
+use async_trait::async_trait;
 use memmap2::{MmapMut};
 use snafu::{location, Location, ResultExt};
 use crate::error::{Result, StdIOSnafu};
 use crate::error::Error::InvalidInput;
+use crate::storage::segment::Segment;
 
 pub struct MemoryMappedFile {
+    file_path: String,
     mmap: MmapMut,
     min_offset: usize,
     max_offset: usize,
@@ -17,48 +23,142 @@ impl MemoryMappedFile {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(file_path).context(StdIOSnafu)?;
-        file.set_len(file_size).context(StdIOSnafu)?;
+        // resizing updates the modified time, which the retention goes by
+        if file.metadata().context(StdIOSnafu)?.len() != file_size {
+            file.set_len(file_size).context(StdIOSnafu)?;
+        }
 
         let mmap = unsafe { MmapMut::map_mut(&file).context(StdIOSnafu)? };
 
-        Ok(MemoryMappedFile { mmap, min_offset: start_offset, max_offset: start_offset })
+        Ok(MemoryMappedFile {
+            file_path: file_path.to_string(),
+            mmap,
+            min_offset: start_offset,
+            max_offset: start_offset,
+        })
     }
 
-    pub fn get_min_offset(&self) -> usize {
-        self.min_offset
+    // Zero out the bytes after the max offset, which may be left by a torn write before crash.
+    fn truncate_dirty_tail(&mut self) -> Result<()> {
+        let write_pos = self.max_offset - self.min_offset;
+        let dirty_len = self.mmap[write_pos..].iter().rposition(|&b| b != 0);
+
+        if let Some(dirty_len) = dirty_len {
+            println!("truncate dirty tail: file={}, offset={}, len={}", &self.file_path,
+                     self.max_offset, dirty_len + 1);
+            self.mmap[write_pos..write_pos + dirty_len + 1].fill(0);
+            self.mmap.flush_range(write_pos, dirty_len + 1).context(StdIOSnafu)?;
+        }
+
+        Ok(())
     }
+}
 
-    pub fn get_max_offset(&self) -> usize {
-        self.max_offset
+#[async_trait]
+impl Segment for MemoryMappedFile {
+    type Context = ();
+
+    fn open(_context: &(), path: &str, start_offset: usize, file_size: u64) -> Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir).context(StdIOSnafu)?;
+        }
+        MemoryMappedFile::new(path, start_offset, file_size)
     }
 
-    pub fn read_record<Func>(&mut self, reader: &Func)
-        where Func: Fn(&MmapMut, usize) -> Option<usize> {
-        let mut write_pos = 0;
-        loop {
-            let read_result = reader(&self.mmap, write_pos);
-            match read_result {
-                None => { break; }
-                Some(offset) => {
-                    write_pos += offset;
-                    self.max_offset += offset;
+    async fn list(_context: &(), dir: &str) -> Result<Vec<(usize, String)>> {
+        let dir_path = Path::new(dir);
+        if !dir_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut files = Vec::new();
+        for entry in dir_path.read_dir().context(StdIOSnafu)?.flatten() {
+            let entry_path = entry.path();
+            if entry_path.is_file() {
+                if let Ok(start_offset) = entry.file_name().to_str().unwrap_or_default().parse::<usize>() {
+                    files.push((start_offset, entry_path.to_str().unwrap().to_string()));
                 }
             }
         }
+
+        Ok(files)
+    }
+
+    // The rename is atomic, so a crash leaves either the old file or the new one.
+    async fn rename(_context: &(), path: &str, new_path: &str) -> Result<()> {
+        fs::rename(path, new_path).context(StdIOSnafu)
+    }
+
+    fn get_path(&self) -> &str {
+        self.file_path.as_str()
+    }
+
+    fn get_last_modified(&self) -> Result<SystemTime> {
+        let metadata = fs::metadata(&self.file_path).context(StdIOSnafu)?;
+        metadata.modified().context(StdIOSnafu)
+    }
+
+    fn set_last_modified(&mut self, last_modified: SystemTime) -> Result<()> {
+        let file = OpenOptions::new().write(true).open(&self.file_path).context(StdIOSnafu)?;
+        file.set_modified(last_modified).context(StdIOSnafu)
+    }
+
+    fn get_min_offset(&self) -> usize {
+        self.min_offset
+    }
+
+    fn get_max_offset(&self) -> usize {
+        self.max_offset
+    }
+
+    fn set_max_offset(&mut self, max_offset: usize) {
+        self.max_offset = max_offset;
+    }
+
+    async fn scan<F>(&mut self, reader: &F, start_offset: usize) -> Result<()>
+        where F: Fn(&[u8], usize) -> Result<Option<usize>> + Sync {
+        self.max_offset = start_offset;
+        let mut write_pos = start_offset - self.min_offset;
+        while let Some(record_size) = reader(&self.mmap, write_pos)? {
+            write_pos += record_size;
+            self.max_offset += record_size;
+        }
+
+        Ok(())
+    }
+
+    async fn truncate(&mut self, offset: usize) -> Result<()> {
+        if offset < self.min_offset || offset > self.max_offset {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Truncate offset {} is out of the file range", offset),
+            });
+        }
+
+        self.max_offset = offset;
+        self.truncate_dirty_tail()
+    }
+
+    async fn flush_from(&mut self, offset: usize) -> Result<()> {
+        let flush_pos = offset.max(self.min_offset) - self.min_offset;
+        let write_pos = self.max_offset - self.min_offset;
+        if flush_pos < write_pos {
+            self.mmap.flush_range(flush_pos, write_pos - flush_pos).context(StdIOSnafu)?;
+        }
+
+        Ok(())
     }
 
     // Write data to the memory-mapped file.
-    pub fn append(&mut self, data: &Vec<u8>) -> Result<usize> {
+    async fn append(&mut self, data: &[u8]) -> Result<usize> {
         let data_len = data.len();
         let write_pos = self.max_offset - self.min_offset;
 
         // Ensure the data fits within the mapped region.
         if write_pos + data_len <= self.mmap.len() {
-            self.mmap[write_pos..write_pos + data_len].copy_from_slice(data.as_slice());
-
-            // Flush changes to disk (optional).
-            self.mmap.flush().context(StdIOSnafu)?;
+            self.mmap[write_pos..write_pos + data_len].copy_from_slice(data);
 
             let old_offset = self.max_offset;
             self.max_offset += data_len;
@@ -72,13 +172,26 @@ impl MemoryMappedFile {
         }
     }
 
+    async fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        if offset < self.min_offset || offset + data.len() > self.max_offset {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Write offset {} is out of the file range", offset),
+            });
+        }
+
+        let write_pos = offset - self.min_offset;
+        self.mmap[write_pos..write_pos + data.len()].copy_from_slice(data);
+        self.mmap.flush_range(write_pos, data.len()).context(StdIOSnafu)
+    }
+
     // Read data from the memory-mapped file.
-    pub fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
+    async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
         let mut buffer = vec![0; data_size];
         let read_pos = offset - self.min_offset;
 
         // Ensure read offset + data size doesn't exceed the max offset.
-        if read_pos + data_size <= self.max_offset {
+        if offset + data_size <= self.max_offset {
             buffer.copy_from_slice(&self.mmap[read_pos..read_pos + data_size]);
 
             Ok(buffer)
@@ -89,12 +202,20 @@ impl MemoryMappedFile {
             })
         }
     }
+
+    // The file is unmapped before it's removed.
+    async fn delete(self) -> Result<()> {
+        let file_path = self.file_path.clone();
+        drop(self);
+        fs::remove_file(&file_path).context(StdIOSnafu)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use tempfile::{TempDir};
     use crate::storage::mmap_file::MemoryMappedFile;
+    use crate::storage::segment::Segment;
     use crate::error::Result;
 
     pub fn create_temp_dir(prefix: &str) -> TempDir {
@@ -112,10 +233,10 @@ mod tests {
 
         // Write data to the memory-mapped file.
         let data_to_write = "Hello, Memory-Mapped File!";
-        mem_mapped_file.append(data_to_write.as_bytes().to_vec().as_ref())?;
+        mem_mapped_file.append(data_to_write.as_bytes()).await?;
 
         // Read data from the memory-mapped file.
-        let read_buffer = mem_mapped_file.read(0, data_to_write.len())?;
+        let read_buffer = mem_mapped_file.read(0, data_to_write.len()).await?;
 
         // Display the read data.
         let read_data = String::from_utf8(read_buffer);
@@ -1,86 +1,334 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::{fs, u32, u64, usize};
-use memmap2::MmapMut;
-use snafu::ResultExt;
-use crate::error::{Result, StdIOSnafu};
+use snafu::{location, Location};
+use crate::error::Error::CorruptedRecord;
+use crate::error::Result;
 use crate::storage::mapped_file_queue::MappedFileQueue;
+use crate::storage::mmap_file::MemoryMappedFile;
+use crate::storage::segment::Segment;
 
-pub struct MessageIndex {
-    mapped_file_queue: MappedFileQueue,
+pub struct MessageIndex<S: Segment = MemoryMappedFile> {
+    mapped_file_queue: MappedFileQueue<S>,
+    // logical min index offset, the messages before it are expired
+    min_index: usize,
+    // total message size referenced by each sealed index file, keyed by the file start offset
+    file_msg_sizes: HashMap<usize, u64>,
 }
 
-pub const MSG_INDEX_UNIT_SIZE: usize = std::mem::size_of::<u64>() + std::mem::size_of::<u32>();
+// Index unit layout: | msg offset (8) | msg size (4) | store timestamp (8) |
+pub const MSG_INDEX_UNIT_SIZE: usize = std::mem::size_of::<u64>() + std::mem::size_of::<u32>()
+    + std::mem::size_of::<u64>();
+
+// Size of the index unit whose message is removed by compaction, the queue offset is kept as a hole.
+pub const COMPACTED_MSG_SIZE: u32 = u32::MAX;
 
 pub struct MessageIndexUnit {
     pub offset: u64,
     pub size: u32,
+    pub store_timestamp: u64,
+}
+
+impl MessageIndexUnit {
+    pub fn is_compacted(&self) -> bool {
+        self.size == COMPACTED_MSG_SIZE
+    }
+
+    // Size of the message in the commit log, zero if it's removed by compaction.
+    pub fn get_msg_size(&self) -> usize {
+        if self.is_compacted() { 0 } else { self.size as usize }
+    }
 }
 
-impl MessageIndex {
+impl<S: Segment> MessageIndex<S> where S::Context: Default {
     // Constructor: Open or create a file for message index.
-    pub fn new(store_path: &str, topic: &str, queue_id: u32, max_file_size: u64) -> Result<Self> {
+    pub async fn new(store_path: &str, topic: &str, queue_id: u32, max_file_size: u64,
+                     checkpoint_index: Option<usize>) -> Result<Self> {
+        Self::with_context(S::Context::default(), store_path, topic, queue_id, max_file_size, checkpoint_index).await
+    }
+}
+
+impl<S: Segment> MessageIndex<S> {
+    // Open the message index whose files are opened with the given context.
+    pub async fn with_context(context: S::Context, store_path: &str, topic: &str, queue_id: u32,
+                              max_file_size: u64, checkpoint_index: Option<usize>) -> Result<Self> {
         let base_dir = PathBuf::from(store_path);
         let msg_index_dir = base_dir.join(topic).join(queue_id.to_string());
 
-        fs::create_dir_all(&msg_index_dir).context(StdIOSnafu)?;
+        let mut mapped_file_queue = MappedFileQueue::with_context(
+            context, msg_index_dir.as_path().to_str().unwrap(), max_file_size)?;
 
-        let read_dir = msg_index_dir.read_dir().unwrap();
-        let file_num = read_dir.count();
+        mapped_file_queue.recovery(|data: &[u8], offset: usize| {
+            if offset + MSG_INDEX_UNIT_SIZE <= data.len() {
+                let size_bytes: [u8; 4] = data[offset + 8..offset + 12].try_into().unwrap();
+                let size = u32::from_le_bytes(size_bytes);
 
-        let mut mapped_file_queue = MappedFileQueue::new(
-            msg_index_dir.as_path().to_str().unwrap(), max_file_size).unwrap();
+                if size > 0 {
+                    return Ok(Some(MSG_INDEX_UNIT_SIZE));
+                }
+            }
+            Ok(None)
+        }, checkpoint_index.map(|index| index * MSG_INDEX_UNIT_SIZE)).await?;
 
-        if file_num != 0 {
-            mapped_file_queue.recovery(|mmap: &MmapMut, offset: usize| {
-                if offset + MSG_INDEX_UNIT_SIZE < mmap.len() {
-                    let mut buffer: Vec<u8> = vec![0; MSG_INDEX_UNIT_SIZE - 8];
-                    buffer.copy_from_slice(&mmap[offset + 8..offset + MSG_INDEX_UNIT_SIZE]);
-                    let msg_unit_slice = buffer.as_slice();
+        let min_index = mapped_file_queue.get_min_offset() / MSG_INDEX_UNIT_SIZE;
+        Ok(MessageIndex { mapped_file_queue, min_index, file_msg_sizes: HashMap::new() })
+    }
 
-                    let size_bytes: [u8; 4] = msg_unit_slice[0..4].try_into().unwrap();
-                    let size = u32::from_le_bytes(size_bytes);
+    // Append the index unit of a message, return its index offset in the queue.
+    pub async fn put_msg_index(&mut self, msg_offset: usize, msg_size: usize, store_timestamp: u64) -> Result<usize> {
+        println!("put_msg_index: msg_offset={} msg_size={}", msg_offset, msg_size);
 
-                    if size > 0 {
-                        return Some(MSG_INDEX_UNIT_SIZE);
-                    }
-                }
-                None
+        let index_unit_bytes = encode_index_unit(&MessageIndexUnit {
+            offset: msg_offset as u64,
+            size: msg_size as u32,
+            store_timestamp,
+        });
+        let index_unit_offset = self.mapped_file_queue.append(&index_unit_bytes).await?;
+        Ok(index_unit_offset / MSG_INDEX_UNIT_SIZE)
+    }
+
+    /*
+     * Put the index unit of a message at the queue offset assigned when it's written. The queue
+     * offsets before it which aren't indexed are kept as holes, like the compacted messages. An
+     * empty queue starts from the offset, the messages before it are expired.
+     */
+    pub async fn put_msg_index_at(&mut self, queue_offset: usize, msg_offset: usize, msg_size: usize,
+                                  store_timestamp: u64) -> Result<usize> {
+        let mut max_index = self.get_max_index();
+        if queue_offset > max_index && max_index * MSG_INDEX_UNIT_SIZE == self.mapped_file_queue.get_min_offset() {
+            self.mapped_file_queue.reset(queue_offset * MSG_INDEX_UNIT_SIZE).await?;
+            self.min_index = queue_offset;
+            max_index = queue_offset;
+        }
+        if queue_offset < max_index {
+            return Err(CorruptedRecord {
+                location: location!(),
+                offset: msg_offset,
+                msg: format!("Queue offset {} is already indexed, the next one is {}", queue_offset, max_index),
             });
         }
 
-        Ok(MessageIndex { mapped_file_queue })
+        for _ in max_index..queue_offset {
+            // the holes point to the message, so the message offsets are still in order
+            let hole_unit_bytes = encode_index_unit(&MessageIndexUnit {
+                offset: msg_offset as u64,
+                size: COMPACTED_MSG_SIZE,
+                store_timestamp,
+            });
+            self.mapped_file_queue.append(&hole_unit_bytes).await?;
+        }
+        self.put_msg_index(msg_offset, msg_size, store_timestamp).await
     }
 
-    // Write data to the memory-mapped file.
-    pub fn put_msg_index(&mut self, msg_offset: usize, msg_size: usize) -> Result<usize> {
-        println!("put_msg_index: msg_offset={} msg_size={}", msg_offset, msg_size);
+    // Overwrite the index unit of a message which is moved or removed by compaction.
+    pub async fn rewrite_msg_index(&mut self, index_offset: usize, index_unit: &MessageIndexUnit) -> Result<()> {
+        let index_unit_bytes = encode_index_unit(index_unit);
+        self.mapped_file_queue.write_at(index_offset * MSG_INDEX_UNIT_SIZE, &index_unit_bytes).await?;
+        // the message sizes of the files are changed
+        self.file_msg_sizes.clear();
+
+        Ok(())
+    }
+
+    // Index offsets of the messages in the given offset range of the commit log.
+    pub async fn find_index_range(&self, start_msg_offset: usize, end_msg_offset: usize) -> Result<(usize, usize)> {
+        Ok((self.find_index_by_msg_offset(start_msg_offset).await?, self.find_index_by_msg_offset(end_msg_offset).await?))
+    }
+
+    pub fn get_min_index(&self) -> usize {
+        self.min_index
+    }
+
+    pub fn get_max_index(&self) -> usize {
+        self.mapped_file_queue.get_max_offset() / MSG_INDEX_UNIT_SIZE
+    }
+
+    // The end offset in the commit log of the last indexed message.
+    pub async fn get_max_msg_offset(&self) -> Result<usize> {
+        let max_index = self.get_max_index();
+        if max_index == 0 {
+            return Ok(0);
+        }
+
+        let index_unit = self.read_msg_index(max_index - 1).await?;
+        Ok(index_unit.offset as usize + index_unit.get_msg_size())
+    }
+
+    // Find the first index whose message is at or after the given offset in the commit log.
+    async fn find_index_by_msg_offset(&self, msg_offset: usize) -> Result<usize> {
+        // messages are indexed in the order of their offset, binary search it
+        let (mut low, mut high) = (self.min_index, self.get_max_index());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if (self.read_msg_index(mid).await?.offset as usize) < msg_offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(low)
+    }
+
+    // Find the first index whose message is stored at or after the given timestamp.
+    pub async fn find_index_by_timestamp(&self, store_timestamp: u64) -> Result<usize> {
+        // store timestamps are assigned in the order of the messages, binary search it
+        let (mut low, mut high) = (self.min_index, self.get_max_index());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.read_msg_index(mid).await?.store_timestamp < store_timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
 
-        // Convert u64 and u32 values to the byte arrays
-        let offset_bytes = u64::to_le_bytes(msg_offset as u64);
-        let size_bytes = u32::to_le_bytes(msg_size as u32);
+        Ok(low)
+    }
+
+    // Drop the index units of the messages at or after the given offset in the commit log.
+    pub async fn truncate_by_msg_offset(&mut self, msg_offset: usize) -> Result<()> {
+        let truncate_index = self.find_index_by_msg_offset(msg_offset).await?;
+        if truncate_index < self.get_max_index() {
+            self.mapped_file_queue.truncate(truncate_index * MSG_INDEX_UNIT_SIZE).await?;
+        }
+
+        Ok(())
+    }
+
+    // Total message size referenced by the index units in the file.
+    async fn get_file_msg_size(&mut self, file_min_offset: usize, file_max_offset: usize, sealed: bool) -> Result<u64> {
+        if let Some(msg_size) = self.file_msg_sizes.get(&file_min_offset) {
+            return Ok(*msg_size);
+        }
+
+        let mut msg_size = 0;
+        for index in file_min_offset / MSG_INDEX_UNIT_SIZE..file_max_offset / MSG_INDEX_UNIT_SIZE {
+            msg_size += self.read_msg_index(index).await?.get_msg_size() as u64;
+        }
+        if sealed {
+            self.file_msg_sizes.insert(file_min_offset, msg_size);
+        }
+
+        Ok(msg_size)
+    }
+
+    /*
+     * Delete the oldest index files whose messages are deleted from the commit log, or expired by
+     * the retention time or size, then move the min index to the first available message.
+     */
+    pub async fn delete_expired_files(&mut self, min_msg_offset: usize, retention_ms: Option<u64>,
+                                retention_bytes: Option<u64>) -> Result<()> {
+        let file_ranges: Vec<(usize, usize)> = self.mapped_file_queue.get_mapped_files().iter()
+            .map(|f| (f.get_min_offset(), f.get_max_offset()))
+            .collect();
+
+        let mut file_msg_sizes = Vec::new();
+        for (file_index, (file_min_offset, file_max_offset)) in file_ranges.iter().enumerate() {
+            let sealed = file_index + 1 < file_ranges.len();
+            file_msg_sizes.push(self.get_file_msg_size(*file_min_offset, *file_max_offset, sealed).await?);
+        }
+        let mut total_msg_size: u64 = file_msg_sizes.iter().sum();
+
+        let mut expired_offset = self.mapped_file_queue.get_min_offset();
+        for (file_index, (_, file_max_offset)) in file_ranges.iter().enumerate().take(file_ranges.len().saturating_sub(1)) {
+            let last_index_unit = self.read_msg_index(file_max_offset / MSG_INDEX_UNIT_SIZE - 1).await?;
+            let expired_by_commit_log = (last_index_unit.offset as usize) < min_msg_offset;
+            let expired_by_time = match retention_ms {
+                Some(retention_ms) => self.mapped_file_queue.get_mapped_files()[file_index].is_older_than(retention_ms)?,
+                None => false,
+            };
+            let expired_by_size = retention_bytes
+                .map(|retention_bytes| total_msg_size > retention_bytes)
+                .unwrap_or(false);
+
+            if !expired_by_commit_log && !expired_by_time && !expired_by_size {
+                break;
+            }
+            expired_offset = *file_max_offset;
+            total_msg_size -= file_msg_sizes[file_index];
+        }
+
+        self.mapped_file_queue.delete_files_before(expired_offset).await?;
+        let file_min_offset = self.mapped_file_queue.get_min_offset();
+        self.file_msg_sizes.retain(|start_offset, _| *start_offset >= file_min_offset);
 
-        let mut index_unit_bytes: Vec<u8> = Vec::new();
-        index_unit_bytes.extend_from_slice(offset_bytes.as_slice());
-        index_unit_bytes.extend_from_slice(size_bytes.as_slice());
+        self.min_index = self.min_index.max(file_min_offset / MSG_INDEX_UNIT_SIZE);
+        self.min_index = self.find_index_by_msg_offset(min_msg_offset).await?;
 
-        self.mapped_file_queue.append(&index_unit_bytes)
+        Ok(())
     }
 
-    pub fn read_msg_index(&self, index_offset: usize) -> Result<MessageIndexUnit> {
+    // Flush the index files, return the flushed index count.
+    pub async fn flush(&mut self) -> Result<usize> {
+        let flushed_offset = self.mapped_file_queue.flush().await?;
+        Ok(flushed_offset / MSG_INDEX_UNIT_SIZE)
+    }
+
+    /*
+     * Drop the index units at the tail which point past the end of the commit log, they are
+     * left by a crash between writing the commit log and writing the index.
+     */
+    pub async fn truncate_invalid_tail(&mut self, max_msg_offset: usize) -> Result<()> {
+        let max_index = self.get_max_index();
+        let mut valid_index = max_index;
+        while valid_index > self.min_index {
+            let index_unit = self.read_msg_index(valid_index - 1).await?;
+            if index_unit.offset as usize + index_unit.get_msg_size() <= max_msg_offset {
+                break;
+            }
+            valid_index -= 1;
+        }
+
+        if valid_index < max_index {
+            println!("truncate msg index: max_index={}, valid_index={}", max_index, valid_index);
+            self.mapped_file_queue.truncate(valid_index * MSG_INDEX_UNIT_SIZE).await?;
+        }
+
+        Ok(())
+    }
+
+    // Byte offset of the first index file, the files before it are deleted.
+    pub fn get_min_file_offset(&self) -> usize {
+        self.mapped_file_queue.get_min_offset()
+    }
+
+    // Encoded index units in the index offset range.
+    pub async fn read_index_data(&self, start_index: usize, end_index: usize) -> Result<Vec<u8>> {
+        let mut index_data = Vec::with_capacity((end_index - start_index) * MSG_INDEX_UNIT_SIZE);
+        for index_offset in start_index..end_index {
+            index_data.extend(encode_index_unit(&self.read_msg_index(index_offset).await?));
+        }
+
+        Ok(index_data)
+    }
+
+    pub async fn read_msg_index(&self, index_offset: usize) -> Result<MessageIndexUnit> {
         let offset = index_offset * MSG_INDEX_UNIT_SIZE;
 
-        let msg_unit_bytes = self.mapped_file_queue.read(offset, MSG_INDEX_UNIT_SIZE)?;
+        let msg_unit_bytes = self.mapped_file_queue.read(offset, MSG_INDEX_UNIT_SIZE).await?;
         let msg_unit_slice = msg_unit_bytes.as_slice();
 
         // Read the values from the array at the specified positions
         let offset_bytes: [u8; 8] = msg_unit_slice[0..8].try_into().unwrap();
         let size_bytes: [u8; 4] = msg_unit_slice[8..12].try_into().unwrap();
+        let store_timestamp_bytes: [u8; 8] = msg_unit_slice[12..20].try_into().unwrap();
 
         // Convert the byte arrays to u64 and u32 values
         let offset = u64::from_le_bytes(offset_bytes);
         let size = u32::from_le_bytes(size_bytes);
+        let store_timestamp = u64::from_le_bytes(store_timestamp_bytes);
 
         // Create and return a MessageIndexUnit object
-        Ok(MessageIndexUnit { offset, size })
+        Ok(MessageIndexUnit { offset, size, store_timestamp })
     }
-}
\ No newline at end of file
+}
+
+fn encode_index_unit(index_unit: &MessageIndexUnit) -> Vec<u8> {
+    // Convert u64 and u32 values to the byte arrays
+    let mut index_unit_bytes: Vec<u8> = Vec::with_capacity(MSG_INDEX_UNIT_SIZE);
+    index_unit_bytes.extend_from_slice(&u64::to_le_bytes(index_unit.offset));
+    index_unit_bytes.extend_from_slice(&u32::to_le_bytes(index_unit.size));
+    index_unit_bytes.extend_from_slice(&u64::to_le_bytes(index_unit.store_timestamp));
+
+    index_unit_bytes
+}
@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+use snafu::{location, Location};
+use crate::config::CompressionCodec;
+use crate::error::Error::{CorruptedRecord, InvalidInput};
+use crate::error::Result;
+use crate::message::Message;
+use crate::storage::encryption::KeyRing;
+use crate::storage::record::{check_record, decode_record_with_header, encode_record_with_key_id, RecordHeader};
+
+/*
+ * Body of the message records, the fields are little endian:
+ * | born timestamp (8) | queue id (4) | queue offset (8) | flags (4) | topic length (4) | topic |
+ * | key length (4) | key | properties length (4) | properties | payload length (4) | payload |
+ * Properties are a sequence of | name length (4) | name | value length (4) | value |, sorted by
+ * name. The bits 4-6 of the flags are the codec of the payload if it's compressed, the payload
+ * section holds the compressed bytes then. The store timestamp is in the record header.
+ *
+ * A batch of messages of one queue is written as a batch header record, followed by the records
+ * of the messages. The header is in the same layout with the batch flag, the queue offset of the
+ * first message, and the payload | message count (4) | size of the message records (4) |. The
+ * messages are indexed by their own records, the header only makes the batch complete or absent
+ * after a crash.
+ *
+ * The body of an encrypted record is | born timestamp | queue id | queue offset | flags | encrypted
+ * sections |, the sections from the topic to the payload are encrypted with the fixed fields before
+ * them as the aad. The fixed fields are kept in plaintext, so the batches can be checked in recovery
+ * without the keys. The batch headers aren't encrypted, they have no topic then. The encrypted
+ * sections start with the data key wrapped by the master key of the record header.
+ */
+const MSG_FLAG_HAS_KEY: u32 = 1;
+// a message without payload is a tombstone
+const MSG_FLAG_HAS_PAYLOAD: u32 = 1 << 1;
+const MSG_FLAG_HAS_PROPERTIES: u32 = 1 << 2;
+const MSG_FLAG_BATCH_HEADER: u32 = 1 << 3;
+const MSG_FLAG_CODEC_SHIFT: u32 = 4;
+const MSG_FLAG_CODEC_MASK: u32 = 0b111 << MSG_FLAG_CODEC_SHIFT;
+const BATCH_HEADER_PAYLOAD_SIZE: usize = 4 + 4;
+// born timestamp, queue id, queue offset and flags
+const MSG_FIXED_FIELDS_SIZE: usize = 8 + 4 + 8 + 4;
+
+#[derive(Debug)]
+pub struct MessageRecord {
+    // milliseconds since the epoch when the record is written
+    pub store_timestamp: u64,
+    pub queue_offset: usize,
+    pub msg: Message,
+}
+
+#[derive(Debug)]
+pub struct BatchHeader {
+    pub msg_count: usize,
+    // size of the message records after the header
+    pub batch_size: usize,
+}
+
+fn put_section(buf: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    let len = u32::try_from(data.len()).map_err(|_| InvalidInput {
+        location: location!(),
+        msg: format!("Message field of {} bytes is too large", data.len()),
+    })?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(data);
+    Ok(())
+}
+
+// Encode the message as a record of the commit log, encrypted by the active key of the key ring.
+pub fn encode_msg_record(msg: &Message, queue_offset: usize, store_timestamp: u64, key_ring: &KeyRing) -> Result<Vec<u8>> {
+    encode_msg_record_with_flags(msg, queue_offset, store_timestamp, 0, key_ring)
+}
+
+/*
+ * Encode the messages of one queue as a batch header record and the message records, which are
+ * written at once. Return the data and the offsets of the message records in it.
+ */
+pub fn encode_batch_records(msgs: &[Message], base_queue_offset: usize, store_timestamp: u64,
+                            key_ring: &KeyRing) -> Result<(Vec<u8>, Vec<usize>)> {
+    let first_msg = msgs.first().ok_or_else(|| InvalidInput {
+        location: location!(),
+        msg: "Message batch is empty".to_string(),
+    })?;
+
+    let mut msg_records = Vec::new();
+    let mut record_offsets = Vec::with_capacity(msgs.len());
+    for (i, msg) in msgs.iter().enumerate() {
+        record_offsets.push(msg_records.len());
+        msg_records.extend(encode_msg_record(msg, base_queue_offset + i, store_timestamp, key_ring)?);
+    }
+
+    let mut batch_payload = Vec::with_capacity(BATCH_HEADER_PAYLOAD_SIZE);
+    batch_payload.extend_from_slice(&(msgs.len() as u32).to_le_bytes());
+    batch_payload.extend_from_slice(&(msg_records.len() as u32).to_le_bytes());
+    let header_msg = Message {
+        topic: if key_ring.active_key_id().is_some() { String::new() } else { first_msg.topic.clone() },
+        queue_id: first_msg.queue_id,
+        timestamp: store_timestamp,
+        payload: Some(batch_payload),
+        key: None,
+        header: None,
+        compression: None,
+    };
+    let mut data = encode_msg_record_with_flags(&header_msg, base_queue_offset, store_timestamp, MSG_FLAG_BATCH_HEADER,
+                                                key_ring)?;
+
+    let header_size = data.len();
+    data.extend(msg_records);
+    Ok((data, record_offsets.into_iter().map(|offset| header_size + offset).collect()))
+}
+
+fn encode_msg_record_with_flags(msg: &Message, queue_offset: usize, store_timestamp: u64, mut flags: u32,
+                               key_ring: &KeyRing) -> Result<Vec<u8>> {
+    if msg.key.is_some() {
+        flags |= MSG_FLAG_HAS_KEY;
+    }
+    if msg.payload.is_some() {
+        flags |= MSG_FLAG_HAS_PAYLOAD;
+    }
+    if msg.header.is_some() {
+        flags |= MSG_FLAG_HAS_PROPERTIES;
+    }
+    flags |= codec_id(msg.compression) << MSG_FLAG_CODEC_SHIFT;
+    let encryption_key_id = key_ring.active_key_id().filter(|_| flags & MSG_FLAG_BATCH_HEADER == 0);
+
+    let mut properties = Vec::new();
+    if let Some(header) = &msg.header {
+        let mut entries: Vec<(&String, &String)> = header.iter().collect();
+        entries.sort();
+        for (name, value) in entries {
+            put_section(&mut properties, name.as_bytes())?;
+            put_section(&mut properties, value.as_bytes())?;
+        }
+    }
+
+    let mut body = Vec::with_capacity(64 + msg.topic.len() + properties.len()
+        + msg.payload.as_ref().map(|payload| payload.len()).unwrap_or(0));
+    body.extend_from_slice(&msg.timestamp.to_le_bytes());
+    body.extend_from_slice(&msg.queue_id.to_le_bytes());
+    body.extend_from_slice(&(queue_offset as u64).to_le_bytes());
+    body.extend_from_slice(&flags.to_le_bytes());
+    put_section(&mut body, msg.topic.as_bytes())?;
+    put_section(&mut body, msg.key.as_deref().unwrap_or_default().as_bytes())?;
+    put_section(&mut body, &properties)?;
+    put_section(&mut body, msg.payload.as_deref().unwrap_or_default())?;
+
+    match encryption_key_id {
+        Some(key_id) => {
+            let encrypted_sections = key_ring.encrypt(key_id, &body[MSG_FIXED_FIELDS_SIZE..], &body[..MSG_FIXED_FIELDS_SIZE])?;
+            body.truncate(MSG_FIXED_FIELDS_SIZE);
+            body.extend(encrypted_sections);
+            Ok(encode_record_with_key_id(&body, store_timestamp, key_id))
+        }
+        None => Ok(encode_record_with_key_id(&body, store_timestamp, 0)),
+    }
+}
+
+// Validate the record read from the given offset and decode its message, decrypted by the key ring.
+pub fn decode_msg_record(data: &[u8], offset: usize, key_ring: &KeyRing) -> Result<MessageRecord> {
+    let (msg_record, flags) = decode_record_body(data, offset, key_ring)?;
+    if flags & MSG_FLAG_BATCH_HEADER != 0 {
+        return Err(InvalidInput {
+            location: location!(),
+            msg: format!("Record at offset {} is a batch header, not a message", offset),
+        });
+    }
+
+    Ok(msg_record)
+}
+
+// Decode the batch header of the record read from the given offset, None if it's a message record.
+pub fn decode_batch_header(data: &[u8], offset: usize) -> Result<Option<BatchHeader>> {
+    // the batch headers aren't encrypted, the message records are left to decode_msg_record to validate
+    if RecordHeader::parse(data).is_some_and(|header| header.key_id != 0) {
+        return Ok(None);
+    }
+    let (msg_record, flags) = decode_record_body(data, offset, &KeyRing::default())?;
+    if flags & MSG_FLAG_BATCH_HEADER == 0 {
+        return Ok(None);
+    }
+
+    let batch_payload = msg_record.msg.payload.unwrap_or_default();
+    if batch_payload.len() != BATCH_HEADER_PAYLOAD_SIZE {
+        return Err(CorruptedRecord {
+            location: location!(),
+            offset,
+            msg: "Invalid batch header".to_string(),
+        });
+    }
+
+    Ok(Some(BatchHeader {
+        msg_count: u32::from_le_bytes(batch_payload[..4].try_into().unwrap()) as usize,
+        batch_size: u32::from_le_bytes(batch_payload[4..].try_into().unwrap()) as usize,
+    }))
+}
+
+/*
+ * Check the record at the beginning of data like check_record, a batch header is checked together
+ * with its message records, so a batch is either complete or absent after recovery.
+ */
+pub fn check_msg_record(data: &[u8]) -> Option<usize> {
+    let record_size = check_record(data)?;
+    let batch_header = match decode_batch_header(&data[..record_size], 0) {
+        Ok(Some(batch_header)) => batch_header,
+        // an intact record which can't be decoded is left to the readers to report
+        Ok(None) | Err(_) => return Some(record_size),
+    };
+
+    let batch_end = record_size + batch_header.batch_size;
+    if batch_end > data.len() {
+        return None;
+    }
+    let mut check_offset = record_size;
+    for _ in 0..batch_header.msg_count {
+        check_offset += check_record(&data[check_offset..batch_end])?;
+    }
+
+    (check_offset == batch_end).then_some(batch_end)
+}
+
+fn decode_record_body(data: &[u8], offset: usize, key_ring: &KeyRing) -> Result<(MessageRecord, u32)> {
+    let (header, body) = decode_record_with_header(data, offset)?;
+    let mut reader = BodyReader { body, pos: 0, offset };
+    let born_timestamp = u64::from_le_bytes(reader.read(8)?.try_into().unwrap());
+    let queue_id = u32::from_le_bytes(reader.read(4)?.try_into().unwrap());
+    let queue_offset = u64::from_le_bytes(reader.read(8)?.try_into().unwrap()) as usize;
+    let flags = u32::from_le_bytes(reader.read(4)?.try_into().unwrap());
+    let decrypted_sections;
+    if header.key_id != 0 {
+        decrypted_sections = key_ring.decrypt(header.key_id, &body[MSG_FIXED_FIELDS_SIZE..], &body[..MSG_FIXED_FIELDS_SIZE])?;
+        reader = BodyReader { body: &decrypted_sections, pos: 0, offset };
+    }
+    let topic = reader.read_string()?;
+    let key = reader.read_string()?;
+    let properties = reader.read_section()?;
+    let payload = reader.read_section()?;
+
+    let mut header_map = HashMap::new();
+    let mut properties_reader = BodyReader { body: properties, pos: 0, offset };
+    while properties_reader.pos < properties.len() {
+        let name = properties_reader.read_string()?;
+        let value = properties_reader.read_string()?;
+        header_map.insert(name, value);
+    }
+
+    Ok((MessageRecord {
+        store_timestamp: header.store_timestamp,
+        queue_offset,
+        msg: Message {
+            topic,
+            queue_id,
+            timestamp: born_timestamp,
+            payload: (flags & MSG_FLAG_HAS_PAYLOAD != 0).then(|| payload.to_vec()),
+            key: (flags & MSG_FLAG_HAS_KEY != 0).then_some(key),
+            header: (flags & MSG_FLAG_HAS_PROPERTIES != 0).then_some(header_map),
+            compression: codec_from_id((flags & MSG_FLAG_CODEC_MASK) >> MSG_FLAG_CODEC_SHIFT, offset)?,
+        },
+    }, flags))
+}
+
+// The ids of the codecs in the flags are stable, 0 means the payload isn't compressed.
+fn codec_id(codec: Option<CompressionCodec>) -> u32 {
+    match codec {
+        None => 0,
+        Some(CompressionCodec::Lz4) => 1,
+        Some(CompressionCodec::Zstd) => 2,
+        Some(CompressionCodec::Snappy) => 3,
+    }
+}
+
+fn codec_from_id(codec_id: u32, offset: usize) -> Result<Option<CompressionCodec>> {
+    match codec_id {
+        0 => Ok(None),
+        1 => Ok(Some(CompressionCodec::Lz4)),
+        2 => Ok(Some(CompressionCodec::Zstd)),
+        3 => Ok(Some(CompressionCodec::Snappy)),
+        _ => Err(CorruptedRecord {
+            location: location!(),
+            offset,
+            msg: format!("Unknown compression codec {}", codec_id),
+        }),
+    }
+}
+
+// Read the fields of a record body, a field beyond the body means the record is corrupted.
+struct BodyReader<'a> {
+    body: &'a [u8],
+    pos: usize,
+    // offset of the record, for the errors
+    offset: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    fn read(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.body.len() - self.pos < len {
+            return Err(CorruptedRecord {
+                location: location!(),
+                offset: self.offset,
+                msg: format!("Message field of {} bytes exceeds the record body", len),
+            });
+        }
+
+        let data = &self.body[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn read_section(&mut self) -> Result<&'a [u8]> {
+        let len = u32::from_le_bytes(self.read(4)?.try_into().unwrap());
+        self.read(len as usize)
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let data = self.read_section()?;
+        String::from_utf8(data.to_vec()).map_err(|_| CorruptedRecord {
+            location: location!(),
+            offset: self.offset,
+            msg: "Message field is not valid UTF-8".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::config::CompressionCodec;
+    use crate::error::Error::{CorruptedRecord, UnsupportedRecordVersion};
+    use crate::error::Result;
+    use crate::message::Message;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use secrecy::SecretString;
+    use crate::config::{EncryptionConfig, EncryptionKey};
+    use crate::error::Error::Encryption;
+    use crate::storage::encryption::{KeyRing, WRAPPED_KEY_SIZE};
+    use crate::storage::msg_record::{check_msg_record, decode_batch_header, decode_msg_record, encode_batch_records,
+                                     encode_msg_record, MSG_FIXED_FIELDS_SIZE};
+    use crate::storage::record::{encode_record, RecordHeader};
+
+    fn test_key_ring(active_key_id: u32, key_ids: &[u32]) -> Result<KeyRing> {
+        KeyRing::new(&EncryptionConfig {
+            active_key_id: Some(active_key_id),
+            keys: key_ids.iter().map(|key_id| EncryptionKey {
+                id: *key_id,
+                key: SecretString::from(STANDARD.encode([*key_id as u8; 32])),
+            }).collect(),
+        })
+    }
+
+    #[tokio::test]
+    pub async fn test_encode_decode() -> Result<()> {
+        let key_ring = KeyRing::default();
+        let msg = Message {
+            topic: "test_topic".to_string(),
+            queue_id: 3,
+            timestamp: 1631894400000,
+            payload: Some(vec![0, 1, 255]),
+            key: Some("key".to_string()),
+            header: Some(HashMap::from([
+                ("trace_id".to_string(), "abc".to_string()),
+                ("source".to_string(), "".to_string()),
+            ])),
+            compression: Some(CompressionCodec::Zstd),
+        };
+        let record = encode_msg_record(&msg, 42, 1631894401000, &key_ring)?;
+
+        let msg_record = decode_msg_record(&record, 0, &key_ring)?;
+        assert_eq!(msg_record.store_timestamp, 1631894401000);
+        assert_eq!(msg_record.queue_offset, 42);
+        assert_eq!(msg_record.msg.topic, "test_topic");
+        assert_eq!(msg_record.msg.queue_id, 3);
+        assert_eq!(msg_record.msg.timestamp, 1631894400000);
+        assert_eq!(msg_record.msg.payload, Some(vec![0, 1, 255]));
+        assert_eq!(msg_record.msg.key.as_deref(), Some("key"));
+        assert_eq!(msg_record.msg.header, msg.header);
+        assert_eq!(msg_record.msg.compression, Some(CompressionCodec::Zstd));
+
+        // a tombstone without key and properties
+        let tombstone = Message {
+            topic: "test_topic".to_string(),
+            queue_id: 0,
+            timestamp: 0,
+            payload: None,
+            key: None,
+            header: None,
+            compression: None,
+        };
+        let msg_record = decode_msg_record(&encode_msg_record(&tombstone, 0, 0, &key_ring)?, 0, &key_ring)?;
+        assert_eq!(msg_record.msg.payload, None);
+        assert_eq!(msg_record.msg.key, None);
+        assert_eq!(msg_record.msg.header, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_batch_records() -> Result<()> {
+        let key_ring = KeyRing::default();
+        let msgs: Vec<Message> = (0..3).map(|i| Message {
+            topic: "test_topic".to_string(),
+            queue_id: 2,
+            timestamp: 0,
+            payload: Some(format!("msg {}", i).into_bytes()),
+            key: None,
+            header: None,
+            compression: None,
+        }).collect();
+        let (data, record_offsets) = encode_batch_records(&msgs, 10, 1631894400000, &key_ring)?;
+        assert_eq!(record_offsets.len(), 3);
+
+        let batch_header = decode_batch_header(&data, 0)?.unwrap();
+        assert_eq!(batch_header.msg_count, 3);
+        assert_eq!(record_offsets[0] + batch_header.batch_size, data.len());
+        assert!(decode_msg_record(&data, 0, &key_ring).is_err());
+        for (i, record_offset) in record_offsets.iter().enumerate() {
+            assert!(decode_batch_header(&data[*record_offset..], *record_offset)?.is_none());
+            let msg_record = decode_msg_record(&data[*record_offset..], *record_offset, &key_ring)?;
+            assert_eq!(msg_record.queue_offset, 10 + i);
+            assert_eq!(msg_record.msg.payload, Some(format!("msg {}", i).into_bytes()));
+        }
+
+        // the batch is checked as a whole
+        assert_eq!(check_msg_record(&data), Some(data.len()));
+        assert_eq!(check_msg_record(&data[..data.len() - 1]), None);
+        assert_eq!(check_msg_record(&data[record_offsets[1]..]), Some(record_offsets[2] - record_offsets[1]));
+        assert!(encode_batch_records(&[], 0, 0, &key_ring).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_invalid_record() -> Result<()> {
+        let key_ring = KeyRing::default();
+        let msg = Message {
+            topic: "test_topic".to_string(),
+            queue_id: 0,
+            timestamp: 0,
+            payload: Some(b"msg".to_vec()),
+            key: None,
+            header: None,
+            compression: None,
+        };
+        let mut record = encode_msg_record(&msg, 0, 0, &key_ring)?;
+        record[1] = 9;
+        assert!(matches!(decode_msg_record(&record, 0, &key_ring), Err(UnsupportedRecordVersion { version: 9, .. })));
+
+        // a section length beyond the body, with a valid checksum
+        let record = encode_record(&[0; 24], 0);
+        assert!(matches!(decode_msg_record(&record, 0, &key_ring), Err(CorruptedRecord { .. })));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_encrypted_records() -> Result<()> {
+        let msgs: Vec<Message> = (0..2).map(|i| Message {
+            topic: "secret_topic".to_string(),
+            queue_id: 1,
+            timestamp: 1631894400000,
+            payload: Some(format!("secret msg {}", i).into_bytes()),
+            key: Some("secret_key".to_string()),
+            header: Some(HashMap::from([("trace_id".to_string(), "abc".to_string())])),
+            compression: None,
+        }).collect();
+        let old_key_ring = test_key_ring(1, &[1])?;
+        let old_record = encode_msg_record(&msgs[0], 5, 1631894401000, &old_key_ring)?;
+        assert_eq!(RecordHeader::parse(&old_record).unwrap().key_id, 1);
+        for plaintext in ["secret_topic", "secret_key", "secret msg", "trace_id"] {
+            assert!(!old_record.windows(plaintext.len()).any(|window| window == plaintext.as_bytes()));
+        }
+
+        // the records of the rotated key are still readable
+        let key_ring = test_key_ring(2, &[1, 2])?;
+        let (data, record_offsets) = encode_batch_records(&msgs, 6, 1631894401000, &key_ring)?;
+        assert!(!data.windows(12).any(|window| window == b"secret_topic"));
+        assert_eq!(check_msg_record(&data), Some(data.len()));
+        assert_eq!(decode_batch_header(&data, 0)?.unwrap().msg_count, 2);
+        let new_record = &data[record_offsets[1]..];
+        assert_eq!(RecordHeader::parse(new_record).unwrap().key_id, 2);
+        assert!(decode_batch_header(new_record, 0)?.is_none());
+
+        for (record, i, queue_offset) in [(old_record.as_slice(), 0, 5), (new_record, 1, 7)] {
+            let msg_record = decode_msg_record(record, 0, &key_ring)?;
+            assert_eq!(msg_record.queue_offset, queue_offset);
+            assert_eq!(msg_record.msg.topic, "secret_topic");
+            assert_eq!(msg_record.msg.key.as_deref(), Some("secret_key"));
+            assert_eq!(msg_record.msg.header, msgs[i].header);
+            assert_eq!(msg_record.msg.payload, msgs[i].payload);
+        }
+
+        // the key isn't configured
+        assert!(matches!(decode_msg_record(new_record, 0, &old_key_ring), Err(Encryption { key_id: 2, .. })));
+        assert!(decode_msg_record(new_record, 0, &KeyRing::default()).is_err());
+
+        // the fixed fields are authenticated, a record with a modified queue offset is rejected
+        let body = &new_record[new_record.len() - RecordHeader::parse(new_record).unwrap().body_len as usize..];
+        let mut tampered_body = body.to_vec();
+        tampered_body[12] ^= 0xFF;
+        let tampered_record = crate::storage::record::encode_record_with_key_id(&tampered_body, 1631894401000, 2);
+        assert!(matches!(decode_msg_record(&tampered_record, 0, &key_ring), Err(Encryption { .. })));
+
+        // the records of a batch share the data key
+        let record_body = |record: &[u8]| record[record.len() - RecordHeader::parse(record).unwrap().body_len as usize..].to_vec();
+        let wrapped_key = |body: &[u8]| body[MSG_FIXED_FIELDS_SIZE..MSG_FIXED_FIELDS_SIZE + WRAPPED_KEY_SIZE].to_vec();
+        assert_eq!(wrapped_key(&record_body(&data[record_offsets[0]..])), wrapped_key(&record_body(new_record)));
+        assert_ne!(wrapped_key(&record_body(&old_record)), wrapped_key(&record_body(new_record)));
+
+        Ok(())
+    }
+}
@@ -1,71 +1,2118 @@
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use snafu::{location, Location, ResultExt};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify};
 use crate::storage::commit_log::CommitLog;
-use crate::config::ConfigOptions;
+use crate::config::{AckMode, CompressionCodec, ConfigOptions, FlushPolicy};
 use crate::storage::index_store::IndexStore;
-use crate::message::{ConsumeMessageRequest, DispatchMessage, Message};
-use crate::error::Result;
+use crate::storage::key_index::KeyIndexEntry;
+use crate::message::{CompressionStats, ConsumeMessageRequest, ConsumeMessageResult, DispatchMessage, Message, MessageId,
+                     PutBatchResult, PutMessageResult, QueriedMessage, StoreStatus};
+use crate::storage::msg_index::MessageIndexUnit;
+use crate::error::Error::{FlushCommitLog, InvalidInput, MessageNotFound, WalBufferFull};
+use crate::error::{Result, StdIOSnafu};
+use crate::storage::checkpoint::Checkpoint;
+use crate::storage::compression::{compress, decompress};
+use crate::storage::compaction::{compact_commit_log, get_compacted_topics, recover_compacted_file, CompactionTask,
+                                 LatestOffsets};
+use crate::storage::encryption::KeyRing;
+use crate::storage::object_store::build_operator;
+use crate::storage::msg_record::{decode_batch_header, decode_msg_record, encode_batch_records, encode_msg_record,
+                                 MessageRecord};
+use crate::storage::manifest::SegmentMeta;
+use crate::storage::migration::migrate_baseline_commit_log;
+use crate::storage::tiered_store::TieredStore;
+use crate::topic_mgr::{CleanupPolicy, TopicMgr};
+
+const PAGE_SIZE: usize = 4096;
+// Max size of the records read from the commit log for one dispatch batch.
+const DISPATCH_BATCH_SIZE: usize = 1024 * 1024;
+const DISPATCH_INTERVAL_MS: u64 = 100;
+
+// Waiting writer of the group commit, notified with the flushed offset or the error message.
+type GroupCommitRequest = oneshot::Sender<std::result::Result<usize, String>>;
 
 pub struct MessageStore {
+    config: ConfigOptions,
+    topic_mgr: Arc<TopicMgr>,
     commit_log: Arc<Mutex<CommitLog>>,
     index_store: Arc<Mutex<IndexStore>>,
+    group_commit_sender: mpsc::UnboundedSender<GroupCommitRequest>,
+    group_commit_receiver: std::sync::Mutex<Option<mpsc::UnboundedReceiver<GroupCommitRequest>>>,
+    group_commit_count: Arc<AtomicUsize>,
+    flush_notify: Arc<Notify>,
+    // messages before this offset of the commit log have been indexed
+    dispatched_offset: Arc<AtomicUsize>,
+    dispatch_notify: Arc<Notify>,
+    // the sealed commit log files uploaded to the object store, if the tiered storage is enabled
+    tiered_store: Option<Arc<TieredStore>>,
+    // the uploaded offset of the commit log, watched by the writers waiting for the upload
+    uploaded_offset: Arc<watch::Sender<usize>>,
+    upload_notify: Arc<Notify>,
+    // payload sizes before and after the compression of each topic
+    compression_stats: std::sync::Mutex<HashMap<String, CompressionStats>>,
+    // keys to encrypt the new records and decrypt the existing ones
+    key_ring: Arc<KeyRing>,
+    // latest offsets of the keys in the compacted topics, kept between the compactions
+    latest_offsets: Arc<Mutex<LatestOffsets>>,
 }
 
 impl MessageStore {
-    // Constructor: Open or create a file for message store.
-    pub fn new(config: &ConfigOptions) -> Result<Self> {
-        let commit_log = Arc::new(Mutex::new(CommitLog::new(
-            config.msg_store_path.as_str(), config.msg_store_file_size)?));
+    /*
+     * Constructor: Open or create a file for message store. With the tiered storage, a node whose
+     * local files are behind the object store is recovered from the manifests there.
+     */
+    pub async fn new(config: &ConfigOptions, topic_mgr: Arc<TopicMgr>) -> Result<Self> {
+        let key_ring = KeyRing::new(&config.encryption)?;
+        fs::create_dir_all(&config.msg_store_path).context(StdIOSnafu)?;
+        let mut checkpoint = Checkpoint::load(config.msg_store_path.as_str())?;
+        let compaction_task = recover_compacted_file(config.msg_store_path.as_str())?;
+        migrate_baseline_commit_log(config.msg_store_path.as_str(), config.commit_log_file_size(), &key_ring).await?;
+
+        let mut commit_log = CommitLog::new(
+            config.msg_store_path.as_str(), config.commit_log_file_size(),
+            checkpoint.as_ref().map(|c| c.commit_log_offset as usize)).await?;
         let config_clone = config.clone();
-        let index_store = Arc::new(Mutex::new(IndexStore::new(config_clone)?));
+        let mut index_store = IndexStore::new(config_clone)?;
+
+        let tiered_store = if config.storage.is_tiered() {
+            let object_store = build_operator(&config.storage.store)?;
+            let tiered_store = TieredStore::open(config.msg_store_path.as_str(), object_store, &config.storage).await?;
+            let uploaded_offset = tiered_store.get_uploaded_offset();
+            if commit_log.get_max_offset() < uploaded_offset {
+                // the indexes of the uploaded messages are in the object store, the key indexes are rebuilt
+                println!("recover from the object store: uploaded_offset={}", uploaded_offset);
+                index_store.truncate(0, 0).await?;
+                tiered_store.download_index_files(config.index_file_size as usize).await?;
+                let key_num = rebuild_key_indexes(&tiered_store, &mut index_store, &key_ring).await?;
+                println!("rebuilt key indexes from the object store: key_num={}", key_num);
+                index_store.write_version()?;
+                commit_log.reset_offset(uploaded_offset).await?;
+                checkpoint = None;
+            }
+            // the uploaded files shouldn't be compacted
+            commit_log.set_uploading_offset(uploaded_offset);
+            // the records of the deleted local files are read from the object store
+            let tiered_store = Arc::new(tiered_store);
+            commit_log.set_remote_segments(tiered_store.clone());
+            Some(tiered_store)
+        } else {
+            None
+        };
+        // the messages in the object store are kept in the indexes
+        let min_offset = commit_log.get_store_min_offset();
+
+        let rebuild_index_from = if index_store.is_compatible()? {
+            config.rebuild_index_from
+        } else {
+            println!("message indexes are written by an old version, rebuild them");
+            Some(0)
+        };
+        let dispatched_offset = match rebuild_index_from {
+            Some(rebuild_offset) => {
+                // the existed indexes may be corrupted, drop them before loading
+                let rebuild_offset = commit_log.align_record_offset(rebuild_offset as usize).await?;
+                index_store.truncate(rebuild_offset, min_offset).await?;
+                index_store.recovery(min_offset, commit_log.get_max_offset(), None).await?;
+                rebuild_index(&commit_log, &mut index_store, &key_ring, rebuild_offset, min_offset).await?
+            }
+            None => {
+                index_store.recovery(min_offset, commit_log.get_max_offset(), checkpoint.as_ref()).await?;
+
+                // only the messages after the checkpoint may be missing in the index, the uploaded ones are indexed
+                let replay_offset = checkpoint.as_ref().map(|c| c.commit_log_offset as usize)
+                    .unwrap_or_else(|| commit_log.get_min_offset());
+                let dispatched_offset = dispatch_commit_log(&commit_log, &mut index_store, &key_ring, replay_offset).await?;
+                println!("replayed commit log: from_offset={}, to_offset={}", replay_offset, dispatched_offset);
+
+                dispatched_offset
+            }
+        };
+        index_store.write_version()?;
+        if let Some(compaction_task) = compaction_task {
+            compaction_task.rewrite_index(&mut index_store).await?;
+            CompactionTask::remove(config.msg_store_path.as_str())?;
+        }
+        commit_log.set_queue_offset_table(index_store.get_max_indexes());
+        commit_log.set_last_store_timestamp(index_store.get_dispatch_timestamp());
+
+        let commit_log = Arc::new(Mutex::new(commit_log));
+        let index_store = Arc::new(Mutex::new(index_store));
+
+        let (group_commit_sender, group_commit_receiver) = mpsc::unbounded_channel();
+        let uploaded_offset = tiered_store.as_ref().map(|tiered_store| tiered_store.get_uploaded_offset()).unwrap_or(0);
+
+        Ok(MessageStore {
+            config: config.clone(),
+            topic_mgr,
+            commit_log,
+            index_store,
+            group_commit_sender,
+            group_commit_receiver: std::sync::Mutex::new(Some(group_commit_receiver)),
+            group_commit_count: Arc::new(AtomicUsize::new(0)),
+            flush_notify: Arc::new(Notify::new()),
+            dispatched_offset: Arc::new(AtomicUsize::new(dispatched_offset)),
+            dispatch_notify: Arc::new(Notify::new()),
+            tiered_store,
+            uploaded_offset: Arc::new(watch::Sender::new(uploaded_offset)),
+            upload_notify: Arc::new(Notify::new()),
+            compression_stats: std::sync::Mutex::new(HashMap::new()),
+            key_ring: Arc::new(key_ring),
+            latest_offsets: Arc::new(Mutex::new(LatestOffsets::default())),
+        })
+    }
+
+    // Start the background services of the message store.
+    pub fn start(&self) {
+        self.start_group_commit_service();
+        self.start_flush_service();
+        self.start_dispatch_service();
+        self.start_clean_service();
+        if let Some(tiered_store) = &self.tiered_store {
+            self.start_tiered_service(tiered_store.clone());
+        }
+
+        let store_path = self.config.msg_store_path.clone();
+        let commit_log = self.commit_log.clone();
+        let index_store = self.index_store.clone();
+        let dispatched_offset = self.dispatched_offset.clone();
+        let checkpoint_interval = Duration::from_millis(self.config.checkpoint_interval_ms);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(checkpoint_interval);
+            loop {
+                interval.tick().await;
+                if let Err(error) = flush_checkpoint(
+                    store_path.as_str(), &commit_log, &index_store, &dispatched_offset).await {
+                    eprintln!("flush checkpoint error: {:?}", error);
+                }
+            }
+        });
+    }
+
+    /*
+     * Group commit: take all the waiting writers, flush the commit log once and notify them.
+     * Writers arriving during the flush are batched into the next one.
+     */
+    fn start_group_commit_service(&self) {
+        let commit_log = self.commit_log.clone();
+        let group_commit_count = self.group_commit_count.clone();
+        let mut receiver = self.group_commit_receiver.lock().unwrap().take()
+            .expect("Group commit service is already started");
+
+        tokio::spawn(async move {
+            while let Some(request) = receiver.recv().await {
+                let mut requests = vec![request];
+                while let Ok(request) = receiver.try_recv() {
+                    requests.push(request);
+                }
+
+                let flush_result = commit_log.lock().await.flush().await
+                    .map_err(|error| format!("{:?}", error));
+                group_commit_count.fetch_add(1, Ordering::Release);
+                for request in requests {
+                    let _ = request.send(flush_result.clone());
+                }
+            }
+        });
+    }
+
+    // Asynchronous flush: flush the commit log on an interval or when notified by the writers.
+    fn start_flush_service(&self) {
+        let commit_log = self.commit_log.clone();
+        let flush_notify = self.flush_notify.clone();
+        let flush_interval = Duration::from_millis(self.config.flush_interval_ms);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(flush_interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = flush_notify.notified() => {}
+                }
 
-        Ok(MessageStore { commit_log, index_store })
+                if let Err(error) = commit_log.lock().await.flush().await {
+                    eprintln!("flush commit log error: {:?}", error);
+                }
+            }
+        });
     }
 
-    pub async fn write_msg(&self, msg: Message) -> Result<usize> {
-        // write the msg
-        let mut commit_log = self.commit_log.lock().unwrap();
+    // Reput service: tail the commit log and index the newly written messages.
+    fn start_dispatch_service(&self) {
+        let commit_log = self.commit_log.clone();
+        let index_store = self.index_store.clone();
+        let dispatched_offset = self.dispatched_offset.clone();
+        let dispatch_notify = self.dispatch_notify.clone();
+        let key_ring = self.key_ring.clone();
 
-        // TODO should write the message content field by field
-        let encoded_msg = msg.encode()?;
-        let msg_len = encoded_msg.len();
-        let mut msg_len_bytes = usize::to_le_bytes(msg_len).to_vec();
-        msg_len_bytes.extend(encoded_msg);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(DISPATCH_INTERVAL_MS));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = dispatch_notify.notified() => {}
+                }
 
-        let msg_offset = commit_log.write_records(&msg_len_bytes)?;
+                if let Err(error) = dispatch_pending(&commit_log, &index_store, &key_ring, &dispatched_offset).await {
+                    eprintln!("dispatch message error: {:?}", error);
+                }
+            }
+        });
+    }
+
+    // Clean service: delete the expired files and compact the commit log periodically.
+    fn start_clean_service(&self) {
+        let config = self.config.clone();
+        let topic_mgr = self.topic_mgr.clone();
+        let commit_log = self.commit_log.clone();
+        let index_store = self.index_store.clone();
+        let dispatched_offset = self.dispatched_offset.clone();
+        let tiered_store = self.tiered_store.clone();
+        let key_ring = self.key_ring.clone();
+        let latest_offsets = self.latest_offsets.clone();
+        let clean_interval = Duration::from_millis(self.config.clean_interval_ms);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(clean_interval);
+            loop {
+                interval.tick().await;
+                if let Some(tiered_store) = &tiered_store {
+                    if let Err(error) = clean_remote_segments(&config, &topic_mgr, &commit_log, &index_store,
+                                                              tiered_store).await {
+                        eprintln!("clean remote segments error: {:?}", error);
+                    }
+                }
+                if let Err(error) = clean_expired_files(&config, &topic_mgr, &commit_log, &index_store,
+                                                        &latest_offsets, tiered_store.as_deref()).await {
+                    eprintln!("clean expired files error: {:?}", error);
+                }
+                if let Err(error) = compact(&config, &topic_mgr, &commit_log, &index_store, &key_ring,
+                                            &latest_offsets, &dispatched_offset).await {
+                    eprintln!("compact commit log error: {:?}", error);
+                }
+            }
+        });
+    }
+
+    /*
+     * Tiered storage service: upload the sealed files of the commit log, and delete the local
+     * ones which have been uploaded and dispatched after the local retention time. In the diskless
+     * mode, the WAL segments roll by size, the file being written is also sealed on the WAL flush
+     * interval if producers wait for the upload, otherwise on the upload interval, so the segments
+     * and the manifest updates aren't flooded by small writes. The local files are deleted once
+     * uploaded.
+     */
+    fn start_tiered_service(&self, tiered_store: Arc<TieredStore>) {
+        let commit_log = self.commit_log.clone();
+        let index_store = self.index_store.clone();
+        let dispatched_offset = self.dispatched_offset.clone();
+        let uploaded_offset = self.uploaded_offset.clone();
+        let upload_notify = self.upload_notify.clone();
+        let key_ring = self.key_ring.clone();
+        let diskless = self.config.storage.diskless;
+        let seal_interval = Duration::from_millis(self.config.storage.upload_interval_ms);
+        let (upload_interval_ms, local_retention_ms) = if diskless {
+            (self.config.storage.wal_flush_interval_ms, 0)
+        } else {
+            (self.config.storage.upload_interval_ms, self.config.storage.local_retention_ms)
+        };
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(upload_interval_ms));
+            let mut sealed_time = Instant::now();
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = upload_notify.notified() => {}
+                }
+
+                // the producers waiting for the upload subscribe to the uploaded offset
+                if diskless && (uploaded_offset.receiver_count() > 0 || sealed_time.elapsed() >= seal_interval) {
+                    if let Err(error) = seal_wal_segment(&commit_log, &index_store, &key_ring, &dispatched_offset).await {
+                        eprintln!("seal WAL segment error: {:?}", error);
+                    }
+                    sealed_time = Instant::now();
+                }
+                if let Err(error) = upload_sealed_files(&commit_log, &index_store, &dispatched_offset, &tiered_store).await {
+                    eprintln!("upload commit log error: {:?}", error);
+                }
+                uploaded_offset.send_replace(tiered_store.get_uploaded_offset());
+
+                let deletable_offset = tiered_store.get_uploaded_offset()
+                    .min(dispatched_offset.load(Ordering::Acquire));
+                if let Err(error) = commit_log.lock().await.delete_uploaded_files(deletable_offset, local_retention_ms).await {
+                    eprintln!("delete uploaded files error: {:?}", error);
+                }
+            }
+        });
+    }
+
+    // Index all the messages which haven't been dispatched, return the dispatched offset.
+    pub async fn dispatch(&self) -> Result<usize> {
+        dispatch_pending(&self.commit_log, &self.index_store, &self.key_ring, &self.dispatched_offset).await
+    }
+
+    pub fn get_dispatched_offset(&self) -> usize {
+        self.dispatched_offset.load(Ordering::Acquire)
+    }
+
+    pub async fn get_store_status(&self) -> StoreStatus {
+        let commit_log = self.commit_log.lock().await;
+        StoreStatus {
+            commit_log_min_offset: commit_log.get_store_min_offset(),
+            commit_log_max_offset: commit_log.get_max_offset(),
+            dispatched_offset: self.get_dispatched_offset(),
+            group_commit_count: self.group_commit_count.load(Ordering::Acquire),
+            remote_read_cache: self.tiered_store.as_ref().map(|tiered_store| tiered_store.get_cache_stats()),
+            compression: self.compression_stats.lock().unwrap().iter()
+                .map(|(topic, stats)| (topic.clone(), CompressionStats {
+                    compression_ratio: stats.raw_bytes as f64 / stats.stored_bytes.max(1) as f64,
+                    ..stats.clone()
+                }))
+                .collect(),
+        }
+    }
 
-        let mut index_store = self.index_store.lock().unwrap();
-        let dispatch_msg = DispatchMessage {
-            topic: msg.topic.clone(),
-            queue_id: msg.queue_id,
-            msg_offset,
-            msg_size: msg_len_bytes.len(),
-            timestamp: msg.timestamp,
+    // Wait for the group commit to flush the write, the commit log is flushed at once before the service is started.
+    async fn wait_group_commit(&self) -> Result<()> {
+        if self.group_commit_receiver.lock().unwrap().is_some() {
+            self.commit_log.lock().await.flush().await?;
+            return Ok(());
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let flush_result = match self.group_commit_sender.send(sender) {
+            Ok(_) => receiver.await.unwrap_or_else(|_| Err("Group commit service stopped".to_string())),
+            Err(_) => Err("Group commit service stopped".to_string()),
         };
 
-        //TODO generate the message index, use channel
-        index_store.put_msg_index(&dispatch_msg)
+        flush_result.map(|_| ()).map_err(|msg| FlushCommitLog { location: location!(), msg })
+    }
+
+    // Wait until the commit log is uploaded to the given offset in the diskless mode.
+    async fn wait_upload(&self, end_offset: usize) -> Result<()> {
+        let mut receiver = self.uploaded_offset.subscribe();
+        let upload_ack_timeout = Duration::from_millis(self.config.storage.upload_ack_timeout_ms);
+
+        let msg = match tokio::time::timeout(upload_ack_timeout, receiver.wait_for(|offset| *offset >= end_offset)).await {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(_)) => "Tiered service stopped".to_string(),
+            Err(_) => format!("Timed out waiting for the upload to offset {}", end_offset),
+        };
+        Err(FlushCommitLog { location: location!(), msg })
+    }
+
+    // The writes are rejected when the local WAL is full in the diskless mode, until it's uploaded.
+    fn check_wal_size(&self, commit_log: &CommitLog) -> Result<usize> {
+        let buffered_size = match &self.tiered_store {
+            Some(tiered_store) if self.config.storage.diskless => {
+                commit_log.get_max_offset().saturating_sub(tiered_store.get_uploaded_offset())
+            }
+            _ => return Ok(0),
+        };
+
+        if buffered_size as u64 >= self.config.storage.wal_max_size {
+            return Err(WalBufferFull { location: location!(), buffered_size, max_size: self.config.storage.wal_max_size });
+        }
+        Ok(buffered_size)
+    }
+
+    // Regenerate the message indexes from the given offset of the commit log.
+    pub async fn rebuild_index(&self, from_offset: usize) -> Result<usize> {
+        let dispatched_offset = {
+            let mut index_store = self.index_store.lock().await;
+            let mut commit_log = self.commit_log.lock().await;
+
+            let min_offset = commit_log.get_store_min_offset();
+            let dispatched_offset = rebuild_index(&commit_log, &mut index_store, &self.key_ring, from_offset, min_offset).await?;
+            self.dispatched_offset.store(dispatched_offset, Ordering::Release);
+            // the next queue offsets follow the rebuilt indexes
+            commit_log.set_queue_offset_table(index_store.get_max_indexes());
+            dispatched_offset
+        };
+
+        flush_checkpoint(self.config.msg_store_path.as_str(), &self.commit_log, &self.index_store,
+                         &self.dispatched_offset).await?;
+        Ok(dispatched_offset)
+    }
+
+    // Index the pending messages, then flush all the data and the checkpoint before exit.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.dispatch().await?;
+        flush_checkpoint(self.config.msg_store_path.as_str(), &self.commit_log, &self.index_store,
+                         &self.dispatched_offset).await
+    }
+
+    pub async fn write_msg(&self, msg: Message) -> Result<PutMessageResult> {
+        let put_result = self.write_msgs(vec![msg], None).await?;
+        Ok(PutMessageResult {
+            msg_id: put_result.msg_ids.into_iter().next().unwrap_or_default(),
+            queue_offset: put_result.start_queue_offset,
+            durability: put_result.durability,
+            uploaded: put_result.uploaded,
+        })
+    }
+
+    /*
+     * Write the messages of one queue with a single commit log write, they get contiguous queue
+     * offsets. The payloads are compressed by the given codec, or the one of the topic.
+     */
+    pub async fn write_batch(&self, msgs: Vec<Message>, compression: Option<CompressionCodec>) -> Result<PutBatchResult> {
+        self.write_msgs(msgs, compression).await
+    }
+
+    // A single message is written as a record, more messages are written as a batch.
+    async fn write_msgs(&self, mut msgs: Vec<Message>, compression: Option<CompressionCodec>) -> Result<PutBatchResult> {
+        let first_msg = msgs.first().ok_or_else(|| InvalidInput {
+            location: location!(),
+            msg: "No message to write".to_string(),
+        })?;
+        let (topic, queue_id) = (first_msg.topic.as_str(), first_msg.queue_id);
+        if msgs.iter().any(|msg| msg.topic != topic || msg.queue_id != queue_id) {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: "Messages of a batch must belong to the same queue".to_string(),
+            });
+        }
+
+        let topic_config = self.topic_mgr.get_topic_config(topic)?;
+        let flush_policy = topic_config.flush_policy.unwrap_or(self.config.flush_policy);
+        // compressed before taking the lock
+        let (raw_bytes, stored_bytes) = compress_payloads(&mut msgs, compression.or(topic_config.compression),
+                                                          self.config.max_msg_size)?;
+        let (msgs, first_msg) = (msgs.as_slice(), &msgs[0]);
+        let (topic, queue_id) = (first_msg.topic.as_str(), first_msg.queue_id);
+
+        let (msg_offsets, msgs_end_offset, start_queue_offset) = {
+            // write the msgs
+            let mut commit_log = self.commit_log.lock().await;
+            let buffered_size = self.check_wal_size(&commit_log)?;
+
+            // the queue offsets are taken after the records are written, so a failed write won't skip them
+            let start_queue_offset = commit_log.peek_queue_offset(topic, queue_id);
+            let store_timestamp = commit_log.next_store_timestamp();
+            let (data, record_offsets) = if msgs.len() == 1 {
+                (encode_msg_record(first_msg, start_queue_offset, store_timestamp, &self.key_ring)?, vec![0])
+            } else {
+                encode_batch_records(msgs, start_queue_offset, store_timestamp, &self.key_ring)?
+            };
+
+            let write_offset = commit_log.write_records(&data).await?;
+            commit_log.next_queue_offsets(topic, queue_id, msgs.len());
+            if self.config.storage.diskless && (buffered_size + data.len()) as u64 >= self.config.storage.wal_segment_size {
+                self.upload_notify.notify_one();
+            }
+
+            match flush_policy {
+                FlushPolicy::Sync => {
+                    commit_log.flush().await?;
+                }
+                FlushPolicy::Async => {
+                    if commit_log.get_unflushed_size() >= self.config.flush_min_pages * PAGE_SIZE {
+                        self.flush_notify.notify_one();
+                    }
+                }
+                FlushPolicy::GroupCommit => {}
+            }
+
+            let msg_offsets: Vec<usize> = record_offsets.iter().map(|offset| write_offset + offset).collect();
+            (msg_offsets, write_offset + data.len(), start_queue_offset)
+        };
+        // the message index is built by the dispatch service
+        self.dispatch_notify.notify_one();
+
+        if flush_policy == FlushPolicy::GroupCommit {
+            self.wait_group_commit().await?;
+        }
+        let uploaded = self.config.storage.diskless && self.config.storage.ack_mode == AckMode::Upload;
+        if uploaded {
+            self.wait_upload(msgs_end_offset).await?;
+        }
+
+        let mut compression_stats = self.compression_stats.lock().unwrap();
+        let topic_stats = compression_stats.entry(topic.to_string()).or_default();
+        topic_stats.raw_bytes += raw_bytes;
+        topic_stats.stored_bytes += stored_bytes;
+        drop(compression_stats);
+
+        let msg_ids = msg_offsets.into_iter().enumerate()
+            .map(|(i, msg_offset)| MessageId::new(self.config.broker_id, msg_offset as u64, topic, queue_id,
+                                                  (start_queue_offset + i) as u64).encode())
+            .collect();
+        Ok(PutBatchResult {
+            msg_ids,
+            start_queue_offset,
+            end_queue_offset: start_queue_offset + msgs.len(),
+            durability: flush_policy,
+            uploaded,
+        })
+    }
+
+    /*
+     * The earliest queue offset whose message is stored at or after the timestamp in milliseconds,
+     * or the max queue offset if there is none.
+     */
+    pub async fn get_offset_by_timestamp(&self, topic: &str, queue_id: u32, timestamp: u64) -> Result<usize> {
+        self.index_store.lock().await.find_index_by_timestamp(topic, queue_id, timestamp).await
+    }
+
+    /*
+     * Read the message located by the message id from the commit log directly. Once the record at
+     * the offset is another message, like after compaction, the message is found by its queue
+     * position in the message index.
+     */
+    pub async fn get_message_by_id(&self, msg_id: &str) -> Result<Message> {
+        let msg_id = MessageId::decode(msg_id)?;
+        if msg_id.broker_id != self.config.broker_id {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Message belongs to broker {}", msg_id.broker_id),
+            });
+        }
+
+        let msg_offset = msg_id.offset as usize;
+        let msg_content = {
+            let commit_log = self.commit_log.lock().await;
+            if msg_offset < commit_log.get_store_min_offset() || msg_offset >= commit_log.get_max_offset() {
+                None
+            } else {
+                commit_log.read_record_at(msg_offset).await.ok()
+            }
+        };
+        let msg_record = match msg_content {
+            Some(msg_content) => Some(decode_msg_record(&msg_content, msg_offset, &self.key_ring)?),
+            None => None,
+        };
+        let mut msg = match msg_record {
+            Some(MessageRecord { queue_offset, msg, .. })
+            if msg_id.matches(msg.topic.as_str(), msg.queue_id, queue_offset as u64) => msg,
+            _ => self.get_message_by_queue_position(&msg_id).await?,
+        };
+        decompress_payload(&mut msg, &[], self.config.max_msg_size)?;
+        Ok(msg)
+    }
+
+    // Read the message of the id by its queue position, the message may be moved by compaction.
+    async fn get_message_by_queue_position(&self, msg_id: &MessageId) -> Result<Message> {
+        let msg_not_found = || MessageNotFound {
+            location: location!(),
+            msg_id: msg_id.encode(),
+        };
+        let queue_offset = msg_id.queue_offset as usize;
+        let msg_index_unit = self.index_store.lock().await
+            .read_msg_index(msg_id.topic.as_str(), msg_id.queue_id, queue_offset, 1).await?
+            .pop().filter(|msg_index_unit| !msg_index_unit.is_compacted())
+            .ok_or_else(msg_not_found)?;
+
+        let msg_content = self.read_records(std::slice::from_ref(&msg_index_unit)).await?.remove(0);
+        let MessageRecord { queue_offset: record_queue_offset, msg, .. } =
+            decode_msg_record(&msg_content, msg_index_unit.offset as usize, &self.key_ring)?;
+        if !msg_id.matches(msg.topic.as_str(), msg.queue_id, record_queue_offset as u64) {
+            return Err(msg_not_found());
+        }
+        Ok(msg)
+    }
+
+    /*
+     * Find the messages of the key stored in the time range, from the latest one, together with
+     * their queue offsets.
+     */
+    pub async fn query_by_key(&self, topic: &str, key: &str, time_range: Range<u64>,
+                              max_msg_count: usize) -> Result<Vec<QueriedMessage>> {
+        // (queue id, queue offset) of the found messages, and their index units
+        let (queue_positions, msg_index_units): (Vec<(u32, usize)>, Vec<MessageIndexUnit>) = {
+            let mut index_store = self.index_store.lock().await;
+            let key_index_entries = index_store.query_by_key(topic, key, &time_range, max_msg_count);
+
+            let mut found_msgs = Vec::new();
+            for key_index_entry in key_index_entries {
+                let queue_offset = key_index_entry.queue_offset as usize;
+                // the message may be expired or removed by compaction
+                let msg_index_unit = match index_store.read_msg_index(topic, key_index_entry.queue_id, queue_offset, 1).await {
+                    Ok(mut index_units) if !index_units.is_empty() => index_units.remove(0),
+                    _ => continue,
+                };
+                if !msg_index_unit.is_compacted() {
+                    found_msgs.push(((key_index_entry.queue_id, queue_offset), msg_index_unit));
+                }
+            }
+            found_msgs.into_iter().unzip()
+        };
+
+        let msg_contents = self.read_records(&msg_index_units).await?;
+        let mut result_msg_list = Vec::new();
+        for (((queue_id, queue_offset), msg_index_unit), msg_content) in
+            queue_positions.into_iter().zip(msg_index_units).zip(msg_contents) {
+            let mut msg = decode_msg_record(&msg_content, msg_index_unit.offset as usize, &self.key_ring)?.msg;
+            // skip the messages of other keys with the same hash
+            if msg.key.as_deref() == Some(key) {
+                decompress_payload(&mut msg, &[], self.config.max_msg_size)?;
+                result_msg_list.push(QueriedMessage { queue_id, queue_offset, message: msg });
+            }
+        }
+
+        Ok(result_msg_list)
     }
 
+    // The messages of the consume request, without the next offset.
+    #[cfg(test)]
     pub async fn read_msg(&self, consume_msg: ConsumeMessageRequest) -> Result<Vec<Message>> {
-        let commit_log = self.commit_log.lock().unwrap();
-        let mut index_store = self.index_store.lock().unwrap();
+        Ok(self.consume(consume_msg).await?.msg_list)
+    }
+
+    /*
+     * Read the messages from the offset of the request, or the committed offset of its consumer
+     * group. A group without a committed offset, or whose committed messages are expired, starts
+     * from the earliest message. The group commits the returned next offset after processing them.
+     */
+    pub async fn consume(&self, consume_msg: ConsumeMessageRequest) -> Result<ConsumeMessageResult> {
+        let (topic, queue_id) = (consume_msg.topic.as_str(), consume_msg.queue_id);
+        // None if the offset is given, or the group hasn't committed
+        let committed_offset = match (consume_msg.offset, consume_msg.group.as_deref()) {
+            (Some(_), _) => None,
+            (None, Some(group)) => self.topic_mgr.fetch_offset(group, topic, queue_id)?,
+            (None, None) => return Err(InvalidInput {
+                location: location!(),
+                msg: "Either the offset or the consumer group is required".to_string(),
+            }),
+        };
 
-        let index_query_result = index_store.read_msg_index(
-            consume_msg.topic.as_str(),
-            consume_msg.queue_id,
-            consume_msg.offset,
-            consume_msg.max_msg_count);
+        // only the dispatched messages can be found in the index
+        let (start_offset, index_query_result) = {
+            let mut index_store = self.index_store.lock().await;
+            let start_offset = match consume_msg.offset {
+                Some(offset) => offset,
+                None => committed_offset.unwrap_or(0).max(index_store.get_min_index(topic, queue_id).await?),
+            };
+            (start_offset, index_store.read_msg_index(topic, queue_id, start_offset, consume_msg.max_msg_count).await?)
+        };
+        let next_offset = start_offset + index_query_result.len();
+        // the queue offsets of the messages removed by compaction are skipped
+        let msg_index_units: Vec<MessageIndexUnit> = index_query_result.into_iter()
+            .filter(|msg_index_unit| !msg_index_unit.is_compacted())
+            .collect();
 
+        let msg_contents = self.read_records(&msg_index_units).await?;
         let mut result_msg_list = Vec::new();
 
-        for msg_index_unit in index_query_result {
-            let msg_content = commit_log.read_records(&msg_index_unit)?;
-            let msg_len_size = std::mem::size_of::<usize>();
-            let msg = Message::decode(&msg_content.as_slice()[msg_len_size..])?;
+        for (msg_index_unit, msg_content) in msg_index_units.iter().zip(msg_contents) {
+            let mut msg = decode_msg_record(&msg_content, msg_index_unit.offset as usize, &self.key_ring)?.msg;
+            decompress_payload(&mut msg, &consume_msg.accepted_compressions, self.config.max_msg_size)?;
 
             result_msg_list.push(msg);
         }
 
-        Ok(result_msg_list)
+        Ok(ConsumeMessageResult { msg_list: result_msg_list, next_offset })
+    }
+
+    // Read the records of the index units from the commit log, which falls back to the object store.
+    async fn read_records(&self, msg_index_units: &[MessageIndexUnit]) -> Result<Vec<Vec<u8>>> {
+        let commit_log = self.commit_log.lock().await;
+        let mut records = Vec::with_capacity(msg_index_units.len());
+        for msg_index_unit in msg_index_units {
+            records.push(commit_log.read_records(msg_index_unit).await?);
+        }
+
+        Ok(records)
+    }
+}
+
+/*
+ * Dispatch the messages in the commit log from the given offset to the index store, messages
+ * which have already been indexed are skipped. Return the offset dispatched to.
+ */
+async fn dispatch_commit_log(commit_log: &CommitLog, index_store: &mut IndexStore, key_ring: &KeyRing,
+                             from_offset: usize) -> Result<usize> {
+    let max_offset = commit_log.get_max_offset();
+    let mut dispatch_offset = from_offset.max(commit_log.get_store_min_offset());
+
+    while dispatch_offset < max_offset {
+        let records = commit_log.read_records_from(dispatch_offset, DISPATCH_BATCH_SIZE).await?;
+        if records.is_empty() {
+            break;
+        }
+
+        for (record_offset, record) in records {
+            dispatch_record(index_store, key_ring, record_offset, &record, true).await?;
+            dispatch_offset = record_offset + record.len();
+        }
+    }
+
+    Ok(dispatch_offset)
+}
+
+/*
+ * Dispatch the messages written after the dispatched offset. The commit log is read in batches,
+ * and only locked while reading, so the writers aren't blocked by the index building.
+ */
+async fn dispatch_pending(commit_log: &Mutex<CommitLog>, index_store: &Mutex<IndexStore>, key_ring: &KeyRing,
+                          dispatched_offset: &AtomicUsize) -> Result<usize> {
+    let mut index_store = index_store.lock().await;
+    loop {
+        let from_offset = dispatched_offset.load(Ordering::Acquire);
+        let records = commit_log.lock().await.read_records_from(from_offset, DISPATCH_BATCH_SIZE).await?;
+        if records.is_empty() {
+            return Ok(from_offset);
+        }
+
+        for (record_offset, record) in records {
+            dispatch_record(&mut index_store, key_ring, record_offset, &record, false).await?;
+            dispatched_offset.store(record_offset + record.len(), Ordering::Release);
+        }
+    }
+}
+
+/*
+ * Compress the payloads with the codec, a payload is kept raw if it doesn't get smaller. The ones
+ * compressed by the producer are checked by decompressing them, a payload beyond the max message
+ * size is rejected. Return the payload sizes before and after the compression.
+ */
+fn compress_payloads(msgs: &mut [Message], codec: Option<CompressionCodec>, max_msg_size: usize) -> Result<(u64, u64)> {
+    let (mut raw_bytes, mut stored_bytes) = (0, 0);
+    for msg in msgs.iter_mut() {
+        let payload = match &msg.payload {
+            Some(payload) => payload,
+            None => continue,
+        };
+
+        if msg.compression.is_none() && payload.len() > max_msg_size {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Message payload of {} bytes exceeds the max size {}", payload.len(), max_msg_size),
+            });
+        }
+
+        match (msg.compression, codec) {
+            (Some(producer_codec), _) => {
+                raw_bytes += decompress(producer_codec, payload, max_msg_size)?.len() as u64;
+            }
+            (None, Some(codec)) => {
+                raw_bytes += payload.len() as u64;
+                let compressed_payload = compress(codec, payload)?;
+                if compressed_payload.len() < payload.len() {
+                    msg.payload = Some(compressed_payload);
+                    msg.compression = Some(codec);
+                }
+            }
+            (None, None) => {
+                raw_bytes += payload.len() as u64;
+            }
+        }
+        stored_bytes += msg.payload.as_ref().map(|payload| payload.len()).unwrap_or(0) as u64;
+    }
+
+    Ok((raw_bytes, stored_bytes))
+}
+
+// Decompress the payload unless its codec is accepted by the consumer.
+fn decompress_payload(msg: &mut Message, accepted_compressions: &[CompressionCodec], max_msg_size: usize) -> Result<()> {
+    if let (Some(codec), Some(payload)) = (msg.compression, &msg.payload) {
+        if !accepted_compressions.contains(&codec) {
+            msg.payload = Some(decompress(codec, payload, max_msg_size)?);
+            msg.compression = None;
+        }
+    }
+
+    Ok(())
+}
+
+async fn dispatch_record(index_store: &mut IndexStore, key_ring: &KeyRing, record_offset: usize, record: &[u8],
+                         skip_indexed: bool) -> Result<()> {
+    // the messages of a batch are indexed by their own records
+    if decode_batch_header(record, record_offset)?.is_some() {
+        return Ok(());
+    }
+    let MessageRecord { store_timestamp, queue_offset, msg } = decode_msg_record(record, record_offset, key_ring)?;
+
+    if skip_indexed && record_offset < index_store.get_max_msg_offset(msg.topic.as_str(), msg.queue_id).await? {
+        return Ok(());
+    }
+    // a queue offset is indexed once, the record reusing it is skipped so the later ones are still dispatched
+    if queue_offset < index_store.get_max_index(msg.topic.as_str(), msg.queue_id).await? {
+        eprintln!("skip the record of an indexed queue offset: offset={}, topic={}, queue_id={}, queue_offset={}",
+                  record_offset, msg.topic, msg.queue_id, queue_offset);
+        return Ok(());
     }
-}
\ No newline at end of file
+
+    // the message is indexed at the queue offset assigned when it's written, the consumers see the same offsets
+    index_store.put_msg_index(&DispatchMessage {
+        topic: msg.topic,
+        queue_id: msg.queue_id,
+        queue_offset,
+        msg_offset: record_offset,
+        msg_size: record.len(),
+        timestamp: store_timestamp,
+        key: msg.key,
+    }).await?;
+
+    Ok(())
+}
+
+/*
+ * Index the keys of the messages in the object store, whose message indexes are downloaded. The
+ * key indexes aren't uploaded, so the segments are streamed once to find the keys.
+ */
+async fn rebuild_key_indexes(tiered_store: &TieredStore, index_store: &mut IndexStore, key_ring: &KeyRing) -> Result<usize> {
+    let mut key_num = 0;
+    tiered_store.scan_records(DISPATCH_BATCH_SIZE, |record_offset, record| {
+        if decode_batch_header(record, record_offset)?.is_some() {
+            return Ok(());
+        }
+        let MessageRecord { store_timestamp, queue_offset, msg } = decode_msg_record(record, record_offset, key_ring)?;
+        if let Some(key) = msg.key {
+            let key_index_entry = KeyIndexEntry {
+                queue_id: msg.queue_id,
+                queue_offset: queue_offset as u64,
+                store_timestamp,
+            };
+            index_store.put_key(msg.topic.as_str(), key.as_str(), &key_index_entry, record_offset + record.len())?;
+            key_num += 1;
+        }
+
+        Ok(())
+    }).await?;
+
+    Ok(key_num)
+}
+
+/*
+ * Drop the indexes of the messages from the given offset, then dispatch them again. The messages
+ * whose local files are deleted are read back from the object store.
+ */
+async fn rebuild_index(commit_log: &CommitLog, index_store: &mut IndexStore, key_ring: &KeyRing, from_offset: usize,
+                       min_offset: usize) -> Result<usize> {
+    let from_offset = commit_log.align_record_offset(from_offset).await?;
+    println!("rebuild message index: from_offset={}", from_offset);
+    index_store.truncate(from_offset, min_offset).await?;
+
+    let dispatched_offset = dispatch_commit_log(commit_log, index_store, key_ring, from_offset).await?;
+    println!("rebuilt message index: from_offset={}, to_offset={}", from_offset, dispatched_offset);
+
+    Ok(dispatched_offset)
+}
+
+/*
+ * Delete the expired files of the commit log, then the index files pointing to them or expired by
+ * the retention settings of their topics. The commit log is shared by all the topics, so its files
+ * are kept until the longest retention time among the topics, and while they hold the latest
+ * messages of the keys in the compacted topics.
+ */
+async fn clean_expired_files(config: &ConfigOptions, topic_mgr: &TopicMgr, commit_log: &Mutex<CommitLog>,
+                             index_store: &Mutex<IndexStore>, latest_offsets: &Mutex<LatestOffsets>,
+                             tiered_store: Option<&TieredStore>) -> Result<usize> {
+    let (retention_ms, retention_bytes) = commit_log_retention(config, topic_mgr)?;
+    let retained_offset = latest_offsets.lock().await.get_retained_offset(&get_compacted_topics(config, topic_mgr)?);
+
+    let mut index_store = index_store.lock().await;
+    let min_offset = {
+        let mut commit_log = commit_log.lock().await;
+        // the files which haven't been uploaded are kept, or the object store would miss them
+        let max_deletable_offset = tiered_store.map(|tiered_store| tiered_store.get_uploaded_offset())
+            .unwrap_or(usize::MAX)
+            .min(retained_offset);
+        commit_log.delete_expired_files(retention_ms, retention_bytes, max_deletable_offset).await?;
+        commit_log.get_store_min_offset()
+    };
+    index_store.delete_expired_files(min_offset, |topic| topic_mgr.get_topic_config(topic)).await?;
+
+    Ok(min_offset)
+}
+
+/*
+ * Retention time and size of the commit log, the longest ones among the global settings and the
+ * topics, None if none of them is set. Without a global setting, the topics which don't set one
+ * are kept as long as the ones which do. The compacted topics keep the latest messages of the keys
+ * instead, so their settings are skipped.
+ */
+fn commit_log_retention(config: &ConfigOptions, topic_mgr: &TopicMgr) -> Result<(Option<u64>, Option<u64>)> {
+    let (mut retention_ms, mut retention_bytes) = (config.retention_ms, config.retention_bytes);
+    for topic in topic_mgr.list_topics()? {
+        let topic_config = topic.get_config();
+        if topic_config.cleanup_policy == Some(CleanupPolicy::Compact) {
+            continue;
+        }
+        // None is less than any value
+        retention_ms = retention_ms.max(topic_config.retention_ms);
+        retention_bytes = retention_bytes.max(topic_config.retention_bytes);
+    }
+
+    Ok((retention_ms, retention_bytes))
+}
+
+/*
+ * Delete the segments of the commit log in the object store which are expired by the retention
+ * settings, they are older than the local files so should be deleted first. The index segments
+ * follow the local index files, which are deleted with the messages they point to.
+ */
+async fn clean_remote_segments(config: &ConfigOptions, topic_mgr: &TopicMgr, commit_log: &Mutex<CommitLog>,
+                               index_store: &Mutex<IndexStore>, tiered_store: &TieredStore) -> Result<usize> {
+    let (retention_ms, retention_bytes) = commit_log_retention(config, topic_mgr)?;
+    let max_offset = commit_log.lock().await.get_max_offset();
+
+    let expired_segments = tiered_store.take_expired_segments(retention_ms, retention_bytes, max_offset).await?;
+    tiered_store.delete_segments(&expired_segments).await?;
+
+    let min_file_offsets = index_store.lock().await.get_min_file_offsets();
+    for (topic, queue_id, min_file_offset) in min_file_offsets {
+        tiered_store.delete_index_segments_before(topic.as_str(), queue_id, min_file_offset).await?;
+    }
+
+    Ok(expired_segments.len())
+}
+
+// Seal the file being written as a WAL segment, and index it so it can be uploaded.
+async fn seal_wal_segment(commit_log: &Mutex<CommitLog>, index_store: &Mutex<IndexStore>, key_ring: &KeyRing,
+                          dispatched_offset: &AtomicUsize) -> Result<()> {
+    commit_log.lock().await.seal_active_file();
+    dispatch_pending(commit_log, index_store, key_ring, dispatched_offset).await?;
+
+    Ok(())
+}
+
+/*
+ * Upload the sealed files of the commit log which haven't been uploaded, return the uploaded count.
+ * The index units of each queue pointing to a file are uploaded before it, so the queues can be
+ * recovered up to the uploaded offset of the commit log. Only the dispatched files are uploaded,
+ * the uploaded segments are added to the manifests in a batch at the end.
+ */
+async fn upload_sealed_files(commit_log: &Mutex<CommitLog>, index_store: &Mutex<IndexStore>,
+                             dispatched_offset: &AtomicUsize, tiered_store: &TieredStore) -> Result<usize> {
+    let mut uploaded_num = 0;
+    loop {
+        let file_to_upload = {
+            let mut commit_log = commit_log.lock().await;
+            // the first segment starts from the local files, the others follow the uploaded ones
+            let upload_offset = tiered_store.get_next_upload_offset().unwrap_or_else(|| commit_log.get_min_offset());
+            commit_log.take_file_to_upload(upload_offset, dispatched_offset.load(Ordering::Acquire))?
+        };
+        match file_to_upload {
+            Some(sealed_file) => {
+                let index_segments = index_store.lock().await
+                    .read_index_segments(sealed_file.start_offset, sealed_file.end_offset).await?;
+                for index_segment in index_segments {
+                    let topic = index_segment.topic.as_str();
+                    // uploaded before a restart
+                    if tiered_store.get_index_uploaded_offset(topic, index_segment.queue_id) > index_segment.start_offset {
+                        continue;
+                    }
+                    let segment = SegmentMeta::new(index_segment.start_offset,
+                                                   index_segment.start_offset + index_segment.data.len(),
+                                                   index_segment.data.len(), sealed_file.last_modified);
+                    tiered_store.upload_index_segment(topic, index_segment.queue_id, segment, &index_segment.data).await?;
+                }
+
+                let segment = SegmentMeta::new(sealed_file.start_offset, sealed_file.end_offset, sealed_file.data_size,
+                                               sealed_file.last_modified);
+                tiered_store.upload_segment(segment, |offset, size| async move {
+                    commit_log.lock().await.read_file_data(offset, size).await
+                }).await?;
+                uploaded_num += 1;
+            }
+            None => {
+                tiered_store.flush_manifests().await?;
+                return Ok(uploaded_num);
+            }
+        }
+    }
+}
+
+// Compact the commit log files which have been flushed and dispatched.
+async fn compact(config: &ConfigOptions, topic_mgr: &TopicMgr, commit_log: &Mutex<CommitLog>,
+                 index_store: &Mutex<IndexStore>, key_ring: &KeyRing, latest_offsets: &Mutex<LatestOffsets>,
+                 dispatched_offset: &AtomicUsize) -> Result<usize> {
+    let compactable_offset = commit_log.lock().await.get_flushed_offset()
+        .min(dispatched_offset.load(Ordering::Acquire));
+    compact_commit_log(config, topic_mgr, commit_log, index_store, key_ring, &mut *latest_offsets.lock().await,
+                       compactable_offset).await
+}
+
+/*
+ * Flush the commit log and the indexes, then persist their positions to the checkpoint file.
+ * Recovery replays the commit log from the checkpoint, so it shouldn't pass the dispatched offset.
+ */
+async fn flush_checkpoint(store_path: &str, commit_log: &Mutex<CommitLog>, index_store: &Mutex<IndexStore>,
+                          dispatched_offset: &AtomicUsize) -> Result<()> {
+    let flushed_offset = commit_log.lock().await.flush().await?;
+
+    let checkpoint = {
+        let mut index_store = index_store.lock().await;
+
+        Checkpoint {
+            commit_log_offset: flushed_offset.min(dispatched_offset.load(Ordering::Acquire)) as u64,
+            index_offsets: index_store.flush().await?,
+            dispatch_timestamp: index_store.get_dispatch_timestamp(),
+        }
+    };
+
+    checkpoint.flush(store_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use secrecy::SecretString;
+    use crate::config::{AckMode, CompressionCodec, ConfigOptions, EncryptionConfig, EncryptionKey, FsConfig,
+                        ObjectStoreConfig, StorageConfig};
+    use crate::error::Result;
+    use crate::message::{ConsumeMessageRequest, Message, MessageId};
+    use std::sync::Arc;
+    use tokio::sync::oneshot;
+    use std::time::{Duration, SystemTime};
+    use crate::config::FlushPolicy;
+    use crate::error::Error::{Encryption, MessageNotFound, OffsetOutOfRange, UnsupportedRecordVersion,
+                              WalBufferFull};
+    use crate::storage::compression::{compress, decompress};
+    use crate::storage::msg_store::{clean_expired_files, compact, upload_sealed_files, MessageStore};
+    use crate::storage::record::{current_timestamp_ms, encode_record, RECORD_VERSION};
+    use crate::topic_mgr::{Topic, TopicMgr};
+
+    pub fn create_temp_dir(prefix: &str) -> TempDir {
+        tempfile::Builder::new().prefix(prefix).tempdir().unwrap()
+    }
+
+    pub fn test_config(dir_path: &TempDir) -> ConfigOptions {
+        ConfigOptions {
+            msg_store_path: dir_path.path().to_str().unwrap().to_string(),
+            msg_store_file_size: 1024,
+            index_file_size: 240,
+            ..Default::default()
+        }
+    }
+
+    pub async fn new_msg_store(config: &ConfigOptions) -> Result<MessageStore> {
+        let topic_mgr = TopicMgr::new(config.msg_store_path.as_str())?;
+        MessageStore::new(config, Arc::new(topic_mgr)).await
+    }
+
+    pub fn test_msg(topic: &str, queue_id: u32, payload: &str) -> Message {
+        Message {
+            topic: topic.to_string(),
+            queue_id,
+            timestamp: 1631894400,
+            payload: Some(payload.as_bytes().to_vec()),
+            key: None,
+            header: None,
+            compression: None,
+        }
+    }
+
+    pub fn test_keyed_msg(topic: &str, key: &str, payload: Option<&str>) -> Message {
+        Message {
+            key: Some(key.to_string()),
+            payload: payload.map(|payload| payload.as_bytes().to_vec()),
+            ..test_msg(topic, 0, "")
+        }
+    }
+
+    pub fn consume_request(topic: &str, queue_id: u32, offset: usize) -> ConsumeMessageRequest {
+        ConsumeMessageRequest {
+            topic: topic.to_string(),
+            queue_id,
+            offset: Some(offset),
+            max_msg_count: 100,
+            group: None,
+            accepted_compressions: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_recovery_from_checkpoint() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..20 {
+            msg_store.write_msg(test_msg("test_topic", i % 2, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.shutdown().await?;
+
+        // the messages written after the checkpoint are recovered by scanning
+        for i in 20..30 {
+            msg_store.write_msg(test_msg("test_topic", i % 2, format!("msg {}", i).as_str())).await?;
+        }
+        drop(msg_store);
+
+        let msg_store = new_msg_store(&config).await?;
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 15);
+        assert_eq!(msg_list[14].payload.as_deref(), Some("msg 28".as_bytes()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_dispatch() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..5 {
+            let put_result = msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+            assert_eq!(put_result.queue_offset, i);
+        }
+
+        // messages are invisible until they are indexed
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, 0)).await?.len(), 0);
+        assert_eq!(msg_store.get_dispatched_offset(), 0);
+
+        let dispatched_offset = msg_store.dispatch().await?;
+        assert_eq!(msg_store.get_dispatched_offset(), dispatched_offset);
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, 0)).await?.len(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_dispatch_by_queue_offset() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..2 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        // the queue offsets 2..5 are skipped, they are left as holes in the index
+        let queue_offset_table = HashMap::from([("test_topic".to_string(), HashMap::from([(0, 5)]))]);
+        msg_store.commit_log.lock().await.set_queue_offset_table(queue_offset_table);
+        let put_result = msg_store.write_msg(test_msg("test_topic", 0, "msg 5")).await?;
+        assert_eq!(put_result.queue_offset, 5);
+        msg_store.dispatch().await?;
+
+        let consume_result = msg_store.consume(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(consume_result.msg_list.len(), 3);
+        assert_eq!(consume_result.next_offset, 6);
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 5)).await?;
+        assert_eq!(msg_list[0].payload.as_deref(), Some("msg 5".as_bytes()));
+
+        // an indexed queue offset isn't indexed again, the record is skipped and the later ones are dispatched
+        let queue_offset_table = HashMap::from([("test_topic".to_string(), HashMap::from([(0, 1)]))]);
+        msg_store.commit_log.lock().await.set_queue_offset_table(queue_offset_table);
+        msg_store.write_msg(test_msg("test_topic", 0, "msg 1 again")).await?;
+        let queue_offset_table = HashMap::from([("test_topic".to_string(), HashMap::from([(0, 6)]))]);
+        msg_store.commit_log.lock().await.set_queue_offset_table(queue_offset_table);
+        msg_store.write_msg(test_msg("test_topic", 0, "msg 6")).await?;
+        msg_store.write_msg(test_msg("other_topic", 0, "other msg")).await?;
+        msg_store.dispatch().await?;
+        assert_eq!(msg_store.get_dispatched_offset(), msg_store.commit_log.lock().await.get_max_offset());
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, 1)).await?[0].payload.as_deref(),
+                   Some("msg 1".as_bytes()));
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, 6)).await?[0].payload.as_deref(),
+                   Some("msg 6".as_bytes()));
+        assert_eq!(msg_store.read_msg(consume_request("other_topic", 0, 0)).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_rebuild_index() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let mut config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..10 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+
+        msg_store.dispatch().await?;
+
+        // rebuild from an offset in the middle of the commit log
+        msg_store.rebuild_index(100).await?;
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, 0)).await?.len(), 10);
+
+        // the queue offsets continue from the rebuilt indexes
+        let queue_offset_table = HashMap::from([("test_topic".to_string(), HashMap::from([(0, 3)]))]);
+        msg_store.commit_log.lock().await.set_queue_offset_table(queue_offset_table);
+        msg_store.rebuild_index(0).await?;
+        assert_eq!(msg_store.write_msg(test_msg("test_topic", 0, "msg 10")).await?.queue_offset, 10);
+        msg_store.dispatch().await?;
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, 0)).await?.len(), 11);
+        drop(msg_store);
+
+        // lost index files are rebuilt on start
+        std::fs::remove_dir_all(dir_path.path().join("index")).unwrap();
+        config.rebuild_index_from = Some(0);
+        let msg_store = new_msg_store(&config).await?;
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 11);
+        assert_eq!(msg_list[10].payload.as_deref(), Some("msg 10".as_bytes()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_flush_policy() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let mut config = test_config(&dir_path);
+        config.flush_policy = FlushPolicy::GroupCommit;
+
+        let msg_store = Arc::new(new_msg_store(&config).await?);
+
+        // the writes are flushed at once before the group commit service is started
+        let put_result = msg_store.write_msg(test_msg("test_topic", 0, "msg 0")).await?;
+        assert_eq!(put_result.durability, FlushPolicy::GroupCommit);
+        let commit_log_max_offset = msg_store.commit_log.lock().await.get_max_offset();
+        assert_eq!(msg_store.commit_log.lock().await.get_flushed_offset(), commit_log_max_offset);
+        assert_eq!(msg_store.get_store_status().await.group_commit_count, 0);
+
+        // the writers waiting together share one flush
+        let mut flush_receivers = Vec::new();
+        for _ in 0..10 {
+            let (sender, receiver) = oneshot::channel();
+            msg_store.group_commit_sender.send(sender).unwrap();
+            flush_receivers.push(receiver);
+        }
+        msg_store.start();
+        for receiver in flush_receivers {
+            assert_eq!(receiver.await.unwrap(), Ok(commit_log_max_offset));
+        }
+        assert_eq!(msg_store.get_store_status().await.group_commit_count, 1);
+
+        let mut write_tasks = Vec::new();
+        for i in 1..10 {
+            let msg_store = msg_store.clone();
+            write_tasks.push(tokio::spawn(async move {
+                msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await
+            }));
+        }
+
+        let mut queue_offsets = Vec::new();
+        for write_task in write_tasks {
+            let put_result = write_task.await.unwrap()?;
+            assert_eq!(put_result.durability, FlushPolicy::GroupCommit);
+            queue_offsets.push(put_result.queue_offset);
+        }
+        queue_offsets.sort();
+        assert_eq!(queue_offsets, (1..10).collect::<Vec<usize>>());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_retention() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let mut config = test_config(&dir_path);
+        config.retention_bytes = Some(1024);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..100 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.dispatch().await?;
+
+        let min_offset = clean_expired_files(&msg_store.config, &msg_store.topic_mgr, &msg_store.commit_log,
+                                             &msg_store.index_store, &msg_store.latest_offsets, None).await?;
+        let store_status = msg_store.get_store_status().await;
+        assert_eq!(store_status.commit_log_min_offset, min_offset);
+        assert!(min_offset > 0);
+        assert!(store_status.commit_log_max_offset - min_offset <= 2 * 1024);
+
+        // reading the deleted messages tells the earliest available offset
+        let min_queue_offset = match msg_store.read_msg(consume_request("test_topic", 0, 0)).await {
+            Err(OffsetOutOfRange { offset, min_offset, .. }) => {
+                assert_eq!(offset, 0);
+                min_offset
+            }
+            other => panic!("unexpected read result: {:?}", other.map(|msg_list| msg_list.len())),
+        };
+        assert!(min_queue_offset > 0);
+
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, min_queue_offset)).await?;
+        assert_eq!(msg_list.len(), 100 - min_queue_offset);
+        assert_eq!(msg_list[0].payload, Some(format!("msg {}", min_queue_offset).into_bytes()));
+        drop(msg_store);
+
+        // the min offset is kept after restart
+        let msg_store = new_msg_store(&config).await?;
+        assert!(msg_store.read_msg(consume_request("test_topic", 0, min_queue_offset - 1)).await.is_err());
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, min_queue_offset)).await?.len(),
+                   100 - min_queue_offset);
+        drop(msg_store);
+
+        // the rebuilt index starts from the queue offset of the first message left
+        config.rebuild_index_from = Some(0);
+        let msg_store = new_msg_store(&config).await?;
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, min_queue_offset)).await?;
+        assert_eq!(msg_list.len(), 100 - min_queue_offset);
+        assert_eq!(msg_list[0].payload, Some(format!("msg {}", min_queue_offset).into_bytes()));
+        assert_eq!(msg_store.write_msg(test_msg("test_topic", 0, "msg 100")).await?.queue_offset, 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_topic_retention() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        // only the topic sets the retention
+        let msg_store = new_msg_store(&config).await?;
+        let topic: Topic = serde_json::from_value(serde_json::json!({
+            "topic_name": "test_topic",
+            "partition_number": 1,
+            "config": { "retention_bytes": 1024 },
+        })).unwrap();
+        msg_store.topic_mgr.create_topic(topic)?;
+        for i in 0..100 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.dispatch().await?;
+
+        let min_offset = clean_expired_files(&msg_store.config, &msg_store.topic_mgr, &msg_store.commit_log,
+                                             &msg_store.index_store, &msg_store.latest_offsets, None).await?;
+        let store_status = msg_store.get_store_status().await;
+        assert!(min_offset > 0);
+        assert!(store_status.commit_log_max_offset - min_offset <= 2 * 1024);
+        assert!(msg_store.read_msg(consume_request("test_topic", 0, 0)).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_compaction() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let mut config = test_config(&dir_path);
+        config.tombstone_retention_ms = 0;
+
+        let msg_store = new_msg_store(&config).await?;
+        let topic: Topic = serde_json::from_value(serde_json::json!({
+            "topic_name": "changelog",
+            "partition_number": 1,
+            "config": { "cleanup_policy": "compact" },
+        })).unwrap();
+        msg_store.topic_mgr.create_topic(topic)?;
+
+        // key_0 is updated many times, key_1 is deleted by a tombstone
+        let removed_msg_id = msg_store.write_msg(test_keyed_msg("changelog", "key_1", Some("value 1"))).await?.msg_id;
+        let mut other_msg_ids = Vec::new();
+        let mut latest_msg_id = String::new();
+        for i in 0..40 {
+            let value = format!("value {}", i);
+            latest_msg_id = msg_store.write_msg(test_keyed_msg("changelog", "key_0", Some(value.as_str()))).await?.msg_id;
+            other_msg_ids.push(msg_store.write_msg(test_msg("other_topic", 0, format!("msg {}", i).as_str())).await?.msg_id);
+        }
+        msg_store.write_msg(test_keyed_msg("changelog", "key_1", None)).await?;
+        // the last file isn't compacted, roll to new ones
+        for i in 0..30 {
+            msg_store.write_msg(test_msg("other_topic", 0, format!("msg {}", 40 + i).as_str())).await?;
+        }
+        msg_store.dispatch().await?;
+        let first_file = dir_path.path().join("commitlog").join(format!("{:020}", 0));
+        let last_modified = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(&first_file).unwrap().set_modified(last_modified).unwrap();
+
+        assert!(compact(&config, &msg_store.topic_mgr, &msg_store.commit_log, &msg_store.index_store,
+                        &msg_store.key_ring, &msg_store.latest_offsets, &msg_store.dispatched_offset).await? > 0);
+        // the compacted file keeps the modified time for the retention
+        assert_eq!(std::fs::metadata(&first_file).unwrap().modified().unwrap(), last_modified);
+
+        // only the latest value is left, and the queue offsets are stable
+        let msg_list = msg_store.read_msg(consume_request("changelog", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 1);
+        assert_eq!(msg_list[0].payload.as_deref(), Some("value 39".as_bytes()));
+        let msg_list = msg_store.read_msg(consume_request("changelog", 0, 40)).await?;
+        assert_eq!(msg_list.len(), 1);
+
+        // messages of the topics which aren't compacted are kept
+        let msg_list = msg_store.read_msg(consume_request("other_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 70);
+        assert_eq!(msg_list[69].payload.as_deref(), Some("msg 69".as_bytes()));
+
+        // the ids of the kept messages still find them after they are moved, the removed ones are not found
+        assert_eq!(msg_list[0].payload.as_deref(), Some("msg 0".as_bytes()));
+        assert!(matches!(msg_store.get_message_by_id(removed_msg_id.as_str()).await, Err(MessageNotFound { .. })));
+        let msg = msg_store.get_message_by_id(latest_msg_id.as_str()).await?;
+        assert_eq!(msg.payload.as_deref(), Some("value 39".as_bytes()));
+        for (i, msg_id) in other_msg_ids.iter().enumerate() {
+            let msg = msg_store.get_message_by_id(msg_id.as_str()).await?;
+            assert_eq!(msg.payload, Some(format!("msg {}", i).into_bytes()));
+        }
+
+        // new messages are appended after the compacted ones
+        let put_result = msg_store.write_msg(test_keyed_msg("changelog", "key_2", Some("value 2"))).await?;
+        assert_eq!(put_result.queue_offset, 42);
+        msg_store.shutdown().await?;
+        drop(msg_store);
+
+        let msg_store = new_msg_store(&config).await?;
+        let msg_list = msg_store.read_msg(consume_request("changelog", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 2);
+        assert_eq!(msg_list[1].payload.as_deref(), Some("value 2".as_bytes()));
+        assert_eq!(msg_store.read_msg(consume_request("other_topic", 0, 0)).await?.len(), 70);
+        let msg = msg_store.get_message_by_id(other_msg_ids[39].as_str()).await?;
+        assert_eq!(msg.payload.as_deref(), Some("msg 39".as_bytes()));
+
+        // the compacted commit log can be indexed again, the removed messages are still holes
+        msg_store.rebuild_index(0).await?;
+        assert_eq!(msg_store.read_msg(consume_request("other_topic", 0, 0)).await?.len(), 70);
+        let msg_list = msg_store.read_msg(consume_request("changelog", 0, 40)).await?;
+        assert_eq!(msg_list[0].payload.as_deref(), Some("value 39".as_bytes()));
+        drop(msg_store);
+
+        // the queue starts from the first message left, the earlier holes are expired
+        config.rebuild_index_from = Some(0);
+        let msg_store = new_msg_store(&config).await?;
+        assert!(matches!(msg_store.read_msg(consume_request("changelog", 0, 0)).await,
+                         Err(OffsetOutOfRange { min_offset: 40, .. })));
+        let consume_result = msg_store.consume(consume_request("changelog", 0, 40)).await?;
+        assert_eq!(consume_result.msg_list.len(), 2);
+        assert_eq!(consume_result.next_offset, 43);
+        let msg_list = msg_store.read_msg(consume_request("changelog", 0, 42)).await?;
+        assert_eq!(msg_list[0].payload.as_deref(), Some("value 2".as_bytes()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_incremental_compaction() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        let topic: Topic = serde_json::from_value(serde_json::json!({
+            "topic_name": "changelog",
+            "partition_number": 1,
+            "config": { "cleanup_policy": "compact" },
+        })).unwrap();
+        msg_store.topic_mgr.create_topic(topic)?;
+
+        for round in 0..2 {
+            for i in 0..20 {
+                let (key, value) = (format!("key_{}", round), format!("value {}", round * 20 + i));
+                msg_store.write_msg(test_keyed_msg("changelog", key.as_str(), Some(value.as_str()))).await?;
+            }
+            // roll to new files, so the ones with the values can be compacted
+            for i in 0..30 {
+                msg_store.write_msg(test_msg("other_topic", 0, format!("msg {}", i).as_str())).await?;
+            }
+            msg_store.dispatch().await?;
+            assert!(compact(&config, &msg_store.topic_mgr, &msg_store.commit_log, &msg_store.index_store,
+                            &msg_store.key_ring, &msg_store.latest_offsets, &msg_store.dispatched_offset).await? > 0);
+        }
+
+        // the latest value of key_0 moved by the first compaction is kept by the second one
+        let consume_result = msg_store.consume(consume_request("changelog", 0, 0)).await?;
+        assert_eq!(consume_result.msg_list.len(), 2);
+        assert_eq!(consume_result.msg_list[0].payload.as_deref(), Some("value 19".as_bytes()));
+        assert_eq!(consume_result.msg_list[1].payload.as_deref(), Some("value 39".as_bytes()));
+        assert_eq!(consume_result.next_offset, 40);
+
+        // the files before the latest values are deleted by the retention, the others are kept
+        let retention_config = ConfigOptions { retention_bytes: Some(0), ..config.clone() };
+        clean_expired_files(&retention_config, &msg_store.topic_mgr, &msg_store.commit_log,
+                            &msg_store.index_store, &msg_store.latest_offsets, None).await?;
+        let msg_list = msg_store.read_msg(consume_request("changelog", 0, 19)).await?;
+        assert_eq!(msg_list.len(), 2);
+        assert_eq!(msg_list[0].payload.as_deref(), Some("value 19".as_bytes()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_offset_by_timestamp() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..5 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.dispatch().await?;
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let replay_timestamp = current_timestamp_ms();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        for i in 5..10 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.dispatch().await?;
+
+        assert_eq!(msg_store.get_offset_by_timestamp("test_topic", 0, 0).await?, 0);
+        assert_eq!(msg_store.get_offset_by_timestamp("test_topic", 0, replay_timestamp).await?, 5);
+        assert_eq!(msg_store.get_offset_by_timestamp("test_topic", 0, u64::MAX).await?, 10);
+
+        // store timestamps are kept after restart
+        msg_store.shutdown().await?;
+        drop(msg_store);
+        let msg_store = new_msg_store(&config).await?;
+        assert_eq!(msg_store.get_offset_by_timestamp("test_topic", 0, replay_timestamp).await?, 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_query_by_key() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let mut config = test_config(&dir_path);
+        config.key_index_slot_num = 4;
+        config.key_index_entry_num = 16;
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..30 {
+            let msg = Message {
+                key: Some(format!("order_{}", i % 3)),
+                ..test_msg("test_topic", i % 2, format!("msg {}", i).as_str())
+            };
+            msg_store.write_msg(msg).await?;
+        }
+        msg_store.dispatch().await?;
+
+        let queried_msg_list = msg_store.query_by_key("test_topic", "order_1", 0..u64::MAX, 100).await?;
+        assert_eq!(queried_msg_list.len(), 10);
+        // the latest message comes first
+        let queried_msg = &queried_msg_list[0];
+        assert_eq!(queried_msg.message.payload.as_deref(), Some("msg 28".as_bytes()));
+        assert_eq!((queried_msg.queue_id, queried_msg.queue_offset), (0, 14));
+
+        assert_eq!(msg_store.query_by_key("test_topic", "order_1", 0..u64::MAX, 3).await?.len(), 3);
+        assert_eq!(msg_store.query_by_key("test_topic", "order_3", 0..u64::MAX, 100).await?.len(), 0);
+        assert_eq!(msg_store.query_by_key("test_topic", "order_1", 0..1, 100).await?.len(), 0);
+
+        // the key indexes are loaded after restart
+        msg_store.shutdown().await?;
+        drop(msg_store);
+        let msg_store = new_msg_store(&config).await?;
+        assert_eq!(msg_store.query_by_key("test_topic", "order_1", 0..u64::MAX, 100).await?.len(), 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_query_by_key_after_recovery() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let mut config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..5 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.shutdown().await?;
+
+        // the key is indexed after the checkpoint, then crashed
+        msg_store.write_msg(test_keyed_msg("test_topic", "order_1", Some("msg 5"))).await?;
+        for i in 6..10 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.dispatch().await?;
+        drop(msg_store);
+
+        // the replayed message isn't indexed by the key again
+        let msg_store = new_msg_store(&config).await?;
+        let queried_msg_list = msg_store.query_by_key("test_topic", "order_1", 0..u64::MAX, 100).await?;
+        assert_eq!(queried_msg_list.len(), 1);
+        assert_eq!(queried_msg_list[0].queue_offset, 5);
+
+        msg_store.rebuild_index(100).await?;
+        assert_eq!(msg_store.query_by_key("test_topic", "order_1", 0..u64::MAX, 100).await?.len(), 1);
+        drop(msg_store);
+
+        config.rebuild_index_from = Some(100);
+        let msg_store = new_msg_store(&config).await?;
+        assert_eq!(msg_store.query_by_key("test_topic", "order_1", 0..u64::MAX, 100).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_get_message_by_id() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let mut config = test_config(&dir_path);
+        config.broker_id = 7;
+
+        let msg_store = new_msg_store(&config).await?;
+        let mut msg_ids = Vec::new();
+        for i in 0..20 {
+            let put_result = msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+            msg_ids.push(put_result.msg_id);
+        }
+
+        // messages can be found by id before they are indexed
+        let msg = msg_store.get_message_by_id(msg_ids[15].as_str()).await?;
+        assert_eq!(msg.payload.as_deref(), Some("msg 15".as_bytes()));
+
+        // the offset pointing to another message, like after compaction, falls back to the queue position
+        msg_store.dispatch().await?;
+        let first_msg_offset = MessageId::decode(msg_ids[0].as_str())?.offset;
+        let moved_msg_id = MessageId::new(7, first_msg_offset, "test_topic", 0, 15).encode();
+        let msg = msg_store.get_message_by_id(moved_msg_id.as_str()).await?;
+        assert_eq!(msg.payload.as_deref(), Some("msg 15".as_bytes()));
+        let missing_msg_id = MessageId::new(7, first_msg_offset, "test_topic", 0, 20).encode();
+        assert!(matches!(msg_store.get_message_by_id(missing_msg_id.as_str()).await, Err(MessageNotFound { .. })));
+
+        assert!(msg_store.get_message_by_id("invalid").await.is_err());
+        let other_broker_msg_id = MessageId::new(8, 0, "test_topic", 0, 0).encode();
+        assert!(msg_store.get_message_by_id(other_broker_msg_id.as_str()).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_consumer_group() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..10 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.dispatch().await?;
+        let group_request = |max_msg_count: usize| ConsumeMessageRequest {
+            offset: None,
+            max_msg_count,
+            group: Some("test_group".to_string()),
+            ..consume_request("test_topic", 0, 0)
+        };
+
+        // a group without a committed offset starts from the earliest message
+        let consume_result = msg_store.consume(group_request(4)).await?;
+        assert_eq!(consume_result.msg_list.len(), 4);
+        assert_eq!(consume_result.msg_list[0].payload.as_deref(), Some("msg 0".as_bytes()));
+        assert_eq!(consume_result.next_offset, 4);
+
+        msg_store.topic_mgr.commit_offset("test_group", "test_topic", 0, consume_result.next_offset)?;
+        let consume_result = msg_store.consume(group_request(100)).await?;
+        assert_eq!(consume_result.msg_list.len(), 6);
+        assert_eq!(consume_result.msg_list[0].payload.as_deref(), Some("msg 4".as_bytes()));
+        assert_eq!(consume_result.next_offset, 10);
+
+        // the offset of the request overrides the committed one
+        let consume_result = msg_store.consume(ConsumeMessageRequest { offset: Some(8), ..group_request(100) }).await?;
+        assert_eq!(consume_result.msg_list.len(), 2);
+        // nothing is returned after the latest message
+        msg_store.topic_mgr.commit_offset("test_group", "test_topic", 0, 10)?;
+        let consume_result = msg_store.consume(group_request(100)).await?;
+        assert!(consume_result.msg_list.is_empty());
+        assert_eq!(consume_result.next_offset, 10);
+
+        let consume_result = msg_store.consume(ConsumeMessageRequest { group: None, ..group_request(100) }).await;
+        assert!(consume_result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_write_batch() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        msg_store.write_msg(test_msg("test_topic", 0, "msg 0")).await?;
+        let batch: Vec<Message> = (1..6).map(|i| test_msg("test_topic", 0, format!("msg {}", i).as_str())).collect();
+        let put_result = msg_store.write_batch(batch, None).await?;
+        assert_eq!((put_result.start_queue_offset, put_result.end_queue_offset), (1, 6));
+        assert_eq!(put_result.msg_ids.len(), 5);
+        assert_eq!(msg_store.write_msg(test_msg("test_topic", 0, "msg 6")).await?.queue_offset, 6);
+
+        // the messages of a batch must be in one queue
+        let mixed_batch = vec![test_msg("test_topic", 0, "msg"), test_msg("test_topic", 1, "msg")];
+        assert!(msg_store.write_batch(mixed_batch, None).await.is_err());
+        assert!(msg_store.write_batch(Vec::new(), None).await.is_err());
+
+        msg_store.dispatch().await?;
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 7);
+        for (i, msg) in msg_list.iter().enumerate() {
+            assert_eq!(msg.payload, Some(format!("msg {}", i).into_bytes()));
+        }
+        let msg = msg_store.get_message_by_id(put_result.msg_ids[2].as_str()).await?;
+        assert_eq!(msg.payload.as_deref(), Some("msg 3".as_bytes()));
+
+        // the batch is recovered by scanning without a checkpoint
+        drop(msg_store);
+        let msg_store = new_msg_store(&config).await?;
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, 0)).await?.len(), 7);
+        assert_eq!(msg_store.write_msg(test_msg("test_topic", 0, "msg 7")).await?.queue_offset, 7);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_compression() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        let topic: Topic = serde_json::from_value(serde_json::json!({
+            "topic_name": "json_topic",
+            "partition_number": 1,
+            "config": { "compression": "zstd" },
+        })).unwrap();
+        msg_store.topic_mgr.create_topic(topic)?;
+
+        let payload = r#"{"user": "photon", "action": "produce"}"#.repeat(10);
+        msg_store.write_msg(test_msg("json_topic", 0, payload.as_str())).await?;
+        // the batch codec overrides the topic one, a payload which can't be smaller is kept raw
+        let batch = vec![test_msg("json_topic", 0, payload.as_str()), test_msg("json_topic", 0, "x")];
+        msg_store.write_batch(batch, Some(CompressionCodec::Lz4)).await?;
+        // compressed by the producer
+        let compressed_msg = Message {
+            payload: Some(compress(CompressionCodec::Snappy, payload.as_bytes())?),
+            compression: Some(CompressionCodec::Snappy),
+            ..test_msg("json_topic", 0, "")
+        };
+        msg_store.write_msg(compressed_msg).await?;
+        let invalid_msg = Message { compression: Some(CompressionCodec::Snappy), ..test_msg("json_topic", 0, "raw") };
+        assert!(msg_store.write_msg(invalid_msg).await.is_err());
+        // a compressed payload beyond the max message size is rejected
+        let oversized_msg = Message {
+            payload: Some(compress(CompressionCodec::Zstd, &vec![0; config.max_msg_size + 1])?),
+            compression: Some(CompressionCodec::Zstd),
+            ..test_msg("json_topic", 0, "")
+        };
+        assert!(msg_store.write_msg(oversized_msg).await.is_err());
+        msg_store.dispatch().await?;
+
+        let msg_list = msg_store.read_msg(consume_request("json_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 4);
+        for (i, msg) in msg_list.iter().enumerate() {
+            assert_eq!(msg.compression, None);
+            let expected_payload = if i == 2 { "x" } else { payload.as_str() };
+            assert_eq!(msg.payload.as_deref(), Some(expected_payload.as_bytes()));
+        }
+
+        // the consumer accepting a codec gets the payloads compressed by it as stored
+        let consume_request = ConsumeMessageRequest {
+            accepted_compressions: vec![CompressionCodec::Zstd, CompressionCodec::Snappy],
+            ..consume_request("json_topic", 0, 0)
+        };
+        let msg_list = msg_store.read_msg(consume_request).await?;
+        let codecs: Vec<Option<CompressionCodec>> = msg_list.iter().map(|msg| msg.compression).collect();
+        assert_eq!(codecs, vec![Some(CompressionCodec::Zstd), None, None, Some(CompressionCodec::Snappy)]);
+        assert_eq!(decompress(CompressionCodec::Zstd, msg_list[0].payload.as_ref().unwrap(), payload.len())?, payload.as_bytes());
+
+        let compression_stats = &msg_store.get_store_status().await.compression["json_topic"];
+        assert_eq!(compression_stats.raw_bytes, payload.len() as u64 * 3 + 1);
+        assert!(compression_stats.compression_ratio > 5.0);
+
+        Ok(())
+    }
+
+    // Whether any file under the directory contains the data.
+    fn dir_contains(dir: &std::path::Path, data: &[u8]) -> bool {
+        std::fs::read_dir(dir).unwrap().flatten().any(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                dir_contains(&path, data)
+            } else {
+                std::fs::read(&path).unwrap().windows(data.len()).any(|window| window == data)
+            }
+        })
+    }
+
+    #[tokio::test]
+    pub async fn test_encryption() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let object_store_dir = create_temp_dir("object_store_test");
+        let mut config = test_config(&dir_path);
+        config.storage = StorageConfig {
+            tiered: true,
+            store: ObjectStoreConfig::Fs(FsConfig { root: object_store_dir.path().to_str().unwrap().to_string() }),
+            ..Default::default()
+        };
+        let encryption_key = |key_id: u32| EncryptionKey {
+            id: key_id,
+            key: SecretString::from(STANDARD.encode([key_id as u8; 32])),
+        };
+        config.encryption = EncryptionConfig { active_key_id: Some(1), keys: vec![encryption_key(1)] };
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..20 {
+            let msg = Message { key: Some("secret_key".to_string()), ..test_msg("test_topic", 0, format!("secret msg {}", i).as_str()) };
+            msg_store.write_msg(msg).await?;
+        }
+        msg_store.dispatch().await?;
+        let tiered_store = msg_store.tiered_store.clone().unwrap();
+        assert!(upload_sealed_files(&msg_store.commit_log, &msg_store.index_store,
+                                    &msg_store.dispatched_offset, &tiered_store).await? > 0);
+        assert!(!dir_contains(&dir_path.path().join("commitlog"), b"secret"));
+        assert!(!dir_contains(object_store_dir.path(), b"secret"));
+        msg_store.shutdown().await?;
+        drop(msg_store);
+        drop(tiered_store);
+
+        // rotate the key, the records of the old key are still readable
+        config.encryption = EncryptionConfig { active_key_id: Some(2), keys: vec![encryption_key(1), encryption_key(2)] };
+        let msg_store = new_msg_store(&config).await?;
+        msg_store.write_batch(vec![test_msg("test_topic", 0, "secret msg 20"), test_msg("test_topic", 0, "secret msg 21")], None).await?;
+        msg_store.dispatch().await?;
+        let msg_list = msg_store.read_msg(ConsumeMessageRequest { max_msg_count: 100, ..consume_request("test_topic", 0, 0) }).await?;
+        assert_eq!(msg_list.len(), 22);
+        for (i, msg) in msg_list.iter().enumerate() {
+            assert_eq!(msg.payload, Some(format!("secret msg {}", i).into_bytes()));
+        }
+        assert_eq!(msg_store.query_by_key("test_topic", "secret_key", 0..u64::MAX, 100).await?.len(), 20);
+        assert!(!dir_contains(&dir_path.path().join("commitlog"), b"secret"));
+        msg_store.shutdown().await?;
+        drop(msg_store);
+
+        // the records can't be read without their key
+        config.encryption = EncryptionConfig { active_key_id: Some(2), keys: vec![encryption_key(2)] };
+        let msg_store = new_msg_store(&config).await?;
+        assert!(matches!(msg_store.read_msg(consume_request("test_topic", 0, 0)).await, Err(Encryption { key_id: 1, .. })));
+        assert_eq!(msg_store.read_msg(consume_request("test_topic", 0, 20)).await?.len(), 2);
+
+        // an active key which isn't configured is rejected
+        config.encryption = EncryptionConfig { active_key_id: Some(3), keys: vec![encryption_key(2)] };
+        assert!(new_msg_store(&config).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_newer_record_version() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        let msg_store = new_msg_store(&config).await?;
+        for i in 0..3 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+        }
+        msg_store.dispatch().await?;
+        let max_offset = msg_store.get_store_status().await.commit_log_max_offset;
+        msg_store.shutdown().await?;
+        drop(msg_store);
+
+        // a record written by a newer version after the known ones
+        let mut record = encode_record(b"future msg", current_timestamp_ms());
+        record[1] = RECORD_VERSION + 1;
+        let commit_log_file = dir_path.path().join("commitlog").join(format!("{:020}", 0));
+        let mut file_data = std::fs::read(&commit_log_file).unwrap();
+        file_data[max_offset..max_offset + record.len()].copy_from_slice(&record);
+        std::fs::write(&commit_log_file, &file_data).unwrap();
+
+        // the store refuses to start instead of truncating the record as a corrupted one
+        assert!(matches!(new_msg_store(&config).await, Err(UnsupportedRecordVersion { .. })));
+        let file_data = std::fs::read(&commit_log_file).unwrap();
+        assert_eq!(&file_data[max_offset..max_offset + record.len()], record.as_slice());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_baseline_commit_log() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let config = test_config(&dir_path);
+
+        // the records of the baseline version are | length (8) | bincode of the message |, in zeroed files
+        let commit_log_dir = dir_path.path().join("commitlog");
+        std::fs::create_dir_all(&commit_log_dir).unwrap();
+        let mut file_start_offset = 0;
+        for file_msgs in [0..3, 3..6] {
+            let mut file_data = Vec::new();
+            for i in file_msgs {
+                let mut msg = test_msg(if i % 2 == 0 { "test_topic" } else { "other_topic" }, 0, format!("msg {}", i).as_str());
+                msg.key = Some(format!("key {}", i));
+                let encoded_msg = msg.encode_baseline()?;
+                file_data.extend_from_slice(&usize::to_le_bytes(encoded_msg.len()));
+                file_data.extend(encoded_msg);
+            }
+            let data_size = file_data.len();
+            file_data.resize(4096, 0);
+            std::fs::write(commit_log_dir.join(format!("{:020}", file_start_offset)), &file_data).unwrap();
+            file_start_offset += data_size;
+        }
+        // the index files of the baseline version are dropped
+        let index_dir = dir_path.path().join("index").join("test_topic").join("0");
+        std::fs::create_dir_all(&index_dir).unwrap();
+        std::fs::write(index_dir.join(format!("{:020}", 0)), [1u8; 36]).unwrap();
+
+        let msg_store = new_msg_store(&config).await?;
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        let payloads: Vec<&[u8]> = msg_list.iter().map(|msg| msg.payload.as_deref().unwrap()).collect();
+        assert_eq!(payloads, vec![b"msg 0".as_slice(), b"msg 2", b"msg 4"]);
+        assert_eq!(msg_store.read_msg(consume_request("other_topic", 0, 2)).await?[0].payload.as_deref(),
+                   Some(b"msg 5".as_slice()));
+        let queried_msg_list = msg_store.query_by_key("other_topic", "key 3", 0..u64::MAX, 10).await?;
+        assert_eq!(queried_msg_list[0].queue_offset, 1);
+        // the queue offsets continue from the migrated messages
+        assert_eq!(msg_store.write_msg(test_msg("test_topic", 0, "msg 6")).await?.queue_offset, 3);
+
+        // the migrated commit log is loaded as it is after restart
+        msg_store.shutdown().await?;
+        drop(msg_store);
+        let msg_store = new_msg_store(&config).await?;
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 4);
+        assert_eq!(msg_list[3].payload.as_deref(), Some(b"msg 6".as_slice()));
+        assert!(!dir_path.path().join("migrating").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_tiered_storage() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let object_store_dir = create_temp_dir("object_store_test");
+        let mut config = test_config(&dir_path);
+        config.storage = StorageConfig {
+            tiered: true,
+            local_retention_ms: 0,
+            store: ObjectStoreConfig::Fs(FsConfig { root: object_store_dir.path().to_str().unwrap().to_string() }),
+            ..Default::default()
+        };
+
+        let msg_store = new_msg_store(&config).await?;
+        let mut msg_ids = Vec::new();
+        for i in 0..100 {
+            let mut msg = test_msg("test_topic", 0, format!("msg {}", i).as_str());
+            msg.key = Some(format!("key {}", i % 10));
+            let put_result = msg_store.write_msg(msg).await?;
+            msg_ids.push(put_result.msg_id);
+        }
+        msg_store.dispatch().await?;
+
+        // the files which haven't been uploaded are kept by the retention
+        let tiered_store = msg_store.tiered_store.clone().unwrap();
+        let retention_config = ConfigOptions { retention_bytes: Some(0), ..config.clone() };
+        clean_expired_files(&retention_config, &msg_store.topic_mgr, &msg_store.commit_log, &msg_store.index_store,
+                            &msg_store.latest_offsets, Some(&tiered_store)).await?;
+        assert_eq!(msg_store.commit_log.lock().await.get_min_offset(), 0);
+
+        let uploaded_num = upload_sealed_files(&msg_store.commit_log, &msg_store.index_store,
+                                               &msg_store.dispatched_offset, &tiered_store).await?;
+        assert!(uploaded_num > 0);
+        let deleted_num = msg_store.commit_log.lock().await
+            .delete_uploaded_files(tiered_store.get_uploaded_offset(), 0).await?;
+        assert_eq!(deleted_num, uploaded_num);
+        assert!(msg_store.commit_log.lock().await.get_min_offset() > 0);
+        assert_eq!(msg_store.get_store_status().await.commit_log_min_offset, 0);
+
+        // the messages of the deleted files are fetched from the object store
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 100);
+        assert_eq!(msg_list[0].payload.as_deref(), Some("msg 0".as_bytes()));
+        let msg = msg_store.get_message_by_id(msg_ids[1].as_str()).await?;
+        assert_eq!(msg.payload.as_deref(), Some("msg 1".as_bytes()));
+        // the blocks of the segments are cached after the first read
+        let cache_stats = msg_store.get_store_status().await.remote_read_cache.unwrap();
+        assert!(cache_stats.miss_count > 0 && cache_stats.hit_count > 0);
+        // the indexes are rebuilt from the records in the object store as well
+        msg_store.rebuild_index(0).await?;
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 100);
+
+        // the uploaded segments are loaded after restart
+        msg_store.shutdown().await?;
+        drop(msg_store);
+        let msg_store = new_msg_store(&config).await?;
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 100);
+        assert_eq!(msg_list[99].payload.as_deref(), Some("msg 99".as_bytes()));
+
+        // a node with an empty disk serves the uploaded messages from the object store
+        let uploaded_offset = tiered_store.get_uploaded_offset();
+        let uploaded_msg_num = msg_ids.iter()
+            .filter(|msg_id| (MessageId::decode(msg_id).unwrap().offset as usize) < uploaded_offset)
+            .count();
+        msg_store.shutdown().await?;
+        drop(msg_store);
+        drop(tiered_store);
+        let new_dir_path = create_temp_dir("msg_store_test");
+        config.msg_store_path = new_dir_path.path().to_str().unwrap().to_string();
+        let msg_store = new_msg_store(&config).await?;
+        assert_eq!(msg_store.get_store_status().await.commit_log_max_offset, uploaded_offset);
+
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), uploaded_msg_num);
+        assert_eq!(msg_list[uploaded_msg_num - 1].payload.as_deref(), Some(format!("msg {}", uploaded_msg_num - 1).as_bytes()));
+        let msg = msg_store.get_message_by_id(msg_ids[1].as_str()).await?;
+        assert_eq!(msg.payload.as_deref(), Some("msg 1".as_bytes()));
+        // the key indexes are rebuilt from the uploaded messages
+        let queried_msg_list = msg_store.query_by_key("test_topic", "key 1", 0..u64::MAX, 100).await?;
+        assert_eq!(queried_msg_list.len(), (uploaded_msg_num + 8) / 10);
+        assert!(queried_msg_list.iter().all(|queried_msg| queried_msg.message.key.as_deref() == Some("key 1")));
+        // new messages follow the uploaded ones
+        let put_result = msg_store.write_msg(test_msg("test_topic", 0, "new msg")).await?;
+        assert_eq!(put_result.queue_offset, uploaded_msg_num);
+        assert!(MessageId::decode(put_result.msg_id.as_str())?.offset as usize >= uploaded_offset);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_diskless_mode() -> Result<()> {
+        let dir_path = create_temp_dir("msg_store_test");
+        let object_store_dir = create_temp_dir("object_store_test");
+        let mut config = test_config(&dir_path);
+        config.storage = StorageConfig {
+            diskless: true,
+            wal_segment_size: 512,
+            wal_flush_interval_ms: 10,
+            ack_mode: AckMode::Upload,
+            store: ObjectStoreConfig::Fs(FsConfig { root: object_store_dir.path().to_str().unwrap().to_string() }),
+            ..Default::default()
+        };
+
+        // each message is acknowledged after its WAL segment is uploaded
+        let msg_store = new_msg_store(&config).await?;
+        msg_store.start();
+        for i in 0..20 {
+            let put_result = msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+            assert!(put_result.uploaded);
+        }
+        let tiered_store = msg_store.tiered_store.clone().unwrap();
+        assert_eq!(tiered_store.get_uploaded_offset(), msg_store.get_store_status().await.commit_log_max_offset);
+
+        // the uploaded WAL segments are deleted locally
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(msg_store.commit_log.lock().await.get_min_offset(), tiered_store.get_uploaded_offset());
+        let msg_list = msg_store.read_msg(consume_request("test_topic", 0, 0)).await?;
+        assert_eq!(msg_list.len(), 20);
+        assert_eq!(msg_list[19].payload.as_deref(), Some("msg 19".as_bytes()));
+
+        // with the local ack, the WAL segments roll by size rather than the flush interval
+        let dir_path = create_temp_dir("msg_store_test");
+        let object_store_dir = create_temp_dir("object_store_test");
+        let mut config = test_config(&dir_path);
+        config.storage = StorageConfig {
+            diskless: true,
+            wal_segment_size: 512,
+            wal_flush_interval_ms: 10,
+            upload_interval_ms: 60000,
+            store: ObjectStoreConfig::Fs(FsConfig { root: object_store_dir.path().to_str().unwrap().to_string() }),
+            ..Default::default()
+        };
+        let msg_store = new_msg_store(&config).await?;
+        msg_store.start();
+        for i in 0..20 {
+            msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await?;
+            tokio::time::sleep(std::time::Duration::from_millis(15)).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let tiered_store = msg_store.tiered_store.clone().unwrap();
+        let commit_log_max_offset = msg_store.get_store_status().await.commit_log_max_offset;
+        assert!(tiered_store.get_uploaded_offset() > 0);
+        assert!(commit_log_max_offset - tiered_store.get_uploaded_offset() < 512);
+        assert!(tiered_store.get_uploaded_offset() < commit_log_max_offset);
+
+        // with the local ack, the writes are rejected once the WAL is full
+        let dir_path = create_temp_dir("msg_store_test");
+        let object_store_dir = create_temp_dir("object_store_test");
+        let mut config = test_config(&dir_path);
+        config.storage = StorageConfig {
+            diskless: true,
+            wal_max_size: 200,
+            store: ObjectStoreConfig::Fs(FsConfig { root: object_store_dir.path().to_str().unwrap().to_string() }),
+            ..Default::default()
+        };
+        let msg_store = new_msg_store(&config).await?;
+        let mut write_result = Ok(());
+        for i in 0..20 {
+            write_result = msg_store.write_msg(test_msg("test_topic", 0, format!("msg {}", i).as_str())).await.map(|_| ());
+            if write_result.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(write_result, Err(WalBufferFull { .. })));
+
+        Ok(())
+    }
+}
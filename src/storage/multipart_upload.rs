@@ -0,0 +1,172 @@
+use bytes::Bytes;
+use reqsign::{AwsCredential, AwsV4Signer};
+use reqwest::{Client, Method, Request, Response, StatusCode, Url};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use snafu::{location, Location};
+use crate::config::S3Config;
+use crate::error::Error::ObjectStoreRequest;
+use crate::error::Result;
+use crate::storage::object_store::DEFAULT_S3_REGION;
+
+// A part of the multipart upload, the part numbers start from 1.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadedPart {
+    pub part_number: u32,
+    pub etag: String,
+}
+
+// An uncompleted multipart upload, it's persisted so the upload can be resumed or aborted after a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultipartUpload {
+    pub upload_id: String,
+    pub parts: Vec<UploadedPart>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InitiateMultipartUploadResult {
+    upload_id: String,
+}
+
+/*
+ * Client of the S3 multipart upload API. The writer of the object store keeps the upload id to
+ * itself, so an upload interrupted by a restart could be neither resumed nor aborted, and its
+ * parts would be kept in the bucket. The requests use the same path style URLs and credentials
+ * as the S3 operator.
+ */
+pub struct S3MultipartClient {
+    client: Client,
+    signer: AwsV4Signer,
+    credential: AwsCredential,
+    bucket_url: String,
+}
+
+impl S3MultipartClient {
+    pub fn new(s3_config: &S3Config) -> Self {
+        S3MultipartClient {
+            client: Client::new(),
+            signer: AwsV4Signer::new("s3", s3_config.region.as_deref().unwrap_or(DEFAULT_S3_REGION)),
+            credential: AwsCredential {
+                access_key_id: s3_config.access_key_id.expose_secret().to_string(),
+                secret_access_key: s3_config.access_key_secret.expose_secret().to_string(),
+                ..Default::default()
+            },
+            bucket_url: format!("{}/{}", s3_config.endpoint.trim_end_matches('/'), s3_config.bucket),
+        }
+    }
+
+    // Start an upload of the object, return the upload id.
+    pub async fn initiate(&self, path: &str) -> Result<String> {
+        let mut url = self.object_url(path)?;
+        url.query_pairs_mut().append_key_only("uploads");
+        let response_body = self.send(Method::POST, url, None).await?.text().await
+            .map_err(|error| request_error(path, error))?;
+
+        let result: InitiateMultipartUploadResult = quick_xml::de::from_str(&response_body)
+            .map_err(|error| request_error(path, error))?;
+        Ok(result.upload_id)
+    }
+
+    // Upload a part of the upload, a part uploaded again with the same number replaces the old one.
+    pub async fn upload_part(&self, path: &str, upload_id: &str, part_number: u32, part: Bytes) -> Result<UploadedPart> {
+        let mut url = self.object_url(path)?;
+        url.query_pairs_mut()
+            .append_pair("partNumber", part_number.to_string().as_str())
+            .append_pair("uploadId", upload_id);
+        let response = self.send(Method::PUT, url, Some(part)).await?;
+
+        let etag = response.headers().get("ETag")
+            .and_then(|etag| etag.to_str().ok())
+            .ok_or_else(|| request_error(path, format!("No ETag of part {}", part_number)))?;
+        Ok(UploadedPart { part_number, etag: etag.to_string() })
+    }
+
+    // Build the object from the uploaded parts, it's visible after it.
+    pub async fn complete(&self, path: &str, upload: &MultipartUpload) -> Result<()> {
+        let mut url = self.object_url(path)?;
+        url.query_pairs_mut().append_pair("uploadId", upload.upload_id.as_str());
+        let mut request_body = String::from("<CompleteMultipartUpload>");
+        for part in upload.parts.iter() {
+            request_body.push_str(format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                                          part.part_number, quick_xml::escape::escape(part.etag.as_str())).as_str());
+        }
+        request_body.push_str("</CompleteMultipartUpload>");
+
+        // the request may fail after the response status is sent, the error is in the body then
+        let response_body = self.send(Method::POST, url, Some(Bytes::from(request_body))).await?.text().await
+            .map_err(|error| request_error(path, error))?;
+        if response_body.contains("<Error>") {
+            return Err(request_error(path, response_body));
+        }
+
+        Ok(())
+    }
+
+    // Whether the upload is in progress, it's gone once it's completed or aborted.
+    pub async fn is_uploading(&self, path: &str, upload_id: &str) -> Result<bool> {
+        let mut url = self.object_url(path)?;
+        url.query_pairs_mut().append_pair("uploadId", upload_id).append_pair("max-parts", "1");
+        let response = self.execute(Method::GET, url, None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+
+        check_status(path, response).await.map(|_| true)
+    }
+
+    // Abort the upload and drop its parts, an upload which doesn't exist is ignored.
+    pub async fn abort(&self, path: &str, upload_id: &str) -> Result<()> {
+        let mut url = self.object_url(path)?;
+        url.query_pairs_mut().append_pair("uploadId", upload_id);
+        let response = self.execute(Method::DELETE, url, None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+
+        check_status(path, response).await.map(|_| ())
+    }
+
+    fn object_url(&self, path: &str) -> Result<Url> {
+        let mut url = Url::parse(self.bucket_url.as_str()).map_err(|error| request_error(path, error))?;
+        url.path_segments_mut()
+            .map_err(|_| request_error(path, "Endpoint can't be a base URL"))?
+            .extend(path.split('/'));
+        Ok(url)
+    }
+
+    // Send the request, the response is an error unless its status is successful.
+    async fn send(&self, method: Method, url: Url, body: Option<Bytes>) -> Result<Response> {
+        let path = url.path().to_string();
+        let response = self.execute(method, url, body).await?;
+        check_status(path.as_str(), response).await
+    }
+
+    async fn execute(&self, method: Method, url: Url, body: Option<Bytes>) -> Result<Response> {
+        let path = url.path().to_string();
+        let mut request = Request::new(method, url);
+        if let Some(body) = body {
+            *request.body_mut() = Some(body.into());
+        }
+        self.signer.sign(&mut request, &self.credential).map_err(|error| request_error(path.as_str(), error))?;
+
+        self.client.execute(request).await.map_err(|error| request_error(path.as_str(), error))
+    }
+}
+
+async fn check_status(path: &str, response: Response) -> Result<Response> {
+    if !response.status().is_success() {
+        let status = response.status();
+        let response_body = response.text().await.unwrap_or_default();
+        return Err(request_error(path, format!("{} {}", status.as_str(), response_body)));
+    }
+
+    Ok(response)
+}
+
+fn request_error(path: &str, error: impl std::fmt::Display) -> crate::error::Error {
+    ObjectStoreRequest {
+        location: location!(),
+        msg: format!("Multipart upload of {} failed: {}", path, error),
+    }
+}
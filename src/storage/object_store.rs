@@ -1,157 +1,701 @@
-use std::str::FromStr;
-use bytes::{BufMut, BytesMut};
-use memmap2::MmapMut;
+use std::sync::Arc;
+use std::time::SystemTime;
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
 use crate::error::{ObjectStoreAccessSnafu, ObjectStoreBuildSnafu, Result};
-use opendal::Operator;
-use opendal::services::Oss;
+use opendal::{ErrorKind, Operator, Writer};
+use opendal::services::{Fs, Memory, Oss, S3};
 use secrecy::{ExposeSecret};
 use snafu::{location, Location, ResultExt};
-use crate::config::OssConfig;
+use crate::config::ObjectStoreConfig;
 use crate::error::Error::{InvalidInput};
+use crate::storage::block_cache::BlockCache;
+use crate::storage::multipart_upload::{MultipartUpload, S3MultipartClient};
+use crate::storage::segment::Segment;
 
-pub struct OssObjectStoreFile {
+// A file in the object store, which can be any service supported by the config.
+pub struct ObjectStoreFile {
     file_path: String,
     object_store: Operator,
     min_offset: usize,
     max_offset: usize,
     max_file_size: usize,
+    // the appended data which isn't uploaded yet, it's uploaded as a part once reaching the part size
     write_cache: BytesMut,
-    read_cache: BytesMut,
+    part_size: usize,
+    // size of the data sent to the object store
+    uploaded_size: usize,
+    // size of the data readable from the object store
+    committed_size: usize,
+    // whether the service appends to the object directly, otherwise the parts are uploaded by a
+    // multipart writer, and the object is visible after the writer is closed
+    append_mode: bool,
+    writer: Option<Writer>,
+    // with the client, the parts are uploaded by the S3 multipart upload whose id is known, instead
+    // of the writer
+    multipart_client: Option<Arc<S3MultipartClient>>,
+    multipart_upload: Option<MultipartUpload>,
+    // whether all the data is uploaded, the file won't change any more
+    completed: bool,
+    // cache of the blocks read from the remote file, shared with the other files
+    block_cache: Option<Arc<BlockCache>>,
+    last_modified: SystemTime,
 }
 
-pub const OBJECT_STORE_FILE_SIZE: usize = 1024 * 1024;
+const DEFAULT_UPLOAD_PART_SIZE: usize = 8 * 1024 * 1024;
+// Region used when it's not configured, the S3 compatible services usually ignore it.
+pub const DEFAULT_S3_REGION: &str = "us-east-1";
 
-impl OssObjectStoreFile {
-    pub fn new(oss_config: OssConfig, file_path: &str, start_offset: usize, file_size: u64) -> Result<Self> {
-        let mut builder = Oss::default();
-        let _ = builder
-            .bucket(&oss_config.bucket)
-            .endpoint(&oss_config.endpoint)
-            .access_key_id(oss_config.access_key_id.expose_secret())
-            .access_key_secret(oss_config.access_key_secret.expose_secret());
-
-        let object_store = Operator::new(builder)
-            .context(ObjectStoreBuildSnafu)?
-            .finish();
+impl ObjectStoreFile {
+    #[cfg(test)]
+    pub fn new(store_config: &ObjectStoreConfig, file_path: &str, start_offset: usize, file_size: u64) -> Result<Self> {
+        let object_store = build_operator(store_config)?;
+        Ok(Self::with_operator(object_store, file_path, start_offset, file_size))
+    }
 
-        Ok(OssObjectStoreFile {
+    // Open the file on an existing operator, which can be shared by the files of the same bucket.
+    pub fn with_operator(object_store: Operator, file_path: &str, start_offset: usize, file_size: u64) -> Self {
+        let append_mode = object_store.info().full_capability().write_can_append;
+        ObjectStoreFile {
             file_path: file_path.to_string(),
             object_store,
             min_offset: start_offset,
             max_offset: start_offset,
             max_file_size: file_size as usize,
-            write_cache: BytesMut::with_capacity(file_size as usize),
-            read_cache: BytesMut::with_capacity(file_size as usize),
-        })
+            write_cache: BytesMut::new(),
+            part_size: DEFAULT_UPLOAD_PART_SIZE,
+            uploaded_size: 0,
+            committed_size: 0,
+            append_mode,
+            writer: None,
+            multipart_client: None,
+            multipart_upload: None,
+            completed: false,
+            block_cache: None,
+            last_modified: SystemTime::now(),
+        }
+    }
+
+    // Upload the appended data in the parts of the given size.
+    pub fn with_part_size(mut self, part_size: usize) -> Self {
+        self.part_size = part_size.max(1);
+        self
+    }
+
+    // Read the remote file in the blocks of the cache, and keep them in the cache.
+    pub fn with_block_cache(mut self, block_cache: Arc<BlockCache>) -> Self {
+        self.block_cache = Some(block_cache);
+        self
+    }
+
+    // Upload the parts by the multipart upload of the client if the service can't append.
+    pub fn with_multipart_client(mut self, multipart_client: Arc<S3MultipartClient>) -> Self {
+        self.multipart_client = Some(multipart_client);
+        self
+    }
+
+    // Open the file which has been uploaded completely with the given data size.
+    pub fn set_uploaded(&mut self, data_size: usize) {
+        self.max_offset = self.min_offset + data_size;
+        self.uploaded_size = data_size;
+        self.committed_size = data_size;
+        self.completed = true;
+    }
+
+    // An interrupted upload can be resumed if the service appends to the object directly, or the
+    // multipart upload id is known.
+    pub fn can_resume_upload(&self) -> bool {
+        self.append_mode || self.multipart_client.is_some()
+    }
+
+    // The multipart upload in progress, it should be persisted to resume or abort the upload.
+    pub fn get_multipart_upload(&self) -> Option<&MultipartUpload> {
+        self.multipart_upload.as_ref()
+    }
+
+    // Continue the multipart upload persisted before a restart.
+    pub fn set_multipart_upload(&mut self, multipart_upload: Option<MultipartUpload>) {
+        self.multipart_upload = multipart_upload;
+    }
+
+    /*
+     * Prepare the upload before any part is uploaded. In append mode, a new upload drops the
+     * object left by an upload which wasn't recorded, the multipart upload is started otherwise,
+     * so its id can be persisted.
+     */
+    pub async fn start_upload(&mut self) -> Result<()> {
+        if self.append_mode {
+            if self.uploaded_size == 0 {
+                self.delete_object().await?;
+            }
+        } else if let (Some(multipart_client), None) = (&self.multipart_client, &self.multipart_upload) {
+            let upload_id = multipart_client.initiate(self.file_path.as_str()).await?;
+            self.multipart_upload = Some(MultipartUpload { upload_id, parts: Vec::new() });
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Send a part of the data following the uploaded ones. In append mode it's readable at once,
+     * otherwise it's buffered by the object store until the upload is completed.
+     */
+    pub async fn upload_part(&mut self, part: Bytes) -> Result<()> {
+        let part_size = part.len();
+        if self.append_mode {
+            self.object_store.write_with(self.file_path.as_str(), part).append(true).await
+                .context(ObjectStoreAccessSnafu)?;
+            self.committed_size += part_size;
+        } else if let Some(multipart_client) = self.multipart_client.clone() {
+            self.start_upload().await?;
+            if let Some(multipart_upload) = self.multipart_upload.as_mut() {
+                let part_number = multipart_upload.parts.len() as u32 + 1;
+                let uploaded_part = multipart_client.upload_part(self.file_path.as_str(), multipart_upload.upload_id.as_str(),
+                                                                 part_number, part).await?;
+                multipart_upload.parts.push(uploaded_part);
+            }
+        } else {
+            if self.writer.is_none() {
+                let writer = self.object_store.writer(self.file_path.as_str()).await.context(ObjectStoreAccessSnafu)?;
+                self.writer = Some(writer);
+            }
+            if let Some(writer) = self.writer.as_mut() {
+                writer.write(part).await.context(ObjectStoreAccessSnafu)?;
+            }
+        }
+        self.uploaded_size += part_size;
+        if self.min_offset + self.uploaded_size > self.max_offset {
+            self.max_offset = self.min_offset + self.uploaded_size;
+        }
+        self.last_modified = SystemTime::now();
+
+        Ok(())
+    }
+
+    // Upload the rest of the data and complete the upload, the file is read only after it.
+    pub async fn complete_upload(&mut self) -> Result<()> {
+        if !self.write_cache.is_empty() {
+            let part = self.write_cache.split().freeze();
+            self.upload_part(part).await?;
+        }
+
+        if let (Some(multipart_upload), Some(multipart_client)) = (self.multipart_upload.take(), &self.multipart_client) {
+            if multipart_upload.parts.is_empty() {
+                multipart_client.abort(self.file_path.as_str(), multipart_upload.upload_id.as_str()).await?;
+            } else {
+                multipart_client.complete(self.file_path.as_str(), &multipart_upload).await?;
+            }
+        }
+        match self.writer.take() {
+            Some(mut writer) => writer.close().await.context(ObjectStoreAccessSnafu)?,
+            // the object isn't created if nothing is uploaded
+            None if self.uploaded_size == 0 => {
+                self.object_store.write(self.file_path.as_str(), Vec::new()).await
+                    .context(ObjectStoreAccessSnafu)?
+            }
+            None => {}
+        }
+        self.committed_size = self.uploaded_size;
+        self.completed = true;
+        if let Some(block_cache) = &self.block_cache {
+            block_cache.invalidate(self.file_path.as_str());
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Continue an upload interrupted by a restart from the data in the object store, return the
+     * uploaded size. The size sent before the restart is persisted by the caller, but the object
+     * may have more data if the restart happens before persisting it. A multipart upload goes on
+     * from the persisted parts, a part uploaded again after them replaces the one not persisted.
+     * It starts over if the multipart upload is completed before the restart.
+     */
+    pub async fn resume_upload(&mut self, uploaded_size: usize) -> Result<usize> {
+        if !self.append_mode {
+            let uploading = match (&self.multipart_client, &self.multipart_upload) {
+                (Some(multipart_client), Some(multipart_upload)) => {
+                    multipart_client.is_uploading(self.file_path.as_str(), multipart_upload.upload_id.as_str()).await?
+                }
+                _ => false,
+            };
+            if !uploading {
+                self.abort_upload().await?;
+                return Ok(0);
+            }
+
+            self.uploaded_size = uploaded_size;
+            self.max_offset = self.min_offset + uploaded_size;
+            return Ok(uploaded_size);
+        }
+
+        let object_size = self.get_object_size().await?;
+        if object_size < uploaded_size {
+            self.abort_upload().await?;
+            return Ok(0);
+        }
+
+        self.uploaded_size = object_size;
+        self.committed_size = object_size;
+        self.max_offset = self.min_offset + object_size;
+        Ok(object_size)
+    }
+
+    // Abort the upload and drop the uploaded data, the upload can start over then.
+    pub async fn abort_upload(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.abort().await.context(ObjectStoreAccessSnafu)?;
+        }
+        if let (Some(multipart_upload), Some(multipart_client)) = (self.multipart_upload.take(), &self.multipart_client) {
+            multipart_client.abort(self.file_path.as_str(), multipart_upload.upload_id.as_str()).await?;
+        }
+        self.delete_object().await?;
+
+        self.write_cache.clear();
+        self.uploaded_size = 0;
+        self.committed_size = 0;
+        self.max_offset = self.min_offset;
+        Ok(())
+    }
+
+    /*
+     * Read the range [start, end) relative to the file start from the remote file. With the block
+     * cache, the range is extended to the aligned blocks, and only the missed blocks are fetched.
+     */
+    async fn read_remote(&self, start: usize, end: usize, file_size: usize) -> Result<Vec<u8>> {
+        let block_cache = match &self.block_cache {
+            Some(block_cache) => block_cache,
+            None => return self.range_get(start, end).await,
+        };
+
+        let block_size = block_cache.get_block_size();
+        let mut data = Vec::with_capacity(end - start);
+        for block_index in start / block_size..end.div_ceil(block_size) {
+            let block_start = block_index * block_size;
+            let block = match block_cache.get(self.file_path.as_str(), block_index) {
+                Some(block) => block,
+                None => {
+                    let block_end = (block_start + block_size).min(file_size);
+                    let block = Arc::new(self.range_get(block_start, block_end).await?);
+                    // the last block of a growing file will change
+                    if block.len() == block_size || self.completed {
+                        block_cache.put(self.file_path.as_str(), block_index, block.clone());
+                    }
+                    block
+                }
+            };
+
+            let copy_start = start.max(block_start) - block_start;
+            let copy_end = end.min(block_start + block.len()) - block_start;
+            if copy_start >= copy_end {
+                return Err(InvalidInput {
+                    location: location!(),
+                    msg: format!("Remote file {} is shorter than {}", self.file_path, end),
+                });
+            }
+            data.extend_from_slice(&block[copy_start..copy_end]);
+        }
+
+        Ok(data)
+    }
+
+    // Ranged GET of the remote file.
+    async fn range_get(&self, start: usize, end: usize) -> Result<Vec<u8>> {
+        self.object_store.read_with(self.file_path.as_str())
+            .range(start as u64..end as u64)
+            .await
+            .context(ObjectStoreAccessSnafu)
+    }
+
+    // Size of the object, zero if it doesn't exist.
+    async fn get_object_size(&self) -> Result<usize> {
+        match self.object_store.stat(self.file_path.as_str()).await {
+            Ok(metadata) => Ok(metadata.content_length() as usize),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(0),
+            Err(error) => Err(error).context(ObjectStoreAccessSnafu),
+        }
+    }
+
+    async fn delete_object(&self) -> Result<()> {
+        self.object_store.delete(self.file_path.as_str()).await.context(ObjectStoreAccessSnafu)?;
+        if let Some(block_cache) = &self.block_cache {
+            block_cache.invalidate(self.file_path.as_str());
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Segment for ObjectStoreFile {
+    type Context = Operator;
+
+    fn open(context: &Operator, path: &str, start_offset: usize, file_size: u64) -> Result<Self> {
+        Ok(Self::with_operator(context.clone(), path, start_offset, file_size))
+    }
+
+    async fn list(context: &Operator, dir: &str) -> Result<Vec<(usize, String)>> {
+        let entries = match context.list(format!("{}/", dir).as_str()).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error).context(ObjectStoreAccessSnafu),
+        };
+
+        Ok(entries.iter()
+            .filter(|entry| entry.metadata().is_file())
+            .filter_map(|entry| entry.name().parse::<usize>().ok().map(|start_offset| (start_offset, entry.path().to_string())))
+            .collect())
+    }
+
+    // Objects can't be renamed in most services, the object is copied before the old one is deleted.
+    async fn rename(context: &Operator, path: &str, new_path: &str) -> Result<()> {
+        let data = context.read(path).await.context(ObjectStoreAccessSnafu)?;
+        context.write(new_path, data).await.context(ObjectStoreAccessSnafu)?;
+        context.delete(path).await.context(ObjectStoreAccessSnafu)
     }
 
-    pub fn get_min_offset(&self) -> usize {
+    fn get_path(&self) -> &str {
+        self.file_path.as_str()
+    }
+
+    fn get_last_modified(&self) -> Result<SystemTime> {
+        Ok(self.last_modified)
+    }
+
+    fn set_last_modified(&mut self, last_modified: SystemTime) -> Result<()> {
+        self.last_modified = last_modified;
+        Ok(())
+    }
+
+    fn get_min_offset(&self) -> usize {
         self.min_offset
     }
 
-    pub fn get_max_offset(&self) -> usize {
+    fn get_max_offset(&self) -> usize {
         self.max_offset
     }
 
-    pub fn read_record<Func>(&mut self, _reader: &Func)
-        where Func: Fn(&MmapMut, usize) -> Option<usize> {
-        // let mut write_pos = 0;
-        // loop {
-        //     let read_result = reader(&self.mmap, write_pos);
-        //     match read_result {
-        //         None => { break; }
-        //         Some(offset) => {
-        //             write_pos += offset;
-        //             self.max_offset += offset;
-        //         }
-        //     }
-        // }
+    fn set_max_offset(&mut self, max_offset: usize) {
+        self.max_offset = max_offset;
     }
 
-    pub async fn append(&mut self, data: &Vec<u8>) -> Result<usize> {
-        let data_len = data.len();
-        let write_pos = self.max_offset - self.min_offset;
+    /*
+     * Scan the records in the object, which is all uploaded. It can't be appended any more if the
+     * records end before the object, or the service can't append to it.
+     */
+    async fn scan<F>(&mut self, reader: &F, start_offset: usize) -> Result<()>
+        where F: Fn(&[u8], usize) -> Result<Option<usize>> + Sync {
+        let object_size = self.get_object_size().await?;
+        let data = if object_size > 0 { self.range_get(0, object_size).await? } else { Vec::new() };
+        self.uploaded_size = object_size;
+        self.committed_size = object_size;
+
+        self.max_offset = start_offset;
+        let mut read_pos = start_offset - self.min_offset;
+        while let Some(record_size) = reader(&data, read_pos)? {
+            read_pos += record_size;
+            self.max_offset += record_size;
+        }
+        self.completed = object_size > 0 && (!self.append_mode || read_pos < object_size);
+
+        Ok(())
+    }
 
-        if write_pos + data_len > self.max_file_size {
-            // write cache data to remote file
-            let cache_data = self.write_cache.split();
-            self.object_store.write(self.file_path.as_str(), cache_data).await.context(ObjectStoreAccessSnafu)?;
+    // Only the data which isn't uploaded can be discarded.
+    async fn truncate(&mut self, offset: usize) -> Result<()> {
+        let uploaded_offset = self.min_offset + self.uploaded_size;
+        if offset < uploaded_offset.min(self.max_offset) || offset > self.max_offset {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Truncate offset {} is out of the unsent data", offset),
+            });
         }
 
-        self.write_cache.put_slice(data.as_slice());
+        if offset >= uploaded_offset {
+            self.write_cache.truncate(offset - uploaded_offset);
+        }
+        self.max_offset = offset;
+        Ok(())
+    }
+
+    // Upload the data in the write cache as a part.
+    async fn flush_from(&mut self, _offset: usize) -> Result<()> {
+        if !self.write_cache.is_empty() {
+            let part = self.write_cache.split().freeze();
+            self.upload_part(part).await?;
+        }
+
+        Ok(())
+    }
 
+    /*
+     * Append the data to the end of the file, the data is uploaded once the unsent data reaches
+     * the part size, so at most a part is lost on a crash.
+     */
+    async fn append(&mut self, data: &[u8]) -> Result<usize> {
+        let data_len = data.len();
+        if self.completed || self.max_offset + data_len > self.min_offset + self.max_file_size {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: "Data size exceeds the remaining size of the file.".to_string(),
+            });
+        }
+
+        self.write_cache.put_slice(data);
         let old_offset = self.max_offset;
         self.max_offset += data_len;
 
+        if self.write_cache.len() >= self.part_size {
+            let part = self.write_cache.split().freeze();
+            self.upload_part(part).await?;
+        }
+
         Ok(old_offset)
     }
 
-    pub async fn read(&mut self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
-        let mut buffer = vec![0; data_size];
-        let read_pos = offset - self.min_offset;
-        if self.read_cache.len() == 0 {
-            // read data from remote file
-            let read_data = self.object_store.read(self.file_path.as_str()).await.context(ObjectStoreAccessSnafu)?;
-            self.read_cache.put_slice(read_data.as_slice());
-            println!("read_cache_size: {}", self.read_cache.len());
+    // Only the data in the write cache can be overwritten, the uploaded parts are immutable.
+    async fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let uploaded_offset = self.min_offset + self.uploaded_size;
+        if offset < uploaded_offset || offset + data.len() > self.max_offset {
+            return Err(InvalidInput {
+                location: location!(),
+                msg: format!("Write offset {} is out of the unsent data", offset),
+            });
         }
 
-        // Ensure read offset + data size doesn't exceed the max offset.
-        if read_pos + data_size <= self.max_offset {
-            buffer.copy_from_slice(&self.read_cache[read_pos..read_pos + data_size]);
+        let cache_pos = offset - uploaded_offset;
+        self.write_cache[cache_pos..cache_pos + data.len()].copy_from_slice(data);
+        Ok(())
+    }
 
-            Ok(buffer)
-        } else {
-            Err(InvalidInput {
+    /*
+     * Read the data in the offset range, the offsets are absolute like the other files. The data
+     * which is not uploaded yet is read from the write cache, and the data in an uncompleted
+     * multipart upload can't be read.
+     */
+    async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
+        let read_end = offset + data_size;
+        let committed_offset = self.min_offset + self.committed_size;
+        let uploaded_offset = self.min_offset + self.uploaded_size;
+        if offset < self.min_offset || read_end > self.max_offset
+            || (offset < uploaded_offset && read_end > committed_offset && committed_offset < uploaded_offset) {
+            return Err(InvalidInput {
                 location: location!(),
-                msg: "Buffer size doesn't match the mapped region's size.".to_string(),
-            })
+                msg: format!("Invalid range: offset={}, size={}", offset, data_size),
+            });
         }
+
+        let mut buffer = Vec::with_capacity(data_size);
+        if offset < committed_offset {
+            let remote_end = read_end.min(committed_offset);
+            buffer.extend(self.read_remote(offset - self.min_offset, remote_end - self.min_offset,
+                                           self.committed_size).await?);
+        }
+        if read_end > uploaded_offset {
+            let cache_start = offset.max(uploaded_offset) - uploaded_offset;
+            buffer.extend_from_slice(&self.write_cache[cache_start..read_end - uploaded_offset]);
+        }
+
+        Ok(buffer)
+    }
+
+    async fn delete(self) -> Result<()> {
+        self.delete_object().await
     }
 }
 
+// Build the operator of the object store service, it's cheap to clone and can be shared.
+pub fn build_operator(store_config: &ObjectStoreConfig) -> Result<Operator> {
+    let operator = match store_config {
+        ObjectStoreConfig::S3(s3_config) => {
+            let mut builder = S3::default();
+            let _ = builder
+                .bucket(&s3_config.bucket)
+                .endpoint(&s3_config.endpoint)
+                .region(s3_config.region.as_deref().unwrap_or(DEFAULT_S3_REGION))
+                .access_key_id(s3_config.access_key_id.expose_secret())
+                .secret_access_key(s3_config.access_key_secret.expose_secret());
+            Operator::new(builder).context(ObjectStoreBuildSnafu)?.finish()
+        }
+        ObjectStoreConfig::Oss(oss_config) => {
+            let mut builder = Oss::default();
+            let _ = builder
+                .bucket(&oss_config.bucket)
+                .endpoint(&oss_config.endpoint)
+                .access_key_id(oss_config.access_key_id.expose_secret())
+                .access_key_secret(oss_config.access_key_secret.expose_secret());
+            Operator::new(builder).context(ObjectStoreBuildSnafu)?.finish()
+        }
+        ObjectStoreConfig::Fs(fs_config) => {
+            let mut builder = Fs::default();
+            let _ = builder.root(&fs_config.root);
+            Operator::new(builder).context(ObjectStoreBuildSnafu)?.finish()
+        }
+        ObjectStoreConfig::Memory => Operator::new(Memory::default()).context(ObjectStoreBuildSnafu)?.finish(),
+    };
+
+    Ok(operator)
+}
+
 #[cfg(test)]
 mod tests {
-    use dotenv::dotenv;
-    use crate::config::{ConfigOptions, ObjectStoreConfig};
+    use std::sync::Arc;
+    use secrecy::SecretString;
+    use tempfile::TempDir;
+    use crate::config::{FsConfig, ObjectStoreConfig, OssConfig, S3Config};
     use crate::error::Result;
-    use crate::storage::object_store::OssObjectStoreFile;
+    use crate::storage::block_cache::BlockCache;
+    use crate::storage::mapped_file_queue::MappedFileQueue;
+    use crate::storage::object_store::{build_operator, ObjectStoreFile};
+    use crate::storage::segment::Segment;
+
+    pub fn create_temp_dir(prefix: &str) -> TempDir {
+        tempfile::Builder::new().prefix(prefix).tempdir().unwrap()
+    }
 
     #[tokio::test]
     pub async fn test_write_read() -> Result<()> {
-        let file_size = 32;
+        let file_size = 64;
         let file_path = "test.log";
-        dotenv().ok();
-        let config_options: ConfigOptions = ConfigOptions::load_layered_options().unwrap();
-        println!("loaded config: {:?}", config_options);
-
-        let oss_config = match config_options.storage.store {
-            ObjectStoreConfig::Oss(cfg) => {
-                Some(cfg)
-            }
-            _ => { None }
-        };
 
-        let mut object_file = OssObjectStoreFile::new(oss_config.unwrap(),
-                                                      file_path, 0, file_size)?;
+        let mut object_file = ObjectStoreFile::new(&ObjectStoreConfig::Memory, file_path, 0, file_size)?
+            .with_part_size(16);
 
         let data_to_write = "Hello, Memory-Mapped File!";
         for i in 0..2 {
             // Write data to the memory-mapped file.
             let str = format!("{}{}", data_to_write, i);
-            object_file.append(str.as_bytes().to_vec().as_ref()).await?;
+            object_file.append(str.as_bytes()).await?;
         }
 
         let data_size = data_to_write.len() + 1;
 
+        // the memory service has no append, the parts aren't readable before the upload is completed
+        assert!(!object_file.can_resume_upload());
+        assert!(object_file.read(0, data_size).await.is_err());
+        object_file.complete_upload().await?;
+
         // Read data from the memory-mapped file.
         let read_buffer = object_file.read(0, data_size).await?;
 
         // Display the read data.
         let read_data = String::from_utf8(read_buffer);
-        println!("{}", read_data.unwrap().as_str());
+        assert_eq!(read_data.unwrap().as_str(), "Hello, Memory-Mapped File!0");
+
+        // the completed file can't be appended
+        assert!(object_file.append(b"more").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_fs_upload_read() -> Result<()> {
+        let dir_path = create_temp_dir("object_store_test");
+        let store_config = ObjectStoreConfig::Fs(FsConfig { root: dir_path.path().to_str().unwrap().to_string() });
+
+        let file_path = "segment/00000000000000000100";
+        let mut object_file = ObjectStoreFile::new(&store_config, file_path, 100, 64)?.with_part_size(4);
+        assert!(object_file.can_resume_upload());
+        for data in ["012", "345", "678"] {
+            object_file.append(data.as_bytes()).await?;
+        }
+        // the first 6 bytes are appended to the object as a part, the rest is in the write cache
+        assert_eq!(object_file.read(101, 3).await?, b"123".to_vec());
+        assert_eq!(object_file.read(104, 4).await?, b"4567".to_vec());
+
+        // the data in the write cache is lost on a crash, resume from the uploaded part
+        drop(object_file);
+        let mut object_file = ObjectStoreFile::new(&store_config, file_path, 100, 64)?.with_part_size(4);
+        assert_eq!(object_file.resume_upload(4).await?, 6);
+        object_file.append(b"6789").await?;
+        object_file.complete_upload().await?;
+
+        // the file is shared by the operators on the same root
+        let mut object_file = ObjectStoreFile::new(&store_config, file_path, 100, 64)?;
+        object_file.set_uploaded(10);
+        assert_eq!(object_file.read(103, 4).await?, b"3456".to_vec());
+        assert!(object_file.read(108, 4).await.is_err());
+        assert!(object_file.read(99, 2).await.is_err());
+
+        object_file.delete().await?;
+        assert!(!dir_path.path().join("segment/00000000000000000100").exists());
+
+        Ok(())
+    }
 
+    #[tokio::test]
+    pub async fn test_read_with_block_cache() -> Result<()> {
+        let store_config = ObjectStoreConfig::Memory;
+        let block_cache = Arc::new(BlockCache::new(1024, 16));
+
+        let mut object_file = ObjectStoreFile::new(&store_config, "segment", 1000, 100)?
+            .with_block_cache(block_cache.clone());
+        let data: Vec<u8> = (0..100).collect();
+        object_file.append(&data).await?;
+        object_file.complete_upload().await?;
+
+        // the range spans the blocks 0 and 1, both are fetched
+        assert_eq!(object_file.read(1010, 10).await?, data[10..20].to_vec());
+        assert_eq!(block_cache.get_stats().miss_count, 2);
+        assert_eq!(block_cache.get_stats().cached_bytes, 32);
+
+        assert_eq!(object_file.read(1005, 25).await?, data[5..30].to_vec());
+        assert_eq!(object_file.read(1090, 10).await?, data[90..100].to_vec());
+        let cache_stats = block_cache.get_stats();
+        assert_eq!((cache_stats.hit_count, cache_stats.miss_count), (2, 4));
+        // the last block is shorter than the block size
+        assert_eq!(cache_stats.cached_bytes, 16 * 3 + 4);
+
+        object_file.delete().await?;
+        assert_eq!(block_cache.get_stats().cached_bytes, 0);
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    pub async fn test_file_queue() -> Result<()> {
+        let dir_path = create_temp_dir("object_store_test");
+        let object_store = build_operator(&ObjectStoreConfig::Fs(FsConfig { root: dir_path.path().to_str().unwrap().to_string() }))?;
+
+        let mut file_queue: MappedFileQueue<ObjectStoreFile> = MappedFileQueue::with_context(
+            object_store.clone(), "queue", 10)?;
+        for i in 0..3u8 {
+            file_queue.append(&[i + 1; 4]).await?;
+        }
+        // the data in the write cache is uploaded by the flush
+        file_queue.flush().await?;
+
+        let mut reloaded_queue: MappedFileQueue<ObjectStoreFile> = MappedFileQueue::with_context(
+            object_store, "queue", 10)?;
+        reloaded_queue.recovery(|data, offset| {
+            if offset < data.len() && data[offset] != 0 { Ok(Some(4)) } else { Ok(None) }
+        }, None).await?;
+        assert_eq!(reloaded_queue.get_mapped_files().len(), 2);
+        assert_eq!(reloaded_queue.get_max_offset(), 12);
+        assert_eq!(reloaded_queue.read(8, 4).await?, vec![3; 4]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_build_operator() -> Result<()> {
+        // building the remote services doesn't access them, no credentials are needed
+        let s3_config = S3Config {
+            bucket: "test-bucket".to_string(),
+            endpoint: "http://127.0.0.1:9000".to_string(),
+            access_key_id: SecretString::from("test".to_string()),
+            access_key_secret: SecretString::from("test".to_string()),
+            region: None,
+        };
+        build_operator(&ObjectStoreConfig::S3(s3_config.clone()))?;
+        build_operator(&ObjectStoreConfig::S3(S3Config { region: Some("eu-west-1".to_string()), ..s3_config }))?;
+
+        build_operator(&ObjectStoreConfig::Oss(OssConfig {
+            bucket: "test-bucket".to_string(),
+            endpoint: "http://127.0.0.1:9000".to_string(),
+            ..Default::default()
+        }))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use snafu::{location, Location, ResultExt};
+use crate::error::Error::{CorruptedRecord, UnsupportedRecordVersion};
+use crate::error::{Result, StdIOSnafu};
+
+// Record layout in the commit log:
+// | magic (1) | version (1) | body length (4) | crc32 (4) | store timestamp (8) | key id (4) | body |
+// The crc32 covers the fields after it and the body. The key id is of the key encrypting the body,
+// 0 if the body isn't encrypted. A newer version keeps the magic, the version and the body length,
+// so the record size can be read from the prefix.
+pub const RECORD_MAGIC: u8 = 0xB7;
+pub const RECORD_VERSION: u8 = 1;
+pub const RECORD_HEADER_SIZE: usize = RECORD_CRC_END + 8 + 4;
+// Size of the header fields which are enough to get the record size.
+pub const RECORD_PREFIX_SIZE: usize = 1 + 1 + 4;
+const RECORD_CRC_END: usize = RECORD_PREFIX_SIZE + 4;
+
+#[derive(Debug)]
+pub struct RecordHeader {
+    pub body_len: u32,
+    pub crc: u32,
+    // milliseconds since the epoch when the record is written
+    pub store_timestamp: u64,
+    // 0 if the body isn't encrypted
+    pub key_id: u32,
+}
+
+impl RecordHeader {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if !has_record_prefix(data) || data.len() < RECORD_HEADER_SIZE {
+            return None;
+        }
+
+        let body_len = u32::from_le_bytes(data[2..6].try_into().unwrap());
+        let crc = u32::from_le_bytes(data[6..10].try_into().unwrap());
+        let store_timestamp = u64::from_le_bytes(data[10..18].try_into().unwrap());
+        let key_id = u32::from_le_bytes(data[18..22].try_into().unwrap());
+
+        Some(RecordHeader { body_len, crc, store_timestamp, key_id })
+    }
+
+    pub fn record_size(&self) -> usize {
+        RECORD_HEADER_SIZE + self.body_len as usize
+    }
+}
+
+fn has_record_prefix(data: &[u8]) -> bool {
+    data.len() >= RECORD_PREFIX_SIZE && data[0] == RECORD_MAGIC && data[1] == RECORD_VERSION
+}
+
+/*
+ * Fail if the data starts with a record written by a newer version, which can't be parsed by
+ * this one. Otherwise it would be taken as a corrupted record, and the data after it dropped.
+ */
+pub fn check_record_version(data: &[u8], offset: usize) -> Result<()> {
+    if data.len() >= RECORD_PREFIX_SIZE && data[0] == RECORD_MAGIC && data[1] > RECORD_VERSION {
+        return Err(UnsupportedRecordVersion {
+            location: location!(),
+            offset,
+            version: data[1],
+            max_version: RECORD_VERSION,
+        });
+    }
+
+    Ok(())
+}
+
+// Get the record size from the prefix of the header.
+pub fn parse_record_size(data: &[u8]) -> Option<usize> {
+    if !has_record_prefix(data) {
+        return None;
+    }
+    let body_len = u32::from_le_bytes(data[2..6].try_into().unwrap());
+
+    Some(RECORD_HEADER_SIZE + body_len as usize)
+}
+
+// The checksum of the data after the crc32 field in the header.
+fn record_crc(header: &RecordHeader, data: &[u8]) -> u32 {
+    crc32fast::hash(&data[RECORD_CRC_END..header.record_size()])
+}
+
+// Wrap the body with the record header.
+pub fn encode_record(body: &[u8], store_timestamp: u64) -> Vec<u8> {
+    encode_record_with_key_id(body, store_timestamp, 0)
+}
+
+// Wrap the body encrypted by the key with the record header.
+pub fn encode_record_with_key_id(body: &[u8], store_timestamp: u64, key_id: u32) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + body.len());
+    record.push(RECORD_MAGIC);
+    record.push(RECORD_VERSION);
+    record.extend_from_slice(&u32::to_le_bytes(body.len() as u32));
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&u64::to_le_bytes(store_timestamp));
+    record.extend_from_slice(&u32::to_le_bytes(key_id));
+    record.extend_from_slice(body);
+
+    let crc = crc32fast::hash(&record[RECORD_CRC_END..]);
+    record[6..10].copy_from_slice(&u32::to_le_bytes(crc));
+
+    record
+}
+
+// Check the record at the beginning of data, return the record size if it's intact.
+pub fn check_record(data: &[u8]) -> Option<usize> {
+    let header = RecordHeader::parse(data)?;
+    if header.body_len == 0 || header.record_size() > data.len() {
+        return None;
+    }
+
+    if record_crc(&header, data) != header.crc {
+        return None;
+    }
+
+    Some(header.record_size())
+}
+
+// Validate the record read from the given offset and return its body.
+pub fn decode_record(data: &[u8], offset: usize) -> Result<&[u8]> {
+    decode_record_with_header(data, offset).map(|(_, body)| body)
+}
+
+// Validate the record read from the given offset and return its header and body.
+pub fn decode_record_with_header(data: &[u8], offset: usize) -> Result<(RecordHeader, &[u8])> {
+    check_record_version(data, offset)?;
+
+    let header = RecordHeader::parse(data).ok_or_else(|| CorruptedRecord {
+        location: location!(),
+        offset,
+        msg: "Invalid record header".to_string(),
+    })?;
+
+    if header.record_size() > data.len() {
+        return Err(CorruptedRecord {
+            location: location!(),
+            offset,
+            msg: format!("Record body length {} exceeds the read size", header.body_len),
+        });
+    }
+
+    if record_crc(&header, data) != header.crc {
+        return Err(CorruptedRecord {
+            location: location!(),
+            offset,
+            msg: "Record checksum mismatch".to_string(),
+        });
+    }
+
+    let body = &data[RECORD_HEADER_SIZE..header.record_size()];
+    Ok((header, body))
+}
+
+// Write the body as a record to the file, through a temporary file so a crash won't leave a partial one.
+pub fn write_record_file(file_path: &Path, body: &[u8]) -> Result<()> {
+    let temp_path = file_path.with_extension("tmp");
+
+    let mut temp_file = File::create(&temp_path).context(StdIOSnafu)?;
+    temp_file.write_all(&encode_record(body, current_timestamp_ms())).context(StdIOSnafu)?;
+    temp_file.sync_all().context(StdIOSnafu)?;
+    fs::rename(&temp_path, file_path).context(StdIOSnafu)?;
+
+    Ok(())
+}
+
+// Read the body of the record file, None if the file doesn't exist.
+pub fn read_record_file(file_path: &Path) -> Result<Option<Vec<u8>>> {
+    if !file_path.exists() {
+        return Ok(None);
+    }
+
+    let record_bytes = fs::read(file_path).context(StdIOSnafu)?;
+    Ok(Some(decode_record(&record_bytes, 0)?.to_vec()))
+}
+
+// Milliseconds since the epoch.
+pub fn current_timestamp_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error::{CorruptedRecord, UnsupportedRecordVersion};
+    use crate::error::Result;
+    use crate::storage::record::{check_record, check_record_version, decode_record, decode_record_with_header,
+                                 encode_record, encode_record_with_key_id, RECORD_HEADER_SIZE, RECORD_VERSION};
+
+    #[tokio::test]
+    pub async fn test_encode_decode() -> Result<()> {
+        let body = "hello record".as_bytes();
+        let record = encode_record(body, 1631894400000);
+
+        assert_eq!(check_record(&record), Some(RECORD_HEADER_SIZE + body.len()));
+        assert_eq!(decode_record(&record, 0)?, body);
+
+        let (header, _) = decode_record_with_header(&record, 0)?;
+        assert_eq!(header.store_timestamp, 1631894400000);
+        assert_eq!(header.key_id, 0);
+
+        // the key id is covered by the checksum
+        let mut record = encode_record_with_key_id(body, 1631894400000, 7);
+        assert_eq!(decode_record_with_header(&record, 0)?.0.key_id, 7);
+        record[18] ^= 0xFF;
+        assert_eq!(check_record(&record), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_corrupted_record() -> Result<()> {
+        let mut record = encode_record("hello record".as_bytes(), 1631894400000);
+        let last = record.len() - 1;
+        record[last] ^= 0xFF;
+
+        assert_eq!(check_record(&record), None);
+        assert!(matches!(decode_record(&record, 0), Err(CorruptedRecord { .. })));
+
+        // the store timestamp is covered by the checksum too
+        let mut record = encode_record("hello record".as_bytes(), 1631894400000);
+        record[12] ^= 0xFF;
+        assert_eq!(check_record(&record), None);
+
+        // zeroed space is never a valid record
+        assert_eq!(check_record(&[0u8; 32]), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_newer_version_record() -> Result<()> {
+        let mut record = encode_record("hello record".as_bytes(), 1631894400000);
+        record[1] = RECORD_VERSION + 1;
+
+        assert!(check_record_version(&record, 0).is_err());
+        assert!(matches!(decode_record(&record, 100),
+            Err(UnsupportedRecordVersion { offset: 100, version, .. }) if version == RECORD_VERSION + 1));
+        assert!(check_record_version(&[0u8; 32], 0).is_ok());
+
+        Ok(())
+    }
+}
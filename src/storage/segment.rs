@@ -0,0 +1,68 @@
+use std::time::SystemTime;
+use async_trait::async_trait;
+use crate::error::Result;
+
+/*
+ * A file of the MappedFileQueue, which covers the offset range [min offset, max offset) of the
+ * queue. The offsets are absolute in the queue, not relative to the file. Segments may live on
+ * the local disk, in the object store or in memory, so the data access methods are async, the
+ * local ones just complete at once.
+ */
+#[async_trait]
+pub trait Segment: Send + Sync + Sized {
+    // Shared by the segments of a queue to open them, like the operator of the object store.
+    type Context: Clone + Send + Sync;
+
+    // Open or create the segment at the path, the data is scanned by the queue during recovery.
+    fn open(context: &Self::Context, path: &str, start_offset: usize, file_size: u64) -> Result<Self>;
+
+    // Start offsets and paths of the segments in the directory, which are named by their start offsets.
+    async fn list(context: &Self::Context, dir: &str) -> Result<Vec<(usize, String)>>;
+
+    // Move the segment at the path to the new path, the segment at the new path is replaced.
+    async fn rename(context: &Self::Context, path: &str, new_path: &str) -> Result<()>;
+
+    fn get_path(&self) -> &str;
+
+    fn get_last_modified(&self) -> Result<SystemTime>;
+
+    // Keep the given modified time, like the one of the segment it's rewritten from.
+    fn set_last_modified(&mut self, last_modified: SystemTime) -> Result<()>;
+
+    // Whether the segment hasn't been modified for the given time.
+    fn is_older_than(&self, duration_ms: u64) -> Result<bool> {
+        let elapsed = SystemTime::now().duration_since(self.get_last_modified()?).unwrap_or_default();
+        Ok(elapsed.as_millis() >= duration_ms as u128)
+    }
+
+    fn get_min_offset(&self) -> usize;
+
+    fn get_max_offset(&self) -> usize;
+
+    // Set the max offset of a sealed segment directly, without scanning its records.
+    fn set_max_offset(&mut self, max_offset: usize);
+
+    /*
+     * Scan the records from the given offset, and move the max offset to the end of the last one.
+     * The reader gets the data of the whole segment and a position relative to its start, and
+     * returns the size of the record there.
+     */
+    async fn scan<F>(&mut self, reader: &F, start_offset: usize) -> Result<()>
+        where F: Fn(&[u8], usize) -> Result<Option<usize>> + Sync;
+
+    // Discard the data after the given offset, the stale bytes after it are cleared as well.
+    async fn truncate(&mut self, offset: usize) -> Result<()>;
+
+    // Persist the data between the given offset and the max offset.
+    async fn flush_from(&mut self, offset: usize) -> Result<()>;
+
+    // Append the data to the end, return the offset it's written at. An InvalidInput error means the segment is full.
+    async fn append(&mut self, data: &[u8]) -> Result<usize>;
+
+    // Overwrite the written data at the given offset, and persist it.
+    async fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<()>;
+
+    async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>>;
+
+    async fn delete(self) -> Result<()>;
+}
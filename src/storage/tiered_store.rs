@@ -0,0 +1,945 @@
+use std::collections::HashMap;
+use std::fs;
+use std::future::{self, Future};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use opendal::{ErrorKind, Operator};
+use serde::{Deserialize, Serialize};
+use snafu::{location, Location, ResultExt};
+use crate::error::Error::{CorruptedRecord, OffsetOutOfRange};
+use crate::config::{ObjectStoreConfig, StorageConfig};
+use crate::error::{DecodeMsgBinSnafu, EncodeMsgBinSnafu, ObjectStoreAccessSnafu, Result, StdIOSnafu};
+use crate::message::CacheStats;
+use crate::storage::block_cache::BlockCache;
+use crate::storage::commit_log::COMMIT_LOG_DIR;
+use crate::storage::index_store::INDEX_DIR;
+use crate::storage::mapped_file_queue::RemoteSegments;
+use crate::storage::manifest::{segment_name, segment_path, Manifest, SegmentMeta};
+use crate::storage::multipart_upload::{MultipartUpload, S3MultipartClient};
+use crate::storage::object_store::ObjectStoreFile;
+use crate::storage::segment::Segment;
+use crate::storage::record::{current_timestamp_ms, parse_record_size, read_record_file, write_record_file,
+                             RECORD_PREFIX_SIZE};
+
+const UPLOAD_PROGRESS_FILE_NAME: &str = "upload_progress";
+// The manifests are flushed once this many commit log segments are uploaded.
+const MANIFEST_BATCH_SIZE: usize = 16;
+
+// Progress of the segment being uploaded, it's used to resume or abort the upload on restart.
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadProgress {
+    object_path: String,
+    data_size: u64,
+    uploaded_size: u64,
+    // the multipart upload of the services which can't append, with the parts uploaded
+    multipart_upload: Option<MultipartUpload>,
+}
+
+/*
+ * The files uploaded to the object store from the commit log and the message index queues. Each
+ * queue has a directory in the object store with the same layout as the local one, and a manifest
+ * of its segments. Commit log segments always precede the local files.
+ */
+pub struct TieredStore {
+    store_path: String,
+    object_store: Operator,
+    // queue directory -> segments in the order of their offsets, the same as the manifests
+    queue_segments: Mutex<HashMap<String, Vec<SegmentMeta>>>,
+    // the manifests are rewritten one at a time, so concurrent changes won't be lost
+    manifest_lock: tokio::sync::Mutex<()>,
+    // segments uploaded but not added to the manifests yet, they're added in a batch
+    pending_segments: Mutex<HashMap<String, Vec<SegmentMeta>>>,
+    block_cache: Arc<BlockCache>,
+    upload_part_size: usize,
+    // segments are uploaded by the multipart uploads of the client on S3
+    multipart_client: Option<Arc<S3MultipartClient>>,
+}
+
+impl TieredStore {
+    // Load the manifests from the object store, the local disk may be empty.
+    pub async fn open(store_path: &str, object_store: Operator, storage_config: &StorageConfig) -> Result<Self> {
+        let mut queue_dirs = vec![COMMIT_LOG_DIR.to_string()];
+        queue_dirs.extend(list_index_queue_dirs(&object_store).await?);
+
+        let mut queue_segments = HashMap::new();
+        for queue_dir in queue_dirs {
+            if let Some(manifest) = Manifest::load(&object_store, queue_dir.as_str()).await? {
+                println!("loaded manifest: queue_dir={}, segment_num={}", queue_dir, manifest.segments.len());
+                queue_segments.insert(queue_dir, manifest.segments);
+            }
+        }
+
+        let multipart_client = match &storage_config.store {
+            ObjectStoreConfig::S3(s3_config) => Some(Arc::new(S3MultipartClient::new(s3_config))),
+            _ => None,
+        };
+        Ok(TieredStore {
+            store_path: store_path.to_string(),
+            object_store,
+            queue_segments: Mutex::new(queue_segments),
+            manifest_lock: tokio::sync::Mutex::new(()),
+            pending_segments: Mutex::new(HashMap::new()),
+            block_cache: Arc::new(BlockCache::new(storage_config.read_cache_size, storage_config.read_block_size)),
+            upload_part_size: storage_config.upload_part_size,
+            multipart_client,
+        })
+    }
+
+    fn progress_path(&self) -> PathBuf {
+        PathBuf::from(self.store_path.as_str()).join(UPLOAD_PROGRESS_FILE_NAME)
+    }
+
+    fn load_progress(&self) -> Result<Option<UploadProgress>> {
+        match read_record_file(&self.progress_path())? {
+            Some(progress_body) => Ok(Some(bincode::deserialize(&progress_body).context(DecodeMsgBinSnafu)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn flush_progress(&self, progress: &UploadProgress) -> Result<()> {
+        let progress_body = bincode::serialize(progress).context(EncodeMsgBinSnafu)?;
+        write_record_file(&self.progress_path(), &progress_body)
+    }
+
+    fn new_segment_file(&self, object_path: &str, start_offset: usize, data_size: u64) -> ObjectStoreFile {
+        let segment_file = ObjectStoreFile::with_operator(self.object_store.clone(), object_path, start_offset, data_size)
+            .with_block_cache(self.block_cache.clone())
+            .with_part_size(self.upload_part_size);
+        match &self.multipart_client {
+            Some(multipart_client) => segment_file.with_multipart_client(multipart_client.clone()),
+            None => segment_file,
+        }
+    }
+
+    // The uploaded segment file to read or delete.
+    fn segment_file(&self, queue_dir: &str, segment: &SegmentMeta) -> ObjectStoreFile {
+        let mut segment_file = self.new_segment_file(segment_path(queue_dir, segment).as_str(),
+                                                     segment.start_offset as usize, segment.data_size);
+        segment_file.set_uploaded(segment.data_size as usize);
+
+        segment_file
+    }
+
+    fn get_segments(&self, queue_dir: &str) -> Vec<SegmentMeta> {
+        self.queue_segments.lock().unwrap().get(queue_dir).cloned().unwrap_or_default()
+    }
+
+    // End offset of the segments uploaded to the queue, including the ones not in the manifest yet.
+    fn get_upload_end_offset(&self, queue_dir: &str) -> Option<usize> {
+        let pending_end_offset = self.pending_segments.lock().unwrap().get(queue_dir)
+            .and_then(|segments| segments.last())
+            .map(|s| s.end_offset as usize);
+        pending_end_offset.or_else(|| self.get_segments(queue_dir).last().map(|s| s.end_offset as usize))
+    }
+
+    // Change the segments of the queue, they are visible after the manifest is stored.
+    async fn update_segments<F, T>(&self, queue_dir: &str, update: F) -> Result<T>
+        where F: FnOnce(&mut Vec<SegmentMeta>) -> T {
+        let _manifest_guard = self.manifest_lock.lock().await;
+        let mut segments = self.get_segments(queue_dir);
+        let update_result = update(&mut segments);
+
+        Manifest::new(segments.clone()).store(&self.object_store, queue_dir).await?;
+        self.queue_segments.lock().unwrap().insert(queue_dir.to_string(), segments);
+
+        Ok(update_result)
+    }
+
+    pub fn get_cache_stats(&self) -> CacheStats {
+        self.block_cache.get_stats()
+    }
+
+    // Min offset of the commit log in the object store, none if nothing is uploaded.
+    pub fn get_min_offset(&self) -> Option<usize> {
+        self.queue_segments.lock().unwrap().get(COMMIT_LOG_DIR)
+            .and_then(|segments| segments.first())
+            .map(|s| s.start_offset as usize)
+    }
+
+    // Data before this offset of the commit log has been uploaded.
+    pub fn get_uploaded_offset(&self) -> usize {
+        uploaded_offset(&self.get_segments(COMMIT_LOG_DIR))
+    }
+
+    // Offset of the commit log where the next segment is uploaded from, none if nothing is uploaded.
+    pub fn get_next_upload_offset(&self) -> Option<usize> {
+        self.get_upload_end_offset(COMMIT_LOG_DIR)
+    }
+
+    // Index units before this byte offset of the queue have been uploaded, maybe not in the manifest yet.
+    pub fn get_index_uploaded_offset(&self, topic: &str, queue_id: u32) -> usize {
+        self.get_upload_end_offset(index_queue_dir(topic, queue_id).as_str()).unwrap_or(0)
+    }
+
+    // Upload a sealed file of the commit log, see upload_object. The manifests are flushed once a batch is uploaded.
+    pub async fn upload_segment<F, Fut>(&self, segment: SegmentMeta, read_data: F) -> Result<()>
+        where F: Fn(usize, usize) -> Fut, Fut: Future<Output = Result<Vec<u8>>> {
+        self.upload_object(COMMIT_LOG_DIR, segment, read_data).await?;
+
+        let pending_num = self.pending_segments.lock().unwrap().get(COMMIT_LOG_DIR).map_or(0, |s| s.len());
+        if pending_num >= MANIFEST_BATCH_SIZE {
+            self.flush_manifests().await?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Add the uploaded segments to the manifests, the index queues go before the commit log, so
+     * the index units of the uploaded commit log are always uploaded. A manifest is rewritten for
+     * a batch of segments rather than each one. The segments are uploaded again after a restart
+     * if they're not added, return the added segment count.
+     */
+    pub async fn flush_manifests(&self) -> Result<usize> {
+        let mut pending_segments: Vec<(String, Vec<SegmentMeta>)> = self.pending_segments.lock().unwrap()
+            .drain()
+            .collect();
+        pending_segments.sort_by_key(|(queue_dir, _)| queue_dir == COMMIT_LOG_DIR);
+
+        let mut flushed_num = 0;
+        for (queue_dir, segments) in pending_segments {
+            flushed_num += segments.len();
+            self.update_segments(queue_dir.as_str(), |queue_segments| queue_segments.extend(segments)).await?;
+        }
+
+        Ok(flushed_num)
+    }
+
+    // Upload a sealed index file of the queue, its offsets are the byte offsets in the queue.
+    pub async fn upload_index_segment(&self, topic: &str, queue_id: u32, segment: SegmentMeta,
+                                      data: &[u8]) -> Result<()> {
+        let start_offset = segment.start_offset as usize;
+        self.upload_object(index_queue_dir(topic, queue_id).as_str(), segment, |offset, size| {
+            future::ready(Ok(data[offset - start_offset..offset - start_offset + size].to_vec()))
+        }).await
+    }
+
+    /*
+     * Upload a sealed file of the queue part by part, the data of the file is read by the given
+     * function with the offset and size. The progress is persisted after each part, with the id
+     * of the multipart upload if it's used, an upload interrupted by a restart is resumed if the
+     * service supports it, otherwise aborted and started over. The segment is pending for the
+     * manifest with the checksum of its data, and the offsets of the segments in a queue must be
+     * continuous.
+     */
+    async fn upload_object<F, Fut>(&self, queue_dir: &str, mut segment: SegmentMeta, read_data: F) -> Result<()>
+        where F: Fn(usize, usize) -> Fut, Fut: Future<Output = Result<Vec<u8>>> {
+        let object_path = segment_path(queue_dir, &segment);
+        let start_offset = segment.start_offset as usize;
+        let data_size = segment.data_size as usize;
+        let mut segment_file = self.new_segment_file(object_path.as_str(), start_offset, segment.data_size);
+
+        let mut uploaded_size = 0;
+        if let Some(progress) = self.load_progress()? {
+            if progress.object_path == object_path && progress.data_size == segment.data_size {
+                segment_file.set_multipart_upload(progress.multipart_upload);
+                uploaded_size = segment_file.resume_upload(progress.uploaded_size as usize).await?;
+                println!("resume segment upload: path={}, uploaded_size={}", object_path, uploaded_size);
+            } else {
+                // the local file of the interrupted upload is gone
+                println!("abort segment upload: path={}", progress.object_path);
+                let mut interrupted_file = self.new_segment_file(progress.object_path.as_str(), 0, progress.data_size);
+                interrupted_file.set_multipart_upload(progress.multipart_upload);
+                interrupted_file.abort_upload().await?;
+            }
+        }
+        segment_file.start_upload().await?;
+        self.flush_progress(&UploadProgress {
+            object_path: object_path.clone(),
+            data_size: segment.data_size,
+            uploaded_size: uploaded_size as u64,
+            multipart_upload: segment_file.get_multipart_upload().cloned(),
+        })?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut hashed_size = 0;
+        while hashed_size < uploaded_size {
+            let part_size = self.upload_part_size.min(uploaded_size - hashed_size);
+            hasher.update(&read_data(start_offset + hashed_size, part_size).await?);
+            hashed_size += part_size;
+        }
+
+        while uploaded_size < data_size {
+            let part_size = self.upload_part_size.min(data_size - uploaded_size);
+            let part = read_data(start_offset + uploaded_size, part_size).await?;
+            hasher.update(&part);
+            if let Err(error) = segment_file.upload_part(part.into()).await {
+                if !segment_file.can_resume_upload() {
+                    segment_file.abort_upload().await?;
+                }
+                return Err(error);
+            }
+
+            uploaded_size += part_size;
+            self.flush_progress(&UploadProgress {
+                object_path: object_path.clone(),
+                data_size: segment.data_size,
+                uploaded_size: uploaded_size as u64,
+                multipart_upload: segment_file.get_multipart_upload().cloned(),
+            })?;
+        }
+        segment_file.complete_upload().await?;
+
+        segment.checksum = hasher.finalize();
+        println!("uploaded segment: path={}, end_offset={}, checksum={}", object_path, segment.end_offset,
+                 segment.checksum);
+        self.pending_segments.lock().unwrap().entry(queue_dir.to_string()).or_default().push(segment);
+        if self.progress_path().exists() {
+            fs::remove_file(self.progress_path()).context(StdIOSnafu)?;
+        }
+
+        Ok(())
+    }
+
+    // Fetch the data in the offset range from the commit log segment which contains it.
+    pub async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
+        let segment = {
+            let queue_segments = self.queue_segments.lock().unwrap();
+            let segments = queue_segments.get(COMMIT_LOG_DIR).map(|s| s.as_slice()).unwrap_or_default();
+            let segment_index = segments.partition_point(|s| s.start_offset as usize <= offset);
+            match segment_index.checked_sub(1).map(|i| &segments[i]) {
+                Some(segment) if offset < segment.end_offset as usize => segment.clone(),
+                _ => return Err(OffsetOutOfRange {
+                    location: location!(),
+                    offset,
+                    min_offset: segments.first().map(|s| s.start_offset as usize).unwrap_or(0),
+                }),
+            }
+        };
+
+        self.segment_file(COMMIT_LOG_DIR, &segment).read(offset, data_size).await
+    }
+
+    /*
+     * Visit the records of the commit log segments in order. Each segment is streamed once by
+     * sequential reads of the batch size, a record across two reads is completed by the next one.
+     */
+    pub async fn scan_records<F>(&self, batch_size: usize, mut visitor: F) -> Result<()>
+        where F: FnMut(usize, &[u8]) -> Result<()> {
+        for segment in self.get_segments(COMMIT_LOG_DIR) {
+            let segment_file = self.segment_file(COMMIT_LOG_DIR, &segment);
+            let data_end_offset = (segment.start_offset + segment.data_size) as usize;
+            // the data read from the segment, the unvisited part starts at the position
+            let mut buffer = Vec::new();
+            let mut position = 0;
+            let mut record_offset = segment.start_offset as usize;
+            let mut read_offset = record_offset;
+            while record_offset < data_end_offset {
+                let unvisited = &buffer[position..];
+                let record_size = match unvisited.len() >= RECORD_PREFIX_SIZE {
+                    true => Some(parse_record_size(unvisited).ok_or_else(|| CorruptedRecord {
+                        location: location!(),
+                        offset: record_offset,
+                        msg: "Invalid record header".to_string(),
+                    })?),
+                    false => None,
+                };
+                match record_size {
+                    Some(record_size) if unvisited.len() >= record_size => {
+                        visitor(record_offset, &unvisited[..record_size])?;
+                        position += record_size;
+                        record_offset += record_size;
+                    }
+                    _ if read_offset < data_end_offset => {
+                        let read_size = batch_size.min(data_end_offset - read_offset);
+                        buffer.drain(..position);
+                        position = 0;
+                        buffer.extend(segment_file.read(read_offset, read_size).await?);
+                        read_offset += read_size;
+                    }
+                    _ => return Err(CorruptedRecord {
+                        location: location!(),
+                        offset: record_offset,
+                        msg: "Incomplete record at the end of the segment".to_string(),
+                    }),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Download the index segments of the queues which don't exist locally, the data is checked
+     * against the manifests. Segments are split into the local files of the given size, so the
+     * files are loaded as the normal ones then. Return the downloaded segment count.
+     */
+    pub async fn download_index_files(&self, file_size: usize) -> Result<usize> {
+        let index_queues: Vec<(String, Vec<SegmentMeta>)> = self.queue_segments.lock().unwrap().iter()
+            .filter(|(queue_dir, _)| queue_dir.starts_with(INDEX_DIR))
+            .map(|(queue_dir, segments)| (queue_dir.clone(), segments.clone()))
+            .collect();
+
+        let mut downloaded_num = 0;
+        for (queue_dir, segments) in index_queues {
+            let local_queue_dir = PathBuf::from(self.store_path.as_str()).join(queue_dir.as_str());
+            if local_queue_dir.exists() || segments.is_empty() {
+                continue;
+            }
+            fs::create_dir_all(&local_queue_dir).context(StdIOSnafu)?;
+
+            let mut file_start_offset = segments[0].start_offset as usize;
+            let mut file_data = Vec::new();
+            for segment in segments.iter() {
+                let data = self.object_store.read(segment_path(queue_dir.as_str(), segment).as_str()).await
+                    .context(ObjectStoreAccessSnafu)?;
+                if data.len() as u64 != segment.data_size || crc32fast::hash(&data) != segment.checksum {
+                    return Err(CorruptedRecord {
+                        location: location!(),
+                        offset: segment.start_offset as usize,
+                        msg: format!("Segment {} doesn't match the manifest", segment_path(queue_dir.as_str(), segment)),
+                    });
+                }
+
+                // the segments before are deleted locally before uploaded, start a new file
+                if segment.start_offset as usize != file_start_offset + file_data.len() {
+                    write_local_file(&local_queue_dir, file_start_offset, &file_data)?;
+                    file_start_offset = segment.start_offset as usize;
+                    file_data.clear();
+                }
+                file_data.extend(data);
+                while file_data.len() >= file_size {
+                    write_local_file(&local_queue_dir, file_start_offset, &file_data[..file_size])?;
+                    file_start_offset += file_size;
+                    file_data.drain(..file_size);
+                }
+                downloaded_num += 1;
+            }
+            write_local_file(&local_queue_dir, file_start_offset, &file_data)?;
+            println!("downloaded index files: queue_dir={}, segment_num={}", queue_dir, segments.len());
+        }
+
+        Ok(downloaded_num)
+    }
+
+    /*
+     * Remove the oldest commit log segments expired by the retention time or size from the
+     * manifest, the size counts the data up to the given max offset of the commit log. The
+     * removed segments should be deleted from the object store then.
+     */
+    pub async fn take_expired_segments(&self, retention_ms: Option<u64>, retention_bytes: Option<u64>,
+                                       max_offset: usize) -> Result<Vec<SegmentMeta>> {
+        let now = current_timestamp_ms();
+        let expired_num = self.get_segments(COMMIT_LOG_DIR).iter()
+            .take_while(|segment| {
+                let expired_by_time = retention_ms
+                    .map(|retention_ms| now.saturating_sub(segment.sealed_timestamp) >= retention_ms)
+                    .unwrap_or(false);
+                let expired_by_size = retention_bytes
+                    .map(|retention_bytes| max_offset.saturating_sub(segment.start_offset as usize) as u64 > retention_bytes)
+                    .unwrap_or(false);
+
+                expired_by_time || expired_by_size
+            })
+            .count();
+        if expired_num == 0 {
+            return Ok(Vec::new());
+        }
+
+        self.update_segments(COMMIT_LOG_DIR, |segments| segments.drain(..expired_num).collect()).await
+    }
+
+    pub async fn delete_segments(&self, segments: &[SegmentMeta]) -> Result<()> {
+        for segment in segments {
+            println!("delete expired commit log segment: start_offset={}", segment.start_offset);
+            self.segment_file(COMMIT_LOG_DIR, segment).delete().await?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Delete the index segments of the queue before the given byte offset, which is the min
+     * offset of the local index files. Return the deleted segment count.
+     */
+    pub async fn delete_index_segments_before(&self, topic: &str, queue_id: u32, offset: usize) -> Result<usize> {
+        let queue_dir = index_queue_dir(topic, queue_id);
+        let expired_num = self.get_segments(queue_dir.as_str()).iter()
+            .take_while(|segment| segment.end_offset as usize <= offset)
+            .count();
+        if expired_num == 0 {
+            return Ok(0);
+        }
+
+        let expired_segments: Vec<SegmentMeta> = self.update_segments(queue_dir.as_str(), |segments| {
+            segments.drain(..expired_num).collect()
+        }).await?;
+        for segment in expired_segments.iter() {
+            println!("delete expired index segment: queue_dir={}, start_offset={}", queue_dir, segment.start_offset);
+            self.segment_file(queue_dir.as_str(), segment).delete().await?;
+        }
+
+        Ok(expired_num)
+    }
+}
+
+// The uploaded segments of the commit log, which serve the reads before its local files.
+#[async_trait]
+impl RemoteSegments for TieredStore {
+    fn get_min_offset(&self) -> Option<usize> {
+        TieredStore::get_min_offset(self)
+    }
+
+    fn get_segment_range(&self, offset: usize) -> Option<(usize, usize)> {
+        let queue_segments = self.queue_segments.lock().unwrap();
+        let segments = queue_segments.get(COMMIT_LOG_DIR).map(|s| s.as_slice()).unwrap_or_default();
+        let segment_index = segments.partition_point(|s| s.start_offset as usize <= offset);
+        segment_index.checked_sub(1).map(|i| &segments[i])
+            .map(|s| (s.start_offset as usize, s.end_offset as usize))
+            .filter(|(_, end_offset)| offset < *end_offset)
+    }
+
+    async fn read(&self, offset: usize, data_size: usize) -> Result<Vec<u8>> {
+        TieredStore::read(self, offset, data_size).await
+    }
+}
+
+fn write_local_file(dir: &Path, start_offset: usize, data: &[u8]) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    fs::write(dir.join(segment_name(start_offset)), data).context(StdIOSnafu)
+}
+
+fn uploaded_offset(segments: &[SegmentMeta]) -> usize {
+    segments.last().map(|s| s.end_offset as usize).unwrap_or(0)
+}
+
+fn index_queue_dir(topic: &str, queue_id: u32) -> String {
+    format!("{}/{}/{}", INDEX_DIR, topic, queue_id)
+}
+
+// Directories of the index queues in the object store: index/{topic}/{queue id}.
+async fn list_index_queue_dirs(object_store: &Operator) -> Result<Vec<String>> {
+    let mut queue_dirs = Vec::new();
+    for topic_entry in list_dir(object_store, format!("{}/", INDEX_DIR).as_str()).await? {
+        for queue_entry in list_dir(object_store, topic_entry.as_str()).await? {
+            queue_dirs.push(queue_entry.trim_end_matches('/').to_string());
+        }
+    }
+
+    Ok(queue_dirs)
+}
+
+// Paths of the sub directories, they end with '/'.
+async fn list_dir(object_store: &Operator, dir: &str) -> Result<Vec<String>> {
+    let entries = match object_store.list(dir).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error).context(ObjectStoreAccessSnafu),
+    };
+
+    Ok(entries.iter()
+        .filter(|entry| entry.metadata().is_dir() && entry.path() != dir)
+        .map(|entry| entry.path().to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::future;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use axum::body::{Body, Bytes};
+    use axum::extract::{Query, State};
+    use axum::http::{HeaderMap, Method, Response, StatusCode, Uri};
+    use axum::Router;
+    use opendal::Operator;
+    use secrecy::SecretString;
+    use snafu::{location, Location};
+    use tempfile::TempDir;
+    use crate::config::{FsConfig, ObjectStoreConfig, S3Config, StorageConfig};
+    use crate::error::Error::InvalidInput;
+    use crate::error::Result;
+    use crate::storage::manifest::{Manifest, SegmentMeta};
+    use crate::storage::object_store::build_operator;
+    use crate::storage::record::{current_timestamp_ms, decode_record, encode_record};
+    use crate::storage::multipart_upload::MultipartUpload;
+    use crate::storage::tiered_store::{TieredStore, UploadProgress, MANIFEST_BATCH_SIZE};
+
+    pub fn create_temp_dir(prefix: &str) -> TempDir {
+        tempfile::Builder::new().prefix(prefix).tempdir().unwrap()
+    }
+
+    pub fn memory_operator() -> Operator {
+        build_operator(&ObjectStoreConfig::Memory).unwrap()
+    }
+
+    pub fn test_storage_config() -> StorageConfig {
+        StorageConfig {
+            upload_part_size: 16,
+            read_block_size: 32,
+            read_cache_size: 1024,
+            ..Default::default()
+        }
+    }
+
+    // Objects and multipart uploads of the fake S3 service, the uploads are keyed by their ids.
+    #[derive(Default)]
+    pub struct FakeS3 {
+        objects: HashMap<String, Vec<u8>>,
+        uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+        upload_num: usize,
+    }
+
+    // Serve the requests of the S3 operator and the multipart client in memory.
+    async fn serve_fake_s3(State(fake_s3): State<Arc<Mutex<FakeS3>>>, method: Method, uri: Uri, headers: HeaderMap,
+                           Query(query): Query<HashMap<String, String>>, body: Bytes) -> Response<Body> {
+        let mut fake_s3 = fake_s3.lock().unwrap();
+        let key = uri.path().trim_start_matches("/test-bucket").trim_start_matches('/').to_string();
+        let response = Response::builder();
+        let response = match (method, query.get("uploadId")) {
+            (Method::POST, None) if query.contains_key("uploads") => {
+                fake_s3.upload_num += 1;
+                let upload_id = format!("upload-{}", fake_s3.upload_num);
+                fake_s3.uploads.insert(upload_id.clone(), BTreeMap::new());
+                response.body(Body::from(format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>", upload_id)))
+            }
+            (Method::PUT, Some(upload_id)) => {
+                let part_number: u32 = query["partNumber"].parse().unwrap();
+                match fake_s3.uploads.get_mut(upload_id) {
+                    Some(parts) => {
+                        parts.insert(part_number, body.to_vec());
+                        response.header("ETag", format!("\"etag-{}\"", part_number)).body(Body::empty())
+                    }
+                    None => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+                }
+            }
+            (Method::POST, Some(upload_id)) => match fake_s3.uploads.remove(upload_id) {
+                Some(parts) => {
+                    fake_s3.objects.insert(key, parts.into_values().flatten().collect());
+                    response.body(Body::from("<CompleteMultipartUploadResult></CompleteMultipartUploadResult>"))
+                }
+                None => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+            },
+            (Method::GET, Some(upload_id)) => match fake_s3.uploads.contains_key(upload_id) {
+                true => response.body(Body::from("<ListPartsResult></ListPartsResult>")),
+                false => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+            },
+            (Method::DELETE, Some(upload_id)) => match fake_s3.uploads.remove(upload_id) {
+                Some(_) => response.status(StatusCode::NO_CONTENT).body(Body::empty()),
+                None => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+            },
+            (Method::GET, None) if query.contains_key("list-type") => {
+                response.body(Body::from("<ListBucketResult><IsTruncated>false</IsTruncated></ListBucketResult>"))
+            }
+            (Method::PUT, None) => {
+                fake_s3.objects.insert(key, body.to_vec());
+                response.body(Body::empty())
+            }
+            (Method::DELETE, None) => {
+                fake_s3.objects.remove(&key);
+                response.status(StatusCode::NO_CONTENT).body(Body::empty())
+            }
+            (method, None) => match fake_s3.objects.get(&key) {
+                Some(data) if method == Method::HEAD => {
+                    response.header("Content-Length", data.len()).body(Body::empty())
+                }
+                Some(data) => {
+                    let (start, end) = match headers.get("Range").and_then(|range| range.to_str().ok()) {
+                        Some(range) => {
+                            let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+                            (start.parse().unwrap(), end.parse::<usize>().unwrap() + 1)
+                        }
+                        None => (0, data.len()),
+                    };
+                    response.status(StatusCode::PARTIAL_CONTENT)
+                        .header("Content-Range", format!("bytes {}-{}/{}", start, end - 1, data.len()))
+                        .body(Body::from(data[start..end].to_vec()))
+                }
+                None => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+            },
+            _ => response.status(StatusCode::BAD_REQUEST).body(Body::empty()),
+        };
+
+        response.unwrap()
+    }
+
+    // Start the fake S3 service, return the storage config to access it.
+    pub fn start_fake_s3(fake_s3: Arc<Mutex<FakeS3>>) -> StorageConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().fallback(serve_fake_s3).with_state(fake_s3);
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+
+        StorageConfig {
+            store: ObjectStoreConfig::S3(S3Config {
+                bucket: "test-bucket".to_string(),
+                endpoint,
+                access_key_id: SecretString::from("test".to_string()),
+                access_key_secret: SecretString::from("test".to_string()),
+                region: None,
+            }),
+            ..test_storage_config()
+        }
+    }
+
+    pub async fn upload_data(tiered_store: &TieredStore, start_offset: usize, end_offset: usize, data: &[u8],
+                             sealed_timestamp: u64) -> Result<()> {
+        let segment = SegmentMeta::new(start_offset, end_offset, data.len(), sealed_timestamp);
+        tiered_store.upload_segment(segment, |offset, size| {
+            future::ready(Ok(data[offset - start_offset..offset - start_offset + size].to_vec()))
+        }).await?;
+        tiered_store.flush_manifests().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_upload_read() -> Result<()> {
+        let dir_path = create_temp_dir("tiered_store_test");
+        let store_path = dir_path.path().to_str().unwrap();
+        let object_store = memory_operator();
+
+        let tiered_store = TieredStore::open(store_path, object_store.clone(), &test_storage_config()).await?;
+        let mut segment_data = Vec::new();
+        for i in 0..10 {
+            segment_data.extend(encode_record(format!("msg {}", i).as_bytes(), 0));
+        }
+        let record_size = segment_data.len() / 10;
+        // the segment covers a range longer than its data, like a compacted file
+        upload_data(&tiered_store, 100, 100 + segment_data.len() + 10, &segment_data, current_timestamp_ms()).await?;
+        assert_eq!(tiered_store.get_min_offset(), Some(100));
+
+        let record = tiered_store.read(100 + record_size * 3, record_size).await?;
+        assert_eq!(decode_record(&record, 0)?, b"msg 3");
+        // the records are scanned in order, the ones across two reads are completed
+        let mut scanned_records = Vec::new();
+        tiered_store.scan_records(record_size + 3, |offset, record| {
+            scanned_records.push((offset, decode_record(record, 0)?.to_vec()));
+            Ok(())
+        }).await?;
+        assert_eq!(scanned_records.len(), 10);
+        assert_eq!(scanned_records[7], (100 + record_size * 7, b"msg 7".to_vec()));
+        assert!(tiered_store.read(50, 10).await.is_err());
+
+        // the segments are loaded from the manifest, even with an empty local directory
+        drop(tiered_store);
+        let new_dir_path = create_temp_dir("tiered_store_test");
+        let tiered_store = TieredStore::open(new_dir_path.path().to_str().unwrap(), object_store,
+                                             &test_storage_config()).await?;
+        assert_eq!(tiered_store.get_uploaded_offset(), 100 + record_size * 10 + 10);
+        let record = tiered_store.read(100 + record_size * 9, record_size).await?;
+        assert_eq!(decode_record(&record, 0)?, b"msg 9");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_resume_upload() -> Result<()> {
+        let dir_path = create_temp_dir("tiered_store_test");
+        let object_store_dir = create_temp_dir("object_store_test");
+        let store_path = dir_path.path().to_str().unwrap();
+        let object_store = build_operator(&ObjectStoreConfig::Fs(FsConfig {
+            root: object_store_dir.path().to_str().unwrap().to_string(),
+        }))?;
+        let segment_data: Vec<u8> = (0..100).collect();
+        let segment = SegmentMeta::new(0, 100, 100, 0);
+
+        // the upload is interrupted after 3 parts
+        let tiered_store = TieredStore::open(store_path, object_store.clone(), &test_storage_config()).await?;
+        let upload_result = tiered_store.upload_segment(segment.clone(), |offset, size| {
+            future::ready(if offset >= 48 {
+                Err(InvalidInput { location: location!(), msg: "Interrupted".to_string() })
+            } else {
+                Ok(segment_data[offset..offset + size].to_vec())
+            })
+        }).await;
+        assert!(upload_result.is_err());
+        assert_eq!(tiered_store.get_uploaded_offset(), 0);
+
+        // the file system service appends to the object, the upload continues from the 4th part,
+        // and the uploaded parts are only read to compute the checksum
+        drop(tiered_store);
+        let tiered_store = TieredStore::open(store_path, object_store.clone(), &test_storage_config()).await?;
+        let read_size = AtomicUsize::new(0);
+        tiered_store.upload_segment(segment, |offset, size| {
+            read_size.fetch_add(size, Ordering::Relaxed);
+            future::ready(Ok(segment_data[offset..offset + size].to_vec()))
+        }).await?;
+        tiered_store.flush_manifests().await?;
+        assert_eq!(read_size.load(Ordering::Relaxed), 100);
+        assert_eq!(tiered_store.read(0, 100).await?, segment_data);
+        assert_eq!(tiered_store.get_segments("commitlog")[0].checksum, crc32fast::hash(&segment_data));
+
+        // an interrupted upload of a segment which won't be uploaded again is aborted
+        let segment = SegmentMeta::new(100, 200, 100, 0);
+        let _ = tiered_store.upload_segment(segment, |offset, size| {
+            future::ready(if offset >= 132 {
+                Err(InvalidInput { location: location!(), msg: "Interrupted".to_string() })
+            } else {
+                Ok(segment_data[offset - 100..offset - 100 + size].to_vec())
+            })
+        }).await;
+        assert!(object_store_dir.path().join("commitlog/00000000000000000100").exists());
+        upload_data(&tiered_store, 200, 300, &segment_data, 0).await?;
+        assert!(!object_store_dir.path().join("commitlog/00000000000000000100").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_resume_multipart_upload() -> Result<()> {
+        let dir_path = create_temp_dir("tiered_store_test");
+        let store_path = dir_path.path().to_str().unwrap();
+        let fake_s3 = Arc::new(Mutex::new(FakeS3::default()));
+        let storage_config = start_fake_s3(fake_s3.clone());
+        let object_store = build_operator(&storage_config.store)?;
+        let segment_data: Vec<u8> = (0..100).collect();
+        let segment = SegmentMeta::new(0, 100, 100, 0);
+
+        // the upload is interrupted after 3 parts, which are kept by the multipart upload
+        let tiered_store = TieredStore::open(store_path, object_store.clone(), &storage_config).await?;
+        let upload_result = tiered_store.upload_segment(segment.clone(), |offset, size| {
+            future::ready(if offset >= 48 {
+                Err(InvalidInput { location: location!(), msg: "Interrupted".to_string() })
+            } else {
+                Ok(segment_data[offset..offset + size].to_vec())
+            })
+        }).await;
+        assert!(upload_result.is_err());
+        let multipart_upload = tiered_store.load_progress()?.unwrap().multipart_upload.unwrap();
+        assert_eq!(multipart_upload.parts.len(), 3);
+        assert_eq!(fake_s3.lock().unwrap().uploads[&multipart_upload.upload_id].len(), 3);
+
+        // the upload goes on from the 4th part by the persisted upload id
+        drop(tiered_store);
+        let tiered_store = TieredStore::open(store_path, object_store.clone(), &storage_config).await?;
+        upload_data(&tiered_store, 0, 100, &segment_data, 0).await?;
+        assert_eq!(fake_s3.lock().unwrap().upload_num, 1);
+        assert!(fake_s3.lock().unwrap().uploads.is_empty());
+        assert_eq!(tiered_store.read(0, 100).await?, segment_data);
+        assert!(tiered_store.load_progress()?.is_none());
+
+        // an interrupted upload of a segment which won't be uploaded again is aborted
+        let segment = SegmentMeta::new(100, 200, 100, 0);
+        let _ = tiered_store.upload_segment(segment, |offset, size| {
+            future::ready(if offset >= 132 {
+                Err(InvalidInput { location: location!(), msg: "Interrupted".to_string() })
+            } else {
+                Ok(segment_data[offset - 100..offset - 100 + size].to_vec())
+            })
+        }).await;
+        assert_eq!(fake_s3.lock().unwrap().uploads.len(), 1);
+        drop(tiered_store);
+        let tiered_store = TieredStore::open(store_path, object_store.clone(), &storage_config).await?;
+        upload_data(&tiered_store, 200, 300, &segment_data, 0).await?;
+        assert!(fake_s3.lock().unwrap().uploads.is_empty());
+        assert_eq!(tiered_store.read(250, 10).await?, segment_data[50..60].to_vec());
+
+        // the upload starts over if its multipart upload is completed before the restart
+        tiered_store.flush_progress(&UploadProgress {
+            object_path: "commitlog/00000000000000000300".to_string(),
+            data_size: 100,
+            uploaded_size: 100,
+            multipart_upload: Some(MultipartUpload { upload_id: "upload-1".to_string(), parts: Vec::new() }),
+        })?;
+        upload_data(&tiered_store, 300, 400, &segment_data, 0).await?;
+        assert_eq!(tiered_store.read(300, 100).await?, segment_data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_flush_manifests() -> Result<()> {
+        let dir_path = create_temp_dir("tiered_store_test");
+        let object_store = memory_operator();
+        let tiered_store = TieredStore::open(dir_path.path().to_str().unwrap(), object_store.clone(),
+                                             &test_storage_config()).await?;
+
+        // the uploaded segments are pending until the manifests are flushed
+        for i in 0..3 {
+            tiered_store.upload_segment(SegmentMeta::new(i * 10, i * 10 + 10, 10, 0), |_, size| {
+                future::ready(Ok(vec![i as u8; size]))
+            }).await?;
+        }
+        assert_eq!(tiered_store.get_next_upload_offset(), Some(30));
+        assert_eq!(tiered_store.get_uploaded_offset(), 0);
+        assert!(Manifest::load(&object_store, "commitlog").await?.is_none());
+        assert!(tiered_store.read(10, 10).await.is_err());
+
+        assert_eq!(tiered_store.flush_manifests().await?, 3);
+        assert_eq!(Manifest::load(&object_store, "commitlog").await?.unwrap().segments.len(), 3);
+        assert_eq!(tiered_store.get_uploaded_offset(), 30);
+        assert_eq!(tiered_store.read(10, 10).await?, vec![1; 10]);
+
+        // a full batch is flushed by the upload
+        for i in 3..3 + MANIFEST_BATCH_SIZE {
+            tiered_store.upload_segment(SegmentMeta::new(i * 10, i * 10 + 10, 10, 0), |_, size| {
+                future::ready(Ok(vec![i as u8; size]))
+            }).await?;
+        }
+        assert_eq!(tiered_store.get_uploaded_offset(), (3 + MANIFEST_BATCH_SIZE) * 10);
+
+        // the segments which aren't flushed are uploaded again after a restart
+        tiered_store.upload_segment(SegmentMeta::new(190, 200, 10, 0), |_, size| future::ready(Ok(vec![0; size]))).await?;
+        drop(tiered_store);
+        let tiered_store = TieredStore::open(dir_path.path().to_str().unwrap(), object_store, &test_storage_config()).await?;
+        assert_eq!(tiered_store.get_next_upload_offset(), Some(190));
+        upload_data(&tiered_store, 190, 200, &[19; 10], 0).await?;
+        assert_eq!(tiered_store.read(190, 10).await?, vec![19; 10]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_expire_segments() -> Result<()> {
+        let dir_path = create_temp_dir("tiered_store_test");
+        let tiered_store = TieredStore::open(dir_path.path().to_str().unwrap(), memory_operator(),
+                                             &test_storage_config()).await?;
+
+        let now = current_timestamp_ms();
+        upload_data(&tiered_store, 0, 100, &[1; 100], now - 10000).await?;
+        upload_data(&tiered_store, 100, 200, &[2; 100], now - 5000).await?;
+        upload_data(&tiered_store, 200, 300, &[3; 100], now).await?;
+
+        let expired_segments = tiered_store.take_expired_segments(Some(8000), None, 400).await?;
+        assert_eq!(expired_segments.len(), 1);
+        let expired_segments = tiered_store.take_expired_segments(None, Some(250), 400).await?;
+        assert_eq!(expired_segments.len(), 1);
+        assert_eq!(expired_segments[0].start_offset, 100);
+        tiered_store.delete_segments(&expired_segments).await?;
+
+        assert_eq!(tiered_store.get_min_offset(), Some(200));
+        assert!(tiered_store.read(150, 10).await.is_err());
+        assert_eq!(tiered_store.read(250, 10).await?, vec![3; 10]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn test_index_segments() -> Result<()> {
+        let dir_path = create_temp_dir("tiered_store_test");
+        let object_store = memory_operator();
+        let tiered_store = TieredStore::open(dir_path.path().to_str().unwrap(), object_store.clone(),
+                                             &test_storage_config()).await?;
+
+        let index_data: Vec<u8> = (0..100).collect();
+        tiered_store.upload_index_segment("test_topic", 0, SegmentMeta::new(0, 60, 60, 0), &index_data[..60]).await?;
+        tiered_store.upload_index_segment("test_topic", 0, SegmentMeta::new(60, 100, 40, 0), &index_data[60..]).await?;
+        assert_eq!(tiered_store.get_index_uploaded_offset("test_topic", 0), 100);
+        assert_eq!(tiered_store.flush_manifests().await?, 2);
+
+        // the queues are found from the object store, and split into the files of the local size
+        let new_dir_path = create_temp_dir("tiered_store_test");
+        let tiered_store = TieredStore::open(new_dir_path.path().to_str().unwrap(), object_store.clone(),
+                                             &test_storage_config()).await?;
+        assert_eq!(tiered_store.download_index_files(40).await?, 2);
+        let queue_dir = new_dir_path.path().join("index/test_topic/0");
+        assert_eq!(std::fs::read(queue_dir.join("00000000000000000040")).unwrap(), index_data[40..80].to_vec());
+        assert_eq!(std::fs::read(queue_dir.join("00000000000000000080")).unwrap(), index_data[80..].to_vec());
+        // the existing queues aren't downloaded again
+        assert_eq!(tiered_store.download_index_files(40).await?, 0);
+
+        tiered_store.delete_index_segments_before("test_topic", 0, 80).await?;
+        assert_eq!(tiered_store.get_segments("index/test_topic/0").len(), 1);
+        let tiered_store = TieredStore::open(new_dir_path.path().to_str().unwrap(), object_store,
+                                             &test_storage_config()).await?;
+        assert_eq!(tiered_store.get_segments("index/test_topic/0")[0].start_offset, 60);
+
+        Ok(())
+    }
+}
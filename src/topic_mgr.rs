@@ -2,21 +2,66 @@ use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
-use snafu::ResultExt;
-use crate::error::{RusqliteSnafu, StdIOSnafu};
+use snafu::{location, Location, ResultExt};
+use crate::config::{CompressionCodec, FlushPolicy};
+use crate::error::{Error, RusqliteSnafu, StdIOSnafu};
 use crate::error::Result;
+use crate::storage::record::current_timestamp_ms;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Topic {
     topic_name: String,
     partition_number: u32,
+    #[serde(default)]
+    config: TopicConfig,
+}
+
+// Per topic settings, which override the global ones in the config options.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopicConfig {
+    pub flush_policy: Option<FlushPolicy>,
+    pub retention_ms: Option<u64>,
+    pub retention_bytes: Option<u64>,
+    pub cleanup_policy: Option<CleanupPolicy>,
+    // how long the latest tombstone of a key is kept in a compacted topic
+    pub tombstone_retention_ms: Option<u64>,
+    // codec of the message payloads, unless the batch chooses one
+    pub compression: Option<CompressionCodec>,
+}
+
+/*
+ * How the old messages of a topic are cleaned:
+ * Delete - delete them by the retention time and size.
+ * Compact - keep only the latest message of each key, a message without payload is a tombstone
+ *           which deletes the key.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CleanupPolicy {
+    #[default]
+    Delete,
+    Compact,
+}
+
+impl Topic {
+    pub fn get_topic_name(&self) -> &str {
+        self.topic_name.as_str()
+    }
+
+    pub fn get_config(&self) -> &TopicConfig {
+        &self.config
+    }
 }
 
 pub struct TopicMgr {
     db_connection: Arc<Mutex<Connection>>,
     topic_cache: Arc<RwLock<HashMap<String, Topic>>>,
+    // configs of the existing topics which are looked up on the write path, the missing topics
+    // aren't cached, or the map could grow with any topic name of the requests
+    topic_config_cache: Arc<RwLock<HashMap<String, TopicConfig>>>,
 }
 
 impl TopicMgr {
@@ -33,23 +78,49 @@ impl TopicMgr {
             "CREATE TABLE IF NOT EXISTS topic (\
             id INTEGER PRIMARY KEY, \
             topic_name TEXT, \
-            partition_number INTEGER)",
+            partition_number INTEGER, \
+            config TEXT)",
             [],
         ).unwrap();
 
+        // add the config column to the table created by old versions
+        let config_column_num: u32 = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('topic') WHERE name='config'",
+            [],
+            |row| row.get(0),
+        ).context(RusqliteSnafu)?;
+        if config_column_num == 0 {
+            conn.execute("ALTER TABLE topic ADD COLUMN config TEXT", []).context(RusqliteSnafu)?;
+        }
+
+        // committed offsets of the consumer groups, which are the next queue offsets to consume
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS consumer_offset (\
+            group_name TEXT, \
+            topic_name TEXT, \
+            queue_id INTEGER, \
+            queue_offset INTEGER, \
+            commit_timestamp INTEGER, \
+            PRIMARY KEY (group_name, topic_name, queue_id))",
+            [],
+        ).context(RusqliteSnafu)?;
+
         Ok(TopicMgr {
             db_connection: Arc::new(Mutex::new(conn)),
             topic_cache: Arc::new(RwLock::new(HashMap::new())),
+            topic_config_cache: Arc::new(RwLock::new(HashMap::new())),
         })
     }
 
     pub fn create_topic(&self, topic: Topic) -> Result<()> {
         let conn = self.db_connection.lock().unwrap();
+        let config_json = serde_json::to_string(&topic.config).unwrap();
         conn.execute(
-            "INSERT INTO topic (topic_name, partition_number) VALUES (?1, ?2)",
-            params![topic.topic_name, topic.partition_number],
+            "INSERT INTO topic (topic_name, partition_number, config) VALUES (?1, ?2, ?3)",
+            params![topic.topic_name, topic.partition_number, config_json],
         ).context(RusqliteSnafu)?;
 
+        self.topic_config_cache.write().unwrap().remove(topic.topic_name.as_str());
         let mut topics = self.topic_cache.write().unwrap();
 
         let topic_name = topic.topic_name.clone();
@@ -65,7 +136,11 @@ impl TopicMgr {
         conn.execute("DELETE FROM topic WHERE topic_name=?1",
                      params![topic_name],
         ).context(RusqliteSnafu)?;
+        conn.execute("DELETE FROM consumer_offset WHERE topic_name=?1",
+                     params![topic_name],
+        ).context(RusqliteSnafu)?;
 
+        self.topic_config_cache.write().unwrap().remove(topic_name);
         let mut topics = self.topic_cache.write().unwrap();
         topics.remove(topic_name);
 
@@ -75,12 +150,7 @@ impl TopicMgr {
     pub fn list_topics(&self) -> Result<Vec<Topic>> {
         let conn = self.db_connection.lock().unwrap();
         let mut stmt = conn.prepare("SELECT * FROM topic").context(RusqliteSnafu)?;
-        let topic_iter = stmt.query_map([], |row| {
-            Ok(Topic {
-                topic_name: row.get(1)?,
-                partition_number: row.get(2)?,
-            })
-        }).context(RusqliteSnafu);
+        let topic_iter = stmt.query_map([], read_topic_row).context(RusqliteSnafu);
 
         match topic_iter {
             Ok(_) => {
@@ -109,20 +179,80 @@ impl TopicMgr {
         conn.query_row(
             "SELECT * FROM topic WHERE topic_name=?1",
             [topic_name],
-            |row| {
-                Ok(Topic {
-                    topic_name: row.get(1)?,
-                    partition_number: row.get(2)?,
-                })
-            },
+            read_topic_row,
         ).context(RusqliteSnafu)
     }
+
+    // Get the config of the topic, the default one is returned if the topic doesn't exist.
+    pub fn get_topic_config(&self, topic_name: &str) -> Result<TopicConfig> {
+        if let Some(topic_config) = self.topic_config_cache.read().unwrap().get(topic_name) {
+            return Ok(topic_config.clone());
+        }
+
+        let topic_config = match self.get_topic_info(topic_name) {
+            Ok(topic) => topic.config,
+            Err(Error::Rusqlite { source: rusqlite::Error::QueryReturnedNoRows, .. }) => return Ok(TopicConfig::default()),
+            Err(error) => return Err(error),
+        };
+
+        let mut topic_configs = self.topic_config_cache.write().unwrap();
+        topic_configs.insert(topic_name.to_string(), topic_config.clone());
+
+        Ok(topic_config)
+    }
+
+    // Commit the next queue offset the consumer group consumes from the queue.
+    pub fn commit_offset(&self, group: &str, topic_name: &str, queue_id: u32, queue_offset: usize) -> Result<()> {
+        if group.is_empty() {
+            return Err(Error::InvalidInput {
+                location: location!(),
+                msg: "Consumer group name is empty".to_string(),
+            });
+        }
+
+        let conn = self.db_connection.lock().unwrap();
+        conn.execute(
+            "INSERT INTO consumer_offset (group_name, topic_name, queue_id, queue_offset, commit_timestamp) \
+            VALUES (?1, ?2, ?3, ?4, ?5) \
+            ON CONFLICT (group_name, topic_name, queue_id) \
+            DO UPDATE SET queue_offset=excluded.queue_offset, commit_timestamp=excluded.commit_timestamp",
+            params![group, topic_name, queue_id, queue_offset as u64, current_timestamp_ms()],
+        ).context(RusqliteSnafu)?;
+
+        Ok(())
+    }
+
+    // Get the committed offset of the consumer group, None if the group hasn't committed on the queue.
+    pub fn fetch_offset(&self, group: &str, topic_name: &str, queue_id: u32) -> Result<Option<usize>> {
+        let conn = self.db_connection.lock().unwrap();
+        let queue_offset: Option<u64> = conn.query_row(
+            "SELECT queue_offset FROM consumer_offset WHERE group_name=?1 AND topic_name=?2 AND queue_id=?3",
+            params![group, topic_name, queue_id],
+            |row| row.get(0),
+        ).optional().context(RusqliteSnafu)?;
+
+        Ok(queue_offset.map(|queue_offset| queue_offset as usize))
+    }
+}
+
+fn read_topic_row(row: &Row) -> rusqlite::Result<Topic> {
+    let config_json: Option<String> = row.get(3)?;
+    let config = config_json
+        .and_then(|json| serde_json::from_str(json.as_str()).ok())
+        .unwrap_or_default();
+
+    Ok(Topic {
+        topic_name: row.get(1)?,
+        partition_number: row.get(2)?,
+        config,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use tempfile::{TempDir};
-    use crate::topic_mgr::{Topic, TopicMgr};
+    use crate::config::FlushPolicy;
+    use crate::topic_mgr::{Topic, TopicConfig, TopicMgr};
     use crate::error::Result;
 
     pub fn create_temp_dir(prefix: &str) -> TempDir {
@@ -138,15 +268,57 @@ mod tests {
         topic_mgr.create_topic(Topic {
             topic_name: "test_topic_name".to_string(),
             partition_number: 4,
+            config: TopicConfig { flush_policy: Some(FlushPolicy::Async), ..Default::default() },
         }).unwrap();
 
         topic_mgr.get_topic_info("test_topic_name").expect("topic should exist");
         topic_mgr.get_topic_info("test_topic_name_xxx").expect_err("topic should not exist");
 
+        let topic_config = topic_mgr.get_topic_config("test_topic_name")?;
+        assert_eq!(topic_config.flush_policy, Some(FlushPolicy::Async));
+        let topic_config = topic_mgr.get_topic_config("test_topic_name_xxx")?;
+        assert_eq!(topic_config.flush_policy, None);
+        // only the config of the existing topic is cached
+        let topic_configs = topic_mgr.topic_config_cache.read().unwrap();
+        assert!(topic_configs.contains_key("test_topic_name"));
+        assert!(!topic_configs.contains_key("test_topic_name_xxx"));
+        drop(topic_configs);
+
         topic_mgr.list_topics().expect("topic list should exist");
 
         topic_mgr.delete_topic("test_topic_name").unwrap();
 
         Ok(())
     }
+
+    #[tokio::test]
+    pub async fn test_consumer_offset() -> Result<()> {
+        let dir_path = create_temp_dir("topic_mgr_test");
+        let topic_mgr = TopicMgr::new(dir_path.path().to_str().unwrap())?;
+        topic_mgr.create_topic(Topic {
+            topic_name: "test_topic".to_string(),
+            partition_number: 2,
+            config: TopicConfig::default(),
+        })?;
+
+        assert_eq!(topic_mgr.fetch_offset("group_a", "test_topic", 0)?, None);
+        topic_mgr.commit_offset("group_a", "test_topic", 0, 10)?;
+        topic_mgr.commit_offset("group_a", "test_topic", 0, 15)?;
+        topic_mgr.commit_offset("group_a", "test_topic", 1, 3)?;
+        topic_mgr.commit_offset("group_b", "test_topic", 0, 7)?;
+        assert!(topic_mgr.commit_offset("", "test_topic", 0, 1).is_err());
+
+        assert_eq!(topic_mgr.fetch_offset("group_a", "test_topic", 0)?, Some(15));
+        assert_eq!(topic_mgr.fetch_offset("group_a", "test_topic", 1)?, Some(3));
+        assert_eq!(topic_mgr.fetch_offset("group_b", "test_topic", 0)?, Some(7));
+
+        // the offsets are persisted, and deleted with the topic
+        drop(topic_mgr);
+        let topic_mgr = TopicMgr::new(dir_path.path().to_str().unwrap())?;
+        assert_eq!(topic_mgr.fetch_offset("group_a", "test_topic", 0)?, Some(15));
+        topic_mgr.delete_topic("test_topic")?;
+        assert_eq!(topic_mgr.fetch_offset("group_a", "test_topic", 0)?, None);
+
+        Ok(())
+    }
 }
\ No newline at end of file